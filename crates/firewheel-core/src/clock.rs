@@ -1,4 +1,7 @@
-use std::ops::{Add, AddAssign, Sub, SubAssign};
+use std::{
+    collections::VecDeque,
+    ops::{Add, AddAssign, Sub, SubAssign},
+};
 
 /// When a particular audio event should occur.
 #[derive(Default, Debug, Clone, Copy, PartialEq)]
@@ -290,3 +293,170 @@ impl TempoMap {
         }
     }
 }
+
+/// A helper which computes a tempo from a series of taps, i.e. from a "tap
+/// tempo" button used during a live performance.
+///
+/// The tempo is the average of the intervals between the last
+/// [`TapTempo::num_taps`] taps. A tap whose interval differs from the
+/// current average by more than [`TapTempo::outlier_tolerance`] is rejected
+/// as an outlier (i.e. an accidental double tap). Two outliers in a row are
+/// taken as a deliberate change in tempo, and averaging starts over from
+/// there. If no tap happens for [`TapTempo::timeout_secs`], then the next
+/// tap starts over as well.
+#[derive(Debug, Clone)]
+pub struct TapTempo {
+    /// The maximum number of taps to average over.
+    ///
+    /// By default this is set to `4`.
+    pub num_taps: usize,
+    /// How much the interval of a tap may differ from the current average
+    /// interval before the tap is rejected, as a fraction of the average.
+    ///
+    /// By default this is set to `0.2`.
+    pub outlier_tolerance: f64,
+    /// If the time since the last tap is longer than this, then the next tap
+    /// starts a new measurement.
+    ///
+    /// By default this is set to `2.0`.
+    pub timeout_secs: f64,
+
+    intervals: VecDeque<f64>,
+    last_tap: Option<ClockSeconds>,
+    outlier_tap: Option<ClockSeconds>,
+}
+
+impl TapTempo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a tap at the given time, and return the new tempo in beats
+    /// per minute if one could be computed.
+    ///
+    /// Use the same clock for every tap, i.e. [`AudioGraph::clock_seconds`].
+    pub fn tap(&mut self, time: ClockSeconds) -> Option<f64> {
+        let Some(last_tap) = self.last_tap else {
+            self.last_tap = Some(time);
+            return None;
+        };
+
+        let interval = time.0 - last_tap.0;
+        if interval <= 0.0 {
+            return self.beats_per_minute();
+        }
+
+        if interval > self.timeout_secs {
+            self.reset();
+            self.last_tap = Some(time);
+            return None;
+        }
+
+        if let Some(average) = self.average_interval() {
+            if (interval - average).abs() > average * self.outlier_tolerance {
+                if let Some(outlier_tap) = self.outlier_tap.take() {
+                    // Two outliers in a row, so the tempo has changed.
+                    self.intervals.clear();
+                    self.push_interval(time.0 - outlier_tap.0);
+                    self.last_tap = Some(time);
+                } else {
+                    self.outlier_tap = Some(time);
+                }
+
+                return self.beats_per_minute();
+            }
+        }
+
+        self.outlier_tap = None;
+        self.push_interval(interval);
+        self.last_tap = Some(time);
+
+        self.beats_per_minute()
+    }
+
+    /// The current tempo in beats per minute, or `None` if there have not
+    /// been enough taps yet.
+    pub fn beats_per_minute(&self) -> Option<f64> {
+        self.average_interval().map(|interval| 60.0 / interval)
+    }
+
+    /// The current tempo as a [`TempoMap`] that can be used to drive
+    /// tempo-synced nodes, or `None` if there have not been enough taps yet.
+    pub fn tempo_map(&self) -> Option<TempoMap> {
+        self.beats_per_minute()
+            .map(|beats_per_minute| TempoMap::Constant { beats_per_minute })
+    }
+
+    /// Forget all previous taps.
+    pub fn reset(&mut self) {
+        self.intervals.clear();
+        self.last_tap = None;
+        self.outlier_tap = None;
+    }
+
+    fn push_interval(&mut self, interval: f64) {
+        self.intervals.push_back(interval);
+
+        while self.intervals.len() > self.num_taps.max(2) - 1 {
+            self.intervals.pop_front();
+        }
+    }
+
+    fn average_interval(&self) -> Option<f64> {
+        if self.intervals.is_empty() {
+            None
+        } else {
+            Some(self.intervals.iter().sum::<f64>() / self.intervals.len() as f64)
+        }
+    }
+}
+
+impl Default for TapTempo {
+    fn default() -> Self {
+        Self {
+            num_taps: 4,
+            outlier_tolerance: 0.2,
+            timeout_secs: 2.0,
+            intervals: VecDeque::new(),
+            last_tap: None,
+            outlier_tap: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tap_tempo() {
+        let mut tap_tempo = TapTempo::new();
+        assert_eq!(tap_tempo.tap(ClockSeconds(10.0)), None);
+
+        for i in 1..8 {
+            let bpm = tap_tempo.tap(ClockSeconds(10.0 + i as f64 * 0.5)).unwrap();
+            assert!((bpm - 120.0).abs() < 1e-9);
+        }
+        assert_eq!(
+            tap_tempo.tempo_map(),
+            Some(TempoMap::Constant {
+                beats_per_minute: 120.0
+            })
+        );
+
+        // An accidental tap in between is rejected.
+        let bpm = tap_tempo.tap(ClockSeconds(13.6)).unwrap();
+        assert!((bpm - 120.0).abs() < 1e-9);
+        let bpm = tap_tempo.tap(ClockSeconds(14.0)).unwrap();
+        assert!((bpm - 120.0).abs() < 1e-9);
+
+        // Two outliers in a row change the tempo.
+        tap_tempo.tap(ClockSeconds(15.0));
+        let bpm = tap_tempo.tap(ClockSeconds(16.0)).unwrap();
+        assert!((bpm - 60.0).abs() < 1e-9);
+
+        // Taps after a long pause start over.
+        assert_eq!(tap_tempo.tap(ClockSeconds(18.5)), None);
+        assert_eq!(tap_tempo.beats_per_minute(), None);
+    }
+}
@@ -65,6 +65,11 @@ pub trait AudioNode<C>: 'static + Downcast {
     /// Note the host will call [`AudioNode::channel_config_supported`] with
     /// the given number of inputs and outputs before calling this method, and
     /// it will only call this method if that method returned `Ok`.
+    ///
+    /// The host may also call this method again while the node is already
+    /// active (i.e. when the sample rate of the stream changes). In that case
+    /// the new processor replaces the old one, and the old processor is
+    /// dropped without [`AudioNode::deactivate`] being called.
     fn activate(
         &mut self,
         stream_info: &StreamInfo,
@@ -116,6 +121,35 @@ pub struct AudioNodeInfo {
     ///
     /// By default this is set to `false`.
     pub updates: bool,
+
+    /// Whether or not this node's outputs are always silent when all of
+    /// its inputs are silent.
+    ///
+    /// If this is `true`, then the processor will not be called while all
+    /// of its inputs are silent, and its outputs will be marked as silent.
+    /// This can save a lot of processing in large graphs with many idle
+    /// nodes.
+    ///
+    /// Only set this to `true` if the node does not generate any sound on
+    /// its own and has no tail (i.e. do not set this on nodes like
+    /// oscillators, delays, or reverbs). Nodes with no inputs are never
+    /// skipped.
+    ///
+    /// By default this is set to `false`.
+    pub silent_when_inputs_silent: bool,
+
+    /// Whether or not this node needs exact IEEE floating point behavior
+    /// for denormal (subnormal) numbers.
+    ///
+    /// Denormal numbers are normally flushed to zero while the graph is
+    /// processed, since they are extremely slow to compute with on x86
+    /// processors. If this is `true`, then flushing is temporarily
+    /// disabled while this node is processed and the previous setting is
+    /// restored afterwards. This is useful for measurement and analysis
+    /// nodes.
+    ///
+    /// By default this is set to `false`.
+    pub preserve_denormals: bool,
 }
 
 impl Default for AudioNodeInfo {
@@ -128,6 +162,8 @@ impl Default for AudioNodeInfo {
             default_channel_config: ChannelConfig::default(),
             equal_num_ins_and_outs: false,
             updates: false,
+            silent_when_inputs_silent: false,
+            preserve_denormals: false,
         }
     }
 }
@@ -137,7 +173,7 @@ impl Default for AudioNodeInfo {
 ///
 /// The generic is for the user's custom global processing context which
 /// is sent to the processor in [`ProcInfo`].
-pub trait AudioNodeProcessor<C>: 'static + Send {
+pub trait AudioNodeProcessor<C>: 'static + Send + Downcast {
     /// Process the given block of audio. Only process data in the
     /// buffers up to `samples`.
     ///
@@ -153,8 +189,55 @@ pub trait AudioNodeProcessor<C>: 'static + Send {
         proc_info: ProcInfo,
         cx: &mut C,
     ) -> ProcessStatus;
+
+    /// Called when this processor takes over from the processor `old` of
+    /// the same node in the audio graph (i.e. when the node is replaced),
+    /// right before this processor is first processed.
+    ///
+    /// This can be used to carry over state such as filter history or
+    /// delay lines from the old processor to avoid clicks. Downcast `old`
+    /// to check whether it is of a compatible type, and leave the state
+    /// untouched if it is not.
+    ///
+    /// This is called in the audio thread, so it must be realtime-safe.
+    ///
+    /// By default this does nothing.
+    fn migrate_state_from(&mut self, old: &dyn AudioNodeProcessor<C>) {
+        let _ = old;
+    }
+
+    /// Write the complete DSP state of this processor (such as filter
+    /// history, delay lines, and reverb tails) to `out`, so that a session
+    /// can be saved and later resumed exactly where it left off with
+    /// [`AudioNodeProcessor::restore_state`].
+    ///
+    /// `out` is empty and has the capacity which was requested with
+    /// `FirewheelGraphCtx::request_node_state`.
+    ///
+    /// This is called in the audio thread, so it must be realtime-safe.
+    /// Writing more than the capacity of `out` will allocate.
+    ///
+    /// By default this does nothing.
+    fn serialize_state(&self, out: &mut Vec<u8>) {
+        let _ = out;
+    }
+
+    /// Restore the DSP state which was previously returned by
+    /// [`AudioNodeProcessor::serialize_state`].
+    ///
+    /// The state may come from a processor of a different version or
+    /// configuration, so leave the state untouched if it is not valid.
+    ///
+    /// This is called in the audio thread, so it must be realtime-safe.
+    ///
+    /// By default this does nothing.
+    fn restore_state(&mut self, state: &[u8]) {
+        let _ = state;
+    }
 }
 
+downcast_rs::impl_downcast!(AudioNodeProcessor<C>);
+
 /// Additional information for processing audio
 #[derive(Debug, Clone)]
 pub struct ProcInfo {
@@ -238,3 +321,60 @@ impl ProcessStatus {
         Self::OutputsModified { out_silence_mask }
     }
 }
+
+/// The runtime state of a node (i.e. the playback position of a sample
+/// player or the stage of an envelope), in the form of a small `Copy`
+/// struct which is typed per node.
+///
+/// The node handle keeps the [`NodeStatus`] to query the state, and gives
+/// a [`NodeStatusWriter`] to each processor it creates, which the processor
+/// uses to write the latest state once every block.
+///
+/// The status is passed through a triple buffer, so neither side ever
+/// waits for the other: writing in the audio thread is wait-free, and
+/// reading always returns the latest complete status.
+pub struct NodeStatus<T: Copy + Send + 'static> {
+    output: triple_buffer::Output<T>,
+}
+
+impl<T: Copy + Send + 'static> NodeStatus<T> {
+    pub fn new(initial: T) -> Self {
+        let (_, output) = triple_buffer::triple_buffer(&initial);
+
+        Self { output }
+    }
+
+    /// The latest status written by the processor.
+    pub fn get(&mut self) -> T {
+        *self.output.read()
+    }
+
+    /// Create a writer to give to a new processor (i.e. in
+    /// [`AudioNode::activate`]).
+    ///
+    /// A triple buffer only has a single writer, so this starts a new one
+    /// (initialized with the latest status), and from then on only the
+    /// status written by the newest processor is read.
+    pub fn writer(&mut self) -> NodeStatusWriter<T> {
+        let latest = self.get();
+        let (input, output) = triple_buffer::triple_buffer(&latest);
+        self.output = output;
+
+        NodeStatusWriter { input }
+    }
+}
+
+/// The processor side of a [`NodeStatus`].
+pub struct NodeStatusWriter<T: Copy + Send + 'static> {
+    input: triple_buffer::Input<T>,
+}
+
+impl<T: Copy + Send + 'static> NodeStatusWriter<T> {
+    /// Write the latest status.
+    ///
+    /// This never blocks or allocates, so it is safe to call in the audio
+    /// thread.
+    pub fn write(&mut self, status: T) {
+        self.input.write(status);
+    }
+}
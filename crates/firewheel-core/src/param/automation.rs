@@ -0,0 +1,237 @@
+use arrayvec::ArrayVec;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use crate::{clock::ClockSamples, node::ProcInfo};
+
+/// The maximum number of events which can be waiting in an
+/// [`AutomationLane`] to be picked up by the processor.
+pub const AUTOMATION_LANE_CAPACITY: usize = 16;
+
+/// An event in an [`AutomationLane`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AutomationEvent {
+    /// Jump to `value` at the given time.
+    SetValue { value: f32, at: ClockSamples },
+    /// Ramp linearly to `value`, reaching it at the given time.
+    ///
+    /// The ramp starts at the time and value of the previous event in the
+    /// lane. If there is no previous event waiting in the lane, then it
+    /// starts from the current value at the time the processor receives
+    /// the ramp.
+    LinearRamp { value: f32, end: ClockSamples },
+}
+
+impl AutomationEvent {
+    fn time(&self) -> ClockSamples {
+        match *self {
+            Self::SetValue { at, .. } => at,
+            Self::LinearRamp { end, .. } => end,
+        }
+    }
+
+    fn value(&self) -> f32 {
+        match *self {
+            Self::SetValue { value, .. } | Self::LinearRamp { value, .. } => value,
+        }
+    }
+}
+
+/// A parameter which follows a timeline of events with sample accuracy
+/// (i.e. to fade in an effect over a few seconds).
+///
+/// The node handle schedules events with [`AutomationLane::set_value_at`]
+/// and [`AutomationLane::linear_ramp_to`], and the processor computes the
+/// value of the parameter for every sample with
+/// [`AutomationReceiver::process`]. Events must be scheduled in order of
+/// their time.
+///
+/// Events are sent to the processor through a preallocated lock-free
+/// queue, so the processor never waits for the node handle and never
+/// misses a block of events.
+pub struct AutomationLane {
+    producer: rtrb::Producer<LaneEvent>,
+    /// The number of times the lane was cancelled, shared with the receiver.
+    cancel_count: Arc<AtomicU64>,
+}
+
+/// An event along with the value of the cancel count when it was pushed,
+/// so that the receiver can discard the events which were pushed before a
+/// cancel.
+#[derive(Clone, Copy)]
+struct LaneEvent {
+    event: AutomationEvent,
+    cancel_count: u64,
+}
+
+impl AutomationLane {
+    pub fn new() -> Self {
+        Self {
+            producer: rtrb::RingBuffer::new(AUTOMATION_LANE_CAPACITY).0,
+            cancel_count: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Jump to `value` at the given time of the sample clock.
+    ///
+    /// Returns `false` if the lane is full, in which case the event is
+    /// discarded.
+    pub fn set_value_at(&mut self, value: f32, at: ClockSamples) -> bool {
+        self.push(AutomationEvent::SetValue { value, at })
+    }
+
+    /// Ramp linearly to `value`, reaching it at the given time of the
+    /// sample clock. See [`AutomationEvent::LinearRamp`] for where the ramp
+    /// starts.
+    ///
+    /// Returns `false` if the lane is full, in which case the event is
+    /// discarded.
+    pub fn linear_ramp_to(&mut self, value: f32, end: ClockSamples) -> bool {
+        self.push(AutomationEvent::LinearRamp { value, end })
+    }
+
+    /// Discard all events which have not taken effect yet, including the
+    /// ones the processor has already received. The parameter keeps its
+    /// current value.
+    ///
+    /// Events which were not received by the processor yet still take up
+    /// room in the lane until the next processed block.
+    pub fn cancel(&mut self) {
+        self.cancel_count.fetch_add(1, Ordering::Release);
+    }
+
+    fn push(&mut self, event: AutomationEvent) -> bool {
+        self.producer
+            .push(LaneEvent {
+                event,
+                cancel_count: self.cancel_count.load(Ordering::Relaxed),
+            })
+            .is_ok()
+    }
+
+    /// Create a receiver to give to a new processor (i.e. in
+    /// [`AudioNode::activate`]), starting at the given value.
+    ///
+    /// The queue only has a single consumer, so this starts a new one, and
+    /// from then on only the newest processor receives events.
+    ///
+    /// [`AudioNode::activate`]: crate::node::AudioNode::activate
+    pub fn receiver(&mut self, value: f32) -> AutomationReceiver {
+        let (producer, consumer) = rtrb::RingBuffer::new(AUTOMATION_LANE_CAPACITY);
+        self.producer = producer;
+
+        AutomationReceiver {
+            consumer,
+            cancel_count: Arc::clone(&self.cancel_count),
+            seen_cancel_count: self.cancel_count.load(Ordering::Acquire),
+            events: ArrayVec::new(),
+            value,
+            ramp_start: (ClockSamples(0), value),
+        }
+    }
+}
+
+impl Default for AutomationLane {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The processor side of an [`AutomationLane`].
+pub struct AutomationReceiver {
+    consumer: rtrb::Consumer<LaneEvent>,
+    cancel_count: Arc<AtomicU64>,
+    /// The latest cancel count the received events were pushed after.
+    seen_cancel_count: u64,
+    /// The events which have been received but not yet reached.
+    events: ArrayVec<AutomationEvent, AUTOMATION_LANE_CAPACITY>,
+    value: f32,
+    /// The time and value the next ramp starts from.
+    ramp_start: (ClockSamples, f32),
+}
+
+impl AutomationReceiver {
+    /// The value of the parameter at the end of the last processed block.
+    pub fn value(&self) -> f32 {
+        self.value
+    }
+
+    /// Returns `true` if there are no events waiting to take effect, so the
+    /// value stays the same for the whole block. Only valid after calling
+    /// [`AutomationReceiver::process`].
+    pub fn is_static(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Fill the first `proc_info.samples` samples of `values` with the
+    /// value of the parameter at each sample of the block. Call this once
+    /// at the start of every block.
+    ///
+    /// This never blocks or allocates, so it is safe to call in the audio
+    /// thread.
+    pub fn process(&mut self, proc_info: &ProcInfo, values: &mut [f32]) {
+        let block_start = proc_info.clock_samples;
+        self.receive(block_start);
+
+        for (i, out) in values[..proc_info.samples].iter_mut().enumerate() {
+            let t = block_start + ClockSamples(i as u64);
+
+            while let Some(event) = self.events.first().copied() {
+                if event.time() > t {
+                    break;
+                }
+
+                self.value = event.value();
+                self.ramp_start = (event.time(), self.value);
+                self.events.remove(0);
+            }
+
+            if let Some(AutomationEvent::LinearRamp { value, end }) = self.events.first().copied() {
+                let (start, start_value) = self.ramp_start;
+                let progress = (t.0 - start.0) as f64 / (end.0 - start.0) as f64;
+                self.value = start_value + (value - start_value) * progress as f32;
+            }
+
+            *out = self.value;
+        }
+    }
+
+    fn receive(&mut self, now: ClockSamples) {
+        self.apply_cancel(self.cancel_count.load(Ordering::Acquire));
+
+        // Events which don't fit yet are left in the queue until the next
+        // block.
+        while !self.events.is_full() {
+            let Ok(LaneEvent {
+                event,
+                cancel_count,
+            }) = self.consumer.pop()
+            else {
+                break;
+            };
+
+            // The lane may have been cancelled after the count was loaded
+            // above, in which case this event was pushed after the cancel.
+            self.apply_cancel(cancel_count);
+
+            if cancel_count < self.seen_cancel_count {
+                continue;
+            }
+
+            if self.events.is_empty() {
+                self.ramp_start = (now, self.value);
+            }
+
+            self.events.push(event);
+        }
+    }
+
+    fn apply_cancel(&mut self, cancel_count: u64) {
+        if cancel_count > self.seen_cancel_count {
+            self.seen_cancel_count = cancel_count;
+            self.events.clear();
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use crate::{clock::EventDelay, node::ProcInfo};
+
+/// A group of parameters which are changed together in a single message,
+/// so that they all take effect on the same processing block (i.e. when
+/// switching the patch of a synth).
+///
+/// Setting several parameters one by one can cause audible artifacts if
+/// the processor happens to read some of the new values in one block and
+/// the rest in the next block. With a [`ParamGroup`], the node handle sets
+/// all of the values at once with [`ParamGroup::set`], and the processor
+/// applies them at the start of a single block with
+/// [`ParamGroupReceiver::poll`].
+///
+/// The change is passed through a triple buffer, so the processor always
+/// sees a complete group without ever waiting for the node handle.
+///
+/// `T` is a small `Copy` struct containing the parameters of the group.
+pub struct ParamGroup<T: Copy + Send + 'static> {
+    input: triple_buffer::Input<Option<Change<T>>>,
+    /// The latest change, used to initialize the buffer of a new receiver.
+    latest: Option<Change<T>>,
+    /// The sequence number of the latest change the processor applied.
+    applied_seq: Arc<AtomicU64>,
+}
+
+#[derive(Clone, Copy)]
+struct Change<T: Copy> {
+    params: T,
+    delay: EventDelay,
+    seq: u64,
+}
+
+impl<T: Copy + Send + 'static> ParamGroup<T> {
+    pub fn new() -> Self {
+        let (input, _) = triple_buffer::triple_buffer(&None);
+
+        Self {
+            input,
+            latest: None,
+            applied_seq: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Set all of the parameters in the group at once.
+    ///
+    /// The parameters take effect at the start of the block in which
+    /// `delay` occurs (or the next processed block if the delay is
+    /// [`EventDelay::Immediate`]). Only one change is kept at a time, so
+    /// setting the group again replaces any change which has not yet taken
+    /// effect.
+    pub fn set(&mut self, params: T, delay: EventDelay) {
+        let change = Change {
+            params,
+            delay,
+            seq: self.latest.map_or(1, |c| c.seq + 1),
+        };
+
+        self.latest = Some(change);
+        self.input.write(Some(change));
+    }
+
+    /// Returns `true` if a change has been set but not yet applied by the
+    /// processor.
+    pub fn is_pending(&self) -> bool {
+        self.latest
+            .is_some_and(|c| c.seq != self.applied_seq.load(Ordering::Acquire))
+    }
+
+    /// Create a receiver to give to a new processor (i.e. in
+    /// [`AudioNode::activate`]).
+    ///
+    /// A triple buffer only has a single receiver, so this starts a new one,
+    /// and from then on only the newest processor receives changes. A change
+    /// which was not applied yet is carried over to the new receiver.
+    ///
+    /// [`AudioNode::activate`]: crate::node::AudioNode::activate
+    pub fn receiver(&mut self) -> ParamGroupReceiver<T> {
+        let (input, output) = triple_buffer::triple_buffer(&self.latest);
+        self.input = input;
+
+        ParamGroupReceiver {
+            output,
+            pending: self.latest.filter(|_| self.is_pending()),
+            applied_seq: Arc::clone(&self.applied_seq),
+        }
+    }
+}
+
+impl<T: Copy + Send + 'static> Default for ParamGroup<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The processor side of a [`ParamGroup`].
+pub struct ParamGroupReceiver<T: Copy + Send + 'static> {
+    output: triple_buffer::Output<Option<Change<T>>>,
+    /// The latest change, which has not taken effect yet.
+    pending: Option<Change<T>>,
+    applied_seq: Arc<AtomicU64>,
+}
+
+impl<T: Copy + Send + 'static> ParamGroupReceiver<T> {
+    /// Returns the new parameters if they should take effect in the block
+    /// described by `proc_info`. Call this once at the start of every block.
+    ///
+    /// This never blocks or allocates, so it is safe to call in the audio
+    /// thread.
+    pub fn poll(&mut self, proc_info: &ProcInfo) -> Option<T> {
+        if self.output.updated() {
+            self.pending = *self.output.read();
+        }
+
+        let change = self.pending?;
+
+        let due = match change.delay {
+            EventDelay::Immediate => true,
+            EventDelay::DelayUntilSeconds(clock_seconds) => {
+                clock_seconds < proc_info.clock_seconds.end
+            }
+            EventDelay::DelayUntilSample(clock_samples) => {
+                clock_samples.0 < proc_info.clock_samples.0 + proc_info.samples as u64
+            }
+        };
+
+        if due {
+            self.pending = None;
+            self.applied_seq.store(change.seq, Ordering::Release);
+            Some(change.params)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::{ClockSamples, ClockSeconds},
+        node::StreamStatus,
+        SilenceMask,
+    };
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Patch {
+        cutoff_hz: f32,
+        resonance: f32,
+        gain: f32,
+    }
+
+    fn proc_info(block: u64, samples: usize) -> ProcInfo {
+        let start = block * samples as u64;
+
+        ProcInfo {
+            samples,
+            in_silence_mask: SilenceMask::NONE_SILENT,
+            out_silence_mask: SilenceMask::NONE_SILENT,
+            clock_seconds: ClockSeconds(start as f64 / 44100.0)
+                ..ClockSeconds((start + samples as u64) as f64 / 44100.0),
+            clock_samples: ClockSamples(start),
+            stream_status: StreamStatus::empty(),
+        }
+    }
+
+    #[test]
+    fn params_change_together() {
+        let mut group = ParamGroup::new();
+        let mut receiver = group.receiver();
+
+        let mut patch = Patch {
+            cutoff_hz: 1000.0,
+            resonance: 0.5,
+            gain: 1.0,
+        };
+        let new_patch = Patch {
+            cutoff_hz: 250.0,
+            resonance: 0.9,
+            gain: 0.5,
+        };
+
+        // Take effect in the block containing sample 1000.
+        group.set(new_patch, EventDelay::DelayUntilSample(ClockSamples(1000)));
+        assert!(group.is_pending());
+
+        let mut applied_blocks = Vec::new();
+        for block in 0..8 {
+            if let Some(new_params) = receiver.poll(&proc_info(block, 256)) {
+                patch = new_params;
+                applied_blocks.push(block);
+            }
+        }
+
+        assert_eq!(applied_blocks, vec![3]);
+        assert_eq!(patch, new_patch);
+        assert!(!group.is_pending());
+
+        // A newer change replaces one which has not taken effect yet.
+        group.set(
+            new_patch,
+            EventDelay::DelayUntilSeconds(ClockSeconds(1000.0)),
+        );
+        group.set(patch, EventDelay::Immediate);
+        assert_eq!(receiver.poll(&proc_info(8, 256)), Some(patch));
+        assert_eq!(receiver.poll(&proc_info(9, 256)), None);
+    }
+
+    #[test]
+    fn new_receiver_takes_over_pending_change() {
+        let mut group = ParamGroup::new();
+        let patch = Patch {
+            cutoff_hz: 250.0,
+            resonance: 0.9,
+            gain: 0.5,
+        };
+
+        let mut old_receiver = group.receiver();
+        group.set(patch, EventDelay::DelayUntilSample(ClockSamples(1000)));
+
+        // I.e. the node was re-activated before the change took effect.
+        let mut receiver = group.receiver();
+        assert_eq!(receiver.poll(&proc_info(0, 256)), None);
+        assert_eq!(receiver.poll(&proc_info(3, 256)), Some(patch));
+        assert!(!group.is_pending());
+
+        // Only the newest receiver gets new changes.
+        let new_patch = Patch {
+            cutoff_hz: 500.0,
+            ..patch
+        };
+        group.set(new_patch, EventDelay::Immediate);
+        assert_ne!(old_receiver.poll(&proc_info(4, 256)), Some(new_patch));
+        assert_eq!(receiver.poll(&proc_info(4, 256)), Some(new_patch));
+    }
+}
@@ -1,2 +1,4 @@
+pub mod automation;
+pub mod group;
 pub mod range;
 pub mod smoother;
@@ -79,6 +79,7 @@ pub struct ParamSmoother {
     b: f32,
     last_output: f32,
 
+    smooth_secs: f32,
     settle_epsilon: f32,
 }
 
@@ -107,10 +108,20 @@ impl ParamSmoother {
             a,
             b,
             last_output: val,
+            smooth_secs: config.smooth_secs,
             settle_epsilon: config.settle_epsilon,
         }
     }
 
+    /// Recompute the filter coefficients for a new sampling rate.
+    ///
+    /// The current value and target value are preserved, so any smoothing
+    /// which is in progress will continue at the new rate.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.b = (-1.0f32 / (self.smooth_secs * sample_rate as f32)).exp();
+        self.a = 1.0f32 - self.b;
+    }
+
     /// Reset the filter with the new given initial value.
     pub fn reset(&mut self, val: f32) {
         if self.is_active() {
@@ -40,6 +40,82 @@ pub fn gain_to_db_clamped_neg_100_db(amp: f32) -> f32 {
     }
 }
 
+/// Returns the frequency in Hz of the given MIDI note number.
+///
+/// `a4_hz` is the tuning reference, which is the frequency of MIDI note
+/// `69` (A4). This is usually `440.0`. Fractional note numbers are
+/// allowed, which can be used to apply pitch bend.
+#[inline]
+pub fn midi_note_to_freq(midi_note: f32, a4_hz: f32) -> f32 {
+    a4_hz * 2.0f32.powf((midi_note - 69.0) * (1.0 / 12.0))
+}
+
+/// Fill `out` with a per-sample linear ramp from a control value of the
+/// previous block to the control value of the current block, so that a
+/// parameter driven by a control-rate input is applied smoothly across the
+/// block instead of stepping once per block.
+///
+/// The ramp starts one step after `prev` and reaches `current` exactly on
+/// the last frame of the block, so consecutive blocks join without a
+/// discontinuity. Only the first `block_frames` samples of `out` are
+/// written.
+pub fn control_ramp(prev: f32, current: f32, block_frames: usize, out: &mut [f32]) {
+    let block_frames = block_frames.min(out.len());
+    let out = &mut out[..block_frames];
+
+    if prev == current {
+        out.fill(current);
+        return;
+    }
+
+    let step = (current - prev) / block_frames as f32;
+    for (i, s) in out.iter_mut().enumerate() {
+        *s = prev + step * (i + 1) as f32;
+    }
+
+    // Avoid any accumulated rounding error on the final frame.
+    if let Some(last) = out.last_mut() {
+        *last = current;
+    }
+}
+
+/// The shape of the gain curve used by click-free fades.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FadeCurve {
+    /// The gain changes linearly.
+    ///
+    /// When crossfading between two uncorrelated signals, this causes a
+    /// dip in loudness of about 3 dB at the midpoint.
+    #[default]
+    Linear,
+    /// The gain follows a quarter of a sine wave, so that the combined
+    /// power of a fade out and a fade in stays constant. This is best for
+    /// crossfading between uncorrelated signals.
+    EqualPower,
+    /// The gain follows half of a cosine wave, which starts and ends
+    /// smoothly (an "S" curve). This is best for fading a signal in or out
+    /// on its own.
+    Cosine,
+}
+
+impl FadeCurve {
+    /// Returns the gain of a fade in at the given position, where `0.0` is
+    /// the start of the fade and `1.0` is the end of the fade.
+    ///
+    /// For the gain of a fade out, use `1.0 - t` as the position. The
+    /// position is clamped to the range `[0.0, 1.0]`.
+    #[inline]
+    pub fn gain(&self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+
+        match self {
+            Self::Linear => t,
+            Self::EqualPower => (t * std::f32::consts::FRAC_PI_2).sin(),
+            Self::Cosine => 0.5 - 0.5 * (t * std::f32::consts::PI).cos(),
+        }
+    }
+}
+
 /// De-interleave audio channels
 pub fn deinterleave<V: AsMut<[f32]>>(
     channels: &mut [V],
@@ -221,3 +297,50 @@ pub fn interleave<V: AsRef<[f32]>>(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_ramps() {
+        let mut out = [0.0; 8];
+
+        control_ramp(0.0, 1.0, 4, &mut out);
+        assert_eq!(out, [0.25, 0.5, 0.75, 1.0, 0.0, 0.0, 0.0, 0.0]);
+
+        // The next block continues from the end value of the previous one.
+        control_ramp(1.0, -1.0, 8, &mut out);
+        assert_eq!(out, [0.75, 0.5, 0.25, 0.0, -0.25, -0.5, -0.75, -1.0]);
+
+        control_ramp(0.5, 0.5, 8, &mut out);
+        assert!(out.iter().all(|&s| s == 0.5));
+
+        // The block can't be longer than the output buffer.
+        let mut out = [0.0; 2];
+        control_ramp(0.0, 1.0, 4, &mut out);
+        assert_eq!(out, [0.5, 1.0]);
+    }
+
+    #[test]
+    fn fade_curves() {
+        for curve in [FadeCurve::Linear, FadeCurve::EqualPower, FadeCurve::Cosine] {
+            assert_eq!(curve.gain(0.0), 0.0);
+            assert_eq!(curve.gain(1.0), 1.0);
+            assert_eq!(curve.gain(-1.0), 0.0);
+            assert_eq!(curve.gain(2.0), 1.0);
+        }
+
+        assert_eq!(FadeCurve::Linear.gain(0.5), 0.5);
+        assert!((FadeCurve::EqualPower.gain(0.5) - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+        assert!((FadeCurve::Cosine.gain(0.5) - 0.5).abs() < 1e-6);
+
+        // The power of a fade out and a fade in adds up to one.
+        for i in 0..=10 {
+            let t = i as f32 / 10.0;
+            let fade_in = FadeCurve::EqualPower.gain(t);
+            let fade_out = FadeCurve::EqualPower.gain(1.0 - t);
+            assert!((fade_in * fade_in + fade_out * fade_out - 1.0).abs() < 1e-5);
+        }
+    }
+}
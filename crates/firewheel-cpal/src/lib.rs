@@ -7,7 +7,7 @@ use firewheel_graph::{
     error::ActivateCtxError,
     graph::AudioGraph,
     processor::{FirewheelProcessor, FirewheelProcessorStatus},
-    FirewheelConfig, FirewheelGraphCtx, UpdateStatus,
+    DspLoad, FirewheelConfig, FirewheelGraphCtx, UpdateStatus,
 };
 
 /// 1024 samples is a latency of about 23 milliseconds, which should
@@ -367,6 +367,14 @@ impl<C: Send + 'static> FirewheelCpalCtx<C> {
         self.active_state.as_ref().map(|s| &s.cpal_config)
     }
 
+    /// Get the most recent measurement of how much of the time available
+    /// for processing is being used by the audio graph.
+    ///
+    /// Returns `None` if the context is not currently activated.
+    pub fn dsp_load(&self) -> Option<DspLoad> {
+        self.cx.dsp_load()
+    }
+
     /// Update the firewheel context.
     ///
     /// This must be called reguarly once the context has been activated
@@ -563,6 +571,8 @@ impl<C: Send + 'static> DataCallback<C> {
             ) {
                 FirewheelProcessorStatus::Ok => {}
                 FirewheelProcessorStatus::DropProcessor => drop_processor = true,
+                // The processor has already filled the output with silence.
+                FirewheelProcessorStatus::InvalidBuffer => {}
             }
         } else {
             output.fill(0.0);
@@ -1,5 +1,6 @@
+use atomic_float::AtomicF32;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicBool, AtomicU8, Ordering},
     Arc,
 };
 
@@ -8,10 +9,44 @@ use firewheel_core::{
     ChannelConfig, ChannelCount, StreamInfo,
 };
 
+/// The default tuning reference (the frequency of A4) used by
+/// [`BeepTestNode::set_note`].
+pub const DEFAULT_TUNING_A4_HZ: f32 = 440.0;
+
+/// The shape of the wave generated by a [`BeepTestNode`].
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Waveform {
+    #[default]
+    Sine = 0,
+    /// A square wave, band-limited with PolyBLEP.
+    Square,
+    /// A sawtooth wave, band-limited with PolyBLEP.
+    Saw,
+    /// A triangle wave.
+    ///
+    /// This is not band-limited, but its harmonics fall off quickly enough
+    /// that the aliasing is usually not noticeable.
+    Triangle,
+}
+
+impl Waveform {
+    fn from_u8(val: u8) -> Self {
+        match val {
+            1 => Self::Square,
+            2 => Self::Saw,
+            3 => Self::Triangle,
+            _ => Self::Sine,
+        }
+    }
+}
+
 pub struct BeepTestNode {
     enabled: Arc<AtomicBool>,
-    freq_hz: f32,
+    freq_hz: Arc<AtomicF32>,
+    waveform: Arc<AtomicU8>,
     gain: f32,
+    tuning_a4_hz: f32,
 }
 
 impl BeepTestNode {
@@ -20,12 +55,62 @@ impl BeepTestNode {
         let gain = firewheel_core::util::db_to_gain_clamped_neg_100_db(gain_db).clamp(0.0, 1.0);
 
         Self {
-            freq_hz,
+            freq_hz: Arc::new(AtomicF32::new(freq_hz)),
+            waveform: Arc::new(AtomicU8::new(Waveform::Sine as u8)),
             gain,
             enabled: Arc::new(AtomicBool::new(enabled)),
+            tuning_a4_hz: DEFAULT_TUNING_A4_HZ,
         }
     }
 
+    pub fn freq_hz(&self) -> f32 {
+        self.freq_hz.load(Ordering::Relaxed)
+    }
+
+    /// Set the frequency of the oscillator in Hz.
+    ///
+    /// The frequency is clamped to the range `[20.0, 20_000.0]`.
+    pub fn set_freq_hz(&self, freq_hz: f32) {
+        self.freq_hz
+            .store(freq_hz.clamp(20.0, 20_000.0), Ordering::Relaxed);
+    }
+
+    pub fn waveform(&self) -> Waveform {
+        Waveform::from_u8(self.waveform.load(Ordering::Relaxed))
+    }
+
+    /// Set the shape of the generated wave.
+    ///
+    /// By default this is set to [`Waveform::Sine`].
+    pub fn set_waveform(&self, waveform: Waveform) {
+        self.waveform.store(waveform as u8, Ordering::Relaxed);
+    }
+
+    /// Set the frequency of the oscillator from a MIDI note number.
+    ///
+    /// `pitch_bend_semitones` is added to the note, so a value of `2.0`
+    /// bends the note up by a whole tone. Use `0.0` for no pitch bend.
+    pub fn set_note(&self, midi_note: u8, pitch_bend_semitones: f32) {
+        self.set_freq_hz(firewheel_core::util::midi_note_to_freq(
+            f32::from(midi_note) + pitch_bend_semitones,
+            self.tuning_a4_hz,
+        ));
+    }
+
+    /// The tuning reference (the frequency of A4) used by
+    /// [`BeepTestNode::set_note`].
+    pub fn tuning_a4_hz(&self) -> f32 {
+        self.tuning_a4_hz
+    }
+
+    /// Set the tuning reference (the frequency of A4) used by
+    /// [`BeepTestNode::set_note`].
+    ///
+    /// By default this is set to [`DEFAULT_TUNING_A4_HZ`].
+    pub fn set_tuning_a4_hz(&mut self, a4_hz: f32) {
+        self.tuning_a4_hz = a4_hz.max(1.0);
+    }
+
     pub fn enabled(&self) -> bool {
         self.enabled.load(Ordering::Relaxed)
     }
@@ -59,8 +144,10 @@ impl<C> AudioNode<C> for BeepTestNode {
     ) -> Result<Box<dyn AudioNodeProcessor<C>>, Box<dyn std::error::Error>> {
         Ok(Box::new(BeepTestProcessor {
             enabled: Arc::clone(&self.enabled),
+            freq_hz: Arc::clone(&self.freq_hz),
+            waveform: Arc::clone(&self.waveform),
             phasor: 0.0,
-            phasor_inc: self.freq_hz / stream_info.sample_rate as f32,
+            sample_rate_recip: (stream_info.sample_rate as f32).recip(),
             gain: self.gain,
         }))
     }
@@ -68,8 +155,10 @@ impl<C> AudioNode<C> for BeepTestNode {
 
 struct BeepTestProcessor {
     enabled: Arc<AtomicBool>,
+    freq_hz: Arc<AtomicF32>,
+    waveform: Arc<AtomicU8>,
     phasor: f32,
-    phasor_inc: f32,
+    sample_rate_recip: f32,
     gain: f32,
 }
 
@@ -89,9 +178,45 @@ impl<C> AudioNodeProcessor<C> for BeepTestProcessor {
             return ProcessStatus::NoOutputsModified;
         }
 
-        for s in out1[..proc_info.samples].iter_mut() {
-            *s = (self.phasor * std::f32::consts::TAU).sin() * self.gain;
-            self.phasor = (self.phasor + self.phasor_inc).fract();
+        let phasor_inc = self.freq_hz.load(Ordering::Relaxed) * self.sample_rate_recip;
+
+        let gain = self.gain;
+
+        match Waveform::from_u8(self.waveform.load(Ordering::Relaxed)) {
+            Waveform::Sine => {
+                for s in out1[..proc_info.samples].iter_mut() {
+                    *s = (self.phasor * std::f32::consts::TAU).sin() * gain;
+                    self.phasor = (self.phasor + phasor_inc).fract();
+                }
+            }
+            Waveform::Square => {
+                for s in out1[..proc_info.samples].iter_mut() {
+                    let t = self.phasor;
+                    let naive = if t < 0.5 { 1.0 } else { -1.0 };
+
+                    *s = (naive + poly_blep(t, phasor_inc)
+                        - poly_blep((t + 0.5).fract(), phasor_inc))
+                        * gain;
+                    self.phasor = (self.phasor + phasor_inc).fract();
+                }
+            }
+            Waveform::Saw => {
+                for s in out1[..proc_info.samples].iter_mut() {
+                    let t = self.phasor;
+
+                    *s = (2.0 * t - 1.0 - poly_blep(t, phasor_inc)) * gain;
+                    self.phasor = (self.phasor + phasor_inc).fract();
+                }
+            }
+            Waveform::Triangle => {
+                for s in out1[..proc_info.samples].iter_mut() {
+                    // Offset the phase so the wave starts at zero like the sine.
+                    let t = (self.phasor + 0.25).fract();
+
+                    *s = (1.0 - 4.0 * (t - 0.5).abs()) * gain;
+                    self.phasor = (self.phasor + phasor_inc).fract();
+                }
+            }
         }
 
         for out2 in outputs.iter_mut() {
@@ -102,8 +227,199 @@ impl<C> AudioNodeProcessor<C> for BeepTestProcessor {
     }
 }
 
+/// The PolyBLEP correction for a discontinuity of `-2.0` at `t = 0.0`,
+/// where `t` is the phase in the range `[0.0, 1.0)` and `dt` is the phase
+/// increment per sample.
+#[inline]
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let t = t / dt;
+        2.0 * t - t * t - 1.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt;
+        t * t + 2.0 * t + 1.0
+    } else {
+        0.0
+    }
+}
+
 impl<C> Into<Box<dyn AudioNode<C>>> for BeepTestNode {
     fn into(self) -> Box<dyn AudioNode<C>> {
         Box::new(self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use firewheel_core::SilenceMask;
+
+    use super::*;
+    use crate::basic_nodes::process_block;
+
+    #[test]
+    fn square_wave() {
+        let stream_info = StreamInfo::default();
+        let samples = stream_info.max_block_samples as usize;
+        let gain = firewheel_core::util::db_to_gain(-6.0);
+
+        let mut node = BeepTestNode::new(100.0, -6.0, true);
+        node.set_waveform(Waveform::Square);
+        let mut processor = AudioNode::<()>::activate(
+            &mut node,
+            &stream_info,
+            ChannelConfig {
+                num_inputs: ChannelCount::ZERO,
+                num_outputs: ChannelCount::MONO,
+            },
+        )
+        .unwrap();
+
+        let mut output = vec![0.0; samples];
+        process_block(
+            processor.as_mut(),
+            &[],
+            &mut [&mut output],
+            SilenceMask::NONE_SILENT,
+        );
+
+        // Only the samples right next to a transition are smoothed.
+        let num_settled = output
+            .iter()
+            .filter(|&&s| (s.abs() - gain).abs() < 0.001)
+            .count();
+        assert!(num_settled >= samples - 10);
+        assert!(output.iter().all(|s| s.abs() <= gain + 0.001));
+
+        // 100 Hz at 44100 Hz changes sign every 220.5 samples.
+        let sign_changes = output
+            .windows(2)
+            .filter(|w| w[0].signum() != w[1].signum())
+            .count();
+        assert_eq!(sign_changes, samples * 2 / 441);
+        assert!(output[1..200].iter().all(|&s| s > 0.0));
+        assert!(output[230..430].iter().all(|&s| s < 0.0));
+
+        // The frequency can be changed while running.
+        node.set_freq_hz(1_000.0);
+        process_block(
+            processor.as_mut(),
+            &[],
+            &mut [&mut output],
+            SilenceMask::NONE_SILENT,
+        );
+        let sign_changes = output
+            .windows(2)
+            .filter(|w| w[0].signum() != w[1].signum())
+            .count();
+        assert!(sign_changes.abs_diff(samples * 2 / 44) <= 1);
+    }
+
+    /// Render 10 periods of a 441 Hz wave (100 samples per period).
+    fn render_wave(waveform: Waveform, gain_db: f32) -> Vec<f32> {
+        let stream_info = StreamInfo::default();
+
+        let mut node = BeepTestNode::new(441.0, gain_db, true);
+        node.set_waveform(waveform);
+        let mut processor = AudioNode::<()>::activate(
+            &mut node,
+            &stream_info,
+            ChannelConfig {
+                num_inputs: ChannelCount::ZERO,
+                num_outputs: ChannelCount::MONO,
+            },
+        )
+        .unwrap();
+
+        let mut output = vec![0.0; 1_000];
+        process_block(
+            processor.as_mut(),
+            &[],
+            &mut [&mut output],
+            SilenceMask::NONE_SILENT,
+        );
+        output
+    }
+
+    /// The average distance in samples between the rising zero crossings.
+    fn measure_period(output: &[f32]) -> f32 {
+        let crossings: Vec<f32> = output
+            .windows(2)
+            .enumerate()
+            .filter(|(_, w)| w[0] < 0.0 && w[1] >= 0.0)
+            .map(|(i, w)| i as f32 + w[0] / (w[0] - w[1]))
+            .collect();
+        assert!(crossings.len() >= 9);
+
+        (crossings[crossings.len() - 1] - crossings[0]) / (crossings.len() - 1) as f32
+    }
+
+    fn mean(output: &[f32]) -> f32 {
+        output.iter().sum::<f32>() / output.len() as f32
+    }
+
+    fn peak(output: &[f32]) -> f32 {
+        output.iter().fold(0.0f32, |peak, &s| peak.max(s.abs()))
+    }
+
+    #[test]
+    fn saw_wave() {
+        let gain = firewheel_core::util::db_to_gain(-6.0);
+        let output = render_wave(Waveform::Saw, -6.0);
+
+        assert!((measure_period(&output) - 100.0).abs() < 0.1);
+        // The PolyBLEP only rounds off the samples next to the reset.
+        assert!(peak(&output) <= gain + 0.001);
+        assert!(peak(&output) > gain * 0.95);
+        assert!(mean(&output).abs() < gain * 0.01);
+
+        // The wave rises linearly between resets.
+        let slope = 2.0 * gain / 100.0;
+        assert!(output[10..90]
+            .windows(2)
+            .all(|w| (w[1] - w[0] - slope).abs() < 0.001));
+    }
+
+    #[test]
+    fn triangle_wave() {
+        let gain = firewheel_core::util::db_to_gain(-6.0);
+        let output = render_wave(Waveform::Triangle, -6.0);
+
+        assert!((measure_period(&output) - 100.0).abs() < 0.1);
+        assert!((peak(&output) - gain).abs() < 0.001);
+        assert!(mean(&output).abs() < gain * 0.01);
+
+        // The wave starts at zero like the sine, and peaks a quarter of a
+        // period later.
+        assert!(output[0].abs() < 0.001);
+        assert!((output[25] - gain).abs() < 0.001);
+        assert!((output[75] + gain).abs() < 0.001);
+    }
+
+    #[test]
+    fn square_wave_shape() {
+        let gain = firewheel_core::util::db_to_gain(-6.0);
+        let output = render_wave(Waveform::Square, -6.0);
+
+        assert!((measure_period(&output) - 100.0).abs() < 0.1);
+        assert!((peak(&output) - gain).abs() < 0.001);
+        assert!(mean(&output).abs() < gain * 0.01);
+    }
+
+    #[test]
+    fn midi_note_to_freq() {
+        let mut node = BeepTestNode::new(1_000.0, 0.0, true);
+
+        node.set_note(69, 0.0);
+        assert!((node.freq_hz() - 440.0).abs() < 0.001);
+
+        node.set_note(81, 0.0);
+        assert!((node.freq_hz() - 880.0).abs() < 0.001);
+
+        node.set_note(79, 2.0);
+        assert!((node.freq_hz() - 880.0).abs() < 0.001);
+
+        node.set_tuning_a4_hz(432.0);
+        node.set_note(69, 0.0);
+        assert!((node.freq_hz() - 432.0).abs() < 0.001);
+    }
+}
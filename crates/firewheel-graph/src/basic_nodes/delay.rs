@@ -0,0 +1,392 @@
+use atomic_float::AtomicF32;
+use std::sync::{atomic::Ordering, Arc};
+
+use firewheel_core::{
+    node::{AudioNode, AudioNodeInfo, AudioNodeProcessor, ProcInfo, ProcessStatus},
+    ChannelConfig, ChannelCount, StreamInfo,
+};
+
+/// How a [`DelayNode`] reads between samples when the delay time is not a
+/// whole number of samples.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DelayInterpolation {
+    /// Round to the nearest sample. This is the cheapest, but it causes
+    /// audible artifacts when the delay time is modulated.
+    None,
+    /// Linearly interpolate between the two nearest samples. This slightly
+    /// dulls the high frequencies of the delayed signal.
+    #[default]
+    Linear,
+    /// Use a first-order allpass filter. This keeps the full frequency
+    /// response, but it is best suited to slowly changing delay times.
+    Allpass,
+    /// Use cubic (Catmull-Rom) interpolation between the four nearest
+    /// samples. This is the most expensive but also the most accurate.
+    ///
+    /// The delay time is limited to a minimum of one sample in this mode.
+    Cubic,
+}
+
+/// A node which delays its input by a given amount of time.
+///
+/// The delay time can be changed while running, which can be used to
+/// build modulated effects like chorus and flanger. Changes to the delay
+/// time are interpolated over each processed block.
+pub struct DelayNode {
+    delay_secs: Arc<AtomicF32>,
+    max_delay_secs: f32,
+    interpolation: DelayInterpolation,
+}
+
+impl DelayNode {
+    /// Create a new delay node.
+    ///
+    /// * `max_delay_secs` - The maximum delay time in seconds. This
+    ///   determines the size of the buffer allocated when the node is
+    ///   activated.
+    /// * `delay_secs` - The initial delay time in seconds.
+    /// * `interpolation` - How to read between samples.
+    pub fn new(max_delay_secs: f32, delay_secs: f32, interpolation: DelayInterpolation) -> Self {
+        let max_delay_secs = max_delay_secs.max(0.0);
+
+        Self {
+            delay_secs: Arc::new(AtomicF32::new(delay_secs.clamp(0.0, max_delay_secs))),
+            max_delay_secs,
+            interpolation,
+        }
+    }
+
+    pub fn delay_secs(&self) -> f32 {
+        self.delay_secs.load(Ordering::Relaxed)
+    }
+
+    /// Set the delay time in seconds.
+    ///
+    /// This is clamped to the range `[0.0, max_delay_secs]`.
+    pub fn set_delay_secs(&self, delay_secs: f32) {
+        self.delay_secs.store(
+            delay_secs.clamp(0.0, self.max_delay_secs),
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn max_delay_secs(&self) -> f32 {
+        self.max_delay_secs
+    }
+
+    pub fn interpolation(&self) -> DelayInterpolation {
+        self.interpolation
+    }
+}
+
+impl<C> AudioNode<C> for DelayNode {
+    fn debug_name(&self) -> &'static str {
+        "delay"
+    }
+
+    fn info(&self) -> AudioNodeInfo {
+        AudioNodeInfo {
+            num_min_supported_inputs: ChannelCount::MONO,
+            num_max_supported_inputs: ChannelCount::MAX,
+            num_min_supported_outputs: ChannelCount::MONO,
+            num_max_supported_outputs: ChannelCount::MAX,
+            default_channel_config: ChannelConfig {
+                num_inputs: ChannelCount::STEREO,
+                num_outputs: ChannelCount::STEREO,
+            },
+            equal_num_ins_and_outs: true,
+            ..Default::default()
+        }
+    }
+
+    fn activate(
+        &mut self,
+        stream_info: &StreamInfo,
+        channel_config: ChannelConfig,
+    ) -> Result<Box<dyn AudioNodeProcessor<C>>, Box<dyn std::error::Error>> {
+        let sample_rate = stream_info.sample_rate as f32;
+
+        // Leave room for the extra samples read by the cubic interpolation.
+        let buffer_len = (self.max_delay_secs * sample_rate).ceil() as usize + 4;
+
+        let min_delay_samples = if self.interpolation == DelayInterpolation::Cubic {
+            1.0
+        } else {
+            0.0
+        };
+
+        Ok(Box::new(DelayProcessor {
+            delay_secs: Arc::clone(&self.delay_secs),
+            channels: (0..channel_config.num_outputs.get())
+                .map(|_| DelayLine {
+                    buffer: vec![0.0; buffer_len],
+                    allpass_prev: 0.0,
+                })
+                .collect(),
+            write_pos: 0,
+            delay_samples: (self.delay_secs() * sample_rate).max(min_delay_samples),
+            min_delay_samples,
+            max_delay_samples: (buffer_len - 3) as f32,
+            silent_frames: buffer_len,
+            sample_rate,
+            interpolation: self.interpolation,
+        }))
+    }
+}
+
+struct DelayLine {
+    buffer: Vec<f32>,
+    allpass_prev: f32,
+}
+
+impl DelayLine {
+    /// The sample which was written `delay` samples ago, where `write_pos`
+    /// is the position of the sample that was just written.
+    #[inline]
+    fn tap(&self, write_pos: usize, delay: usize) -> f32 {
+        let len = self.buffer.len();
+        self.buffer[(write_pos + len - delay) % len]
+    }
+
+    #[inline]
+    fn read(&mut self, write_pos: usize, delay: f32, interpolation: DelayInterpolation) -> f32 {
+        let delay_int = delay as usize;
+        let frac = delay - delay_int as f32;
+
+        match interpolation {
+            DelayInterpolation::None => self.tap(write_pos, delay.round() as usize),
+            DelayInterpolation::Linear => {
+                let a = self.tap(write_pos, delay_int);
+                let b = self.tap(write_pos, delay_int + 1);
+
+                a + (b - a) * frac
+            }
+            DelayInterpolation::Allpass => {
+                let coeff = (1.0 - frac) / (1.0 + frac);
+                let a = self.tap(write_pos, delay_int);
+                let b = self.tap(write_pos, delay_int + 1);
+
+                let y = coeff * a + b - coeff * self.allpass_prev;
+                self.allpass_prev = y;
+                y
+            }
+            DelayInterpolation::Cubic => {
+                let y0 = self.tap(write_pos, delay_int - 1);
+                let y1 = self.tap(write_pos, delay_int);
+                let y2 = self.tap(write_pos, delay_int + 1);
+                let y3 = self.tap(write_pos, delay_int + 2);
+
+                let c1 = 0.5 * (y2 - y0);
+                let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+                let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+
+                ((c3 * frac + c2) * frac + c1) * frac + y1
+            }
+        }
+    }
+}
+
+struct DelayProcessor {
+    delay_secs: Arc<AtomicF32>,
+    channels: Vec<DelayLine>,
+    write_pos: usize,
+    /// The delay time at the end of the previous block.
+    delay_samples: f32,
+    min_delay_samples: f32,
+    max_delay_samples: f32,
+    /// The number of frames of silence that have been written in a row.
+    silent_frames: usize,
+    sample_rate: f32,
+    interpolation: DelayInterpolation,
+}
+
+impl<C> AudioNodeProcessor<C> for DelayProcessor {
+    fn process(
+        &mut self,
+        inputs: &[&[f32]],
+        outputs: &mut [&mut [f32]],
+        proc_info: ProcInfo,
+        _cx: &mut C,
+    ) -> ProcessStatus {
+        let samples = proc_info.samples;
+        let buffer_len = self.channels[0].buffer.len();
+
+        let target_delay_samples = (self.delay_secs.load(Ordering::Relaxed) * self.sample_rate)
+            .clamp(self.min_delay_samples, self.max_delay_samples);
+
+        if proc_info.in_silence_mask.all_channels_silent(inputs.len()) {
+            if self.silent_frames >= buffer_len {
+                // The delay lines only contain silence, so there is no need
+                // to process.
+                for channel in self.channels.iter_mut() {
+                    channel.allpass_prev = 0.0;
+                }
+                self.delay_samples = target_delay_samples;
+
+                return ProcessStatus::NoOutputsModified;
+            }
+
+            self.silent_frames += samples;
+        } else {
+            self.silent_frames = 0;
+        }
+
+        let start_delay_samples = self.delay_samples;
+        let delay_step = (target_delay_samples - start_delay_samples) / samples as f32;
+
+        for ((input, output), channel) in inputs
+            .iter()
+            .zip(outputs.iter_mut())
+            .zip(self.channels.iter_mut())
+        {
+            let mut write_pos = self.write_pos;
+
+            for i in 0..samples {
+                write_pos = if write_pos + 1 == buffer_len {
+                    0
+                } else {
+                    write_pos + 1
+                };
+                channel.buffer[write_pos] = input[i];
+
+                let delay = start_delay_samples + delay_step * (i + 1) as f32;
+                output[i] = channel.read(write_pos, delay, self.interpolation);
+            }
+        }
+
+        self.write_pos = (self.write_pos + samples) % buffer_len;
+        self.delay_samples = target_delay_samples;
+
+        ProcessStatus::all_outputs_filled()
+    }
+}
+
+impl<C> Into<Box<dyn AudioNode<C>>> for DelayNode {
+    fn into(self) -> Box<dyn AudioNode<C>> {
+        Box::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use firewheel_core::SilenceMask;
+
+    use super::*;
+    use crate::basic_nodes::process_block;
+
+    /// Run a sine through a slowly modulated delay and return the energy of
+    /// the second difference of the output, which is a rough measure of the
+    /// high-frequency artifacts caused by the modulation.
+    fn modulated_delay_artifacts(interpolation: DelayInterpolation) -> f32 {
+        let stream_info = StreamInfo::default();
+        let samples = stream_info.max_block_samples as usize;
+        let sample_rate = stream_info.sample_rate as f32;
+
+        let mut node = DelayNode::new(0.05, 0.01, interpolation);
+        let mut processor = AudioNode::<()>::activate(
+            &mut node,
+            &stream_info,
+            ChannelConfig {
+                num_inputs: ChannelCount::MONO,
+                num_outputs: ChannelCount::MONO,
+            },
+        )
+        .unwrap();
+
+        let mut phasor: f32 = 0.0;
+        let mut input = vec![0.0; samples];
+        let mut output = vec![0.0; samples];
+        let mut artifacts = 0.0;
+        let mut prev = (0.0, 0.0);
+
+        for block in 0..40 {
+            for s in input.iter_mut() {
+                *s = (phasor * std::f32::consts::TAU).sin();
+                phasor = (phasor + 440.0 / sample_rate).fract();
+            }
+
+            // Sweep the delay between 10ms and 20ms.
+            let lfo = (block as f32 * 0.1).sin() * 0.5 + 0.5;
+            node.set_delay_secs(0.01 + lfo * 0.01);
+
+            process_block(
+                processor.as_mut(),
+                &[&input],
+                &mut [&mut output],
+                SilenceMask::NONE_SILENT,
+            );
+
+            // Skip the blocks before the delay line has filled up.
+            for &s in output.iter() {
+                if block >= 2 {
+                    let second_diff: f32 = s - 2.0 * prev.1 + prev.0;
+                    artifacts += second_diff * second_diff;
+                }
+                prev = (prev.1, s);
+            }
+        }
+
+        artifacts
+    }
+
+    #[test]
+    fn interpolation_reduces_modulation_artifacts() {
+        let none = modulated_delay_artifacts(DelayInterpolation::None);
+
+        for interpolation in [
+            DelayInterpolation::Linear,
+            DelayInterpolation::Allpass,
+            DelayInterpolation::Cubic,
+        ] {
+            let artifacts = modulated_delay_artifacts(interpolation);
+            assert!(
+                artifacts < none * 0.5,
+                "{:?}: {} vs {}",
+                interpolation,
+                artifacts,
+                none
+            );
+        }
+    }
+
+    #[test]
+    fn integer_delay() {
+        let stream_info = StreamInfo::default();
+        let samples = stream_info.max_block_samples as usize;
+
+        for interpolation in [
+            DelayInterpolation::None,
+            DelayInterpolation::Linear,
+            DelayInterpolation::Allpass,
+            DelayInterpolation::Cubic,
+        ] {
+            let delay_secs = 100.0 / stream_info.sample_rate as f32;
+            let mut node = DelayNode::new(0.01, delay_secs, interpolation);
+            let mut processor = AudioNode::<()>::activate(
+                &mut node,
+                &stream_info,
+                ChannelConfig {
+                    num_inputs: ChannelCount::MONO,
+                    num_outputs: ChannelCount::MONO,
+                },
+            )
+            .unwrap();
+
+            let mut input = vec![0.0; samples];
+            input[10] = 1.0;
+            let mut output = vec![0.0; samples];
+
+            process_block(
+                processor.as_mut(),
+                &[&input],
+                &mut [&mut output],
+                SilenceMask::NONE_SILENT,
+            );
+
+            for (i, &s) in output.iter().enumerate() {
+                let expected = if i == 110 { 1.0 } else { 0.0 };
+                assert!((s - expected).abs() < 0.001, "{:?}", interpolation);
+            }
+        }
+    }
+}
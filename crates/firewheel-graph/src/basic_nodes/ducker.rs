@@ -0,0 +1,461 @@
+use firewheel_core::{
+    node::{AudioNode, AudioNodeInfo, AudioNodeProcessor, ProcInfo, ProcessStatus},
+    util::db_to_gain_clamped_neg_100_db,
+    ChannelConfig, ChannelCount, SilenceMask, StreamInfo,
+};
+
+use crate::{
+    error::{AddEdgeError, NodeError},
+    graph::{AudioGraph, NodeID},
+};
+
+use super::SumNode;
+
+/// The parameters of a [`DuckerNode`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DuckerParams {
+    /// The level in decibels the sidechain signal must exceed in order to
+    /// duck the main signal.
+    ///
+    /// By default this is set to `-40.0`.
+    pub threshold_db: f32,
+    /// The gain in decibels applied to the main signal while it is ducked.
+    ///
+    /// By default this is set to `-12.0`.
+    pub duck_amount_db: f32,
+    /// How long in seconds the main signal stays ducked after the sidechain
+    /// signal falls below the threshold.
+    ///
+    /// By default this is set to `0.5`.
+    pub hold_secs: f32,
+    /// The time constant in seconds of the fade into the ducked state.
+    ///
+    /// By default this is set to `0.01`.
+    pub attack_secs: f32,
+    /// The time constant in seconds of the fade back to unity gain once the
+    /// hold period has passed.
+    ///
+    /// By default this is set to `0.25`.
+    pub release_secs: f32,
+}
+
+impl Default for DuckerParams {
+    fn default() -> Self {
+        Self {
+            threshold_db: -40.0,
+            duck_amount_db: -12.0,
+            hold_secs: 0.5,
+            attack_secs: 0.01,
+            release_secs: 0.25,
+        }
+    }
+}
+
+/// A node which lowers the volume of a main signal while a sidechain signal
+/// is present.
+///
+/// The first `num_outputs` input channels are the main signal, and the
+/// rest of the input channels are the sidechain signal. Only the (ducked)
+/// main signal is sent to the outputs.
+pub struct DuckerNode {
+    params: DuckerParams,
+}
+
+impl DuckerNode {
+    pub fn new(params: DuckerParams) -> Self {
+        Self { params }
+    }
+
+    pub fn params(&self) -> &DuckerParams {
+        &self.params
+    }
+}
+
+impl<C> AudioNode<C> for DuckerNode {
+    fn debug_name(&self) -> &'static str {
+        "ducker"
+    }
+
+    fn info(&self) -> AudioNodeInfo {
+        AudioNodeInfo {
+            num_min_supported_inputs: ChannelCount::STEREO,
+            num_max_supported_inputs: ChannelCount::MAX,
+            num_min_supported_outputs: ChannelCount::MONO,
+            num_max_supported_outputs: ChannelCount::MAX,
+            default_channel_config: ChannelConfig {
+                num_inputs: ChannelCount::new(4).unwrap(),
+                num_outputs: ChannelCount::STEREO,
+            },
+            equal_num_ins_and_outs: false,
+            updates: false,
+            silent_when_inputs_silent: false,
+            preserve_denormals: false,
+        }
+    }
+
+    fn channel_config_supported(
+        &self,
+        channel_config: ChannelConfig,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if channel_config.num_inputs.get() <= channel_config.num_outputs.get() {
+            Err(format!("A DuckerNode must have more inputs than outputs so that it has at least one sidechain input. Got config: {:?}", channel_config).into())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn activate(
+        &mut self,
+        stream_info: &StreamInfo,
+        _channel_config: ChannelConfig,
+    ) -> Result<Box<dyn AudioNodeProcessor<C>>, Box<dyn std::error::Error>> {
+        let sample_rate = stream_info.sample_rate as f32;
+        let coeff =
+            |secs: f32| (-1.0 / (f64::from(secs.max(0.0001)) * f64::from(sample_rate))).exp();
+
+        Ok(Box::new(DuckerProcessor {
+            threshold: db_to_gain_clamped_neg_100_db(self.params.threshold_db),
+            duck_gain: db_to_gain_clamped_neg_100_db(self.params.duck_amount_db).min(1.0),
+            hold_samples: (self.params.hold_secs.max(0.0) * sample_rate).round() as u32,
+            attack_coeff: coeff(self.params.attack_secs),
+            release_coeff: coeff(self.params.release_secs),
+            gain: 1.0,
+            hold_remaining: 0,
+        }))
+    }
+}
+
+struct DuckerProcessor {
+    threshold: f32,
+    duck_gain: f32,
+    hold_samples: u32,
+    attack_coeff: f64,
+    release_coeff: f64,
+
+    // This is stored as an `f64` so that the filter doesn't get stuck
+    // just short of unity gain due to rounding.
+    gain: f64,
+    hold_remaining: u32,
+}
+
+impl<C> AudioNodeProcessor<C> for DuckerProcessor {
+    fn process(
+        &mut self,
+        inputs: &[&[f32]],
+        outputs: &mut [&mut [f32]],
+        proc_info: ProcInfo,
+        _cx: &mut C,
+    ) -> ProcessStatus {
+        let samples = proc_info.samples;
+        let num_outputs = outputs.len();
+
+        let (main, sidechain) = inputs.split_at(num_outputs);
+
+        let mut out_silence_mask = SilenceMask::NONE_SILENT;
+        for ch_i in 0..num_outputs {
+            out_silence_mask.set_channel(ch_i, proc_info.in_silence_mask.is_channel_silent(ch_i));
+        }
+
+        let sidechain_silent = (num_outputs..inputs.len())
+            .all(|ch_i| proc_info.in_silence_mask.is_channel_silent(ch_i));
+
+        if sidechain_silent && self.hold_remaining == 0 && self.gain == 1.0 {
+            // Not ducking, so just pass the main signal through.
+            if out_silence_mask.all_channels_silent(num_outputs) {
+                return ProcessStatus::NoOutputsModified;
+            }
+
+            for (out, input) in outputs.iter_mut().zip(main.iter()) {
+                out[..samples].copy_from_slice(&input[..samples]);
+            }
+
+            return ProcessStatus::outputs_modified(out_silence_mask);
+        }
+
+        for i in 0..samples {
+            let level = if sidechain_silent {
+                0.0
+            } else {
+                sidechain
+                    .iter()
+                    .fold(0.0f32, |level, ch| level.max(ch[i].abs()))
+            };
+
+            let target = if level > self.threshold {
+                self.hold_remaining = self.hold_samples;
+                f64::from(self.duck_gain)
+            } else if self.hold_remaining > 0 {
+                self.hold_remaining -= 1;
+                f64::from(self.duck_gain)
+            } else {
+                1.0
+            };
+
+            let coeff = if target < self.gain {
+                self.attack_coeff
+            } else {
+                self.release_coeff
+            };
+            self.gain = target + (self.gain - target) * coeff;
+
+            // Snap to the target once it is close enough.
+            if (self.gain - target).abs() < 0.00001 {
+                self.gain = target;
+            }
+
+            for (out, input) in outputs.iter_mut().zip(main.iter()) {
+                out[i] = input[i] * self.gain as f32;
+            }
+        }
+
+        ProcessStatus::outputs_modified(out_silence_mask)
+    }
+}
+
+impl<C> Into<Box<dyn AudioNode<C>>> for DuckerNode {
+    fn into(self) -> Box<dyn AudioNode<C>> {
+        Box::new(self)
+    }
+}
+
+/// The nodes created by [`add_talkover`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TalkoverNodes {
+    /// The node which ducks the music.
+    pub ducker: NodeID,
+    /// The node which mixes the ducked music with the voice. Connect the
+    /// outputs of this node to where the music would normally go.
+    pub mix: NodeID,
+}
+
+/// An error that occured while calling [`add_talkover`].
+#[derive(Debug)]
+pub enum TalkoverError {
+    /// The music or voice node does not exist in the graph.
+    NodeNotFound(NodeID),
+    /// The voice node must either be mono or have the same number of
+    /// outputs as the music node.
+    ChannelMismatch {
+        music_channels: ChannelCount,
+        voice_channels: ChannelCount,
+    },
+    NodeError(NodeError),
+    AddEdgeError(AddEdgeError),
+}
+
+impl std::error::Error for TalkoverError {}
+
+impl std::fmt::Display for TalkoverError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NodeNotFound(node_id) => {
+                write!(f, "Node {:?} does not exist in the graph", node_id)
+            }
+            Self::ChannelMismatch {
+                music_channels,
+                voice_channels,
+            } => {
+                write!(f, "The voice node must either be mono or have the same number of outputs as the music node. Got {} music channels and {} voice channels", music_channels.get(), voice_channels.get())
+            }
+            Self::NodeError(e) => e.fmt(f),
+            Self::AddEdgeError(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<NodeError> for TalkoverError {
+    fn from(e: NodeError) -> Self {
+        Self::NodeError(e)
+    }
+}
+
+impl From<AddEdgeError> for TalkoverError {
+    fn from(e: AddEdgeError) -> Self {
+        Self::AddEdgeError(e)
+    }
+}
+
+/// Set up a "talkover" which automatically ducks the music while the voice
+/// is present, for radio or podcast style mixes.
+///
+/// This adds a [`DuckerNode`] which uses the voice as its sidechain, and a
+/// [`SumNode`] which mixes the ducked music with the voice. Connect the
+/// outputs of [`TalkoverNodes::mix`] to where the music would normally go.
+///
+/// The voice node must either be mono or have the same number of outputs
+/// as the music node.
+pub fn add_talkover<C: Send + 'static>(
+    graph: &mut AudioGraph<C>,
+    music: NodeID,
+    voice: NodeID,
+    params: DuckerParams,
+) -> Result<TalkoverNodes, TalkoverError> {
+    let num_music_channels = graph
+        .node_info(music)
+        .ok_or(TalkoverError::NodeNotFound(music))?
+        .channel_config
+        .num_outputs;
+    let num_voice_channels = graph
+        .node_info(voice)
+        .ok_or(TalkoverError::NodeNotFound(voice))?
+        .channel_config
+        .num_outputs;
+
+    if num_voice_channels != ChannelCount::MONO && num_voice_channels != num_music_channels {
+        return Err(TalkoverError::ChannelMismatch {
+            music_channels: num_music_channels,
+            voice_channels: num_voice_channels,
+        });
+    }
+
+    let n = num_music_channels.get();
+
+    let ducker = graph.add_node(
+        Box::new(DuckerNode::new(params)),
+        Some(ChannelConfig {
+            num_inputs: ChannelCount::new(n + num_voice_channels.get()).unwrap(),
+            num_outputs: num_music_channels,
+        }),
+    )?;
+    let mix = match graph.add_node(
+        Box::new(SumNode),
+        Some(ChannelConfig {
+            num_inputs: ChannelCount::new(n * 2).unwrap(),
+            num_outputs: num_music_channels,
+        }),
+    ) {
+        Ok(mix) => mix,
+        Err(e) => {
+            let _ = graph.remove_node(ducker);
+            return Err(e.into());
+        }
+    };
+
+    let num_music_ports = n as usize;
+    let num_voice_ports = num_voice_channels.get() as usize;
+
+    let mut connect = || -> Result<(), AddEdgeError> {
+        for ch_i in 0..num_music_ports {
+            let voice_port = if num_voice_channels == ChannelCount::MONO {
+                0
+            } else {
+                ch_i
+            };
+
+            graph.connect(music, ch_i, ducker, ch_i, false)?;
+            graph.connect(ducker, ch_i, mix, ch_i, false)?;
+            graph.connect(voice, voice_port, mix, num_music_ports + ch_i, false)?;
+        }
+
+        for ch_i in 0..num_voice_ports {
+            graph.connect(voice, ch_i, ducker, num_music_ports + ch_i, false)?;
+        }
+
+        Ok(())
+    };
+
+    if let Err(e) = connect() {
+        let _ = graph.remove_node(ducker);
+        let _ = graph.remove_node(mix);
+        return Err(e.into());
+    }
+
+    Ok(TalkoverNodes { ducker, mix })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic_nodes::process_block;
+
+    #[test]
+    fn ducks_while_voice_is_active() {
+        let stream_info = StreamInfo::default();
+        let samples = 441;
+
+        let mut node = DuckerNode::new(DuckerParams {
+            hold_secs: 0.1,
+            ..Default::default()
+        });
+        let mut processor = AudioNode::<()>::activate(
+            &mut node,
+            &stream_info,
+            ChannelConfig {
+                num_inputs: ChannelCount::STEREO,
+                num_outputs: ChannelCount::MONO,
+            },
+        )
+        .unwrap();
+
+        let music = vec![1.0; samples];
+        let voice = vec![0.5; samples];
+        let silence = vec![0.0; samples];
+        let mut output = vec![0.0; samples];
+
+        // Each block is 10 milliseconds long.
+        let mut process = |voice: &[f32], output: &mut [f32]| {
+            let voice_silent = voice.iter().all(|&s| s == 0.0);
+
+            process_block(
+                processor.as_mut(),
+                &[&music, voice],
+                &mut [output],
+                SilenceMask(if voice_silent { 0b10 } else { 0 }),
+            )
+        };
+
+        process(&silence, &mut output);
+        assert!(output.iter().all(|&s| s == 1.0));
+
+        // The music is ducked by 12dB while the voice is active.
+        for _ in 0..10 {
+            process(&voice, &mut output);
+        }
+        let duck_gain = db_to_gain_clamped_neg_100_db(-12.0);
+        assert!((output[samples - 1] - duck_gain).abs() < 0.01);
+
+        // The music stays ducked during the hold period.
+        for _ in 0..9 {
+            process(&silence, &mut output);
+            assert!((output[samples - 1] - duck_gain).abs() < 0.01);
+        }
+
+        // The music recovers after the hold period.
+        for _ in 0..400 {
+            process(&silence, &mut output);
+        }
+        assert!(output.iter().all(|&s| s == 1.0));
+    }
+
+    #[test]
+    fn talkover_wiring() {
+        use crate::{basic_nodes::beep_test::BeepTestNode, FirewheelConfig, FirewheelGraphCtx};
+
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig::default());
+        let _processor = cx.activate(StreamInfo::default(), ()).unwrap();
+
+        let graph = cx.graph_mut().unwrap();
+        let music = graph
+            .add_node(Box::new(BeepTestNode::new(220.0, -12.0, true)), None)
+            .unwrap();
+        let voice = graph
+            .add_node(
+                Box::new(BeepTestNode::new(440.0, -12.0, true)),
+                Some(ChannelConfig {
+                    num_inputs: ChannelCount::ZERO,
+                    num_outputs: ChannelCount::MONO,
+                }),
+            )
+            .unwrap();
+
+        let talkover = add_talkover(graph, music, voice, DuckerParams::default()).unwrap();
+        let graph_out = graph.graph_out_node();
+        graph.connect(talkover.mix, 0, graph_out, 0, false).unwrap();
+        graph.connect(talkover.mix, 1, graph_out, 1, false).unwrap();
+
+        assert!(matches!(
+            cx.update(),
+            crate::UpdateStatus::Active { graph_error: None }
+        ));
+    }
+}
@@ -0,0 +1,375 @@
+use atomic_float::AtomicF32;
+use firewheel_core::{
+    node::{AudioNode, AudioNodeInfo, AudioNodeProcessor, ProcInfo, ProcessStatus},
+    util::db_to_gain_clamped_neg_100_db,
+    ChannelConfig, ChannelCount, SilenceMask, StreamInfo,
+};
+use std::sync::{atomic::Ordering, Arc};
+
+use super::filter::{Biquad, BiquadCoeffs};
+
+/// A band of a [`DynamicEqNode`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DynamicEqBand {
+    /// The center frequency of the band in hertz.
+    ///
+    /// By default this is set to `1000.0`.
+    pub freq_hz: f32,
+    /// The quality factor of the band. Higher values make the band
+    /// narrower.
+    ///
+    /// By default this is set to `2.0`.
+    pub q: f32,
+    /// The level in decibels the signal in the band must exceed before the
+    /// band is attenuated.
+    ///
+    /// By default this is set to `-20.0`.
+    pub threshold_db: f32,
+    /// The maximum attenuation of the band in decibels. This is always
+    /// less than or equal to `0.0`.
+    ///
+    /// By default this is set to `-12.0`.
+    pub range_db: f32,
+    /// The time constant in seconds of the level detector when the level
+    /// rises.
+    ///
+    /// By default this is set to `0.005`.
+    pub attack_secs: f32,
+    /// The time constant in seconds of the level detector when the level
+    /// falls.
+    ///
+    /// By default this is set to `0.1`.
+    pub release_secs: f32,
+}
+
+impl Default for DynamicEqBand {
+    fn default() -> Self {
+        Self {
+            freq_hz: 1000.0,
+            q: 2.0,
+            threshold_db: -20.0,
+            range_db: -12.0,
+            attack_secs: 0.005,
+            release_secs: 0.1,
+        }
+    }
+}
+
+struct SharedBand {
+    threshold_db: AtomicF32,
+    range_db: AtomicF32,
+}
+
+/// A node which turns down each of its bands only while the level in that
+/// band is above its threshold, like a compressor which only acts on a
+/// narrow range of frequencies. This is useful for taming resonances only
+/// when they occur.
+///
+/// Unlike a multiband compressor, the signal is not split into bands.
+/// Each band is a peaking filter whose (negative) gain follows the level
+/// in the band, so the output is identical to the input while every band
+/// is below its threshold.
+pub struct DynamicEqNode {
+    bands: Vec<DynamicEqBand>,
+    shared: Arc<[SharedBand]>,
+}
+
+impl DynamicEqNode {
+    pub fn new(bands: &[DynamicEqBand]) -> Self {
+        let node = Self {
+            bands: bands.to_vec(),
+            shared: bands
+                .iter()
+                .map(|_| SharedBand {
+                    threshold_db: AtomicF32::new(0.0),
+                    range_db: AtomicF32::new(0.0),
+                })
+                .collect(),
+        };
+
+        for (i, band) in bands.iter().enumerate() {
+            node.set_threshold_db(i, band.threshold_db);
+            node.set_range_db(i, band.range_db);
+        }
+
+        node
+    }
+
+    pub fn num_bands(&self) -> usize {
+        self.bands.len()
+    }
+
+    /// The settings of the given band, or `None` if the band does not
+    /// exist.
+    pub fn band(&self, band: usize) -> Option<DynamicEqBand> {
+        self.bands.get(band).map(|b| DynamicEqBand {
+            threshold_db: self.shared[band].threshold_db.load(Ordering::Relaxed),
+            range_db: self.shared[band].range_db.load(Ordering::Relaxed),
+            ..*b
+        })
+    }
+
+    /// Set the threshold in decibels of the given band.
+    ///
+    /// This does nothing if the band does not exist.
+    pub fn set_threshold_db(&self, band: usize, threshold_db: f32) {
+        if let Some(shared) = self.shared.get(band) {
+            shared.threshold_db.store(threshold_db, Ordering::Relaxed);
+        }
+    }
+
+    /// Set the maximum attenuation in decibels of the given band.
+    ///
+    /// This is clamped to be less than or equal to `0.0`. This does nothing
+    /// if the band does not exist.
+    pub fn set_range_db(&self, band: usize, range_db: f32) {
+        if let Some(shared) = self.shared.get(band) {
+            shared.range_db.store(range_db.min(0.0), Ordering::Relaxed);
+        }
+    }
+}
+
+impl<C> AudioNode<C> for DynamicEqNode {
+    fn debug_name(&self) -> &'static str {
+        "dynamic_eq"
+    }
+
+    fn info(&self) -> AudioNodeInfo {
+        AudioNodeInfo {
+            num_min_supported_inputs: ChannelCount::MONO,
+            num_max_supported_inputs: ChannelCount::MAX,
+            num_min_supported_outputs: ChannelCount::MONO,
+            num_max_supported_outputs: ChannelCount::MAX,
+            default_channel_config: ChannelConfig {
+                num_inputs: ChannelCount::STEREO,
+                num_outputs: ChannelCount::STEREO,
+            },
+            equal_num_ins_and_outs: true,
+            updates: false,
+            silent_when_inputs_silent: false,
+            preserve_denormals: false,
+        }
+    }
+
+    fn activate(
+        &mut self,
+        stream_info: &StreamInfo,
+        channel_config: ChannelConfig,
+    ) -> Result<Box<dyn AudioNodeProcessor<C>>, Box<dyn std::error::Error>> {
+        let sample_rate = stream_info.sample_rate as f32;
+        let coeff = |secs: f32| (-1.0 / (secs.max(0.0001) * sample_rate)).exp();
+
+        let bands: Vec<BandProcessor> = self
+            .bands
+            .iter()
+            .map(|band| BandProcessor {
+                coeffs: BiquadCoeffs::bandpass(
+                    band.freq_hz.clamp(1.0, sample_rate * 0.49),
+                    band.q.max(0.1),
+                    sample_rate,
+                ),
+                attack_coeff: coeff(band.attack_secs),
+                release_coeff: coeff(band.release_secs),
+                threshold: 1.0,
+                min_gain: 1.0,
+            })
+            .collect();
+
+        Ok(Box::new(DynamicEqProcessor {
+            shared: Arc::clone(&self.shared),
+            channels: (0..channel_config.num_outputs.get())
+                .map(|_| {
+                    bands
+                        .iter()
+                        .map(|band| BandState {
+                            filter: Biquad::new(band.coeffs),
+                            envelope: 0.0,
+                        })
+                        .collect()
+                })
+                .collect(),
+            bands,
+        }))
+    }
+}
+
+struct BandProcessor {
+    coeffs: BiquadCoeffs,
+    attack_coeff: f32,
+    release_coeff: f32,
+    /// The threshold as a linear level, updated every block.
+    threshold: f32,
+    /// The range as a linear gain, updated every block.
+    min_gain: f32,
+}
+
+struct BandState {
+    filter: Biquad,
+    envelope: f32,
+}
+
+struct DynamicEqProcessor {
+    shared: Arc<[SharedBand]>,
+    bands: Vec<BandProcessor>,
+    /// The state of each band for each channel.
+    channels: Vec<Vec<BandState>>,
+}
+
+impl<C> AudioNodeProcessor<C> for DynamicEqProcessor {
+    fn process(
+        &mut self,
+        inputs: &[&[f32]],
+        outputs: &mut [&mut [f32]],
+        proc_info: ProcInfo,
+        _cx: &mut C,
+    ) -> ProcessStatus {
+        let samples = proc_info.samples;
+
+        if proc_info.in_silence_mask.all_channels_silent(inputs.len())
+            && self
+                .channels
+                .iter()
+                .flatten()
+                .all(|state| state.envelope < 0.00001)
+        {
+            // The bands are not attenuating, so the output is silent too.
+            for state in self.channels.iter_mut().flatten() {
+                state.filter.reset();
+                state.envelope = 0.0;
+            }
+
+            return ProcessStatus::NoOutputsModified;
+        }
+
+        for (band, shared) in self.bands.iter_mut().zip(self.shared.iter()) {
+            band.threshold =
+                db_to_gain_clamped_neg_100_db(shared.threshold_db.load(Ordering::Relaxed));
+            band.min_gain = db_to_gain_clamped_neg_100_db(shared.range_db.load(Ordering::Relaxed));
+        }
+
+        for (ch_i, ((input, output), states)) in inputs
+            .iter()
+            .zip(outputs.iter_mut())
+            .zip(self.channels.iter_mut())
+            .enumerate()
+        {
+            if proc_info.in_silence_mask.is_channel_silent(ch_i) {
+                output[..samples].fill(0.0);
+            } else {
+                output[..samples].copy_from_slice(&input[..samples]);
+            }
+
+            for s in output[..samples].iter_mut() {
+                for (band, state) in self.bands.iter().zip(states.iter_mut()) {
+                    let band_s = state.filter.process(*s);
+
+                    let level = band_s.abs();
+                    let coeff = if level > state.envelope {
+                        band.attack_coeff
+                    } else {
+                        band.release_coeff
+                    };
+                    state.envelope = level + (state.envelope - level) * coeff;
+
+                    // Attenuate the band by as much as the level exceeds the
+                    // threshold, up to the range of the band.
+                    let gain = if state.envelope > band.threshold {
+                        (band.threshold / state.envelope).max(band.min_gain)
+                    } else {
+                        1.0
+                    };
+
+                    // Adding a scaled bandpass filter to the signal is a
+                    // peaking filter with the given gain at the center
+                    // frequency.
+                    *s += (gain - 1.0) * band_s;
+                }
+            }
+        }
+
+        ProcessStatus::outputs_modified(SilenceMask::NONE_SILENT)
+    }
+}
+
+impl<C> Into<Box<dyn AudioNode<C>>> for DynamicEqNode {
+    fn into(self) -> Box<dyn AudioNode<C>> {
+        Box::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic_nodes::process_block;
+
+    /// Process one second of a sine wave with the given frequency and
+    /// amplitude, and return the ratio between the RMS levels of the
+    /// settled output and the input.
+    fn output_gain(freq_hz: f32, amplitude: f32) -> f32 {
+        let stream_info = StreamInfo::default();
+        let samples = stream_info.max_block_samples as usize;
+        let sample_rate = stream_info.sample_rate as f32;
+
+        let mut node = DynamicEqNode::new(&[DynamicEqBand {
+            freq_hz: 1000.0,
+            q: 4.0,
+            threshold_db: -20.0,
+            range_db: -12.0,
+            ..Default::default()
+        }]);
+        let mut processor = AudioNode::<()>::activate(
+            &mut node,
+            &stream_info,
+            ChannelConfig {
+                num_inputs: ChannelCount::MONO,
+                num_outputs: ChannelCount::MONO,
+            },
+        )
+        .unwrap();
+
+        let mut input = vec![0.0; samples];
+        let mut output = vec![0.0; samples];
+        let (mut in_power, mut out_power) = (0.0, 0.0);
+
+        let num_blocks = (sample_rate as usize / samples).max(1);
+        for block in 0..num_blocks {
+            for (i, s) in input.iter_mut().enumerate() {
+                let t = (block * samples + i) as f32 / sample_rate;
+                *s = (std::f32::consts::TAU * freq_hz * t).sin() * amplitude;
+            }
+
+            process_block(
+                processor.as_mut(),
+                &[&input],
+                &mut [&mut output],
+                SilenceMask::NONE_SILENT,
+            );
+
+            // Skip the first half second while the detector settles.
+            if block >= num_blocks / 2 {
+                for (&in_s, &out_s) in input.iter().zip(output.iter()) {
+                    in_power += in_s * in_s;
+                    out_power += out_s * out_s;
+                }
+            }
+        }
+
+        (out_power / in_power).sqrt()
+    }
+
+    #[test]
+    fn attenuates_loud_resonance() {
+        // A loud tone in the band is attenuated by the full range of 12 dB,
+        // since it is 14 dB above the threshold.
+        let gain = output_gain(1000.0, 0.5);
+        assert!((gain - 0.25).abs() < 0.03, "{}", gain);
+
+        // A quiet tone in the band is left alone.
+        let gain = output_gain(1000.0, 0.05);
+        assert!((gain - 1.0).abs() < 0.001, "{}", gain);
+
+        // So is a loud tone outside of the band.
+        let gain = output_gain(100.0, 0.5);
+        assert!((gain - 1.0).abs() < 0.001, "{}", gain);
+    }
+}
@@ -0,0 +1,336 @@
+use atomic_float::AtomicF32;
+use std::sync::{atomic::Ordering, Arc, Mutex};
+
+use firewheel_core::{
+    node::{AudioNode, AudioNodeInfo, AudioNodeProcessor, ProcInfo, ProcessStatus},
+    ChannelConfig, ChannelCount, StreamInfo,
+};
+
+/// The amount of delay a feedback loop introduces to break the cycle in the
+/// audio graph.
+///
+/// A feedback loop can never be shorter than the block size, since the
+/// whole loop is only processed once per block. The block size is a setting
+/// of the whole graph and not of each feedback loop, so a
+/// [`FeedbackDelay::Samples`] delay shorter than
+/// [`StreamInfo::max_block_samples`] is rejected when the nodes are
+/// activated. To get a feedback loop of exactly `n` samples, set
+/// `FirewheelConfig::fixed_block_samples` to `n` (or less) for the whole
+/// graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FeedbackDelay {
+    /// Delay by this many samples. This must be at least
+    /// [`StreamInfo::max_block_samples`].
+    Samples(u32),
+    /// Delay by this many blocks of [`StreamInfo::max_block_samples`]
+    /// frames. This must be at least one.
+    Blocks(u32),
+}
+
+impl Default for FeedbackDelay {
+    fn default() -> Self {
+        Self::Blocks(1)
+    }
+}
+
+impl FeedbackDelay {
+    /// The delay in samples when the maximum block size of the stream is
+    /// `max_block_samples`.
+    ///
+    /// Returns `None` if the delay is shorter than one block.
+    pub fn to_samples(&self, max_block_samples: u32) -> Option<usize> {
+        let max_block_samples = max_block_samples.max(1);
+
+        match *self {
+            Self::Samples(samples) => (samples >= max_block_samples).then_some(samples as usize),
+            Self::Blocks(blocks) => {
+                (blocks > 0).then(|| blocks as usize * max_block_samples as usize)
+            }
+        }
+    }
+}
+
+/// Create a pair of nodes which feed a signal back to an earlier point in
+/// the audio graph.
+///
+/// Everything connected to the inputs of the [`FeedbackSendNode`] comes
+/// out of the outputs of the [`FeedbackReturnNode`] after the given delay,
+/// multiplied by `gain`. Since the send node has no outputs and the return
+/// node has no inputs, the loop does not create a cycle in the graph.
+///
+/// The loop can't be shorter than one block. If `delay` is shorter than
+/// [`StreamInfo::max_block_samples`], then activating either node fails.
+/// Note that the block size applies to the whole graph, see
+/// [`FeedbackDelay`].
+pub fn feedback_nodes(delay: FeedbackDelay, gain: f32) -> (FeedbackSendNode, FeedbackReturnNode) {
+    let buffer = Arc::new(Mutex::new(FeedbackBuffer {
+        channels: Vec::new(),
+        delay_samples: 0,
+    }));
+
+    (
+        FeedbackSendNode {
+            buffer: Arc::clone(&buffer),
+            delay,
+        },
+        FeedbackReturnNode {
+            buffer,
+            delay,
+            gain: Arc::new(AtomicF32::new(gain)),
+        },
+    )
+}
+
+/// The delay line shared by both nodes of a pair.
+///
+/// Unlike the other state shared with the audio thread, this is behind a
+/// [`Mutex`]. It is only ever locked with `lock` on the main thread, while
+/// the nodes are activated and the buffer is (re)allocated. The processors
+/// only use `try_lock`, so the audio thread never blocks. At worst it
+/// skips a block while the pair is being reactivated, during which the
+/// loop's contents are being replaced anyway.
+struct FeedbackBuffer {
+    channels: Vec<Vec<f32>>,
+    delay_samples: usize,
+}
+
+impl FeedbackBuffer {
+    /// Allocate the buffer for the given stream. Both nodes of the pair
+    /// call this when they are activated.
+    fn prepare(
+        &mut self,
+        num_channels: usize,
+        stream_info: &StreamInfo,
+        delay: FeedbackDelay,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(delay_samples) = delay.to_samples(stream_info.max_block_samples) else {
+            return Err(format!(
+                "A feedback delay of {:?} is shorter than the block size of {} samples",
+                delay, stream_info.max_block_samples
+            )
+            .into());
+        };
+        let buffer_len = delay_samples + stream_info.max_block_samples as usize;
+
+        let num_channels = num_channels.max(self.channels.len());
+        if self.delay_samples != delay_samples
+            || self.channels.len() != num_channels
+            || self.channels.iter().any(|ch| ch.len() != buffer_len)
+        {
+            self.channels = (0..num_channels).map(|_| vec![0.0; buffer_len]).collect();
+            self.delay_samples = delay_samples;
+        }
+
+        Ok(())
+    }
+}
+
+/// The node which receives the signal to feed back. See [`feedback_nodes`].
+pub struct FeedbackSendNode {
+    buffer: Arc<Mutex<FeedbackBuffer>>,
+    delay: FeedbackDelay,
+}
+
+impl FeedbackSendNode {
+    pub fn delay(&self) -> FeedbackDelay {
+        self.delay
+    }
+}
+
+impl<C> AudioNode<C> for FeedbackSendNode {
+    fn debug_name(&self) -> &'static str {
+        "feedback_send"
+    }
+
+    fn info(&self) -> AudioNodeInfo {
+        AudioNodeInfo {
+            num_min_supported_inputs: ChannelCount::MONO,
+            num_max_supported_inputs: ChannelCount::MAX,
+            num_min_supported_outputs: ChannelCount::ZERO,
+            num_max_supported_outputs: ChannelCount::ZERO,
+            default_channel_config: ChannelConfig {
+                num_inputs: ChannelCount::STEREO,
+                num_outputs: ChannelCount::ZERO,
+            },
+            equal_num_ins_and_outs: false,
+            updates: false,
+            silent_when_inputs_silent: false,
+            preserve_denormals: false,
+        }
+    }
+
+    fn activate(
+        &mut self,
+        stream_info: &StreamInfo,
+        channel_config: ChannelConfig,
+    ) -> Result<Box<dyn AudioNodeProcessor<C>>, Box<dyn std::error::Error>> {
+        self.buffer.lock().unwrap().prepare(
+            channel_config.num_inputs.get() as usize,
+            stream_info,
+            self.delay,
+        )?;
+
+        Ok(Box::new(FeedbackSendProcessor {
+            buffer: Arc::clone(&self.buffer),
+        }))
+    }
+}
+
+struct FeedbackSendProcessor {
+    buffer: Arc<Mutex<FeedbackBuffer>>,
+}
+
+impl<C> AudioNodeProcessor<C> for FeedbackSendProcessor {
+    fn process(
+        &mut self,
+        inputs: &[&[f32]],
+        _outputs: &mut [&mut [f32]],
+        proc_info: ProcInfo,
+        _cx: &mut C,
+    ) -> ProcessStatus {
+        // The buffer is only locked by the main thread while the pair is
+        // being activated.
+        let Ok(mut buffer) = self.buffer.try_lock() else {
+            return ProcessStatus::NoOutputsModified;
+        };
+
+        let start = proc_info.clock_samples.0 as usize;
+
+        for (ch_i, (input, channel)) in inputs.iter().zip(buffer.channels.iter_mut()).enumerate() {
+            let buffer_len = channel.len();
+            let silent = proc_info.in_silence_mask.is_channel_silent(ch_i);
+
+            for (i, &s) in input[..proc_info.samples].iter().enumerate() {
+                channel[(start + i) % buffer_len] = if silent { 0.0 } else { s };
+            }
+        }
+
+        ProcessStatus::NoOutputsModified
+    }
+}
+
+impl<C> Into<Box<dyn AudioNode<C>>> for FeedbackSendNode {
+    fn into(self) -> Box<dyn AudioNode<C>> {
+        Box::new(self)
+    }
+}
+
+/// The node which outputs the delayed signal. See [`feedback_nodes`].
+pub struct FeedbackReturnNode {
+    buffer: Arc<Mutex<FeedbackBuffer>>,
+    delay: FeedbackDelay,
+    gain: Arc<AtomicF32>,
+}
+
+impl FeedbackReturnNode {
+    pub fn delay(&self) -> FeedbackDelay {
+        self.delay
+    }
+
+    /// The delay of the loop in samples, or `None` if the pair has not been
+    /// activated yet.
+    ///
+    pub fn delay_samples(&self) -> Option<usize> {
+        let buffer = self.buffer.lock().unwrap();
+        (!buffer.channels.is_empty()).then_some(buffer.delay_samples)
+    }
+
+    pub fn gain(&self) -> f32 {
+        self.gain.load(Ordering::Relaxed)
+    }
+
+    /// Set the raw linear gain applied to the signal which is fed back.
+    pub fn set_gain(&self, gain: f32) {
+        self.gain.store(gain, Ordering::Relaxed);
+    }
+}
+
+impl<C> AudioNode<C> for FeedbackReturnNode {
+    fn debug_name(&self) -> &'static str {
+        "feedback_return"
+    }
+
+    fn info(&self) -> AudioNodeInfo {
+        AudioNodeInfo {
+            num_min_supported_inputs: ChannelCount::ZERO,
+            num_max_supported_inputs: ChannelCount::ZERO,
+            num_min_supported_outputs: ChannelCount::MONO,
+            num_max_supported_outputs: ChannelCount::MAX,
+            default_channel_config: ChannelConfig {
+                num_inputs: ChannelCount::ZERO,
+                num_outputs: ChannelCount::STEREO,
+            },
+            equal_num_ins_and_outs: false,
+            updates: false,
+            silent_when_inputs_silent: false,
+            preserve_denormals: false,
+        }
+    }
+
+    fn activate(
+        &mut self,
+        stream_info: &StreamInfo,
+        channel_config: ChannelConfig,
+    ) -> Result<Box<dyn AudioNodeProcessor<C>>, Box<dyn std::error::Error>> {
+        self.buffer.lock().unwrap().prepare(
+            channel_config.num_outputs.get() as usize,
+            stream_info,
+            self.delay,
+        )?;
+
+        Ok(Box::new(FeedbackReturnProcessor {
+            buffer: Arc::clone(&self.buffer),
+            gain: Arc::clone(&self.gain),
+        }))
+    }
+}
+
+struct FeedbackReturnProcessor {
+    buffer: Arc<Mutex<FeedbackBuffer>>,
+    gain: Arc<AtomicF32>,
+}
+
+impl<C> AudioNodeProcessor<C> for FeedbackReturnProcessor {
+    fn process(
+        &mut self,
+        _inputs: &[&[f32]],
+        outputs: &mut [&mut [f32]],
+        proc_info: ProcInfo,
+        _cx: &mut C,
+    ) -> ProcessStatus {
+        let Ok(buffer) = self.buffer.try_lock() else {
+            return ProcessStatus::NoOutputsModified;
+        };
+
+        let gain = self.gain.load(Ordering::Relaxed);
+        let start = proc_info.clock_samples.0 as usize;
+        let delay_samples = buffer.delay_samples;
+
+        for (ch_i, output) in outputs.iter_mut().enumerate() {
+            let output = &mut output[..proc_info.samples];
+
+            let Some(channel) = buffer.channels.get(ch_i) else {
+                output.fill(0.0);
+                continue;
+            };
+            let buffer_len = channel.len();
+
+            // Since the delay is at least one block long, every sample read
+            // here was written by the send node in a previous block.
+            for (i, s) in output.iter_mut().enumerate() {
+                *s = match (start + i).checked_sub(delay_samples) {
+                    Some(t) => channel[t % buffer_len] * gain,
+                    None => 0.0,
+                };
+            }
+        }
+
+        ProcessStatus::all_outputs_filled()
+    }
+}
+
+impl<C> Into<Box<dyn AudioNode<C>>> for FeedbackReturnNode {
+    fn into(self) -> Box<dyn AudioNode<C>> {
+        Box::new(self)
+    }
+}
@@ -0,0 +1,117 @@
+/// The coefficients of a biquad filter, normalized so that `a0 = 1`.
+#[derive(Default, Clone, Copy)]
+pub(super) struct BiquadCoeffs {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl BiquadCoeffs {
+    /// A 2nd order Butterworth lowpass or highpass filter.
+    pub fn butterworth(freq_hz: f32, sample_rate: f32, highpass: bool) -> Self {
+        let w0 = std::f32::consts::TAU * freq_hz / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin * std::f32::consts::FRAC_1_SQRT_2;
+        let a0_recip = (1.0 + alpha).recip();
+
+        let (b0, b1) = if highpass {
+            ((1.0 + cos) * 0.5, -(1.0 + cos))
+        } else {
+            ((1.0 - cos) * 0.5, 1.0 - cos)
+        };
+
+        Self {
+            b0: b0 * a0_recip,
+            b1: b1 * a0_recip,
+            b2: b0 * a0_recip,
+            a1: -2.0 * cos * a0_recip,
+            a2: (1.0 - alpha) * a0_recip,
+        }
+    }
+
+    /// A 2nd order bandpass filter with a gain of 0 dB at the center
+    /// frequency.
+    pub fn bandpass(freq_hz: f32, q: f32, sample_rate: f32) -> Self {
+        let w0 = std::f32::consts::TAU * freq_hz / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q);
+        let a0_recip = (1.0 + alpha).recip();
+
+        Self {
+            b0: alpha * a0_recip,
+            b1: 0.0,
+            b2: -alpha * a0_recip,
+            a1: -2.0 * cos * a0_recip,
+            a2: (1.0 - alpha) * a0_recip,
+        }
+    }
+}
+
+/// A biquad filter in transposed direct form II.
+#[derive(Default, Clone, Copy)]
+pub(super) struct Biquad {
+    coeffs: BiquadCoeffs,
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+    pub fn new(coeffs: BiquadCoeffs) -> Self {
+        Self {
+            coeffs,
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    #[inline]
+    pub fn process(&mut self, x: f32) -> f32 {
+        let c = &self.coeffs;
+        let y = c.b0 * x + self.z1;
+        self.z1 = c.b1 * x - c.a1 * y + self.z2;
+        self.z2 = c.b2 * x - c.a2 * y;
+        y
+    }
+
+    pub fn reset(&mut self) {
+        self.z1 = 0.0;
+        self.z2 = 0.0;
+    }
+}
+
+/// A 4th order Linkwitz-Riley crossover, made of two cascaded Butterworth
+/// filters per band.
+#[derive(Default)]
+pub(super) struct Crossover {
+    lowpass: [Biquad; 2],
+    highpass: [Biquad; 2],
+}
+
+impl Crossover {
+    pub fn set_coeffs(&mut self, lowpass: BiquadCoeffs, highpass: BiquadCoeffs) {
+        for filter in self.lowpass.iter_mut() {
+            filter.coeffs = lowpass;
+        }
+        for filter in self.highpass.iter_mut() {
+            filter.coeffs = highpass;
+        }
+    }
+
+    /// Returns the `(low, high)` bands of the given sample.
+    #[inline]
+    pub fn process(&mut self, x: f32) -> (f32, f32) {
+        let low = self.lowpass[0].process(x);
+        let low = self.lowpass[1].process(low);
+        let high = self.highpass[0].process(x);
+        let high = self.highpass[1].process(high);
+        (low, high)
+    }
+
+    pub fn reset(&mut self) {
+        for filter in self.lowpass.iter_mut().chain(self.highpass.iter_mut()) {
+            filter.reset();
+        }
+    }
+}
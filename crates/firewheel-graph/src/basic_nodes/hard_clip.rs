@@ -32,6 +32,8 @@ impl<C> AudioNode<C> for HardClipNode {
             },
             equal_num_ins_and_outs: true,
             updates: false,
+            silent_when_inputs_silent: true,
+            preserve_denormals: false,
         }
     }
 
@@ -0,0 +1,350 @@
+use atomic_float::AtomicF32;
+use std::sync::{
+    atomic::{AtomicU8, Ordering},
+    Arc,
+};
+
+use firewheel_core::{
+    node::{AudioNode, AudioNodeInfo, AudioNodeProcessor, ProcInfo, ProcessStatus},
+    ChannelConfig, ChannelCount, StreamInfo,
+};
+
+const STATE_IDLE: u8 = 0;
+const STATE_TRIGGERED: u8 = 1;
+const STATE_PLAYING: u8 = 2;
+const STATE_FINISHED: u8 = 3;
+
+/// The lowest frequency that can be played. This determines the size of
+/// the delay line allocated in [`AudioNode::activate`].
+pub const KARPLUS_STRONG_MIN_FREQ_HZ: f32 = 20.0;
+
+/// The amount of feedback lost per trip around the delay line when the
+/// damping is set to `1.0`.
+const MAX_DAMPING_LOSS: f32 = 0.02;
+
+/// Once the output stays below this amplitude for a full period, the note
+/// is considered finished (-80dB).
+const SILENCE_THRESHOLD: f32 = 0.0001;
+
+struct SharedState {
+    freq_hz: AtomicF32,
+    damping: AtomicF32,
+    state: AtomicU8,
+}
+
+/// A node which synthesizes a plucked-string tone using the Karplus-Strong
+/// algorithm.
+///
+/// A burst of noise is fed into a delay line whose length sets the pitch,
+/// and the output of the delay line is fed back into itself through a
+/// lowpass filter so that the tone gets duller and quieter over time.
+///
+/// No sound is made until [`KarplusStrongNode::pluck`] is called.
+pub struct KarplusStrongNode {
+    shared: Arc<SharedState>,
+    gain: f32,
+}
+
+impl KarplusStrongNode {
+    /// Create a new plucked-string node.
+    ///
+    /// * `freq_hz` - The frequency of the note in hertz.
+    /// * `damping` - How fast the note decays in the range `[0.0, 1.0]`.
+    /// * `gain_db` - The volume of the note in decibels.
+    pub fn new(freq_hz: f32, damping: f32, gain_db: f32) -> Self {
+        let gain = firewheel_core::util::db_to_gain_clamped_neg_100_db(gain_db).clamp(0.0, 1.0);
+
+        let node = Self {
+            shared: Arc::new(SharedState {
+                freq_hz: AtomicF32::new(0.0),
+                damping: AtomicF32::new(0.0),
+                state: AtomicU8::new(STATE_IDLE),
+            }),
+            gain,
+        };
+        node.set_freq_hz(freq_hz);
+        node.set_damping(damping);
+
+        node
+    }
+
+    pub fn freq_hz(&self) -> f32 {
+        self.shared.freq_hz.load(Ordering::Relaxed)
+    }
+
+    /// Set the frequency of the note in hertz.
+    ///
+    /// This takes effect the next time the string is plucked.
+    pub fn set_freq_hz(&self, freq_hz: f32) {
+        self.shared.freq_hz.store(
+            freq_hz.clamp(KARPLUS_STRONG_MIN_FREQ_HZ, 20_000.0),
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn damping(&self) -> f32 {
+        self.shared.damping.load(Ordering::Relaxed)
+    }
+
+    /// Set how fast the note decays in the range `[0.0, 1.0]`, where `0.0`
+    /// lets the string ring for as long as possible.
+    ///
+    /// This takes effect immediately, even on a note which is currently
+    /// playing.
+    pub fn set_damping(&self, damping: f32) {
+        self.shared
+            .damping
+            .store(damping.clamp(0.0, 1.0), Ordering::Relaxed);
+    }
+
+    /// Pluck the string, starting a new note.
+    pub fn pluck(&self) {
+        self.shared.state.store(STATE_TRIGGERED, Ordering::Release);
+    }
+
+    /// Returns `true` if the string has been plucked and the note has not
+    /// yet decayed.
+    pub fn is_active(&self) -> bool {
+        let state = self.shared.state.load(Ordering::Acquire);
+        state == STATE_TRIGGERED || state == STATE_PLAYING
+    }
+
+    /// Returns `true` if the last note has fully decayed. This is reset
+    /// when the string is plucked again.
+    pub fn is_finished(&self) -> bool {
+        self.shared.state.load(Ordering::Acquire) == STATE_FINISHED
+    }
+}
+
+impl<C> AudioNode<C> for KarplusStrongNode {
+    fn debug_name(&self) -> &'static str {
+        "karplus_strong"
+    }
+
+    fn info(&self) -> AudioNodeInfo {
+        AudioNodeInfo {
+            num_min_supported_outputs: ChannelCount::MONO,
+            num_max_supported_outputs: ChannelCount::MAX,
+            default_channel_config: ChannelConfig {
+                num_inputs: ChannelCount::ZERO,
+                num_outputs: ChannelCount::STEREO,
+            },
+            ..Default::default()
+        }
+    }
+
+    fn activate(
+        &mut self,
+        stream_info: &StreamInfo,
+        _channel_config: ChannelConfig,
+    ) -> Result<Box<dyn AudioNodeProcessor<C>>, Box<dyn std::error::Error>> {
+        let sample_rate = stream_info.sample_rate as f32;
+        let max_delay_len = (sample_rate / KARPLUS_STRONG_MIN_FREQ_HZ).ceil() as usize + 1;
+
+        Ok(Box::new(KarplusStrongProcessor {
+            shared: Arc::clone(&self.shared),
+            delay_line: vec![0.0; max_delay_len],
+            delay_len: 0,
+            read_pos: 0,
+            quiet_samples: 0,
+            rng_state: 0x9E37_79B9,
+            sample_rate,
+            gain: self.gain,
+        }))
+    }
+}
+
+struct KarplusStrongProcessor {
+    shared: Arc<SharedState>,
+    delay_line: Vec<f32>,
+    delay_len: usize,
+    read_pos: usize,
+    quiet_samples: usize,
+    rng_state: u32,
+    sample_rate: f32,
+    gain: f32,
+}
+
+impl KarplusStrongProcessor {
+    fn pluck(&mut self) {
+        let freq_hz = self.shared.freq_hz.load(Ordering::Relaxed);
+
+        // The averaging filter in the feedback loop adds half a sample of
+        // delay.
+        self.delay_len =
+            ((self.sample_rate / freq_hz - 0.5).round() as usize).clamp(2, self.delay_line.len());
+        self.read_pos = 0;
+        self.quiet_samples = 0;
+
+        for s in self.delay_line[..self.delay_len].iter_mut() {
+            // Xorshift white noise in the range `[-1.0, 1.0]`.
+            self.rng_state ^= self.rng_state << 13;
+            self.rng_state ^= self.rng_state >> 17;
+            self.rng_state ^= self.rng_state << 5;
+
+            *s = (self.rng_state as f32 / u32::MAX as f32) * 2.0 - 1.0;
+        }
+    }
+}
+
+impl<C> AudioNodeProcessor<C> for KarplusStrongProcessor {
+    fn process(
+        &mut self,
+        _inputs: &[&[f32]],
+        outputs: &mut [&mut [f32]],
+        proc_info: ProcInfo,
+        _cx: &mut C,
+    ) -> ProcessStatus {
+        let Some((out1, outputs)) = outputs.split_first_mut() else {
+            return ProcessStatus::NoOutputsModified;
+        };
+
+        if self
+            .shared
+            .state
+            .compare_exchange(
+                STATE_TRIGGERED,
+                STATE_PLAYING,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_ok()
+        {
+            self.pluck();
+        } else if self.shared.state.load(Ordering::Acquire) != STATE_PLAYING {
+            return ProcessStatus::NoOutputsModified;
+        }
+
+        let feedback = 1.0 - self.shared.damping.load(Ordering::Relaxed) * MAX_DAMPING_LOSS;
+        let delay_line = &mut self.delay_line[..self.delay_len];
+
+        for s in out1[..proc_info.samples].iter_mut() {
+            let next_pos = if self.read_pos + 1 == delay_line.len() {
+                0
+            } else {
+                self.read_pos + 1
+            };
+
+            let current = delay_line[self.read_pos];
+            delay_line[self.read_pos] = (current + delay_line[next_pos]) * 0.5 * feedback;
+            self.read_pos = next_pos;
+
+            if current.abs() < SILENCE_THRESHOLD {
+                self.quiet_samples += 1;
+            } else {
+                self.quiet_samples = 0;
+            }
+
+            *s = current * self.gain;
+        }
+
+        if self.quiet_samples >= self.delay_len {
+            // Only mark the note as finished if the string wasn't plucked
+            // again in the meantime.
+            let _ = self.shared.state.compare_exchange(
+                STATE_PLAYING,
+                STATE_FINISHED,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            );
+        }
+
+        for out2 in outputs.iter_mut() {
+            out2[..proc_info.samples].copy_from_slice(&out1[..proc_info.samples]);
+        }
+
+        ProcessStatus::all_outputs_filled()
+    }
+}
+
+impl<C> Into<Box<dyn AudioNode<C>>> for KarplusStrongNode {
+    fn into(self) -> Box<dyn AudioNode<C>> {
+        Box::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use firewheel_core::SilenceMask;
+
+    use super::*;
+    use crate::basic_nodes::process_block;
+
+    fn rms(buffer: &[f32]) -> f32 {
+        (buffer.iter().map(|s| s * s).sum::<f32>() / buffer.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn pluck_fundamental_and_decay() {
+        let stream_info = StreamInfo::default();
+        let samples = stream_info.max_block_samples as usize;
+
+        let mut node = KarplusStrongNode::new(441.0, 0.5, 0.0);
+        let mut processor = AudioNode::<()>::activate(
+            &mut node,
+            &stream_info,
+            ChannelConfig {
+                num_inputs: ChannelCount::ZERO,
+                num_outputs: ChannelCount::MONO,
+            },
+        )
+        .unwrap();
+
+        let mut process = |output: &mut [f32]| {
+            process_block(
+                processor.as_mut(),
+                &[],
+                &mut [output],
+                SilenceMask::NONE_SILENT,
+            )
+        };
+
+        let mut output = vec![0.0; samples];
+        assert_eq!(process(&mut output), ProcessStatus::NoOutputsModified);
+
+        node.pluck();
+        assert!(node.is_active());
+
+        let mut blocks = Vec::new();
+        for _ in 0..4 {
+            assert_eq!(process(&mut output), ProcessStatus::all_outputs_filled());
+            blocks.extend_from_slice(&output);
+        }
+
+        // Find the fundamental period with autocorrelation, skipping the
+        // initial noise burst.
+        let signal = &blocks[samples..];
+        let autocorrelation = |lag: usize| -> f32 {
+            signal[..signal.len() - lag]
+                .iter()
+                .zip(signal[lag..].iter())
+                .map(|(a, b)| a * b)
+                .sum()
+        };
+        let period = (50..200)
+            .max_by(|&a, &b| autocorrelation(a).total_cmp(&autocorrelation(b)))
+            .unwrap();
+        // The period can only be measured to the nearest sample.
+        let fundamental_hz = stream_info.sample_rate as f32 / period as f32;
+        assert!((fundamental_hz - 441.0).abs() < 441.0 * 0.011);
+
+        assert!(rms(&blocks[3 * samples..]) < rms(&blocks[..samples]));
+
+        let mut finished = false;
+        for _ in 0..(stream_info.sample_rate as usize * 10 / samples) {
+            process(&mut output);
+
+            if node.is_finished() {
+                finished = true;
+                break;
+            }
+        }
+        assert!(finished);
+        assert!(!node.is_active());
+        assert!(output[samples - 64..]
+            .iter()
+            .all(|s| s.abs() < SILENCE_THRESHOLD));
+
+        assert_eq!(process(&mut output), ProcessStatus::NoOutputsModified);
+    }
+}
@@ -0,0 +1,337 @@
+use atomic_float::AtomicF32;
+use firewheel_core::{
+    node::{AudioNode, AudioNodeInfo, AudioNodeProcessor, ProcInfo, ProcessStatus},
+    param::smoother::ParamSmoother,
+    ChannelConfig, ChannelCount, SilenceMask, StreamInfo,
+};
+use std::sync::{atomic::Ordering, Arc};
+
+use super::filter::{BiquadCoeffs, Crossover};
+
+/// The lowest crossover frequency of a [`MidSideNode`] in hertz.
+pub const MID_SIDE_MIN_CROSSOVER_HZ: f32 = 20.0;
+/// The highest crossover frequency of a [`MidSideNode`] in hertz.
+pub const MID_SIDE_MAX_CROSSOVER_HZ: f32 = 2_000.0;
+
+struct SharedState {
+    balance: AtomicF32,
+    crossover_hz: AtomicF32,
+    mono_amount: AtomicF32,
+}
+
+/// A mastering node which controls the balance between the mid (`L + R`)
+/// and side (`L - R`) parts of a stereo signal, and which can sum the low
+/// frequencies below a crossover to mono while keeping the highs stereo
+/// (i.e. for vinyl or club playback).
+///
+/// The signal is split with a 4th order Linkwitz-Riley crossover, so with
+/// a mono amount of `0.0` the bands sum back to a flat response.
+pub struct MidSideNode {
+    shared: Arc<SharedState>,
+}
+
+impl MidSideNode {
+    /// Create a new mid/side node.
+    ///
+    /// * `balance` - The mid/side balance in the range `[-1.0, 1.0]`.
+    /// * `crossover_hz` - The frequency below which the signal is summed to
+    ///   mono.
+    /// * `mono_amount` - How much of the signal below the crossover is summed
+    ///   to mono in the range `[0.0, 1.0]`.
+    pub fn new(balance: f32, crossover_hz: f32, mono_amount: f32) -> Self {
+        let node = Self {
+            shared: Arc::new(SharedState {
+                balance: AtomicF32::new(0.0),
+                crossover_hz: AtomicF32::new(MID_SIDE_MIN_CROSSOVER_HZ),
+                mono_amount: AtomicF32::new(0.0),
+            }),
+        };
+        node.set_balance(balance);
+        node.set_crossover_hz(crossover_hz);
+        node.set_mono_amount(mono_amount);
+
+        node
+    }
+
+    pub fn balance(&self) -> f32 {
+        self.shared.balance.load(Ordering::Relaxed)
+    }
+
+    /// Set the mid/side balance in the range `[-1.0, 1.0]`, where `-1.0`
+    /// only keeps the mid signal (mono), `0.0` leaves the signal unchanged,
+    /// and `1.0` only keeps the side signal.
+    ///
+    /// The change is smoothed in the processor to avoid clicks.
+    pub fn set_balance(&self, balance: f32) {
+        self.shared
+            .balance
+            .store(balance.clamp(-1.0, 1.0), Ordering::Relaxed);
+    }
+
+    pub fn crossover_hz(&self) -> f32 {
+        self.shared.crossover_hz.load(Ordering::Relaxed)
+    }
+
+    /// Set the frequency in hertz below which the signal is summed to mono.
+    ///
+    /// This is clamped to the range
+    /// `[MID_SIDE_MIN_CROSSOVER_HZ, MID_SIDE_MAX_CROSSOVER_HZ]`.
+    pub fn set_crossover_hz(&self, crossover_hz: f32) {
+        self.shared.crossover_hz.store(
+            crossover_hz.clamp(MID_SIDE_MIN_CROSSOVER_HZ, MID_SIDE_MAX_CROSSOVER_HZ),
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn mono_amount(&self) -> f32 {
+        self.shared.mono_amount.load(Ordering::Relaxed)
+    }
+
+    /// Set how much of the signal below the crossover is summed to mono in
+    /// the range `[0.0, 1.0]`, where `1.0` makes the low frequencies fully
+    /// mono.
+    ///
+    /// The change is smoothed in the processor to avoid clicks.
+    pub fn set_mono_amount(&self, mono_amount: f32) {
+        self.shared
+            .mono_amount
+            .store(mono_amount.clamp(0.0, 1.0), Ordering::Relaxed);
+    }
+}
+
+/// Returns the `(mid, side)` gains for the given mid/side balance.
+#[inline]
+fn balance_gains(balance: f32) -> (f32, f32) {
+    ((1.0 - balance).min(1.0), (1.0 + balance).min(1.0))
+}
+
+impl<C> AudioNode<C> for MidSideNode {
+    fn debug_name(&self) -> &'static str {
+        "mid_side"
+    }
+
+    fn info(&self) -> AudioNodeInfo {
+        AudioNodeInfo {
+            num_min_supported_inputs: ChannelCount::STEREO,
+            num_max_supported_inputs: ChannelCount::STEREO,
+            num_min_supported_outputs: ChannelCount::STEREO,
+            num_max_supported_outputs: ChannelCount::STEREO,
+            default_channel_config: ChannelConfig {
+                num_inputs: ChannelCount::STEREO,
+                num_outputs: ChannelCount::STEREO,
+            },
+            equal_num_ins_and_outs: true,
+            updates: false,
+            silent_when_inputs_silent: true,
+            preserve_denormals: false,
+        }
+    }
+
+    fn activate(
+        &mut self,
+        stream_info: &StreamInfo,
+        _channel_config: ChannelConfig,
+    ) -> Result<Box<dyn AudioNodeProcessor<C>>, Box<dyn std::error::Error>> {
+        let smoother = |val: f32| {
+            ParamSmoother::new(
+                val,
+                stream_info.sample_rate,
+                stream_info.max_block_samples as usize,
+                Default::default(),
+            )
+        };
+
+        let mut processor = MidSideProcessor {
+            shared: Arc::clone(&self.shared),
+            balance_smoother: smoother(self.balance()),
+            mono_amount_smoother: smoother(self.mono_amount()),
+            sample_rate: stream_info.sample_rate as f32,
+            crossover_hz: 0.0,
+            mid: Crossover::default(),
+            side: Crossover::default(),
+        };
+        processor.set_crossover_hz(self.crossover_hz());
+
+        Ok(Box::new(processor))
+    }
+}
+
+struct MidSideProcessor {
+    shared: Arc<SharedState>,
+    balance_smoother: ParamSmoother,
+    mono_amount_smoother: ParamSmoother,
+    sample_rate: f32,
+    crossover_hz: f32,
+    mid: Crossover,
+    side: Crossover,
+}
+
+impl MidSideProcessor {
+    fn set_crossover_hz(&mut self, crossover_hz: f32) {
+        if self.crossover_hz == crossover_hz {
+            return;
+        }
+        self.crossover_hz = crossover_hz;
+
+        // Keep the crossover well below the Nyquist frequency.
+        let freq_hz = crossover_hz.min(self.sample_rate * 0.45);
+        let lowpass = BiquadCoeffs::butterworth(freq_hz, self.sample_rate, false);
+        let highpass = BiquadCoeffs::butterworth(freq_hz, self.sample_rate, true);
+
+        self.mid.set_coeffs(lowpass, highpass);
+        self.side.set_coeffs(lowpass, highpass);
+    }
+}
+
+impl<C> AudioNodeProcessor<C> for MidSideProcessor {
+    fn process(
+        &mut self,
+        inputs: &[&[f32]],
+        outputs: &mut [&mut [f32]],
+        proc_info: ProcInfo,
+        _cx: &mut C,
+    ) -> ProcessStatus {
+        let samples = proc_info.samples;
+
+        let balance = self.shared.balance.load(Ordering::Relaxed);
+        let mono_amount = self.shared.mono_amount.load(Ordering::Relaxed);
+        self.set_crossover_hz(self.shared.crossover_hz.load(Ordering::Relaxed));
+
+        if proc_info.in_silence_mask.all_channels_silent(inputs.len()) {
+            // All channels are silent, so there is no need to process. Also reset
+            // the filters since they don't need to smooth anything.
+            self.balance_smoother.reset(balance);
+            self.mono_amount_smoother.reset(mono_amount);
+            self.mid.reset();
+            self.side.reset();
+
+            return ProcessStatus::NoOutputsModified;
+        }
+
+        let balance = self.balance_smoother.set_and_process(balance, samples);
+        let mono_amount = self
+            .mono_amount_smoother
+            .set_and_process(mono_amount, samples);
+
+        let in_l = &inputs[0][..samples];
+        let in_r = &inputs[1][..samples];
+        let (out_l, out_r) = outputs.split_first_mut().unwrap();
+        let out_l = &mut out_l[..samples];
+        let out_r = &mut out_r[0][..samples];
+
+        // Hint to the compiler to optimize loop.
+        assert!(samples <= balance.values.len());
+        assert!(samples <= mono_amount.values.len());
+
+        for i in 0..samples {
+            let mid = (in_l[i] + in_r[i]) * 0.5;
+            let side = (in_l[i] - in_r[i]) * 0.5;
+
+            // The mid signal goes through the same crossover so that it
+            // stays in phase with the side signal.
+            let (mid_low, mid_high) = self.mid.process(mid);
+            let (side_low, side_high) = self.side.process(side);
+
+            let mid = mid_low + mid_high;
+            let side = side_high + side_low * (1.0 - mono_amount[i]);
+
+            let (mid_gain, side_gain) = balance_gains(balance[i]);
+            let mid = mid * mid_gain;
+            let side = side * side_gain;
+
+            out_l[i] = mid + side;
+            out_r[i] = mid - side;
+        }
+
+        ProcessStatus::outputs_modified(SilenceMask::NONE_SILENT)
+    }
+}
+
+impl<C> Into<Box<dyn AudioNode<C>>> for MidSideNode {
+    fn into(self) -> Box<dyn AudioNode<C>> {
+        Box::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic_nodes::process_block;
+
+    fn rms(buffer: &[f32]) -> f32 {
+        (buffer.iter().map(|s| s * s).sum::<f32>() / buffer.len() as f32).sqrt()
+    }
+
+    /// Process a stereo sine wave whose right channel is half as loud as its
+    /// left channel, and return the `(mid, side)` RMS of the settled output.
+    fn process_sine(freq_hz: f32) -> (f32, f32) {
+        let stream_info = StreamInfo::default();
+        let samples = stream_info.max_block_samples as usize;
+        let sample_rate = stream_info.sample_rate as f32;
+
+        let mut node = MidSideNode::new(0.0, 200.0, 1.0);
+        let mut processor = AudioNode::<()>::activate(
+            &mut node,
+            &stream_info,
+            ChannelConfig {
+                num_inputs: ChannelCount::STEREO,
+                num_outputs: ChannelCount::STEREO,
+            },
+        )
+        .unwrap();
+
+        let mut in_l = vec![0.0; samples];
+        let mut in_r = vec![0.0; samples];
+        let mut out_l = vec![0.0; samples];
+        let mut out_r = vec![0.0; samples];
+        let mut mid = Vec::new();
+        let mut side = Vec::new();
+
+        let num_blocks = (sample_rate as usize / samples).max(1);
+        for block in 0..num_blocks {
+            for i in 0..samples {
+                let t = (block * samples + i) as f32 / sample_rate;
+                let s = (std::f32::consts::TAU * freq_hz * t).sin();
+                in_l[i] = s;
+                in_r[i] = s * 0.5;
+            }
+
+            process_block(
+                processor.as_mut(),
+                &[&in_l, &in_r],
+                &mut [&mut out_l, &mut out_r],
+                SilenceMask::NONE_SILENT,
+            );
+
+            // Skip the first half second while the filters settle.
+            if block >= num_blocks / 2 {
+                mid.extend(out_l.iter().zip(out_r.iter()).map(|(l, r)| l + r));
+                side.extend(out_l.iter().zip(out_r.iter()).map(|(l, r)| l - r));
+            }
+        }
+
+        (rms(&mid), rms(&side))
+    }
+
+    #[test]
+    fn mono_bass() {
+        // Below the crossover, the output is mono (L = R).
+        let (mid, side) = process_sine(40.0);
+        assert!(mid > 1.0);
+        assert!(side < mid * 0.01);
+
+        // Above the crossover, the stereo difference is kept.
+        let (mid, side) = process_sine(4000.0);
+        let expected_side = 0.5 * std::f32::consts::FRAC_1_SQRT_2;
+        assert!(mid > 1.0);
+        assert!((side - expected_side).abs() < expected_side * 0.01);
+    }
+
+    #[test]
+    fn balance() {
+        assert_eq!(balance_gains(0.0), (1.0, 1.0));
+        assert_eq!(balance_gains(-1.0), (1.0, 0.0));
+        assert_eq!(balance_gains(1.0), (0.0, 1.0));
+        assert_eq!(balance_gains(0.5), (0.5, 1.0));
+    }
+}
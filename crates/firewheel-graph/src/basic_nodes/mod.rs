@@ -1,11 +1,93 @@
 pub mod beep_test;
+mod delay;
+mod ducker;
 pub mod dummy;
+mod dynamic_eq;
+mod feedback;
+mod filter;
 mod hard_clip;
+mod karplus_strong;
+mod mid_side;
+mod multiband_width;
+mod pan;
+mod sample_player;
 mod stereo_to_mono;
 mod sum;
+mod sweep;
 mod volume;
+mod wet_dry;
 
+pub use delay::{DelayInterpolation, DelayNode};
+pub use ducker::{add_talkover, DuckerNode, DuckerParams, TalkoverError, TalkoverNodes};
+pub use dynamic_eq::{DynamicEqBand, DynamicEqNode};
+pub use feedback::{feedback_nodes, FeedbackDelay, FeedbackReturnNode, FeedbackSendNode};
 pub use hard_clip::HardClipNode;
+pub use karplus_strong::{KarplusStrongNode, KARPLUS_STRONG_MIN_FREQ_HZ};
+pub use mid_side::{MidSideNode, MID_SIDE_MAX_CROSSOVER_HZ, MID_SIDE_MIN_CROSSOVER_HZ};
+pub use multiband_width::{
+    MultibandWidthNode, WidthBand, MULTIBAND_WIDTH_MAX_CROSSOVER_HZ, MULTIBAND_WIDTH_MAX_WIDTH,
+    MULTIBAND_WIDTH_MIN_CROSSOVER_HZ,
+};
+pub use pan::StereoPanNode;
+pub use sample_player::{
+    SamplePlayerNode, SamplePlayerStatus, DEFAULT_STRETCH_WINDOW_SECS, SAMPLE_PLAYER_MAX_SPEED,
+    SAMPLE_PLAYER_MIN_SPEED,
+};
 pub use stereo_to_mono::StereoToMonoNode;
 pub use sum::SumNode;
+pub use sweep::{SweepKind, SweepNode, SweepParams};
 pub use volume::VolumeNode;
+pub use wet_dry::WetDryNode;
+
+#[cfg(test)]
+pub(crate) use test_util::{process_block, process_block_at};
+
+#[cfg(test)]
+mod test_util {
+    use firewheel_core::{
+        clock::{ClockSamples, ClockSeconds},
+        node::{AudioNodeProcessor, ProcInfo, ProcessStatus, StreamStatus},
+        SilenceMask,
+    };
+
+    /// Process one block with a node's processor, using the length of the
+    /// first output (or input) buffer as the number of samples.
+    pub(crate) fn process_block(
+        processor: &mut dyn AudioNodeProcessor<()>,
+        inputs: &[&[f32]],
+        outputs: &mut [&mut [f32]],
+        in_silence_mask: SilenceMask,
+    ) -> ProcessStatus {
+        process_block_at(processor, inputs, outputs, in_silence_mask, ClockSamples(0))
+    }
+
+    /// The same as [`process_block`], but for a block starting at the given
+    /// sample time.
+    pub(crate) fn process_block_at(
+        processor: &mut dyn AudioNodeProcessor<()>,
+        inputs: &[&[f32]],
+        outputs: &mut [&mut [f32]],
+        in_silence_mask: SilenceMask,
+        clock_samples: ClockSamples,
+    ) -> ProcessStatus {
+        let samples = outputs
+            .first()
+            .map(|o| o.len())
+            .or_else(|| inputs.first().map(|i| i.len()))
+            .unwrap_or(0);
+
+        processor.process(
+            inputs,
+            outputs,
+            ProcInfo {
+                samples,
+                in_silence_mask,
+                out_silence_mask: SilenceMask::new_all_silent(outputs.len()),
+                clock_seconds: ClockSeconds(0.0)..ClockSeconds(0.0),
+                clock_samples,
+                stream_status: StreamStatus::empty(),
+            },
+            &mut (),
+        )
+    }
+}
@@ -0,0 +1,398 @@
+use atomic_float::AtomicF32;
+use firewheel_core::{
+    node::{AudioNode, AudioNodeInfo, AudioNodeProcessor, ProcInfo, ProcessStatus},
+    param::smoother::ParamSmoother,
+    ChannelConfig, ChannelCount, SilenceMask, StreamInfo,
+};
+use std::sync::{atomic::Ordering, Arc};
+
+use super::filter::{BiquadCoeffs, Crossover};
+
+/// The lowest crossover frequency of a [`MultibandWidthNode`] in hertz.
+pub const MULTIBAND_WIDTH_MIN_CROSSOVER_HZ: f32 = 20.0;
+/// The highest crossover frequency of a [`MultibandWidthNode`] in hertz.
+pub const MULTIBAND_WIDTH_MAX_CROSSOVER_HZ: f32 = 20_000.0;
+/// The highest stereo width of a band in a [`MultibandWidthNode`].
+pub const MULTIBAND_WIDTH_MAX_WIDTH: f32 = 2.0;
+
+/// A frequency band of a [`MultibandWidthNode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WidthBand {
+    /// The frequencies below the low crossover.
+    Low,
+    /// The frequencies between the low and high crossovers.
+    Mid,
+    /// The frequencies above the high crossover.
+    High,
+}
+
+struct SharedState {
+    widths: [AtomicF32; 3],
+    low_crossover_hz: AtomicF32,
+    high_crossover_hz: AtomicF32,
+}
+
+/// A mastering node which applies a different stereo width to each of
+/// three frequency bands (i.e. to keep the bass narrow while widening the
+/// highs).
+///
+/// The side (`L - R`) part of the signal is split into bands with 4th
+/// order Linkwitz-Riley crossovers, and each band is scaled by its width.
+/// The mid (`L + R`) part goes through the same crossovers so that it stays
+/// in phase with the side part. With every width set to `1.0`, the output
+/// has a flat magnitude response.
+pub struct MultibandWidthNode {
+    shared: Arc<SharedState>,
+}
+
+impl MultibandWidthNode {
+    /// Create a new multiband width node.
+    ///
+    /// * `low_crossover_hz` - The frequency between the low and mid bands.
+    /// * `high_crossover_hz` - The frequency between the mid and high bands.
+    /// * `widths` - The stereo width of the `[low, mid, high]` bands in the
+    ///   range `[0.0, MULTIBAND_WIDTH_MAX_WIDTH]`.
+    pub fn new(low_crossover_hz: f32, high_crossover_hz: f32, widths: [f32; 3]) -> Self {
+        let node = Self {
+            shared: Arc::new(SharedState {
+                widths: [
+                    AtomicF32::new(1.0),
+                    AtomicF32::new(1.0),
+                    AtomicF32::new(1.0),
+                ],
+                low_crossover_hz: AtomicF32::new(MULTIBAND_WIDTH_MIN_CROSSOVER_HZ),
+                high_crossover_hz: AtomicF32::new(MULTIBAND_WIDTH_MAX_CROSSOVER_HZ),
+            }),
+        };
+        node.set_low_crossover_hz(low_crossover_hz);
+        node.set_high_crossover_hz(high_crossover_hz);
+        node.set_width(WidthBand::Low, widths[0]);
+        node.set_width(WidthBand::Mid, widths[1]);
+        node.set_width(WidthBand::High, widths[2]);
+
+        node
+    }
+
+    pub fn width(&self, band: WidthBand) -> f32 {
+        self.shared.widths[band as usize].load(Ordering::Relaxed)
+    }
+
+    /// Set the stereo width of the given band in the range
+    /// `[0.0, MULTIBAND_WIDTH_MAX_WIDTH]`, where `0.0` makes the band mono,
+    /// `1.0` leaves it unchanged, and values above `1.0` make it wider.
+    ///
+    /// The change is smoothed in the processor to avoid clicks.
+    pub fn set_width(&self, band: WidthBand, width: f32) {
+        self.shared.widths[band as usize].store(
+            width.clamp(0.0, MULTIBAND_WIDTH_MAX_WIDTH),
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn low_crossover_hz(&self) -> f32 {
+        self.shared.low_crossover_hz.load(Ordering::Relaxed)
+    }
+
+    /// Set the frequency in hertz between the low and mid bands.
+    ///
+    /// This is clamped to the range
+    /// `[MULTIBAND_WIDTH_MIN_CROSSOVER_HZ, MULTIBAND_WIDTH_MAX_CROSSOVER_HZ]`.
+    pub fn set_low_crossover_hz(&self, crossover_hz: f32) {
+        self.shared.low_crossover_hz.store(
+            crossover_hz.clamp(
+                MULTIBAND_WIDTH_MIN_CROSSOVER_HZ,
+                MULTIBAND_WIDTH_MAX_CROSSOVER_HZ,
+            ),
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn high_crossover_hz(&self) -> f32 {
+        self.shared.high_crossover_hz.load(Ordering::Relaxed)
+    }
+
+    /// Set the frequency in hertz between the mid and high bands.
+    ///
+    /// This is clamped to the range
+    /// `[MULTIBAND_WIDTH_MIN_CROSSOVER_HZ, MULTIBAND_WIDTH_MAX_CROSSOVER_HZ]`.
+    /// If this is lower than the low crossover, then the low crossover is
+    /// used instead (leaving the mid band empty).
+    pub fn set_high_crossover_hz(&self, crossover_hz: f32) {
+        self.shared.high_crossover_hz.store(
+            crossover_hz.clamp(
+                MULTIBAND_WIDTH_MIN_CROSSOVER_HZ,
+                MULTIBAND_WIDTH_MAX_CROSSOVER_HZ,
+            ),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+impl<C> AudioNode<C> for MultibandWidthNode {
+    fn debug_name(&self) -> &'static str {
+        "multiband_width"
+    }
+
+    fn info(&self) -> AudioNodeInfo {
+        AudioNodeInfo {
+            num_min_supported_inputs: ChannelCount::STEREO,
+            num_max_supported_inputs: ChannelCount::STEREO,
+            num_min_supported_outputs: ChannelCount::STEREO,
+            num_max_supported_outputs: ChannelCount::STEREO,
+            default_channel_config: ChannelConfig {
+                num_inputs: ChannelCount::STEREO,
+                num_outputs: ChannelCount::STEREO,
+            },
+            equal_num_ins_and_outs: true,
+            updates: false,
+            silent_when_inputs_silent: true,
+            preserve_denormals: false,
+        }
+    }
+
+    fn activate(
+        &mut self,
+        stream_info: &StreamInfo,
+        _channel_config: ChannelConfig,
+    ) -> Result<Box<dyn AudioNodeProcessor<C>>, Box<dyn std::error::Error>> {
+        let smoother = |band: WidthBand| {
+            ParamSmoother::new(
+                self.width(band),
+                stream_info.sample_rate,
+                stream_info.max_block_samples as usize,
+                Default::default(),
+            )
+        };
+
+        let mut processor = MultibandWidthProcessor {
+            shared: Arc::clone(&self.shared),
+            width_smoothers: [
+                smoother(WidthBand::Low),
+                smoother(WidthBand::Mid),
+                smoother(WidthBand::High),
+            ],
+            sample_rate: stream_info.sample_rate as f32,
+            crossover_hz: (0.0, 0.0),
+            mid: BandSplitter::default(),
+            side: BandSplitter::default(),
+        };
+        processor.set_crossover_hz(self.low_crossover_hz(), self.high_crossover_hz());
+
+        Ok(Box::new(processor))
+    }
+}
+
+struct MultibandWidthProcessor {
+    shared: Arc<SharedState>,
+    width_smoothers: [ParamSmoother; 3],
+    sample_rate: f32,
+    crossover_hz: (f32, f32),
+    mid: BandSplitter,
+    side: BandSplitter,
+}
+
+impl MultibandWidthProcessor {
+    fn set_crossover_hz(&mut self, low_hz: f32, high_hz: f32) {
+        if self.crossover_hz == (low_hz, high_hz) {
+            return;
+        }
+        self.crossover_hz = (low_hz, high_hz);
+
+        // Keep the crossovers well below the Nyquist frequency.
+        let max_hz = self.sample_rate * 0.45;
+        let low_hz = low_hz.min(max_hz);
+        let high_hz = high_hz.max(low_hz).min(max_hz);
+
+        self.mid.set_freqs(low_hz, high_hz, self.sample_rate);
+        self.side.set_freqs(low_hz, high_hz, self.sample_rate);
+    }
+}
+
+impl<C> AudioNodeProcessor<C> for MultibandWidthProcessor {
+    fn process(
+        &mut self,
+        inputs: &[&[f32]],
+        outputs: &mut [&mut [f32]],
+        proc_info: ProcInfo,
+        _cx: &mut C,
+    ) -> ProcessStatus {
+        let samples = proc_info.samples;
+
+        let widths = [
+            self.shared.widths[0].load(Ordering::Relaxed),
+            self.shared.widths[1].load(Ordering::Relaxed),
+            self.shared.widths[2].load(Ordering::Relaxed),
+        ];
+        self.set_crossover_hz(
+            self.shared.low_crossover_hz.load(Ordering::Relaxed),
+            self.shared.high_crossover_hz.load(Ordering::Relaxed),
+        );
+
+        if proc_info.in_silence_mask.all_channels_silent(inputs.len()) {
+            // All channels are silent, so there is no need to process. Also reset
+            // the filters since they don't need to smooth anything.
+            for (smoother, width) in self.width_smoothers.iter_mut().zip(widths) {
+                smoother.reset(width);
+            }
+            self.mid.reset();
+            self.side.reset();
+
+            return ProcessStatus::NoOutputsModified;
+        }
+
+        let [low_smoother, mid_smoother, high_smoother] = &mut self.width_smoothers;
+        let low_width = low_smoother.set_and_process(widths[0], samples);
+        let mid_width = mid_smoother.set_and_process(widths[1], samples);
+        let high_width = high_smoother.set_and_process(widths[2], samples);
+
+        let in_l = &inputs[0][..samples];
+        let in_r = &inputs[1][..samples];
+        let (out_l, out_r) = outputs.split_first_mut().unwrap();
+        let out_l = &mut out_l[..samples];
+        let out_r = &mut out_r[0][..samples];
+
+        // Hint to the compiler to optimize loop.
+        assert!(samples <= low_width.values.len());
+        assert!(samples <= mid_width.values.len());
+        assert!(samples <= high_width.values.len());
+
+        for i in 0..samples {
+            let mid = (in_l[i] + in_r[i]) * 0.5;
+            let side = (in_l[i] - in_r[i]) * 0.5;
+
+            let [mid_low, mid_mid, mid_high] = self.mid.process(mid);
+            let [side_low, side_mid, side_high] = self.side.process(side);
+
+            let mid = mid_low + mid_mid + mid_high;
+            let side =
+                side_low * low_width[i] + side_mid * mid_width[i] + side_high * high_width[i];
+
+            out_l[i] = mid + side;
+            out_r[i] = mid - side;
+        }
+
+        ProcessStatus::outputs_modified(SilenceMask::NONE_SILENT)
+    }
+}
+
+impl<C> Into<Box<dyn AudioNode<C>>> for MultibandWidthNode {
+    fn into(self) -> Box<dyn AudioNode<C>> {
+        Box::new(self)
+    }
+}
+
+/// Splits a signal into three bands with two Linkwitz-Riley crossovers.
+#[derive(Default)]
+struct BandSplitter {
+    low_split: Crossover,
+    high_split: Crossover,
+    /// Gives the low band the same phase shift as the high crossover
+    /// gives the other two bands, so that the bands sum back together
+    /// with a flat response.
+    low_allpass: Crossover,
+}
+
+impl BandSplitter {
+    fn set_freqs(&mut self, low_hz: f32, high_hz: f32, sample_rate: f32) {
+        self.low_split.set_coeffs(
+            BiquadCoeffs::butterworth(low_hz, sample_rate, false),
+            BiquadCoeffs::butterworth(low_hz, sample_rate, true),
+        );
+
+        let high_lowpass = BiquadCoeffs::butterworth(high_hz, sample_rate, false);
+        let high_highpass = BiquadCoeffs::butterworth(high_hz, sample_rate, true);
+        self.high_split.set_coeffs(high_lowpass, high_highpass);
+        self.low_allpass.set_coeffs(high_lowpass, high_highpass);
+    }
+
+    /// Returns the `[low, mid, high]` bands of the given sample.
+    #[inline]
+    fn process(&mut self, x: f32) -> [f32; 3] {
+        let (low, rest) = self.low_split.process(x);
+        let (mid, high) = self.high_split.process(rest);
+        let (low_a, low_b) = self.low_allpass.process(low);
+        [low_a + low_b, mid, high]
+    }
+
+    fn reset(&mut self) {
+        self.low_split.reset();
+        self.high_split.reset();
+        self.low_allpass.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic_nodes::process_block;
+
+    /// Process a stereo sine wave whose right channel lags behind its left
+    /// channel by 60 degrees, and return the correlation between the left
+    /// and right channels of the settled output.
+    fn output_correlation(freq_hz: f32) -> f32 {
+        let stream_info = StreamInfo::default();
+        let samples = stream_info.max_block_samples as usize;
+        let sample_rate = stream_info.sample_rate as f32;
+
+        let mut node = MultibandWidthNode::new(200.0, 2_000.0, [0.0, 1.0, 2.0]);
+        let mut processor = AudioNode::<()>::activate(
+            &mut node,
+            &stream_info,
+            ChannelConfig {
+                num_inputs: ChannelCount::STEREO,
+                num_outputs: ChannelCount::STEREO,
+            },
+        )
+        .unwrap();
+
+        let mut in_l = vec![0.0; samples];
+        let mut in_r = vec![0.0; samples];
+        let mut out_l = vec![0.0; samples];
+        let mut out_r = vec![0.0; samples];
+        let (mut lr, mut ll, mut rr) = (0.0, 0.0, 0.0);
+
+        let num_blocks = (sample_rate as usize / samples).max(1);
+        for block in 0..num_blocks {
+            for i in 0..samples {
+                let t = (block * samples + i) as f32 / sample_rate;
+                let phase = std::f32::consts::TAU * freq_hz * t;
+                in_l[i] = phase.sin();
+                in_r[i] = (phase - std::f32::consts::FRAC_PI_3).sin();
+            }
+
+            process_block(
+                processor.as_mut(),
+                &[&in_l, &in_r],
+                &mut [&mut out_l, &mut out_r],
+                SilenceMask::NONE_SILENT,
+            );
+
+            // Skip the first half second while the filters settle.
+            if block >= num_blocks / 2 {
+                for (&l, &r) in out_l.iter().zip(out_r.iter()) {
+                    lr += l * r;
+                    ll += l * l;
+                    rr += r * r;
+                }
+            }
+        }
+
+        lr / (ll * rr).sqrt()
+    }
+
+    #[test]
+    fn per_band_width() {
+        // The correlation of the input is cos(60 degrees) = 0.5.
+
+        // The low band is made mono.
+        assert!(output_correlation(40.0) > 0.999);
+
+        // The mid band is left unchanged.
+        assert!((output_correlation(630.0) - 0.5).abs() < 0.05);
+
+        // The high band is widened. With twice the side signal, the
+        // correlation becomes (0.75 - 1.0) / (0.75 + 1.0).
+        let expected = -0.25 / 1.75;
+        assert!((output_correlation(10_000.0) - expected).abs() < 0.01);
+    }
+}
@@ -0,0 +1,235 @@
+use atomic_float::AtomicF32;
+use firewheel_core::{
+    node::{AudioNode, AudioNodeInfo, AudioNodeProcessor, ProcInfo, ProcessStatus},
+    param::smoother::ParamSmoother,
+    ChannelConfig, ChannelCount, SilenceMask, StreamInfo,
+};
+use std::sync::{atomic::Ordering, Arc};
+
+/// A node which pans a mono or stereo signal in a stereo field.
+///
+/// This uses an equal-power panning law, so a center-panned signal is
+/// attenuated by -3dB on each channel. If the input is stereo, then each
+/// channel is attenuated by the gain of its side.
+pub struct StereoPanNode {
+    pan: Arc<AtomicF32>,
+}
+
+impl StereoPanNode {
+    /// Create a new panning node.
+    ///
+    /// * `pan` - The pan amount in the range `[-1.0, 1.0]`, where `-1.0`
+    ///   is fully left, `0.0` is center, and `1.0` is fully right.
+    pub fn new(pan: f32) -> Self {
+        Self {
+            pan: Arc::new(AtomicF32::new(pan.clamp(-1.0, 1.0))),
+        }
+    }
+
+    pub fn pan(&self) -> f32 {
+        self.pan.load(Ordering::Relaxed)
+    }
+
+    /// Set the pan amount in the range `[-1.0, 1.0]`, where `-1.0` is fully
+    /// left, `0.0` is center, and `1.0` is fully right.
+    ///
+    /// The change is smoothed in the processor to avoid clicks.
+    pub fn set_pan(&self, pan: f32) {
+        self.pan.store(pan.clamp(-1.0, 1.0), Ordering::Relaxed);
+    }
+}
+
+/// Returns the `(left, right)` gains for the given pan amount using an
+/// equal-power panning law.
+#[inline]
+fn pan_gains(pan: f32) -> (f32, f32) {
+    let angle = (pan + 1.0) * std::f32::consts::FRAC_PI_4;
+    let (sin, cos) = angle.sin_cos();
+    (cos, sin)
+}
+
+impl<C> AudioNode<C> for StereoPanNode {
+    fn debug_name(&self) -> &'static str {
+        "stereo_pan"
+    }
+
+    fn info(&self) -> AudioNodeInfo {
+        AudioNodeInfo {
+            num_min_supported_inputs: ChannelCount::MONO,
+            num_max_supported_inputs: ChannelCount::STEREO,
+            num_min_supported_outputs: ChannelCount::STEREO,
+            num_max_supported_outputs: ChannelCount::STEREO,
+            default_channel_config: ChannelConfig {
+                num_inputs: ChannelCount::MONO,
+                num_outputs: ChannelCount::STEREO,
+            },
+            equal_num_ins_and_outs: false,
+            updates: false,
+            silent_when_inputs_silent: true,
+            preserve_denormals: false,
+        }
+    }
+
+    fn activate(
+        &mut self,
+        stream_info: &StreamInfo,
+        _channel_config: ChannelConfig,
+    ) -> Result<Box<dyn AudioNodeProcessor<C>>, Box<dyn std::error::Error>> {
+        Ok(Box::new(StereoPanProcessor {
+            pan: Arc::clone(&self.pan),
+            pan_smoother: ParamSmoother::new(
+                self.pan(),
+                stream_info.sample_rate,
+                stream_info.max_block_samples as usize,
+                Default::default(),
+            ),
+        }))
+    }
+}
+
+struct StereoPanProcessor {
+    pan: Arc<AtomicF32>,
+    pan_smoother: ParamSmoother,
+}
+
+impl<C> AudioNodeProcessor<C> for StereoPanProcessor {
+    fn process(
+        &mut self,
+        inputs: &[&[f32]],
+        outputs: &mut [&mut [f32]],
+        proc_info: ProcInfo,
+        _cx: &mut C,
+    ) -> ProcessStatus {
+        let samples = proc_info.samples;
+
+        let pan = self.pan.load(Ordering::Relaxed);
+
+        if proc_info.in_silence_mask.all_channels_silent(inputs.len()) {
+            // All channels are silent, so there is no need to process. Also reset
+            // the filter since it doesn't need to smooth anything.
+            self.pan_smoother.reset(pan);
+
+            return ProcessStatus::NoOutputsModified;
+        }
+
+        let pan = self.pan_smoother.set_and_process(pan, samples);
+
+        let (in_l, in_r) = if inputs.len() == 1 {
+            (inputs[0], inputs[0])
+        } else {
+            (inputs[0], inputs[1])
+        };
+
+        let (out_l, out_r) = outputs.split_first_mut().unwrap();
+        let out_l = &mut out_l[..samples];
+        let out_r = &mut out_r[0][..samples];
+        let in_l = &in_l[..samples];
+        let in_r = &in_r[..samples];
+
+        if pan.is_smoothing() {
+            // Hint to the compiler to optimize loop.
+            assert!(samples <= pan.values.len());
+
+            for i in 0..samples {
+                let (gain_l, gain_r) = pan_gains(pan[i]);
+
+                out_l[i] = in_l[i] * gain_l;
+                out_r[i] = in_r[i] * gain_r;
+            }
+        } else {
+            let (gain_l, gain_r) = pan_gains(pan[0]);
+
+            for i in 0..samples {
+                out_l[i] = in_l[i] * gain_l;
+                out_r[i] = in_r[i] * gain_r;
+            }
+        }
+
+        let out_silence_mask = if inputs.len() == 1 {
+            // The input is not silent, so neither are the outputs.
+            SilenceMask::NONE_SILENT
+        } else {
+            proc_info.in_silence_mask
+        };
+
+        ProcessStatus::outputs_modified(out_silence_mask)
+    }
+}
+
+impl<C> Into<Box<dyn AudioNode<C>>> for StereoPanNode {
+    fn into(self) -> Box<dyn AudioNode<C>> {
+        Box::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic_nodes::process_block;
+
+    fn rms(buffer: &[f32]) -> f32 {
+        (buffer.iter().map(|s| s * s).sum::<f32>() / buffer.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn equal_power_pan() {
+        let stream_info = StreamInfo::default();
+        let samples = stream_info.max_block_samples as usize;
+
+        let mut node = StereoPanNode::new(-1.0);
+        let mut processor = AudioNode::<()>::activate(
+            &mut node,
+            &stream_info,
+            ChannelConfig {
+                num_inputs: ChannelCount::MONO,
+                num_outputs: ChannelCount::STEREO,
+            },
+        )
+        .unwrap();
+
+        let input = vec![1.0; samples];
+        let mut out_l = vec![0.0; samples];
+        let mut out_r = vec![0.0; samples];
+
+        let mut process = |in_silence_mask: SilenceMask, out_l: &mut [f32], out_r: &mut [f32]| {
+            process_block(
+                processor.as_mut(),
+                &[&input],
+                &mut [out_l, out_r],
+                in_silence_mask,
+            )
+        };
+
+        let mut prev_rms = (f32::MAX, f32::MIN);
+        for i in 0..=8 {
+            let pan = -1.0 + i as f32 * 0.25;
+            node.set_pan(pan);
+
+            // Let the smoother settle.
+            for _ in 0..4 {
+                process(SilenceMask::NONE_SILENT, &mut out_l, &mut out_r);
+            }
+
+            let rms_l = rms(&out_l);
+            let rms_r = rms(&out_r);
+
+            assert!(rms_l < prev_rms.0);
+            assert!(rms_r > prev_rms.1);
+            assert!((rms_l * rms_l + rms_r * rms_r - 1.0).abs() < 0.001);
+
+            if pan == 0.0 {
+                assert!((rms_l - std::f32::consts::FRAC_1_SQRT_2).abs() < 0.001);
+                assert!((rms_r - std::f32::consts::FRAC_1_SQRT_2).abs() < 0.001);
+            }
+
+            prev_rms = (rms_l, rms_r);
+        }
+
+        assert!(prev_rms.0 < 0.001);
+
+        assert_eq!(
+            process(SilenceMask::MONO_SILENT, &mut out_l, &mut out_r),
+            ProcessStatus::NoOutputsModified
+        );
+    }
+}
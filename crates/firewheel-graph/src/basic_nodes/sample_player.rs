@@ -0,0 +1,715 @@
+use arrayvec::ArrayVec;
+use atomic_float::AtomicF32;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU8, Ordering},
+    Arc,
+};
+
+use firewheel_core::{
+    node::{
+        AudioNode, AudioNodeInfo, AudioNodeProcessor, NodeStatus, NodeStatusWriter, ProcInfo,
+        ProcessStatus,
+    },
+    sample_resource::SampleResource,
+    ChannelConfig, ChannelCount, SilenceMask, StreamInfo,
+};
+
+const STATE_IDLE: u8 = 0;
+const STATE_TRIGGERED: u8 = 1;
+const STATE_PLAYING: u8 = 2;
+const STATE_FINISHED: u8 = 3;
+
+/// The lowest supported playback speed.
+pub const SAMPLE_PLAYER_MIN_SPEED: f32 = 0.25;
+/// The highest supported playback speed.
+pub const SAMPLE_PLAYER_MAX_SPEED: f32 = 4.0;
+
+/// The default length in seconds of the window used when time-stretching.
+pub const DEFAULT_STRETCH_WINDOW_SECS: f32 = 0.04;
+
+struct SharedState {
+    speed: AtomicF32,
+    time_stretch: AtomicBool,
+    state: AtomicU8,
+}
+
+/// The runtime state of a [`SamplePlayerNode`], see
+/// [`SamplePlayerNode::status`].
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SamplePlayerStatus {
+    /// The position of the playhead in frames of the sample, as of the end
+    /// of the last processed block.
+    pub position_frames: u64,
+}
+
+/// A node which plays a [`SampleResource`] at a variable speed.
+///
+/// By default, changing the speed also changes the pitch, just like
+/// speeding up or slowing down a tape. If time-stretching is enabled with
+/// [`SamplePlayerNode::set_time_stretch`], then the pitch is preserved
+/// instead, using WSOLA (waveform similarity overlap-add).
+///
+/// WSOLA cuts the sample into overlapping windows and shifts each window
+/// slightly so that it lines up with the waveform of the previous one. The
+/// length of the window is a tradeoff: longer windows handle low-pitched
+/// and noisy material better, while shorter windows smear transients less
+/// and respond faster to speed changes. Time-stretching adds a latency of
+/// half of a window, and it is also much more expensive than plain
+/// variable-rate playback. See [`SamplePlayerNode::set_stretch_window_secs`].
+///
+/// No sound is made until [`SamplePlayerNode::play`] is called.
+pub struct SamplePlayerNode<S: SampleResource + Clone> {
+    sample: S,
+    shared: Arc<SharedState>,
+    stretch_window_secs: f32,
+    status: NodeStatus<SamplePlayerStatus>,
+}
+
+impl<S: SampleResource + Clone> SamplePlayerNode<S> {
+    pub fn new(sample: S) -> Self {
+        Self {
+            sample,
+            shared: Arc::new(SharedState {
+                speed: AtomicF32::new(1.0),
+                time_stretch: AtomicBool::new(false),
+                state: AtomicU8::new(STATE_IDLE),
+            }),
+            stretch_window_secs: DEFAULT_STRETCH_WINDOW_SECS,
+            status: NodeStatus::new(SamplePlayerStatus::default()),
+        }
+    }
+
+    /// The runtime state of the player, such as the playback position.
+    ///
+    /// This is updated by the processor once every block.
+    pub fn status(&mut self) -> SamplePlayerStatus {
+        self.status.get()
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.shared.speed.load(Ordering::Relaxed)
+    }
+
+    /// Set the playback speed, where `1.0` is the original speed and `0.5`
+    /// is half of the original speed.
+    ///
+    /// The speed is clamped to the range
+    /// `[SAMPLE_PLAYER_MIN_SPEED, SAMPLE_PLAYER_MAX_SPEED]`.
+    pub fn set_speed(&self, speed: f32) {
+        self.shared.speed.store(
+            speed.clamp(SAMPLE_PLAYER_MIN_SPEED, SAMPLE_PLAYER_MAX_SPEED),
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn time_stretch(&self) -> bool {
+        self.shared.time_stretch.load(Ordering::Relaxed)
+    }
+
+    /// Set whether or not the pitch is preserved when the speed is changed.
+    ///
+    /// Switching modes during playback may cause a click.
+    ///
+    /// By default this is set to `false`.
+    pub fn set_time_stretch(&self, time_stretch: bool) {
+        self.shared
+            .time_stretch
+            .store(time_stretch, Ordering::Relaxed);
+    }
+
+    /// The length in seconds of the window used when time-stretching.
+    pub fn stretch_window_secs(&self) -> f32 {
+        self.stretch_window_secs
+    }
+
+    /// Set the length in seconds of the window used when time-stretching.
+    ///
+    /// The window should be at least twice as long as the period of the
+    /// lowest pitch in the sample. This takes effect the next time the node
+    /// is activated.
+    ///
+    /// By default this is set to [`DEFAULT_STRETCH_WINDOW_SECS`].
+    pub fn set_stretch_window_secs(&mut self, secs: f32) {
+        self.stretch_window_secs = secs.clamp(0.005, 0.2);
+    }
+
+    /// Start playing the sample from the beginning.
+    pub fn play(&self) {
+        self.shared.state.store(STATE_TRIGGERED, Ordering::Release);
+    }
+
+    /// Stop playing the sample.
+    pub fn stop(&self) {
+        self.shared.state.store(STATE_IDLE, Ordering::Release);
+    }
+
+    /// Returns `true` if the sample is currently playing.
+    pub fn is_playing(&self) -> bool {
+        let state = self.shared.state.load(Ordering::Acquire);
+        state == STATE_TRIGGERED || state == STATE_PLAYING
+    }
+
+    /// Returns `true` if the sample was played to the end. This is reset
+    /// when the sample is played again.
+    pub fn is_finished(&self) -> bool {
+        self.shared.state.load(Ordering::Acquire) == STATE_FINISHED
+    }
+}
+
+impl<C, S: SampleResource + Clone> AudioNode<C> for SamplePlayerNode<S> {
+    fn debug_name(&self) -> &'static str {
+        "sample_player"
+    }
+
+    fn info(&self) -> AudioNodeInfo {
+        AudioNodeInfo {
+            num_min_supported_outputs: ChannelCount::MONO,
+            num_max_supported_outputs: ChannelCount::MAX,
+            default_channel_config: ChannelConfig {
+                num_inputs: ChannelCount::ZERO,
+                num_outputs: ChannelCount::STEREO,
+            },
+            ..Default::default()
+        }
+    }
+
+    fn activate(
+        &mut self,
+        stream_info: &StreamInfo,
+        channel_config: ChannelConfig,
+    ) -> Result<Box<dyn AudioNodeProcessor<C>>, Box<dyn std::error::Error>> {
+        let num_channels = self
+            .sample
+            .num_channels()
+            .get()
+            .min(channel_config.num_outputs.get() as usize);
+
+        // Enough frames to interpolate a full block at the highest speed.
+        let max_read_frames =
+            (stream_info.max_block_samples as f32 * SAMPLE_PLAYER_MAX_SPEED).ceil() as usize + 2;
+
+        // Use an even window length so that the windows overlap by exactly
+        // half.
+        let window_len = ((self.stretch_window_secs * stream_info.sample_rate as f32 / 2.0).round()
+            as usize)
+            .max(4)
+            * 2;
+
+        Ok(Box::new(SamplePlayerProcessor {
+            sample: self.sample.clone(),
+            shared: Arc::clone(&self.shared),
+            status: self.status.writer(),
+            playhead: 0.0,
+            stretching: false,
+            read_buffers: vec![vec![0.0; max_read_frames]; num_channels],
+            wsola: Wsola::new(window_len, num_channels),
+        }))
+    }
+}
+
+struct SamplePlayerProcessor<S: SampleResource> {
+    sample: S,
+    shared: Arc<SharedState>,
+    status: NodeStatusWriter<SamplePlayerStatus>,
+    /// The position of the playhead in frames of the sample, used when not
+    /// time-stretching.
+    playhead: f64,
+    stretching: bool,
+    read_buffers: Vec<Vec<f32>>,
+    wsola: Wsola,
+}
+
+impl<S: SampleResource> SamplePlayerProcessor<S> {
+    /// Play with linear interpolation, changing the pitch along with the
+    /// speed.
+    ///
+    /// Returns `false` if the end of the sample was reached.
+    fn process_resampled(
+        &mut self,
+        outputs: &mut [&mut [f32]],
+        samples: usize,
+        speed: f64,
+    ) -> bool {
+        let len_frames = self.sample.len_samples() as f64;
+
+        if self.playhead >= len_frames {
+            for out in outputs.iter_mut() {
+                out[..samples].fill(0.0);
+            }
+            return false;
+        }
+
+        let start_frame = self.playhead.floor();
+        let start_fract = self.playhead - start_frame;
+        let read_frames = ((start_fract + (samples - 1) as f64 * speed).floor() as usize + 2)
+            .min(self.read_buffers.first().map(|b| b.len()).unwrap_or(0));
+
+        read_sample(
+            &self.sample,
+            &mut self.read_buffers,
+            read_frames,
+            start_frame as i64,
+        );
+
+        for (out, buf) in outputs.iter_mut().zip(self.read_buffers.iter()) {
+            for (i, s) in out[..samples].iter_mut().enumerate() {
+                let pos = start_fract + i as f64 * speed;
+                let idx = pos as usize;
+                let fract = (pos - idx as f64) as f32;
+
+                let s0 = buf.get(idx).copied().unwrap_or(0.0);
+                let s1 = buf.get(idx + 1).copied().unwrap_or(0.0);
+                *s = s0 + (s1 - s0) * fract;
+            }
+        }
+
+        self.playhead += samples as f64 * speed;
+
+        true
+    }
+}
+
+impl<C, S: SampleResource> AudioNodeProcessor<C> for SamplePlayerProcessor<S> {
+    fn process(
+        &mut self,
+        _inputs: &[&[f32]],
+        outputs: &mut [&mut [f32]],
+        proc_info: ProcInfo,
+        _cx: &mut C,
+    ) -> ProcessStatus {
+        let samples = proc_info.samples;
+        let speed = f64::from(self.shared.speed.load(Ordering::Relaxed));
+        let time_stretch = self.shared.time_stretch.load(Ordering::Relaxed);
+
+        if self
+            .shared
+            .state
+            .compare_exchange(
+                STATE_TRIGGERED,
+                STATE_PLAYING,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_ok()
+        {
+            self.playhead = 0.0;
+            self.stretching = time_stretch;
+
+            if time_stretch {
+                self.wsola.reset(&self.sample, 0.0, speed);
+            }
+        } else if self.shared.state.load(Ordering::Acquire) != STATE_PLAYING {
+            return ProcessStatus::NoOutputsModified;
+        }
+
+        if time_stretch != self.stretching {
+            self.stretching = time_stretch;
+
+            if time_stretch {
+                self.wsola.reset(&self.sample, self.playhead, speed);
+            } else {
+                self.playhead = self.wsola.position(speed);
+            }
+        }
+
+        let num_channels = self.read_buffers.len().min(outputs.len());
+
+        let still_playing = if self.stretching {
+            self.wsola
+                .process(&self.sample, &mut outputs[..num_channels], samples, speed)
+        } else {
+            self.process_resampled(&mut outputs[..num_channels], samples, speed)
+        };
+
+        let position = if self.stretching {
+            self.wsola.position(speed)
+        } else {
+            self.playhead
+        };
+        self.status.write(SamplePlayerStatus {
+            position_frames: (position as u64).min(self.sample.len_samples()),
+        });
+
+        if !still_playing {
+            // Only mark the sample as finished if it wasn't played again in
+            // the meantime.
+            let _ = self.shared.state.compare_exchange(
+                STATE_PLAYING,
+                STATE_FINISHED,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            );
+        }
+
+        let mut out_silence_mask = SilenceMask::NONE_SILENT;
+
+        if num_channels == 1 {
+            // Play mono samples on every output channel.
+            let (out1, outputs) = outputs.split_first_mut().unwrap();
+            for out in outputs.iter_mut() {
+                out[..samples].copy_from_slice(&out1[..samples]);
+            }
+        } else {
+            for (i, out) in outputs.iter_mut().enumerate().skip(num_channels) {
+                out[..samples].fill(0.0);
+                out_silence_mask.set_channel(i, true);
+            }
+        }
+
+        ProcessStatus::outputs_modified(out_silence_mask)
+    }
+}
+
+/// A time-stretcher using WSOLA (waveform similarity overlap-add).
+struct Wsola {
+    /// A Hann window.
+    window: Vec<f32>,
+    /// The distance between two windows in the output.
+    hop: usize,
+    /// How far a window may be shifted to line it up with the previous one.
+    tolerance: usize,
+    /// The nominal position in the sample of the next window.
+    analysis_pos: f64,
+    /// The position in the sample of the previous window.
+    prev_window_pos: Option<i64>,
+    /// The part of the sample which continues on from the previous window,
+    /// used as the template for lining up the next window.
+    template: Vec<Vec<f32>>,
+    /// The part of the sample in which the next window is searched for.
+    search: Vec<Vec<f32>>,
+    /// The overlap-add buffer. The first `hop` frames are finished.
+    overlap: Vec<Vec<f32>>,
+    /// The number of finished frames which were already output.
+    out_pos: usize,
+    /// Whether or not the tail of the last window was output.
+    flushed: bool,
+}
+
+impl Wsola {
+    fn new(window_len: usize, num_channels: usize) -> Self {
+        let window = (0..window_len)
+            .map(|i| 0.5 - 0.5 * (std::f32::consts::TAU * i as f32 / window_len as f32).cos())
+            .collect();
+        let hop = window_len / 2;
+        let tolerance = window_len / 4;
+
+        Self {
+            window,
+            hop,
+            tolerance,
+            analysis_pos: 0.0,
+            prev_window_pos: None,
+            template: vec![vec![0.0; window_len]],
+            search: vec![vec![0.0; window_len + tolerance * 2]; num_channels],
+            overlap: vec![vec![0.0; window_len]; num_channels],
+            out_pos: hop,
+            flushed: false,
+        }
+    }
+
+    /// Start stretching from the given position in the sample.
+    fn reset<S: SampleResource>(&mut self, sample: &S, position: f64, speed: f64) {
+        for ch in self.overlap.iter_mut() {
+            ch.fill(0.0);
+        }
+
+        // Start with a window centered on the start position, and throw
+        // away its fade in. This way the output starts at full volume.
+        self.analysis_pos = position - self.hop as f64;
+        self.prev_window_pos = None;
+        self.flushed = false;
+        self.add_window(sample, speed);
+        self.out_pos = self.hop;
+    }
+
+    /// The position in the sample which is currently being output.
+    fn position(&self, speed: f64) -> f64 {
+        (self.analysis_pos - self.hop as f64 * speed).max(0.0)
+    }
+
+    /// Returns `false` if the end of the sample was reached.
+    fn process<S: SampleResource>(
+        &mut self,
+        sample: &S,
+        outputs: &mut [&mut [f32]],
+        samples: usize,
+        speed: f64,
+    ) -> bool {
+        let len_frames = sample.len_samples() as f64;
+        let mut frame = 0;
+
+        while frame < samples {
+            if self.out_pos == self.hop {
+                let window_len = self.window.len();
+                for ch in self.overlap.iter_mut() {
+                    ch.copy_within(self.hop.., 0);
+                    ch[window_len - self.hop..].fill(0.0);
+                }
+                self.out_pos = 0;
+
+                if self.analysis_pos < len_frames {
+                    self.add_window(sample, speed);
+                } else if self.flushed {
+                    for out in outputs.iter_mut() {
+                        out[frame..samples].fill(0.0);
+                    }
+                    return false;
+                } else {
+                    // Output the tail of the last window.
+                    self.flushed = true;
+                }
+            }
+
+            let count = (self.hop - self.out_pos).min(samples - frame);
+
+            for (out, ch) in outputs.iter_mut().zip(self.overlap.iter()) {
+                out[frame..frame + count].copy_from_slice(&ch[self.out_pos..self.out_pos + count]);
+            }
+
+            self.out_pos += count;
+            frame += count;
+        }
+
+        true
+    }
+
+    /// Find the best position for the next window and add it to the
+    /// overlap-add buffer.
+    fn add_window<S: SampleResource>(&mut self, sample: &S, speed: f64) {
+        let window_len = self.window.len();
+        let nominal_pos = self.analysis_pos.round() as i64;
+        let search_start = nominal_pos - self.tolerance as i64;
+
+        read_sample(
+            sample,
+            &mut self.search,
+            window_len + self.tolerance * 2,
+            search_start,
+        );
+
+        let offset = if let Some(prev_window_pos) = self.prev_window_pos {
+            // Line the window up with the natural continuation of the
+            // previous window, using the first channel.
+            read_sample(
+                sample,
+                &mut self.template,
+                window_len,
+                prev_window_pos + self.hop as i64,
+            );
+
+            let template = &self.template[0];
+            let search = &self.search[0];
+
+            (0..=self.tolerance * 2)
+                .map(|offset| {
+                    let correlation: f32 = template
+                        .iter()
+                        .zip(search[offset..offset + window_len].iter())
+                        .map(|(a, b)| a * b)
+                        .sum();
+                    (offset, correlation)
+                })
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(offset, _)| offset)
+                .unwrap_or(self.tolerance)
+        } else {
+            self.tolerance
+        };
+
+        for (ch, search) in self.overlap.iter_mut().zip(self.search.iter()) {
+            for ((s, &x), &w) in ch
+                .iter_mut()
+                .zip(search[offset..offset + window_len].iter())
+                .zip(self.window.iter())
+            {
+                *s += x * w;
+            }
+        }
+
+        self.prev_window_pos = Some(search_start + offset as i64);
+        self.analysis_pos += self.hop as f64 * speed;
+    }
+}
+
+/// Read `frames` frames of the sample starting at `start_frame` into the
+/// given buffers, filling any part outside of the sample with silence.
+fn read_sample<S: SampleResource>(
+    sample: &S,
+    buffers: &mut [Vec<f32>],
+    frames: usize,
+    start_frame: i64,
+) {
+    let len_frames = sample.len_samples() as i64;
+
+    let data_start = (-start_frame).clamp(0, frames as i64) as usize;
+    let data_end = (len_frames - start_frame).clamp(data_start as i64, frames as i64) as usize;
+
+    let mut buffers: ArrayVec<&mut [f32], 64> =
+        buffers.iter_mut().map(|b| &mut b[..frames]).collect();
+
+    for buf in buffers.iter_mut() {
+        buf[..data_start].fill(0.0);
+        buf[data_end..].fill(0.0);
+    }
+
+    if data_end > data_start {
+        sample.fill_buffers(
+            &mut buffers,
+            data_start..data_end,
+            (start_frame + data_start as i64) as u64,
+        );
+    }
+}
+
+impl<C, S: SampleResource + Clone> Into<Box<dyn AudioNode<C>>> for SamplePlayerNode<S> {
+    fn into(self) -> Box<dyn AudioNode<C>> {
+        Box::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic_nodes::process_block;
+
+    const SAMPLE_RATE: u32 = 44100;
+    const TONE_HZ: f32 = 441.0;
+
+    /// Play half a second of a tone at half speed, returning the output
+    /// until the sample has finished playing.
+    fn play_tone_at_half_speed(time_stretch: bool) -> Vec<f32> {
+        let tone: Vec<f32> = (0..SAMPLE_RATE as usize / 2)
+            .map(|i| (std::f32::consts::TAU * TONE_HZ * i as f32 / SAMPLE_RATE as f32).sin() * 0.5)
+            .collect();
+
+        let stream_info = StreamInfo::default();
+        assert_eq!(stream_info.sample_rate, SAMPLE_RATE);
+        let samples = stream_info.max_block_samples as usize;
+
+        let mut node = SamplePlayerNode::new(Arc::new(vec![tone]));
+        node.set_speed(0.5);
+        node.set_time_stretch(time_stretch);
+        let mut processor = AudioNode::<()>::activate(
+            &mut node,
+            &stream_info,
+            ChannelConfig {
+                num_inputs: ChannelCount::ZERO,
+                num_outputs: ChannelCount::MONO,
+            },
+        )
+        .unwrap();
+
+        node.play();
+
+        let mut output = Vec::new();
+        let mut block = vec![0.0; samples];
+        while node.is_playing() {
+            process_block(
+                processor.as_mut(),
+                &[],
+                &mut [&mut block],
+                SilenceMask::NONE_SILENT,
+            );
+            output.extend_from_slice(&block);
+
+            assert!(output.len() < SAMPLE_RATE as usize * 2);
+        }
+        assert!(node.is_finished());
+
+        output
+    }
+
+    /// Estimate the frequency of a tone by counting zero crossings.
+    fn measure_freq_hz(signal: &[f32]) -> f32 {
+        let crossings = signal
+            .windows(2)
+            .filter(|w| (w[0] < 0.0) != (w[1] < 0.0))
+            .count();
+
+        crossings as f32 * 0.5 * SAMPLE_RATE as f32 / signal.len() as f32
+    }
+
+    fn last_audible_frame(signal: &[f32]) -> usize {
+        signal.iter().rposition(|s| s.abs() > 0.01).unwrap()
+    }
+
+    #[test]
+    fn time_stretch_preserves_pitch() {
+        let stretched = play_tone_at_half_speed(true);
+
+        // The duration is doubled (within the length of a window).
+        let duration = last_audible_frame(&stretched) as f32 / SAMPLE_RATE as f32;
+        assert!((duration - 1.0).abs() < DEFAULT_STRETCH_WINDOW_SECS);
+
+        // The pitch is preserved.
+        let freq_hz = measure_freq_hz(&stretched[4410..SAMPLE_RATE as usize - 4410]);
+        assert!((freq_hz - TONE_HZ).abs() < TONE_HZ * 0.02);
+
+        // The level stays steady instead of pulsing between windows.
+        assert!(stretched[4410..SAMPLE_RATE as usize - 4410]
+            .chunks_exact(441)
+            .all(|c| {
+                let peak = c.iter().fold(0.0f32, |p, s| p.max(s.abs()));
+                (peak - 0.5).abs() < 0.05
+            }));
+    }
+
+    #[test]
+    fn resampling_changes_pitch() {
+        let resampled = play_tone_at_half_speed(false);
+
+        let duration = last_audible_frame(&resampled) as f32 / SAMPLE_RATE as f32;
+        assert!((duration - 1.0).abs() < 0.01);
+
+        let freq_hz = measure_freq_hz(&resampled[4410..SAMPLE_RATE as usize - 4410]);
+        assert!((freq_hz - TONE_HZ * 0.5).abs() < TONE_HZ * 0.02);
+    }
+
+    #[test]
+    fn reports_playback_position() {
+        let len_frames = 10_000;
+        let stream_info = StreamInfo::default();
+        let samples = stream_info.max_block_samples as usize;
+
+        let mut node = SamplePlayerNode::new(Arc::new(vec![vec![0.5; len_frames]]));
+        node.set_speed(0.5);
+        let mut processor = AudioNode::<()>::activate(
+            &mut node,
+            &stream_info,
+            ChannelConfig {
+                num_inputs: ChannelCount::ZERO,
+                num_outputs: ChannelCount::MONO,
+            },
+        )
+        .unwrap();
+
+        let mut block = vec![0.0; samples];
+        let mut process = |processor: &mut Box<dyn AudioNodeProcessor<()>>| {
+            process_block(
+                processor.as_mut(),
+                &[],
+                &mut [&mut block],
+                SilenceMask::NONE_SILENT,
+            );
+        };
+
+        process(&mut processor);
+        assert_eq!(node.status().position_frames, 0);
+
+        node.play();
+        let mut num_blocks = 0;
+        while node.is_playing() {
+            process(&mut processor);
+            num_blocks += 1;
+
+            let expected = (num_blocks * samples / 2).min(len_frames) as u64;
+            assert_eq!(node.status().position_frames, expected);
+        }
+        assert_eq!(node.status().position_frames, len_frames as u64);
+
+        // Playing again starts from the beginning.
+        node.play();
+        process(&mut processor);
+        assert_eq!(node.status().position_frames, samples as u64 / 2);
+    }
+}
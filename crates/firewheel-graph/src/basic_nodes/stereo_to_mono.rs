@@ -22,6 +22,8 @@ impl<C> AudioNode<C> for StereoToMonoNode {
             },
             equal_num_ins_and_outs: false,
             updates: false,
+            silent_when_inputs_silent: true,
+            preserve_denormals: false,
         }
     }
 
@@ -1,8 +1,19 @@
 use firewheel_core::{
     node::{AudioNode, AudioNodeInfo, AudioNodeProcessor, ProcInfo, ProcessStatus},
-    ChannelConfig, ChannelCount, StreamInfo,
+    ChannelConfig, ChannelCount, SilenceMask, StreamInfo,
 };
 
+/// A node which sums multiple input ports together.
+///
+/// An input port can only be connected to a single output, so use this
+/// node to mix multiple signals into one. The inputs are grouped into
+/// ports of `num_outputs` channels each, so a node with 4 inputs and 2
+/// outputs mixes two stereo signals.
+///
+/// Input channels which are marked as silent are skipped when summing. An
+/// output channel is only marked as silent when all of the input channels
+/// summed into it are silent (the AND of their silence masks), so nodes
+/// further down the graph can still skip processing it.
 pub struct SumNode;
 
 impl<C> AudioNode<C> for SumNode {
@@ -22,6 +33,8 @@ impl<C> AudioNode<C> for SumNode {
             },
             equal_num_ins_and_outs: false,
             updates: false,
+            silent_when_inputs_silent: true,
+            preserve_denormals: false,
         }
     }
 
@@ -80,6 +93,16 @@ impl<C> AudioNodeProcessor<C> for SumNodeProcessor {
             return ProcessStatus::outputs_modified(proc_info.in_silence_mask);
         }
 
+        if proc_info.in_silence_mask.any_channel_silent(num_inputs) {
+            return sum_with_silence(
+                inputs,
+                outputs,
+                self.num_in_ports,
+                samples,
+                proc_info.in_silence_mask,
+            );
+        }
+
         match self.num_in_ports {
             // Provide a few optimized loops for common number of input ports.
             2 => {
@@ -133,13 +156,7 @@ impl<C> AudioNodeProcessor<C> for SumNodeProcessor {
                     out.copy_from_slice(&inputs[ch_i][..samples]);
 
                     for in_port_i in 1..n {
-                        let in_ch_i = (num_outputs * in_port_i) + ch_i;
-
-                        if proc_info.in_silence_mask.is_channel_silent(in_ch_i) {
-                            continue;
-                        }
-
-                        let input = &inputs[in_ch_i][..samples];
+                        let input = &inputs[(num_outputs * in_port_i) + ch_i][..samples];
 
                         for i in 0..samples {
                             out[i] += input[i];
@@ -153,8 +170,149 @@ impl<C> AudioNodeProcessor<C> for SumNodeProcessor {
     }
 }
 
+/// Sum the inputs when some of them are silent, skipping the silent
+/// channels.
+///
+/// An output channel is only marked as silent if all of the input channels
+/// that are summed into it are silent, so that nodes further down the
+/// graph can still skip processing it.
+fn sum_with_silence(
+    inputs: &[&[f32]],
+    outputs: &mut [&mut [f32]],
+    num_in_ports: usize,
+    samples: usize,
+    in_silence_mask: SilenceMask,
+) -> ProcessStatus {
+    let num_outputs = outputs.len();
+    assert!(inputs.len() >= (num_outputs * num_in_ports));
+
+    let mut out_silence_mask = SilenceMask::NONE_SILENT;
+
+    for (ch_i, out) in outputs.iter_mut().enumerate() {
+        let out = &mut out[0..samples];
+        let mut is_silent = true;
+
+        for in_port_i in 0..num_in_ports {
+            let in_ch_i = (num_outputs * in_port_i) + ch_i;
+
+            if in_silence_mask.is_channel_silent(in_ch_i) {
+                continue;
+            }
+
+            let input = &inputs[in_ch_i][..samples];
+
+            if is_silent {
+                out.copy_from_slice(input);
+                is_silent = false;
+            } else {
+                for i in 0..samples {
+                    out[i] += input[i];
+                }
+            }
+        }
+
+        if is_silent {
+            out.fill(0.0);
+            out_silence_mask.set_channel(ch_i, true);
+        }
+    }
+
+    ProcessStatus::outputs_modified(out_silence_mask)
+}
+
 impl<C> Into<Box<dyn AudioNode<C>>> for SumNode {
     fn into(self) -> Box<dyn AudioNode<C>> {
         Box::new(self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic_nodes::process_block;
+
+    #[test]
+    fn sum_beep_and_silence() {
+        let stream_info = StreamInfo::default();
+        let samples = 64;
+
+        let mut node = SumNode;
+        let mut processor = AudioNode::<()>::activate(
+            &mut node,
+            &stream_info,
+            ChannelConfig {
+                num_inputs: ChannelCount::new(4).unwrap(),
+                num_outputs: ChannelCount::STEREO,
+            },
+        )
+        .unwrap();
+
+        let beep: Vec<f32> = (0..samples)
+            .map(|i| (i as f32 * 440.0 / 44100.0 * std::f32::consts::TAU).sin())
+            .collect();
+        let silence = vec![0.0; samples];
+        let mut out_l = vec![1.0; samples];
+        let mut out_r = vec![1.0; samples];
+
+        // The first port is a mono beep on the left channel, and the second
+        // port is completely silent.
+        let in_silence_mask = SilenceMask(0b1110);
+
+        let status = process_block(
+            processor.as_mut(),
+            &[&beep, &silence, &silence, &silence],
+            &mut [&mut out_l, &mut out_r],
+            in_silence_mask,
+        );
+
+        assert_eq!(status, ProcessStatus::outputs_modified(SilenceMask(0b10)));
+        assert_eq!(out_l, beep);
+        assert_eq!(out_r, silence);
+    }
+
+    #[test]
+    fn sum_partially_silent_ports() {
+        let stream_info = StreamInfo::default();
+        let samples = 64;
+
+        // Three ports of three channels each.
+        let mut node = SumNode;
+        let mut processor = AudioNode::<()>::activate(
+            &mut node,
+            &stream_info,
+            ChannelConfig {
+                num_inputs: ChannelCount::new(9).unwrap(),
+                num_outputs: ChannelCount::new(3).unwrap(),
+            },
+        )
+        .unwrap();
+
+        let a: Vec<f32> = (0..samples).map(|i| (i as f32 * 0.1).sin()).collect();
+        let b: Vec<f32> = (0..samples).map(|i| (i as f32 * 0.3).cos()).collect();
+        let silence = vec![0.0; samples];
+        let mut out = [vec![1.0; samples], vec![1.0; samples], vec![1.0; samples]];
+
+        // The first output channel gets `a` from the first port and `b`
+        // from the third port, the second output channel only gets `b` from
+        // the second port, and every input of the third output channel is
+        // silent.
+        let in_silence_mask = SilenceMask(0b110_101_110);
+        let [out_0, out_1, out_2] = &mut out;
+
+        let status = process_block(
+            processor.as_mut(),
+            &[
+                &a, &silence, &silence, &silence, &b, &silence, &b, &silence, &silence,
+            ],
+            &mut [out_0, out_1, out_2],
+            in_silence_mask,
+        );
+
+        assert_eq!(status, ProcessStatus::outputs_modified(SilenceMask(0b100)));
+        for i in 0..samples {
+            assert_eq!(out[0][i], a[i] + b[i]);
+        }
+        assert_eq!(out[1], b);
+        assert_eq!(out[2], silence);
+    }
+}
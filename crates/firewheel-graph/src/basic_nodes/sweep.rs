@@ -0,0 +1,415 @@
+use atomic_float::AtomicF32;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU8, Ordering},
+    Arc,
+};
+
+use firewheel_core::{
+    node::{AudioNode, AudioNodeInfo, AudioNodeProcessor, ProcInfo, ProcessStatus},
+    ChannelConfig, ChannelCount, StreamInfo,
+};
+
+const STATE_IDLE: u8 = 0;
+const STATE_TRIGGERED: u8 = 1;
+const STATE_PLAYING: u8 = 2;
+const STATE_FINISHED: u8 = 3;
+
+/// How the frequency of a [`SweepNode`] changes over time.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SweepKind {
+    /// The frequency changes linearly over time.
+    Linear,
+    /// The frequency changes exponentially over time, so each octave
+    /// takes the same amount of time.
+    #[default]
+    Logarithmic,
+}
+
+/// The parameters of a sine sweep.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SweepParams {
+    pub start_hz: f32,
+    pub end_hz: f32,
+    pub duration_secs: f32,
+    pub kind: SweepKind,
+}
+
+impl SweepParams {
+    /// The instantaneous frequency of the sweep at the given time in
+    /// seconds from the start of the sweep.
+    pub fn freq_at(&self, secs: f32) -> f32 {
+        let t = (secs / self.duration_secs).clamp(0.0, 1.0);
+
+        match self.kind {
+            SweepKind::Linear => self.start_hz + (self.end_hz - self.start_hz) * t,
+            SweepKind::Logarithmic => self.start_hz * (self.end_hz / self.start_hz).powf(t),
+        }
+    }
+}
+
+struct SharedState {
+    start_hz: AtomicF32,
+    end_hz: AtomicF32,
+    duration_secs: AtomicF32,
+    logarithmic: AtomicBool,
+    state: AtomicU8,
+}
+
+impl SharedState {
+    fn params(&self) -> SweepParams {
+        SweepParams {
+            start_hz: self.start_hz.load(Ordering::Relaxed),
+            end_hz: self.end_hz.load(Ordering::Relaxed),
+            duration_secs: self.duration_secs.load(Ordering::Relaxed),
+            kind: if self.logarithmic.load(Ordering::Relaxed) {
+                SweepKind::Logarithmic
+            } else {
+                SweepKind::Linear
+            },
+        }
+    }
+}
+
+/// A node which generates a sine sweep (chirp) over a range of frequencies.
+///
+/// This is useful for measuring the frequency response of a system. The
+/// sweep does not start until [`SweepNode::trigger`] is called. The
+/// parameters of the sweep are read at the moment it starts, so they can
+/// be changed at any time before triggering.
+pub struct SweepNode {
+    shared: Arc<SharedState>,
+    gain: f32,
+}
+
+impl SweepNode {
+    pub fn new(params: SweepParams, gain_db: f32) -> Self {
+        let gain = firewheel_core::util::db_to_gain_clamped_neg_100_db(gain_db).clamp(0.0, 1.0);
+
+        let node = Self {
+            shared: Arc::new(SharedState {
+                start_hz: AtomicF32::new(0.0),
+                end_hz: AtomicF32::new(0.0),
+                duration_secs: AtomicF32::new(0.0),
+                logarithmic: AtomicBool::new(false),
+                state: AtomicU8::new(STATE_IDLE),
+            }),
+            gain,
+        };
+        node.set_params(params);
+
+        node
+    }
+
+    pub fn params(&self) -> SweepParams {
+        self.shared.params()
+    }
+
+    /// Set the parameters of the sweep.
+    ///
+    /// This takes effect the next time the sweep is triggered.
+    pub fn set_params(&self, params: SweepParams) {
+        let start_hz = params.start_hz.clamp(1.0, 22_000.0);
+        let end_hz = params.end_hz.clamp(1.0, 22_000.0);
+
+        self.shared.start_hz.store(start_hz, Ordering::Relaxed);
+        self.shared.end_hz.store(end_hz, Ordering::Relaxed);
+        self.shared
+            .duration_secs
+            .store(params.duration_secs.max(0.001), Ordering::Relaxed);
+        self.shared
+            .logarithmic
+            .store(params.kind == SweepKind::Logarithmic, Ordering::Relaxed);
+    }
+
+    /// Start the sweep from the beginning.
+    pub fn trigger(&self) {
+        self.shared.state.store(STATE_TRIGGERED, Ordering::Release);
+    }
+
+    /// Returns `true` if the sweep has been triggered and has not yet
+    /// finished.
+    pub fn is_active(&self) -> bool {
+        let state = self.shared.state.load(Ordering::Acquire);
+        state == STATE_TRIGGERED || state == STATE_PLAYING
+    }
+
+    /// Returns `true` if the sweep has played to completion. This is reset
+    /// when the sweep is triggered again.
+    pub fn is_finished(&self) -> bool {
+        self.shared.state.load(Ordering::Acquire) == STATE_FINISHED
+    }
+}
+
+impl<C> AudioNode<C> for SweepNode {
+    fn debug_name(&self) -> &'static str {
+        "sweep"
+    }
+
+    fn info(&self) -> AudioNodeInfo {
+        AudioNodeInfo {
+            num_min_supported_outputs: ChannelCount::MONO,
+            num_max_supported_outputs: ChannelCount::MAX,
+            default_channel_config: ChannelConfig {
+                num_inputs: ChannelCount::ZERO,
+                num_outputs: ChannelCount::STEREO,
+            },
+            ..Default::default()
+        }
+    }
+
+    fn activate(
+        &mut self,
+        stream_info: &StreamInfo,
+        _channel_config: ChannelConfig,
+    ) -> Result<Box<dyn AudioNodeProcessor<C>>, Box<dyn std::error::Error>> {
+        Ok(Box::new(SweepProcessor {
+            shared: Arc::clone(&self.shared),
+            params: self.params(),
+            phasor: 0.0,
+            elapsed_samples: 0,
+            sample_rate: stream_info.sample_rate as f32,
+            sample_rate_recip: (stream_info.sample_rate as f32).recip(),
+            gain: self.gain,
+        }))
+    }
+}
+
+struct SweepProcessor {
+    shared: Arc<SharedState>,
+    params: SweepParams,
+    phasor: f32,
+    elapsed_samples: u64,
+    sample_rate: f32,
+    sample_rate_recip: f32,
+    gain: f32,
+}
+
+impl SweepProcessor {
+    fn start(&mut self) {
+        self.params = self.shared.params();
+        self.phasor = 0.0;
+        self.elapsed_samples = 0;
+    }
+}
+
+impl<C> AudioNodeProcessor<C> for SweepProcessor {
+    fn process(
+        &mut self,
+        _inputs: &[&[f32]],
+        outputs: &mut [&mut [f32]],
+        proc_info: ProcInfo,
+        _cx: &mut C,
+    ) -> ProcessStatus {
+        let Some((out1, outputs)) = outputs.split_first_mut() else {
+            return ProcessStatus::NoOutputsModified;
+        };
+
+        if self
+            .shared
+            .state
+            .compare_exchange(
+                STATE_TRIGGERED,
+                STATE_PLAYING,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_ok()
+        {
+            self.start();
+        } else if self.shared.state.load(Ordering::Acquire) != STATE_PLAYING {
+            return ProcessStatus::NoOutputsModified;
+        }
+
+        let total_samples = (self.params.duration_secs * self.sample_rate).round() as u64;
+
+        for s in out1[..proc_info.samples].iter_mut() {
+            if self.elapsed_samples >= total_samples {
+                *s = 0.0;
+                continue;
+            }
+
+            *s = (self.phasor * std::f32::consts::TAU).sin() * self.gain;
+
+            let freq_hz = self
+                .params
+                .freq_at(self.elapsed_samples as f32 * self.sample_rate_recip);
+            self.phasor = (self.phasor + freq_hz * self.sample_rate_recip).fract();
+            self.elapsed_samples += 1;
+        }
+
+        if self.elapsed_samples >= total_samples {
+            // Only mark the sweep as finished if it wasn't retriggered in
+            // the meantime.
+            let _ = self.shared.state.compare_exchange(
+                STATE_PLAYING,
+                STATE_FINISHED,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            );
+        }
+
+        for out2 in outputs.iter_mut() {
+            out2[..proc_info.samples].copy_from_slice(&out1[..proc_info.samples]);
+        }
+
+        ProcessStatus::all_outputs_filled()
+    }
+}
+
+impl<C> Into<Box<dyn AudioNode<C>>> for SweepNode {
+    fn into(self) -> Box<dyn AudioNode<C>> {
+        Box::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use firewheel_core::SilenceMask;
+
+    use super::*;
+    use crate::basic_nodes::process_block;
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    fn render_sweep(params: SweepParams) -> Vec<f32> {
+        let stream_info = StreamInfo {
+            sample_rate: SAMPLE_RATE,
+            ..Default::default()
+        };
+        let samples = stream_info.max_block_samples as usize;
+
+        let mut node = SweepNode::new(params, 0.0);
+        let mut processor = AudioNode::<()>::activate(
+            &mut node,
+            &stream_info,
+            ChannelConfig {
+                num_inputs: ChannelCount::ZERO,
+                num_outputs: ChannelCount::MONO,
+            },
+        )
+        .unwrap();
+
+        node.trigger();
+
+        let mut output = Vec::new();
+        let mut block = vec![0.0; samples];
+        while !node.is_finished() {
+            process_block(
+                processor.as_mut(),
+                &[],
+                &mut [&mut block],
+                SilenceMask::NONE_SILENT,
+            );
+            output.extend_from_slice(&block);
+        }
+
+        output
+    }
+
+    /// Estimate the frequency of a signal from the spacing of its zero
+    /// crossings, interpolating between samples to find each crossing.
+    fn measure_freq_hz(signal: &[f32]) -> f32 {
+        let crossings: Vec<f32> = signal
+            .windows(2)
+            .enumerate()
+            .filter(|(_, w)| (w[0] < 0.0) != (w[1] < 0.0))
+            .map(|(i, w)| i as f32 + w[0] / (w[0] - w[1]))
+            .collect();
+
+        let span = crossings[crossings.len() - 1] - crossings[0];
+        (crossings.len() - 1) as f32 * 0.5 * SAMPLE_RATE as f32 / span
+    }
+
+    #[test]
+    fn sweep_freq_range() {
+        for kind in [SweepKind::Linear, SweepKind::Logarithmic] {
+            let params = SweepParams {
+                start_hz: 1_000.0,
+                end_hz: 4_000.0,
+                duration_secs: 1.0,
+                kind,
+            };
+
+            assert!((params.freq_at(0.0) - 1_000.0).abs() < 0.01);
+            assert!((params.freq_at(1.0) - 4_000.0).abs() < 0.1);
+
+            let mid = params.freq_at(0.5);
+            match kind {
+                SweepKind::Linear => assert!((mid - 2_500.0).abs() < 0.1),
+                SweepKind::Logarithmic => assert!((mid - 2_000.0).abs() < 0.1),
+            }
+
+            // Measure the frequency of the generated signal over the first
+            // and last 4 milliseconds of the sweep.
+            let output = render_sweep(params);
+            let total_samples = SAMPLE_RATE as usize;
+            let window = SAMPLE_RATE as usize * 4 / 1000;
+
+            let start_hz = measure_freq_hz(&output[..window]);
+            let end_hz = measure_freq_hz(&output[total_samples - window..total_samples]);
+
+            assert!(
+                (start_hz - params.start_hz).abs() < params.start_hz * 0.01,
+                "{kind:?} sweep starts at {start_hz} Hz"
+            );
+            assert!(
+                (end_hz - params.end_hz).abs() < params.end_hz * 0.01,
+                "{kind:?} sweep ends at {end_hz} Hz"
+            );
+            assert!(output[total_samples..].iter().all(|&s| s == 0.0));
+        }
+    }
+
+    #[test]
+    fn sweep_finishes() {
+        let stream_info = StreamInfo::default();
+        let samples = stream_info.max_block_samples as usize;
+
+        let mut node = SweepNode::new(
+            SweepParams {
+                start_hz: 100.0,
+                end_hz: 1_000.0,
+                duration_secs: 0.1,
+                kind: SweepKind::Logarithmic,
+            },
+            0.0,
+        );
+        let mut processor = AudioNode::<()>::activate(
+            &mut node,
+            &stream_info,
+            ChannelConfig {
+                num_inputs: ChannelCount::ZERO,
+                num_outputs: ChannelCount::MONO,
+            },
+        )
+        .unwrap();
+
+        let mut output = vec![0.0; samples];
+        let mut process = |output: &mut [f32]| {
+            process_block(
+                processor.as_mut(),
+                &[],
+                &mut [output],
+                SilenceMask::NONE_SILENT,
+            )
+        };
+
+        assert_eq!(process(&mut output), ProcessStatus::NoOutputsModified);
+
+        node.trigger();
+        assert!(node.is_active());
+
+        // 0.1 seconds at 44100 Hz is 4410 samples, which is just over 4 blocks.
+        for _ in 0..4 {
+            assert_eq!(process(&mut output), ProcessStatus::all_outputs_filled());
+            assert!(!node.is_finished());
+        }
+
+        process(&mut output);
+        assert!(node.is_finished());
+        assert!(!node.is_active());
+        assert!(output[4410 - 4 * samples..].iter().all(|&s| s == 0.0));
+
+        assert_eq!(process(&mut output), ProcessStatus::NoOutputsModified);
+    }
+}
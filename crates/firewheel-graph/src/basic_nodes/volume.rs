@@ -2,6 +2,7 @@ use atomic_float::AtomicF32;
 use firewheel_core::{
     node::{AudioNode, AudioNodeInfo, AudioNodeProcessor, ProcInfo, ProcessStatus},
     param::{range::percent_volume_to_raw_gain, smoother::ParamSmoother},
+    util::{db_to_gain_clamped_neg_100_db, gain_to_db_clamped_neg_100_db},
     ChannelConfig, ChannelCount, StreamInfo,
 };
 use std::sync::{atomic::Ordering, Arc};
@@ -22,6 +23,18 @@ impl VolumeNode {
         }
     }
 
+    /// Create a new volume node with the given gain in decibels.
+    ///
+    /// A value of `-100.0` dB or less means mute.
+    pub fn from_gain_db(gain_db: f32) -> Self {
+        let raw_gain = db_to_gain_clamped_neg_100_db(gain_db);
+
+        Self {
+            raw_gain: Arc::new(AtomicF32::new(raw_gain)),
+            percent_volume: raw_gain_to_percent_volume(raw_gain),
+        }
+    }
+
     pub fn percent_volume(&self) -> f32 {
         self.percent_volume
     }
@@ -34,11 +47,32 @@ impl VolumeNode {
         self.percent_volume = percent_volume.max(0.0);
     }
 
+    /// The gain in decibels, where `-100.0` means mute.
+    pub fn gain_db(&self) -> f32 {
+        gain_to_db_clamped_neg_100_db(self.raw_gain())
+    }
+
+    /// Set the gain in decibels.
+    ///
+    /// A value of `-100.0` dB or less means mute. The change is smoothed
+    /// in the processor to avoid clicks.
+    pub fn set_gain_db(&mut self, gain_db: f32) {
+        let raw_gain = db_to_gain_clamped_neg_100_db(gain_db);
+
+        self.raw_gain.store(raw_gain, Ordering::Relaxed);
+        self.percent_volume = raw_gain_to_percent_volume(raw_gain);
+    }
+
     pub fn raw_gain(&self) -> f32 {
         self.raw_gain.load(Ordering::Relaxed)
     }
 }
 
+/// The inverse of [`percent_volume_to_raw_gain`].
+fn raw_gain_to_percent_volume(raw_gain: f32) -> f32 {
+    raw_gain.max(0.0).sqrt() * 100.0
+}
+
 impl<C> AudioNode<C> for VolumeNode {
     fn debug_name(&self) -> &'static str {
         "volume"
@@ -56,6 +90,8 @@ impl<C> AudioNode<C> for VolumeNode {
             },
             equal_num_ins_and_outs: true,
             updates: false,
+            silent_when_inputs_silent: true,
+            preserve_denormals: false,
         }
     }
 
@@ -152,3 +188,58 @@ impl<C> Into<Box<dyn AudioNode<C>>> for VolumeNode {
         Box::new(self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use firewheel_core::SilenceMask;
+
+    use super::*;
+    use crate::basic_nodes::process_block;
+
+    #[test]
+    fn gain_db_is_smoothed() {
+        let stream_info = StreamInfo::default();
+        let samples = stream_info.max_block_samples as usize;
+
+        let mut node = VolumeNode::from_gain_db(f32::NEG_INFINITY);
+        let mut processor = AudioNode::<()>::activate(
+            &mut node,
+            &stream_info,
+            ChannelConfig {
+                num_inputs: ChannelCount::MONO,
+                num_outputs: ChannelCount::MONO,
+            },
+        )
+        .unwrap();
+
+        let input = vec![1.0; samples];
+        let mut output = vec![0.0; samples];
+
+        let process = |processor: &mut Box<dyn AudioNodeProcessor<()>>, output: &mut [f32]| {
+            process_block(
+                processor.as_mut(),
+                &[&input],
+                &mut [output],
+                SilenceMask::NONE_SILENT,
+            )
+        };
+
+        // Muted, so the output is left untouched.
+        assert_eq!(
+            process(&mut processor, &mut output),
+            ProcessStatus::NoOutputsModified
+        );
+
+        node.set_gain_db(0.0);
+        assert_eq!(node.gain_db(), 0.0);
+        assert_eq!(node.percent_volume(), 100.0);
+
+        process(&mut processor, &mut output);
+
+        // The gain should rise smoothly instead of jumping straight to unity.
+        assert!(output[0] < 0.01);
+        assert!(output.windows(2).all(|w| w[1] >= w[0]));
+        assert!(output.windows(2).all(|w| w[1] - w[0] < 0.01));
+        assert!(output[samples - 1] > 0.5 && output[samples - 1] <= 1.0);
+    }
+}
@@ -0,0 +1,386 @@
+use firewheel_core::{
+    node::{AudioNode, AudioNodeInfo, AudioNodeProcessor, ProcInfo, ProcessStatus},
+    param::automation::{AutomationLane, AutomationReceiver},
+    ChannelConfig, StreamInfo,
+};
+
+/// A node which wraps an effect node and mixes its output (the wet
+/// signal) with its input (the dry signal).
+///
+/// The mix is an [`AutomationLane`], so it can be automated with sample
+/// accuracy (i.e. to fade in a reverb), where `0.0` is fully dry and `1.0`
+/// is fully wet. The mix applies to the output of this node, so a ramp
+/// reaches its value at the scheduled time no matter the latency of the
+/// effect.
+///
+/// If the effect has latency, then pass it as `latency_samples` so that
+/// the dry signal is delayed by the same amount and stays aligned with the
+/// wet signal.
+///
+/// The effect must have the same number of inputs and outputs.
+pub struct WetDryNode<C> {
+    effect: Box<dyn AudioNode<C>>,
+    mix: AutomationLane,
+    initial_mix: f32,
+    latency_samples: u32,
+}
+
+impl<C: 'static> WetDryNode<C> {
+    /// Create a new wet/dry node.
+    ///
+    /// * `effect` - The node to wrap.
+    /// * `mix` - The initial mix in the range `[0.0, 1.0]`.
+    /// * `latency_samples` - The latency of the effect in samples.
+    pub fn new(effect: impl Into<Box<dyn AudioNode<C>>>, mix: f32, latency_samples: u32) -> Self {
+        Self {
+            effect: effect.into(),
+            mix: AutomationLane::new(),
+            initial_mix: mix.clamp(0.0, 1.0),
+            latency_samples,
+        }
+    }
+
+    pub fn effect(&self) -> &dyn AudioNode<C> {
+        self.effect.as_ref()
+    }
+
+    pub fn effect_mut(&mut self) -> &mut dyn AudioNode<C> {
+        self.effect.as_mut()
+    }
+
+    /// The automation lane of the wet/dry mix. Use this to schedule
+    /// changes to the mix.
+    pub fn mix_mut(&mut self) -> &mut AutomationLane {
+        &mut self.mix
+    }
+
+    pub fn latency_samples(&self) -> u32 {
+        self.latency_samples
+    }
+}
+
+impl<C: 'static> AudioNode<C> for WetDryNode<C> {
+    fn debug_name(&self) -> &'static str {
+        "wet_dry"
+    }
+
+    fn info(&self) -> AudioNodeInfo {
+        let info = self.effect.info();
+
+        AudioNodeInfo {
+            num_min_supported_inputs: info
+                .num_min_supported_inputs
+                .max(info.num_min_supported_outputs),
+            num_max_supported_inputs: info
+                .num_max_supported_inputs
+                .min(info.num_max_supported_outputs),
+            num_min_supported_outputs: info
+                .num_min_supported_inputs
+                .max(info.num_min_supported_outputs),
+            num_max_supported_outputs: info
+                .num_max_supported_inputs
+                .min(info.num_max_supported_outputs),
+            default_channel_config: ChannelConfig {
+                num_inputs: info.default_channel_config.num_outputs,
+                num_outputs: info.default_channel_config.num_outputs,
+            },
+            equal_num_ins_and_outs: true,
+            updates: info.updates,
+            // The dry signal is still coming out of the delay line for a
+            // while after the inputs become silent.
+            silent_when_inputs_silent: info.silent_when_inputs_silent && self.latency_samples == 0,
+            preserve_denormals: info.preserve_denormals,
+        }
+    }
+
+    fn channel_config_supported(
+        &self,
+        channel_config: ChannelConfig,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.effect.channel_config_supported(channel_config)
+    }
+
+    fn activate(
+        &mut self,
+        stream_info: &StreamInfo,
+        channel_config: ChannelConfig,
+    ) -> Result<Box<dyn AudioNodeProcessor<C>>, Box<dyn std::error::Error>> {
+        let effect = self.effect.activate(stream_info, channel_config)?;
+
+        Ok(Box::new(WetDryProcessor {
+            effect,
+            mix: self.mix.receiver(self.initial_mix),
+            mix_values: vec![0.0; stream_info.max_block_samples as usize],
+            dry_delay: (0..channel_config.num_outputs.get())
+                .map(|_| vec![0.0; self.latency_samples as usize])
+                .collect(),
+            delay_pos: 0,
+        }))
+    }
+
+    fn deactivate(&mut self, processor: Option<Box<dyn AudioNodeProcessor<C>>>) {
+        let processor = processor
+            .and_then(|p| p.downcast::<WetDryProcessor<C>>().ok())
+            .map(|p| p.effect);
+
+        self.effect.deactivate(processor);
+    }
+
+    fn update(&mut self) {
+        self.effect.update();
+    }
+}
+
+struct WetDryProcessor<C> {
+    effect: Box<dyn AudioNodeProcessor<C>>,
+    mix: AutomationReceiver,
+    mix_values: Vec<f32>,
+    /// A delay line for each channel of the dry signal, which is as long
+    /// as the latency of the effect.
+    dry_delay: Vec<Vec<f32>>,
+    delay_pos: usize,
+}
+
+impl<C: 'static> AudioNodeProcessor<C> for WetDryProcessor<C> {
+    fn process(
+        &mut self,
+        inputs: &[&[f32]],
+        outputs: &mut [&mut [f32]],
+        proc_info: ProcInfo,
+        cx: &mut C,
+    ) -> ProcessStatus {
+        let samples = proc_info.samples;
+
+        self.mix.process(&proc_info, &mut self.mix_values);
+
+        let wet_silence_mask = match self.effect.process(inputs, outputs, proc_info.clone(), cx) {
+            ProcessStatus::NoOutputsModified => None,
+            ProcessStatus::OutputsModified { out_silence_mask } => Some(out_silence_mask),
+        };
+
+        for (ch_i, (input, output)) in inputs.iter().zip(outputs.iter_mut()).enumerate() {
+            let output = &mut output[..samples];
+
+            if wet_silence_mask.is_none_or(|mask| mask.is_channel_silent(ch_i)) {
+                output.fill(0.0);
+            }
+
+            let input_silent = proc_info.in_silence_mask.is_channel_silent(ch_i);
+            let delay_line = &mut self.dry_delay[ch_i];
+            let mut delay_pos = self.delay_pos;
+
+            for (i, (wet, &mix)) in output.iter_mut().zip(self.mix_values.iter()).enumerate() {
+                let mut dry = if input_silent { 0.0 } else { input[i] };
+
+                if !delay_line.is_empty() {
+                    dry = std::mem::replace(&mut delay_line[delay_pos], dry);
+                    delay_pos = if delay_pos + 1 == delay_line.len() {
+                        0
+                    } else {
+                        delay_pos + 1
+                    };
+                }
+
+                *wet = dry + (*wet - dry) * mix;
+            }
+        }
+
+        if let Some(delay_line) = self.dry_delay.first() {
+            if !delay_line.is_empty() {
+                self.delay_pos = (self.delay_pos + samples) % delay_line.len();
+            }
+        }
+
+        ProcessStatus::all_outputs_filled()
+    }
+
+    fn migrate_state_from(&mut self, old: &dyn AudioNodeProcessor<C>) {
+        if let Some(old) = old.downcast_ref::<WetDryProcessor<C>>() {
+            self.effect.migrate_state_from(old.effect.as_ref());
+        }
+    }
+
+    fn serialize_state(&self, out: &mut Vec<u8>) {
+        self.effect.serialize_state(out);
+    }
+
+    fn restore_state(&mut self, state: &[u8]) {
+        self.effect.restore_state(state);
+    }
+}
+
+impl<C: 'static> Into<Box<dyn AudioNode<C>>> for WetDryNode<C> {
+    fn into(self) -> Box<dyn AudioNode<C>> {
+        Box::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use firewheel_core::{clock::ClockSamples, ChannelCount, SilenceMask};
+
+    use super::*;
+    use crate::basic_nodes::process_block_at;
+
+    /// An effect which inverts its input and delays it by a number of
+    /// samples.
+    struct LatentInvertNode(usize);
+
+    impl AudioNode<()> for LatentInvertNode {
+        fn debug_name(&self) -> &'static str {
+            "latent_invert"
+        }
+
+        fn info(&self) -> AudioNodeInfo {
+            AudioNodeInfo {
+                num_min_supported_inputs: ChannelCount::MONO,
+                num_max_supported_inputs: ChannelCount::MONO,
+                num_min_supported_outputs: ChannelCount::MONO,
+                num_max_supported_outputs: ChannelCount::MONO,
+                default_channel_config: ChannelConfig {
+                    num_inputs: ChannelCount::MONO,
+                    num_outputs: ChannelCount::MONO,
+                },
+                ..Default::default()
+            }
+        }
+
+        fn activate(
+            &mut self,
+            _stream_info: &StreamInfo,
+            _channel_config: ChannelConfig,
+        ) -> Result<Box<dyn AudioNodeProcessor<()>>, Box<dyn std::error::Error>> {
+            Ok(Box::new(LatentInvertProcessor {
+                history: std::collections::VecDeque::from(vec![0.0; self.0]),
+            }))
+        }
+    }
+
+    struct LatentInvertProcessor {
+        history: std::collections::VecDeque<f32>,
+    }
+
+    impl AudioNodeProcessor<()> for LatentInvertProcessor {
+        fn process(
+            &mut self,
+            inputs: &[&[f32]],
+            outputs: &mut [&mut [f32]],
+            proc_info: ProcInfo,
+            _cx: &mut (),
+        ) -> ProcessStatus {
+            for (&in_s, out_s) in inputs[0][..proc_info.samples]
+                .iter()
+                .zip(outputs[0].iter_mut())
+            {
+                self.history.push_back(-in_s);
+                *out_s = self.history.pop_front().unwrap();
+            }
+
+            ProcessStatus::all_outputs_filled()
+        }
+    }
+
+    #[test]
+    fn automated_mix_ramp() {
+        const LATENCY: usize = 64;
+
+        let stream_info = StreamInfo::default();
+        let samples = stream_info.max_block_samples as usize;
+
+        let effect: Box<dyn AudioNode<()>> = Box::new(LatentInvertNode(LATENCY));
+        let mut node = WetDryNode::new(effect, 0.0, LATENCY as u32);
+        let mut processor = AudioNode::<()>::activate(
+            &mut node,
+            &stream_info,
+            ChannelConfig {
+                num_inputs: ChannelCount::MONO,
+                num_outputs: ChannelCount::MONO,
+            },
+        )
+        .unwrap();
+
+        // Fade from fully dry to fully wet between samples 1000 and 3000.
+        assert!(node.mix_mut().set_value_at(0.0, ClockSamples(1000)));
+        assert!(node.mix_mut().linear_ramp_to(1.0, ClockSamples(3000)));
+
+        let input = vec![1.0; samples];
+        let mut block_output = vec![0.0; samples];
+        let mut output = Vec::new();
+
+        for block in 0..4 {
+            process_block_at(
+                processor.as_mut(),
+                &[&input],
+                &mut [&mut block_output],
+                SilenceMask::NONE_SILENT,
+                ClockSamples((block * samples) as u64),
+            );
+            output.extend_from_slice(&block_output);
+        }
+
+        // The dry signal is delayed along with the wet signal, so the
+        // output starts after the latency of the effect.
+        assert!(output[..LATENCY].iter().all(|&s| s == 0.0));
+        assert!(output[LATENCY..1000].iter().all(|&s| s == 1.0));
+
+        // The dry signal is 1.0 and the wet signal is -1.0, so halfway
+        // through the ramp they cancel out.
+        assert!(output[2000].abs() < 1e-6);
+        assert!(output[2999] > -1.0);
+        assert!(output[3000..].iter().all(|&s| s == -1.0));
+
+        for pair in output[1000..3000].windows(2) {
+            assert!(pair[1] < pair[0]);
+        }
+    }
+
+    #[test]
+    fn cancel_mix_automation() {
+        let stream_info = StreamInfo::default();
+        let samples = stream_info.max_block_samples as usize;
+
+        let effect: Box<dyn AudioNode<()>> = Box::new(LatentInvertNode(0));
+        let mut node = WetDryNode::new(effect, 0.0, 0);
+        let mut processor = AudioNode::<()>::activate(
+            &mut node,
+            &stream_info,
+            ChannelConfig {
+                num_inputs: ChannelCount::MONO,
+                num_outputs: ChannelCount::MONO,
+            },
+        )
+        .unwrap();
+
+        let input = vec![1.0; samples];
+        let mut output = vec![0.0; samples];
+        let mut process = |processor: &mut Box<dyn AudioNodeProcessor<()>>, block: usize| {
+            process_block_at(
+                processor.as_mut(),
+                &[&input],
+                &mut [&mut output],
+                SilenceMask::NONE_SILENT,
+                ClockSamples((block * samples) as u64),
+            );
+            output.clone()
+        };
+
+        // Events which were cancelled before the processor received them
+        // are discarded, but events scheduled after the cancel are kept.
+        assert!(node.mix_mut().set_value_at(1.0, ClockSamples(0)));
+        node.mix_mut().cancel();
+        assert!(node
+            .mix_mut()
+            .linear_ramp_to(1.0, ClockSamples(4 * samples as u64)));
+        let first = process(&mut processor, 0);
+        assert_eq!(first[0], 1.0);
+        assert!(first[samples - 1] < 1.0);
+
+        // Cancelling a ramp which the processor already received keeps the
+        // current mix.
+        node.mix_mut().cancel();
+        let second = process(&mut processor, 1);
+        assert!(second
+            .iter()
+            .all(|&s| (s - first[samples - 1]).abs() < 1e-6));
+    }
+}
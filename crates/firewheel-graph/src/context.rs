@@ -1,22 +1,38 @@
 use std::{
+    collections::VecDeque,
     error::Error,
-    sync::{atomic::AtomicU64, Arc},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
-use firewheel_core::{ChannelCount, StreamInfo};
-use rtrb::PushError;
+use ahash::AHashMap;
+use arrayvec::ArrayVec;
+use firewheel_core::{util::FadeCurve, ChannelCount, StreamInfo};
 
 use crate::{
-    error::{ActivateCtxError, CompileGraphError},
-    graph::AudioGraph,
-    processor::{ContextToProcessorMsg, FirewheelProcessor, ProcessorToContextMsg},
+    error::{ActivateCtxError, ChangeSampleRateError, CompileGraphError, OutputChannelMapError},
+    graph::{AudioGraph, NodeID},
+    processor::{
+        ContextToProcessorMsg, FirewheelProcessor, ProcessorSettings, ProcessorToContextMsg,
+        SharedProcessorState,
+    },
+    spsc::{self, PushError},
 };
 
+#[cfg(feature = "metrics")]
+use crate::trace::{TraceEvent, TraceRecorder, TRACE_CHANNEL_CAPACITY};
+
 const CHANNEL_CAPACITY: usize = 32;
 const CLOSE_STREAM_TIMEOUT: Duration = Duration::from_secs(3);
 const CLOSE_STREAM_SLEEP_INTERVAL: Duration = Duration::from_millis(2);
 
+/// The number of recent glitches kept by [`FirewheelGraphCtx::glitches`].
+/// Once the log is full, the oldest glitch is overwritten.
+pub const GLITCH_LOG_CAPACITY: usize = 32;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct FirewheelConfig {
     /// The number of input channels in the audio graph.
@@ -25,6 +41,221 @@ pub struct FirewheelConfig {
     pub num_graph_outputs: ChannelCount,
     pub initial_node_capacity: usize,
     pub initial_edge_capacity: usize,
+    /// If `Some`, then a final safety stage is run over the output buffer
+    /// of the processor which replaces any non-finite samples (NaN or
+    /// infinity) with `0.0` and clamps all samples to the range
+    /// `[-ceiling, ceiling]`.
+    ///
+    /// This is meant to protect speakers (and ears) from misbehaving nodes.
+    /// It is cheap, so it is recommended to leave it enabled.
+    ///
+    /// By default this is set to `Some(1.0)`.
+    pub output_safety_limit: Option<f32>,
+    /// If this is greater than `0.0`, then whenever the processor receives
+    /// a new schedule (i.e. after nodes or edges were added or removed),
+    /// the outputs of the old and the new schedule are crossfaded over this
+    /// many seconds using [`FirewheelConfig::fade_curve`]. This avoids
+    /// audible clicks when changing the graph while it is running.
+    ///
+    /// Nodes which exist in both schedules are only processed once per
+    /// block, and their outputs are used by both schedules. The nodes which
+    /// were removed or replaced keep processing the old schedule until the
+    /// crossfade is done, and only then are they returned to the context.
+    /// Messages sent during a crossfade (such as bypassing a node) are
+    /// applied right away to the nodes of the new schedule, but a new
+    /// schedule sent during a crossfade waits for it to finish, and so do
+    /// any messages sent after it.
+    ///
+    /// By default this is set to `0.0` (disabled).
+    pub schedule_fade_secs: f32,
+    /// If this is greater than `0.0`, then bypassing or un-bypassing a node
+    /// crossfades between the output of the node and its inputs (see
+    /// [`AudioGraph::set_node_bypassed`]) over this many seconds, using
+    /// [`FirewheelConfig::fade_curve`]. Otherwise the node is switched at
+    /// the exact sample.
+    ///
+    /// The processor of the node keeps running until it is fully bypassed.
+    ///
+    /// By default this is set to `0.0` (disabled).
+    pub bypass_fade_secs: f32,
+    /// The shape of the curve used by the click-free fades of the
+    /// processor, such as the crossfade when swapping schedules, the fade
+    /// when bypassing a node, and the fade when muting an input channel.
+    ///
+    /// By default this is set to [`FadeCurve::Linear`].
+    pub fade_curve: FadeCurve,
+    /// If `Some`, then the output of the graph is monitored for rapidly
+    /// increasing levels (which usually means a feedback loop inside of a
+    /// node has become unstable), and the output is attenuated as soon as
+    /// this is detected. See [`RunawayProtection`] for more details.
+    ///
+    /// By default this is set to `None`.
+    pub runaway_protection: Option<RunawayProtection>,
+    /// If `Some`, then low-priority nodes are skipped while the processor
+    /// is under CPU pressure. See [`VoiceCulling`] for more details.
+    ///
+    /// By default this is set to `None`.
+    pub voice_culling: Option<VoiceCulling>,
+    /// When changes to the graph are compiled and sent to the processor.
+    /// This can be changed later with
+    /// [`FirewheelGraphCtx::set_recompile_strategy`].
+    ///
+    /// By default this is set to [`RecompileStrategy::Immediate`].
+    pub recompile_strategy: RecompileStrategy,
+    /// If `Some`, then the audio graph is always processed in blocks of
+    /// exactly this many frames, no matter how many frames the audio
+    /// backend asks for in each callback. This is useful for nodes which
+    /// need a consistent modulation granularity.
+    ///
+    /// This adds this many frames of latency to the stream. The
+    /// [`StreamInfo::max_block_samples`] given to nodes is set to this
+    /// value.
+    ///
+    /// By default this is set to `None`, in which case each buffer from the
+    /// audio backend is split into blocks of up to
+    /// [`StreamInfo::max_block_samples`] frames.
+    pub fixed_block_samples: Option<u32>,
+}
+
+/// When changes to the audio graph are compiled and sent to the processor.
+///
+/// While changes are waiting to be compiled, bypass and priority changes
+/// are held back as well so that they are applied together with the new
+/// schedule.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum RecompileStrategy {
+    /// The graph is compiled on every call to [`FirewheelGraphCtx::update`]
+    /// in which it was changed. This is best for realtime editing.
+    #[default]
+    Immediate,
+    /// The graph is only compiled once it has not been changed for the
+    /// given number of seconds. This avoids compiling many times while a
+    /// lot of changes are being made in quick succession (i.e. while a
+    /// user is dragging a connection around).
+    Debounced { delay_secs: f32 },
+    /// The graph is only compiled when [`FirewheelGraphCtx::flush`] is
+    /// called. This is best for batch and offline processing, or for
+    /// applying a group of changes as a single transaction.
+    ManualFlush,
+}
+
+/// The size of the blocks the audio graph is processed in, returned by
+/// [`FirewheelGraphCtx::block_size`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockSize {
+    /// Every block has exactly this many frames (see
+    /// [`FirewheelConfig::fixed_block_samples`]).
+    Fixed(u32),
+    /// The number of frames in each block depends on the buffer size of the
+    /// audio backend.
+    Variable {
+        /// The maximum number of frames in a block.
+        max_block_samples: u32,
+        /// The number of frames in the most recently processed block, or
+        /// `0` if no block has been processed yet.
+        last_block_samples: u32,
+    },
+}
+
+/// The settings of the monitor which protects against runaway feedback.
+///
+/// The peak level of the output is measured in every processed block. If
+/// the peak level is above [`RunawayProtection::min_level_db`] and has
+/// grown by at least [`RunawayProtection::growth_db_per_block`] for
+/// [`RunawayProtection::num_blocks`] blocks in a row, then the output is
+/// attenuated by [`RunawayProtection::attenuation_db`] until
+/// [`FirewheelGraphCtx::reset_runaway_protection`] is called.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RunawayProtection {
+    /// Blocks with a peak level below this (in decibels) are never
+    /// considered to be running away.
+    ///
+    /// By default this is set to `-24.0`.
+    pub min_level_db: f32,
+    /// The increase in peak level (in decibels) from one block to the next
+    /// which counts as growing.
+    ///
+    /// By default this is set to `1.0`.
+    pub growth_db_per_block: f32,
+    /// The number of blocks in a row the peak level must grow for before
+    /// the output is attenuated.
+    ///
+    /// By default this is set to `4`.
+    pub num_blocks: u32,
+    /// The gain (in decibels) which is applied to the output once a runaway
+    /// has been detected. A value of `-100.0` or less mutes the output.
+    ///
+    /// By default this is set to `-100.0`.
+    pub attenuation_db: f32,
+}
+
+impl Default for RunawayProtection {
+    fn default() -> Self {
+        Self {
+            min_level_db: -24.0,
+            growth_db_per_block: 1.0,
+            num_blocks: 4,
+            attenuation_db: -100.0,
+        }
+    }
+}
+
+/// The settings for culling low-priority nodes under CPU pressure.
+///
+/// This is useful in games with many sound sources, where distant or
+/// unimportant voices can be dropped to keep the audio from glitching.
+///
+/// The processor smooths the DSP load over roughly the last 100
+/// milliseconds. Once it reaches [`VoiceCulling::max_dsp_load`], the
+/// processor is under CPU pressure until it falls below
+/// [`VoiceCulling::resume_dsp_load`]. While under CPU pressure, every node
+/// with a priority (set with [`AudioGraph::set_node_priority`]) below
+/// [`VoiceCulling::min_priority`] is culled: its processor is not run and
+/// its outputs are silent. Culled nodes are reported with
+/// [`FirewheelGraphCtx::culled_nodes`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VoiceCulling {
+    /// Nodes with a priority below this are culled while under CPU
+    /// pressure. Nodes without a priority are never culled.
+    ///
+    /// By default this is set to `0.5`.
+    pub min_priority: f32,
+    /// The DSP load (where `1.0` means all of the available time was used)
+    /// at which the processor is considered to be under CPU pressure.
+    ///
+    /// By default this is set to `0.8`.
+    pub max_dsp_load: f32,
+    /// The DSP load below which the processor is no longer considered to
+    /// be under CPU pressure. The load drops as soon as nodes are culled,
+    /// so this should be far enough below [`VoiceCulling::max_dsp_load`]
+    /// that culled nodes don't keep switching on and off. This is clamped
+    /// to be at most [`VoiceCulling::max_dsp_load`].
+    ///
+    /// By default this is set to `0.6`.
+    pub resume_dsp_load: f32,
+}
+
+impl VoiceCulling {
+    /// Returns whether or not the processor is under CPU pressure, given
+    /// the smoothed DSP load and whether or not it was under CPU pressure
+    /// before.
+    pub(crate) fn is_under_pressure(&self, dsp_load: f32, was_under_pressure: bool) -> bool {
+        if was_under_pressure {
+            dsp_load >= self.resume_dsp_load.min(self.max_dsp_load)
+        } else {
+            dsp_load >= self.max_dsp_load
+        }
+    }
+}
+
+impl Default for VoiceCulling {
+    fn default() -> Self {
+        Self {
+            min_priority: 0.5,
+            max_dsp_load: 0.8,
+            resume_dsp_load: 0.6,
+        }
+    }
 }
 
 impl Default for FirewheelConfig {
@@ -34,18 +265,62 @@ impl Default for FirewheelConfig {
             num_graph_outputs: ChannelCount::STEREO,
             initial_node_capacity: 64,
             initial_edge_capacity: 256,
+            output_safety_limit: Some(1.0),
+            schedule_fade_secs: 0.0,
+            bypass_fade_secs: 0.0,
+            fade_curve: FadeCurve::Linear,
+            runaway_protection: None,
+            voice_culling: None,
+            recompile_strategy: RecompileStrategy::Immediate,
+            fixed_block_samples: None,
         }
     }
 }
 
 struct ActiveState<C: Send + 'static> {
-    // TODO: Do research on whether `rtrb` is compatible with
-    // webassembly. If not, use conditional compilation to
-    // use a different channel type when targeting webassembly.
-    to_executor_tx: rtrb::Producer<ContextToProcessorMsg<C>>,
-    from_executor_rx: rtrb::Consumer<ProcessorToContextMsg<C>>,
+    to_executor_tx: spsc::Producer<(u64, ContextToProcessorMsg<C>)>,
+    from_executor_rx: spsc::Consumer<ProcessorToContextMsg<C>>,
 
     stream_info: StreamInfo,
+    shared_state: Arc<SharedProcessorState>,
+    /// The peak level in decibels at which a runaway was detected.
+    runaway_peak_db: Option<f32>,
+    /// The serialized DSP states of nodes which were sent back by the
+    /// processor and not yet taken.
+    node_states: AHashMap<NodeID, Vec<u8>>,
+    /// A node state buffer which was sent back by the processor, kept to
+    /// be reused for the next state request.
+    spare_state_buffer: Option<Vec<u8>>,
+    /// A new sample rate to send to the processor once the schedule with
+    /// the re-activated nodes has been sent.
+    pending_sample_rate: Option<u32>,
+    /// The number of schedules which have been sent to the processor but
+    /// not yet swapped in.
+    pending_schedule_swaps: usize,
+    #[cfg(feature = "metrics")]
+    trace_rx: spsc::Consumer<TraceEvent>,
+    /// The sequence number of the last message that was sent to the
+    /// processor.
+    sent_msg_seq: u64,
+}
+
+impl<C: Send + 'static> ActiveState<C> {
+    /// Send a message to the processor, tagging it with the next sequence
+    /// number.
+    fn send(
+        &mut self,
+        msg: ContextToProcessorMsg<C>,
+    ) -> Result<(), PushError<ContextToProcessorMsg<C>>> {
+        let seq = self.sent_msg_seq + 1;
+
+        match self.to_executor_tx.push((seq, msg)) {
+            Ok(()) => {
+                self.sent_msg_seq = seq;
+                Ok(())
+            }
+            Err(PushError::Full((_, msg))) => Err(PushError::Full(msg)),
+        }
+    }
 }
 
 /// A firewheel context with no audio backend.
@@ -54,6 +329,27 @@ struct ActiveState<C: Send + 'static> {
 /// node processors.
 pub struct FirewheelGraphCtx<C: Send + 'static> {
     graph: AudioGraph<C>,
+    config: FirewheelConfig,
+    input_gain_db: f32,
+    /// A bitmask of the input channels which are muted.
+    muted_input_channels: u64,
+    /// The physical output channel of each logical output channel.
+    output_channel_map: Option<ArrayVec<u8, 64>>,
+    /// The fade to use when swapping in the next schedule, set by
+    /// [`FirewheelGraphCtx::replace_graph`].
+    next_schedule_fade_secs: Option<f32>,
+    recompile_strategy: RecompileStrategy,
+    /// The value of [`AudioGraph::num_edits`] the last time it was checked,
+    /// and when it was last seen to change.
+    last_graph_edit: (u64, Instant),
+    /// The most recent glitches, oldest first.
+    glitch_log: VecDeque<GlitchEvent>,
+    /// The number of glitches since the log was last cleared.
+    num_glitches: u64,
+    #[cfg(feature = "metrics")]
+    tracing_enabled: bool,
+    #[cfg(feature = "metrics")]
+    trace_events: Vec<TraceEvent>,
 
     active_state: Option<ActiveState<C>>,
 }
@@ -62,6 +358,19 @@ impl<C: Send + 'static> FirewheelGraphCtx<C> {
     pub fn new(config: FirewheelConfig) -> Self {
         Self {
             graph: AudioGraph::new(&config),
+            config,
+            input_gain_db: 0.0,
+            muted_input_channels: 0,
+            output_channel_map: None,
+            next_schedule_fade_secs: None,
+            recompile_strategy: config.recompile_strategy,
+            last_graph_edit: (0, Instant::now()),
+            glitch_log: VecDeque::with_capacity(GLITCH_LOG_CAPACITY),
+            num_glitches: 0,
+            #[cfg(feature = "metrics")]
+            tracing_enabled: false,
+            #[cfg(feature = "metrics")]
+            trace_events: Vec::new(),
             active_state: None,
         }
     }
@@ -84,7 +393,21 @@ impl<C: Send + 'static> FirewheelGraphCtx<C> {
             return Err((ActivateCtxError::AlreadyActivated, user_cx));
         }
 
+        // With a fixed block size, the graph never sees any other block size
+        // than the fixed one.
+        let stream_info = match self.config.fixed_block_samples.filter(|&n| n > 0) {
+            Some(block_samples) => StreamInfo {
+                max_block_samples: block_samples,
+                stream_latency_samples: stream_info
+                    .stream_latency_samples
+                    .map(|latency| latency + block_samples),
+                ..stream_info
+            },
+            None => stream_info,
+        };
+
         let clock_samples_shared = Arc::new(AtomicU64::new(0));
+        let shared_state = Arc::new(SharedProcessorState::new());
         let main_thread_clock_start_instant = Instant::now();
 
         if let Err(e) = self.graph.activate(
@@ -96,23 +419,52 @@ impl<C: Send + 'static> FirewheelGraphCtx<C> {
         }
 
         let (to_executor_tx, from_graph_rx) =
-            rtrb::RingBuffer::<ContextToProcessorMsg<C>>::new(CHANNEL_CAPACITY);
+            spsc::channel::<(u64, ContextToProcessorMsg<C>)>(CHANNEL_CAPACITY);
         let (to_graph_tx, from_executor_rx) =
-            rtrb::RingBuffer::<ProcessorToContextMsg<C>>::new(CHANNEL_CAPACITY);
+            spsc::channel::<ProcessorToContextMsg<C>>(CHANNEL_CAPACITY);
+        #[cfg(feature = "metrics")]
+        let (trace_tx, trace_rx) = spsc::channel::<TraceEvent>(TRACE_CHANNEL_CAPACITY);
 
         self.active_state = Some(ActiveState {
             to_executor_tx,
             from_executor_rx,
             stream_info,
+            shared_state: Arc::clone(&shared_state),
+            runaway_peak_db: None,
+            node_states: AHashMap::new(),
+            spare_state_buffer: None,
+            pending_sample_rate: None,
+            pending_schedule_swaps: 0,
+            #[cfg(feature = "metrics")]
+            trace_rx,
+            sent_msg_seq: 0,
         });
 
         Ok(FirewheelProcessor::new(
             from_graph_rx,
             to_graph_tx,
             clock_samples_shared,
-            main_thread_clock_start_instant,
-            self.graph.current_node_capacity(),
-            stream_info,
+            shared_state,
+            ProcessorSettings {
+                config: &self.config,
+                stream_info,
+                node_capacity: self.graph.current_node_capacity(),
+                main_thread_clock_start_instant,
+                input_raw_gain: firewheel_core::util::db_to_gain_clamped_neg_100_db(
+                    self.input_gain_db,
+                ),
+                muted_input_channels: self.muted_input_channels,
+                output_channel_map: self
+                    .output_channel_map
+                    .clone()
+                    .filter(|m| m.len() == stream_info.num_stream_out_channels as usize),
+            },
+            #[cfg(feature = "metrics")]
+            TraceRecorder::new(
+                trace_tx,
+                main_thread_clock_start_instant,
+                self.tracing_enabled,
+            ),
             user_cx,
         ))
     }
@@ -133,11 +485,143 @@ impl<C: Send + 'static> FirewheelGraphCtx<C> {
         }
     }
 
+    /// Replace the entire audio graph in one go, for example when loading a
+    /// completely different project.
+    ///
+    /// All existing nodes (except the graph input and output nodes) are
+    /// removed, and then `build` is called to add the new nodes and edges.
+    /// The next time the graph is compiled (see [`RecompileStrategy`]), the
+    /// new graph is sent to the processor as a single schedule, so the
+    /// processor never sees a partially built graph. The old nodes are
+    /// deactivated and deallocated on the main thread once the processor
+    /// has returned them.
+    ///
+    /// * `fade_secs` - The length of the crossfade from the output of the
+    ///   old graph to the output of the new graph. Both graphs are processed
+    ///   side by side until it is done, and only then are the old nodes
+    ///   returned. Set this to `None` to use
+    ///   [`FirewheelConfig::schedule_fade_secs`].
+    ///
+    /// Returns `None` if the context is not currently activated.
+    pub fn replace_graph<R>(
+        &mut self,
+        fade_secs: Option<f32>,
+        build: impl FnOnce(&mut AudioGraph<C>) -> R,
+    ) -> Option<R> {
+        if !self.is_activated() {
+            return None;
+        }
+
+        self.graph.reset();
+        self.next_schedule_fade_secs = fade_secs;
+
+        Some((build)(&mut self.graph))
+    }
+
+    /// When changes to the graph are compiled and sent to the processor.
+    pub fn recompile_strategy(&self) -> RecompileStrategy {
+        self.recompile_strategy
+    }
+
+    /// Set when changes to the graph are compiled and sent to the
+    /// processor.
+    ///
+    /// Pending changes are not flushed when switching strategies. Call
+    /// [`FirewheelGraphCtx::flush`] to send them right away.
+    pub fn set_recompile_strategy(&mut self, strategy: RecompileStrategy) {
+        self.recompile_strategy = strategy;
+    }
+
+    /// Compile any pending changes to the graph and send them to the
+    /// processor right away, regardless of the [`RecompileStrategy`].
+    ///
+    /// This does nothing if the context is not activated or if there are
+    /// no pending changes.
+    pub fn flush(&mut self) -> Result<(), CompileGraphError> {
+        if !self.graph.needs_compile() {
+            return Ok(());
+        }
+
+        self.compile_and_send()?;
+        self.send_pending_events();
+
+        Ok(())
+    }
+
     /// Returns whether or not this context is currently activated.
     pub fn is_activated(&self) -> bool {
         self.active_state.is_some()
     }
 
+    /// Returns whether or not the processor is still running.
+    ///
+    /// This becomes `false` once the processor has stopped (either because
+    /// it received a stop signal or because it was dropped). When that
+    /// happens, the context must be deactivated and activated again in order
+    /// to get a new processor.
+    ///
+    /// Returns `false` if the context is not activated.
+    pub fn is_running(&self) -> bool {
+        self.active_state
+            .as_ref()
+            .map(|s| s.shared_state.running.load(Ordering::Relaxed))
+            .unwrap_or(false)
+    }
+
+    /// Returns whether or not every channel of the graph output was silent
+    /// in the last buffer that the processor processed.
+    ///
+    /// This can be used to save power, for example by pausing rendering
+    /// work which only matters while sound is playing. It is based on the
+    /// silence flags of the graph output, so a node which outputs zeros
+    /// without marking its outputs as silent counts as not silent.
+    ///
+    /// Returns `true` if the context is not activated.
+    pub fn output_is_silent(&self) -> bool {
+        self.active_state
+            .as_ref()
+            .map(|s| s.shared_state.output_silent.load(Ordering::Relaxed))
+            .unwrap_or(true)
+    }
+
+    /// The sequence number of the last message that was sent to the
+    /// processor.
+    ///
+    /// Every message sent to the processor (new schedules, parameter
+    /// changes, bypass events, etc.) is tagged with a sequence number,
+    /// starting at `1` and increasing by one for every message. The
+    /// processor always applies messages in the order they were sent.
+    ///
+    /// Returns `None` if the context is not activated.
+    pub fn sent_message_seq(&self) -> Option<u64> {
+        self.active_state.as_ref().map(|s| s.sent_msg_seq)
+    }
+
+    /// The sequence number of the last message that the processor has
+    /// applied, or `0` if no messages have been applied yet.
+    ///
+    /// Once this is equal to [`FirewheelGraphCtx::sent_message_seq`], all
+    /// messages that were sent have been applied.
+    ///
+    /// Returns `None` if the context is not activated.
+    pub fn applied_message_seq(&self) -> Option<u64> {
+        self.active_state
+            .as_ref()
+            .map(|s| s.shared_state.applied_msg_seq.load(Ordering::Acquire))
+    }
+
+    /// Returns `true` if a new schedule has been sent to the processor, but
+    /// the processor has not swapped it in yet.
+    ///
+    /// This only becomes `false` again after [`FirewheelGraphCtx::update`]
+    /// has received the confirmation from the processor. Returns `false` if
+    /// the context is not activated.
+    pub fn schedule_swap_pending(&self) -> bool {
+        self.active_state
+            .as_ref()
+            .is_some_and(|s| s.pending_schedule_swaps > 0)
+    }
+
     /// Get info about the running audio stream.
     ///
     /// Returns `None` if the context is not activated.
@@ -145,6 +629,415 @@ impl<C: Send + 'static> FirewheelGraphCtx<C> {
         self.active_state.as_ref().map(|s| &s.stream_info)
     }
 
+    /// The size of the blocks the audio graph is currently processed in.
+    ///
+    /// Returns `None` if the context is not activated.
+    pub fn block_size(&self) -> Option<BlockSize> {
+        let state = self.active_state.as_ref()?;
+
+        Some(if self.config.fixed_block_samples.is_some_and(|n| n > 0) {
+            BlockSize::Fixed(state.stream_info.max_block_samples)
+        } else {
+            BlockSize::Variable {
+                max_block_samples: state.stream_info.max_block_samples,
+                last_block_samples: state
+                    .shared_state
+                    .last_block_samples
+                    .load(Ordering::Relaxed),
+            }
+        })
+    }
+
+    /// Change the sample rate of the running audio stream without stopping
+    /// playback, i.e. when the output device switches to a different rate.
+    ///
+    /// Every active node is activated again with the new stream info so
+    /// that it can recompute any rate-dependent state. The new processors
+    /// (along with the new sample rate) are sent to the processor the next
+    /// time the graph is compiled (see [`RecompileStrategy`]), and they keep
+    /// the bypass and priority state of the processors they replace. The
+    /// old processors are dropped without [`AudioNode::deactivate`] being
+    /// called.
+    ///
+    /// If a node fails to re-activate, then it keeps running with its old
+    /// processor, the sample rate is still changed, and the first such
+    /// error is returned.
+    ///
+    /// [`AudioNode::deactivate`]: firewheel_core::node::AudioNode::deactivate
+    pub fn change_sample_rate(&mut self, sample_rate: u32) -> Result<(), ChangeSampleRateError> {
+        // TODO: Return an error instead of panicking.
+        assert_ne!(sample_rate, 0);
+
+        let Some(state) = &mut self.active_state else {
+            return Err(ChangeSampleRateError::NotActivated);
+        };
+
+        if state.stream_info.sample_rate == sample_rate {
+            return Ok(());
+        }
+
+        state.stream_info.sample_rate = sample_rate;
+        state.pending_sample_rate = Some(sample_rate);
+
+        self.graph
+            .reactivate(state.stream_info)
+            .map_err(ChangeSampleRateError::NodeFailedToActived)
+    }
+
+    /// The gain in decibels that is applied to the input of the audio
+    /// graph.
+    pub fn input_gain_db(&self) -> f32 {
+        self.input_gain_db
+    }
+
+    /// Set the gain in decibels that is applied to the input of the
+    /// audio graph. This can be used to calibrate hot or quiet input
+    /// devices before the signal reaches any nodes.
+    ///
+    /// Gain changes are smoothed to avoid clicks. Values less than or
+    /// equal to `-100.0` will mute the input.
+    ///
+    /// By default this is set to `0.0` (unity gain).
+    pub fn set_input_gain(&mut self, gain_db: f32) {
+        self.input_gain_db = gain_db;
+
+        if let Some(state) = &mut self.active_state {
+            let raw_gain = firewheel_core::util::db_to_gain_clamped_neg_100_db(gain_db);
+
+            if state
+                .send(ContextToProcessorMsg::SetInputGain(raw_gain))
+                .is_err()
+            {
+                log::error!("Failed to set input gain: Firewheel message channel is full");
+            }
+        }
+    }
+
+    /// Returns whether or not the given input channel is muted.
+    pub fn is_input_channel_muted(&self, channel: usize) -> bool {
+        channel < 64 && self.muted_input_channels & (1 << channel) != 0
+    }
+
+    /// Mute or unmute the given input channel before it reaches the audio
+    /// graph. This can be used to disable a noisy input channel at the
+    /// source.
+    ///
+    /// Muting and unmuting is faded to avoid clicks. Channels greater than
+    /// or equal to `64` are ignored.
+    ///
+    /// By default no channels are muted.
+    pub fn set_input_channel_muted(&mut self, channel: usize, muted: bool) {
+        if channel >= 64 {
+            return;
+        }
+
+        if muted {
+            self.muted_input_channels |= 1 << channel;
+        } else {
+            self.muted_input_channels &= !(1 << channel);
+        }
+
+        if let Some(state) = &mut self.active_state {
+            if state
+                .send(ContextToProcessorMsg::SetInputChannelMuted { channel, muted })
+                .is_err()
+            {
+                log::error!("Failed to mute input channel: Firewheel message channel is full");
+            }
+        }
+    }
+
+    /// The physical output channel that each output channel of the audio
+    /// graph is sent to, or `None` if the channels are sent in the same
+    /// order.
+    pub fn output_channel_map(&self) -> Option<&[u8]> {
+        self.output_channel_map.as_deref()
+    }
+
+    /// Set the physical output channel that each output channel of the
+    /// audio graph is sent to. This can be used when the channel order of
+    /// the hardware differs from the order used in the graph (i.e. with
+    /// different surround channel ordering conventions).
+    ///
+    /// `map[i]` is the physical channel which graph output channel `i` is
+    /// sent to. Every physical channel must appear exactly once, so the
+    /// length of the map must match the number of output channels in the
+    /// audio stream. If the context is not activated, then this is checked
+    /// once it is activated instead, and the map is ignored if it does not
+    /// match the stream.
+    ///
+    /// Set this to `None` to send the channels in the same order (the
+    /// default).
+    pub fn set_output_channel_map(
+        &mut self,
+        map: Option<&[usize]>,
+    ) -> Result<(), OutputChannelMapError> {
+        let map = if let Some(map) = map {
+            if let Some(state) = &self.active_state {
+                let expected = state.stream_info.num_stream_out_channels as usize;
+                if map.len() != expected {
+                    return Err(OutputChannelMapError::InvalidNumChannels {
+                        expected,
+                        got: map.len(),
+                    });
+                }
+            }
+
+            let mut used = 0u64;
+            for &channel in map.iter() {
+                if channel >= map.len().min(64) || used & (1 << channel) != 0 {
+                    return Err(OutputChannelMapError::InvalidChannel(channel));
+                }
+                used |= 1 << channel;
+            }
+
+            Some(map.iter().map(|&channel| channel as u8).collect())
+        } else {
+            None
+        };
+
+        self.output_channel_map = map.clone();
+
+        if let Some(state) = &mut self.active_state {
+            if state
+                .send(ContextToProcessorMsg::SetOutputChannelMap(map))
+                .is_err()
+            {
+                log::error!("Failed to set output channel map: Firewheel message channel is full");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the most recent measurement of how much of the time available
+    /// for processing is being used by the audio graph.
+    ///
+    /// This is updated a few times a second, so it is cheap to poll at
+    /// frame rate.
+    ///
+    /// Returns `None` if the context is not activated.
+    pub fn dsp_load(&self) -> Option<DspLoad> {
+        self.active_state.as_ref().map(|s| DspLoad {
+            average: s.shared_state.dsp_load_average.load(Ordering::Relaxed),
+            peak: s.shared_state.dsp_load_peak.load(Ordering::Relaxed),
+        })
+    }
+
+    /// If the processor has detected runaway feedback in the output and
+    /// attenuated it, then this returns the peak level of the output (in
+    /// decibels) at the moment it was detected.
+    ///
+    /// This only happens if [`FirewheelConfig::runaway_protection`] is
+    /// enabled.
+    ///
+    /// Returns `None` if no runaway was detected or if the context is not
+    /// activated.
+    pub fn runaway_detected(&self) -> Option<f32> {
+        self.active_state.as_ref().and_then(|s| s.runaway_peak_db)
+    }
+
+    /// Restore the output to full volume after runaway feedback was
+    /// detected, and start monitoring for it again.
+    ///
+    /// Make sure the cause of the feedback was fixed before calling this.
+    pub fn reset_runaway_protection(&mut self) {
+        if let Some(state) = &mut self.active_state {
+            state.runaway_peak_db = None;
+
+            if state
+                .send(ContextToProcessorMsg::ResetRunawayProtection)
+                .is_err()
+            {
+                log::error!(
+                    "Failed to reset runaway protection: Firewheel message channel is full"
+                );
+            }
+        }
+    }
+
+    /// The nodes which are currently being culled because of their low
+    /// priority while the processor is under CPU pressure.
+    ///
+    /// This only happens if [`FirewheelConfig::voice_culling`] is enabled.
+    pub fn culled_nodes(&self) -> impl Iterator<Item = NodeID> + '_ {
+        let cull_below_priority = self.cull_below_priority();
+
+        self.graph
+            .nodes()
+            .filter(move |n| is_culled(n.weight.priority, cull_below_priority))
+            .map(|n| n.id)
+    }
+
+    /// Returns whether or not the given node is currently being culled
+    /// because of its low priority while the processor is under CPU
+    /// pressure.
+    pub fn is_node_culled(&self, node_id: NodeID) -> bool {
+        self.graph
+            .node_priority(node_id)
+            .is_some_and(|priority| is_culled(Some(priority), self.cull_below_priority()))
+    }
+
+    /// The number of nodes which the processor culled in the last processed
+    /// block.
+    ///
+    /// Returns `0` if the context is not activated.
+    pub fn num_culled_nodes(&self) -> usize {
+        self.active_state
+            .as_ref()
+            .map(|s| s.shared_state.num_culled_nodes.load(Ordering::Relaxed) as usize)
+            .unwrap_or(0)
+    }
+
+    /// Returns whether or not the processor is currently under CPU
+    /// pressure, in which case low-priority nodes are culled. See
+    /// [`VoiceCulling`].
+    pub fn is_under_cpu_pressure(&self) -> bool {
+        self.active_state
+            .as_ref()
+            .is_some_and(|s| s.shared_state.under_cpu_pressure.load(Ordering::Relaxed))
+    }
+
+    /// The priority below which nodes are currently culled, or `None` if
+    /// no nodes are culled.
+    fn cull_below_priority(&self) -> Option<f32> {
+        self.config
+            .voice_culling
+            .filter(|_| self.is_under_cpu_pressure())
+            .map(|c| c.min_priority)
+    }
+
+    /// The most recent glitches (dropouts) reported by the audio backend,
+    /// oldest first.
+    ///
+    /// At most [`GLITCH_LOG_CAPACITY`] glitches are kept. New glitches are
+    /// collected in [`FirewheelGraphCtx::update`].
+    pub fn glitches(&self) -> impl Iterator<Item = &GlitchEvent> + '_ {
+        self.glitch_log.iter()
+    }
+
+    /// The total number of glitches reported by the audio backend since
+    /// the log was last cleared, including the ones which no longer fit in
+    /// the log.
+    pub fn num_glitches(&self) -> u64 {
+        self.num_glitches
+    }
+
+    /// Clear the log of recent glitches and reset the glitch count.
+    pub fn clear_glitches(&mut self) {
+        self.glitch_log.clear();
+        self.num_glitches = 0;
+    }
+
+    /// Ask the processor for a snapshot of the DSP state of the given node
+    /// (see [`AudioNodeProcessor::serialize_state`]).
+    ///
+    /// `capacity` is the number of bytes to allocate for the state ahead of
+    /// time, since the state is written in the audio thread. It should be
+    /// at least as large as the state of the node.
+    ///
+    /// The state is collected in [`FirewheelGraphCtx::update`] once the
+    /// processor has handled the request, after which it can be retrieved
+    /// with [`FirewheelGraphCtx::take_node_state`]. The snapshot is taken
+    /// at the start of the next processed block.
+    ///
+    /// This does nothing if the context is not activated or if the node is
+    /// not part of the compiled graph yet.
+    ///
+    /// [`AudioNodeProcessor::serialize_state`]: firewheel_core::node::AudioNodeProcessor::serialize_state
+    pub fn request_node_state(&mut self, node_id: NodeID, capacity: usize) {
+        if let Some(state) = &mut self.active_state {
+            let mut buffer = state.spare_state_buffer.take().unwrap_or_default();
+            buffer.clear();
+            buffer.reserve(capacity);
+
+            if state
+                .send(ContextToProcessorMsg::SerializeNodeState { node_id, buffer })
+                .is_err()
+            {
+                log::error!("Failed to request node state: Firewheel message channel is full");
+            }
+        }
+    }
+
+    /// Take the DSP state of the given node which was requested with
+    /// [`FirewheelGraphCtx::request_node_state`], or `None` if it has not
+    /// arrived yet.
+    pub fn take_node_state(&mut self, node_id: NodeID) -> Option<Vec<u8>> {
+        self.active_state
+            .as_mut()
+            .and_then(|s| s.node_states.remove(&node_id))
+    }
+
+    /// Restore the DSP state of the given node which was previously taken
+    /// with [`FirewheelGraphCtx::take_node_state`] (see
+    /// [`AudioNodeProcessor::restore_state`]).
+    ///
+    /// The state is restored at the start of the next processed block.
+    ///
+    /// [`AudioNodeProcessor::restore_state`]: firewheel_core::node::AudioNodeProcessor::restore_state
+    pub fn restore_node_state(&mut self, node_id: NodeID, node_state: Vec<u8>) {
+        if let Some(state) = &mut self.active_state {
+            if state
+                .send(ContextToProcessorMsg::RestoreNodeState {
+                    node_id,
+                    state: node_state,
+                })
+                .is_err()
+            {
+                log::error!("Failed to restore node state: Firewheel message channel is full");
+            }
+        }
+    }
+
+    /// Returns whether or not per-node timing traces are being recorded.
+    #[cfg(feature = "metrics")]
+    pub fn is_tracing_enabled(&self) -> bool {
+        self.tracing_enabled
+    }
+
+    /// Enable or disable recording how long each node takes to process in
+    /// every block.
+    ///
+    /// The recorded events are collected in [`FirewheelGraphCtx::update`]
+    /// and can be written in the Chrome trace event format with
+    /// [`FirewheelGraphCtx::write_chrome_trace`].
+    ///
+    /// By default this is disabled.
+    #[cfg(feature = "metrics")]
+    pub fn set_tracing_enabled(&mut self, enabled: bool) {
+        self.tracing_enabled = enabled;
+
+        if let Some(state) = &mut self.active_state {
+            if state
+                .send(ContextToProcessorMsg::SetTracingEnabled(enabled))
+                .is_err()
+            {
+                log::error!("Failed to set tracing: Firewheel message channel is full");
+            }
+        }
+    }
+
+    /// The trace events which have been collected so far.
+    #[cfg(feature = "metrics")]
+    pub fn trace_events(&self) -> &[TraceEvent] {
+        &self.trace_events
+    }
+
+    /// Clear all of the trace events which have been collected so far.
+    #[cfg(feature = "metrics")]
+    pub fn clear_trace_events(&mut self) {
+        self.trace_events.clear();
+    }
+
+    /// Write the trace events which have been collected so far as JSON in
+    /// the Chrome trace event format, which can be viewed in
+    /// `chrome://tracing`.
+    #[cfg(feature = "metrics")]
+    pub fn write_chrome_trace<W: std::io::Write>(&self, writer: W) -> std::io::Result<()> {
+        crate::trace::write_chrome_trace(&self.trace_events, writer)
+    }
+
     /// Update the firewheel context.
     ///
     /// This must be called reguarly (i.e. once every frame).
@@ -169,37 +1062,121 @@ impl<C: Send + 'static> FirewheelGraphCtx<C> {
             };
         }
 
-        let Some(state) = &mut self.active_state else {
+        if self.active_state.is_none() {
             return UpdateStatus::Inactive;
+        }
+
+        if self.graph.needs_compile() && self.should_compile() {
+            if let Err(e) = self.compile_and_send() {
+                return UpdateStatus::Active {
+                    graph_error: Some(e),
+                };
+            }
+        }
+
+        self.send_pending_events();
+
+        UpdateStatus::Active { graph_error: None }
+    }
+
+    /// Returns whether or not pending changes to the graph should be
+    /// compiled now according to the [`RecompileStrategy`].
+    fn should_compile(&mut self) -> bool {
+        let num_edits = self.graph.num_edits();
+        if num_edits != self.last_graph_edit.0 {
+            self.last_graph_edit = (num_edits, Instant::now());
+        }
+
+        match self.recompile_strategy {
+            RecompileStrategy::Immediate => true,
+            RecompileStrategy::Debounced { delay_secs } => {
+                self.last_graph_edit.1.elapsed().as_secs_f32() >= delay_secs
+            }
+            RecompileStrategy::ManualFlush => false,
+        }
+    }
+
+    /// Compile the graph and send the new schedule to the processor.
+    fn compile_and_send(&mut self) -> Result<(), CompileGraphError> {
+        let Some(state) = &mut self.active_state else {
+            return Ok(());
         };
 
+        let mut schedule_data = self.graph.compile(state.stream_info)?;
+
+        let fade_secs = self.next_schedule_fade_secs.take();
+        let fade_samples = fade_secs.map(|fade_secs| {
+            (fade_secs.max(0.0) * state.stream_info.sample_rate as f32).round() as usize
+        });
+
+        if fade_secs.unwrap_or(self.config.schedule_fade_secs) > 0.0 {
+            schedule_data.prepare_crossfade();
+        }
+
+        if let Err(e) = state.send(ContextToProcessorMsg::NewSchedule {
+            schedule_data: Box::new(schedule_data),
+            fade_samples,
+        }) {
+            let PushError::Full(msg) = e;
+
+            log::error!("Failed to send new schedule: Firewheel message channel is full");
+
+            if let ContextToProcessorMsg::NewSchedule { schedule_data, .. } = msg {
+                self.graph.on_schedule_returned(schedule_data);
+            }
+        } else {
+            state.pending_schedule_swaps += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Send the events which must be applied after the latest schedule.
+    ///
+    /// Nothing is sent while there are changes to the graph waiting to be
+    /// compiled, since the events may refer to nodes which the processor
+    /// doesn't know about yet.
+    fn send_pending_events(&mut self) {
         if self.graph.needs_compile() {
-            match self.graph.compile(state.stream_info) {
-                Ok(schedule_data) => {
-                    if let Err(e) = state
-                        .to_executor_tx
-                        .push(ContextToProcessorMsg::NewSchedule(Box::new(schedule_data)))
-                    {
-                        let PushError::Full(msg) = e;
+            return;
+        }
 
-                        log::error!(
-                            "Failed to send new schedule: Firewheel message channel is full"
-                        );
+        let Some(state) = &mut self.active_state else {
+            return;
+        };
 
-                        if let ContextToProcessorMsg::NewSchedule(schedule_data) = msg {
-                            self.graph.on_schedule_returned(schedule_data);
-                        }
-                    }
-                }
-                Err(e) => {
-                    return UpdateStatus::Active {
-                        graph_error: Some(e),
-                    };
-                }
+        if let Some(sample_rate) = state.pending_sample_rate {
+            if state
+                .send(ContextToProcessorMsg::SetSampleRate(sample_rate))
+                .is_ok()
+            {
+                state.pending_sample_rate = None;
+            } else {
+                log::error!("Failed to send sample rate: Firewheel message channel is full");
             }
         }
 
-        UpdateStatus::Active { graph_error: None }
+        for (node_id, bypassed, delay) in self.graph.drain_bypass_events() {
+            if state
+                .send(ContextToProcessorMsg::SetBypassed {
+                    node_id,
+                    bypassed,
+                    delay,
+                })
+                .is_err()
+            {
+                log::error!("Failed to send bypass event: Firewheel message channel is full");
+            }
+        }
+
+        for (node_id, priority) in self.graph.drain_priority_events() {
+            if state
+                .send(ContextToProcessorMsg::SetPriority { node_id, priority })
+                .is_err()
+            {
+                log::error!("Failed to send node priority: Firewheel message channel is full");
+            }
+        }
     }
 
     /// Deactivate the firewheel context.
@@ -227,7 +1204,7 @@ impl<C: Send + 'static> FirewheelGraphCtx<C> {
         {
             if stream_is_running {
                 loop {
-                    if let Err(_) = state.to_executor_tx.push(ContextToProcessorMsg::Stop) {
+                    if let Err(_) = state.send(ContextToProcessorMsg::Stop) {
                         log::error!(
                             "Failed to send stop signal: Firewheel message channel is full"
                         );
@@ -277,11 +1254,50 @@ impl<C: Send + 'static> FirewheelGraphCtx<C> {
             return;
         };
 
+        #[cfg(feature = "metrics")]
+        while let Ok(event) = state.trace_rx.pop() {
+            self.trace_events.push(event);
+        }
+
         while let Ok(msg) = state.from_executor_rx.pop() {
             match msg {
                 ProcessorToContextMsg::ReturnSchedule(schedule_data) => {
                     self.graph.on_schedule_returned(schedule_data);
                 }
+                ProcessorToContextMsg::SchedulesSwapped(count) => {
+                    state.pending_schedule_swaps =
+                        state.pending_schedule_swaps.saturating_sub(count);
+                }
+                ProcessorToContextMsg::RunawayDetected { peak_db } => {
+                    log::warn!(
+                        "Runaway feedback detected in the output (peak: {:.1} dB), attenuating the output",
+                        peak_db
+                    );
+
+                    state.runaway_peak_db = Some(peak_db);
+                }
+                ProcessorToContextMsg::Glitch(event) => {
+                    log::warn!(
+                        "Audio stream glitch at {:.3} seconds: {:?}",
+                        event.stream_time_secs,
+                        event.kind
+                    );
+
+                    if self.glitch_log.len() == GLITCH_LOG_CAPACITY {
+                        self.glitch_log.pop_front();
+                    }
+                    self.glitch_log.push_back(event);
+                    self.num_glitches += 1;
+                }
+                ProcessorToContextMsg::NodeState {
+                    node_id,
+                    state: node_state,
+                } => {
+                    state.node_states.insert(node_id, node_state);
+                }
+                ProcessorToContextMsg::ReturnStateBuffer(buffer) => {
+                    state.spare_state_buffer = Some(buffer);
+                }
                 ProcessorToContextMsg::Dropped { nodes, user_cx, .. } => {
                     self.graph.on_processor_dropped(nodes);
                     *dropped = true;
@@ -300,6 +1316,47 @@ impl<C: Send + 'static> Drop for FirewheelGraphCtx<C> {
     }
 }
 
+/// Returns whether or not a node with the given priority is culled, given
+/// the priority below which nodes are currently being culled.
+pub(crate) fn is_culled(priority: Option<f32>, cull_below_priority: Option<f32>) -> bool {
+    match (priority, cull_below_priority) {
+        (Some(priority), Some(min_priority)) => priority < min_priority,
+        _ => false,
+    }
+}
+
+/// A measurement of how much of the time available for processing is being
+/// used by the audio graph, where `1.0` means all of the available time is
+/// used (and underruns are likely to occur).
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct DspLoad {
+    /// The average load over the measured period.
+    pub average: f32,
+    /// The highest load of a single block in the measured period.
+    pub peak: f32,
+}
+
+/// The kind of a [`GlitchEvent`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GlitchKind {
+    /// Some input data was discarded because of an overflow condition at
+    /// the audio driver.
+    InputOverflow,
+    /// The output buffer ran low, likely producing a break in the output
+    /// sound.
+    OutputUnderflow,
+}
+
+/// A glitch (dropout) in the audio stream which was reported by the audio
+/// backend.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GlitchEvent {
+    /// The time of the stream in seconds at the start of the buffer in
+    /// which the glitch was reported.
+    pub stream_time_secs: f64,
+    pub kind: GlitchKind,
+}
+
 pub enum UpdateStatus<C: Send + 'static> {
     Inactive,
     Active {
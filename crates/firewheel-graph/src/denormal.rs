@@ -0,0 +1,207 @@
+//! Protection against denormal (subnormal) floating point numbers.
+//!
+//! When a signal such as a reverb or filter tail decays towards zero, it can
+//! enter the subnormal range, which is extremely slow to compute with on x86
+//! processors. Setting the "flush to zero" (FTZ) and "denormals are zero"
+//! (DAZ) flags makes the processor treat these values as zero instead.
+
+/// Enables the FTZ and DAZ flags for as long as this guard is alive.
+///
+/// The previous state of the flags is restored when this guard is dropped,
+/// including when unwinding from a panic.
+///
+/// On targets which do not support this, this is a no-op.
+pub(crate) struct FlushDenormalsGuard {
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature = "sse"
+    ))]
+    prev_mxcsr: u32,
+}
+
+impl FlushDenormalsGuard {
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature = "sse"
+    ))]
+    pub fn new() -> Self {
+        let prev_mxcsr = x86::get_mxcsr();
+        x86::set_mxcsr(prev_mxcsr | x86::FTZ | x86::DAZ);
+
+        Self { prev_mxcsr }
+    }
+
+    #[cfg(not(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature = "sse"
+    )))]
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Drop for FlushDenormalsGuard {
+    fn drop(&mut self) {
+        #[cfg(all(
+            any(target_arch = "x86", target_arch = "x86_64"),
+            target_feature = "sse"
+        ))]
+        x86::set_mxcsr(self.prev_mxcsr);
+    }
+}
+
+/// Disables the FTZ and DAZ flags for as long as this guard is alive, so
+/// that denormal numbers are computed exactly.
+///
+/// This is used to process nodes which need exact IEEE behavior while the
+/// rest of the graph is processed with a [`FlushDenormalsGuard`]. The
+/// previous state of the flags is restored when this guard is dropped.
+///
+/// On targets which do not support this, this is a no-op.
+pub(crate) struct PreserveDenormalsGuard {
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature = "sse"
+    ))]
+    prev_mxcsr: u32,
+}
+
+impl PreserveDenormalsGuard {
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature = "sse"
+    ))]
+    pub fn new() -> Self {
+        let prev_mxcsr = x86::get_mxcsr();
+        x86::set_mxcsr(prev_mxcsr & !(x86::FTZ | x86::DAZ));
+
+        Self { prev_mxcsr }
+    }
+
+    #[cfg(not(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature = "sse"
+    )))]
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Drop for PreserveDenormalsGuard {
+    fn drop(&mut self) {
+        #[cfg(all(
+            any(target_arch = "x86", target_arch = "x86_64"),
+            target_feature = "sse"
+        ))]
+        x86::set_mxcsr(self.prev_mxcsr);
+    }
+}
+
+#[cfg(all(
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse"
+))]
+mod x86 {
+    use std::arch::asm;
+
+    /// The "flush to zero" bit in the MXCSR register
+    pub const FTZ: u32 = 1 << 15;
+    /// The "denormals are zero" bit in the MXCSR register
+    pub const DAZ: u32 = 1 << 6;
+
+    #[inline]
+    pub fn get_mxcsr() -> u32 {
+        let mut mxcsr: u32 = 0;
+
+        // SAFETY:
+        // `stmxcsr` only writes the 32 bit MXCSR register to the given
+        // memory location, which points to a valid `u32`.
+        unsafe {
+            asm!(
+                "stmxcsr [{}]",
+                in(reg) &mut mxcsr,
+                options(nostack, preserves_flags),
+            );
+        }
+
+        mxcsr
+    }
+
+    #[inline]
+    pub fn set_mxcsr(mxcsr: u32) {
+        // SAFETY:
+        // `ldmxcsr` only reads the 32 bit value from the given memory
+        // location, which points to a valid `u32`. Only the FTZ and DAZ
+        // bits are ever modified from a value previously read from the
+        // register, so no reserved bits are set.
+        unsafe {
+            asm!(
+                "ldmxcsr [{}]",
+                in(reg) &mxcsr,
+                options(nostack, readonly, preserves_flags),
+            );
+        }
+    }
+}
+
+#[cfg(all(
+    test,
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse"
+))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flush_denormals_guard() {
+        let denormal = || std::hint::black_box(f32::MIN_POSITIVE) * std::hint::black_box(0.5);
+
+        assert!(denormal().is_subnormal());
+
+        {
+            let _guard = FlushDenormalsGuard::new();
+            assert_eq!(denormal(), 0.0);
+        }
+
+        assert!(denormal().is_subnormal());
+    }
+
+    /// Run the tails of a bank of one-pole lowpass filters, which have
+    /// decayed into the subnormal range, and return how long it took along
+    /// with the final state of the first filter.
+    fn decay_filter_tails() -> (std::time::Duration, f32) {
+        let coeff = std::hint::black_box(0.001);
+        let mut states = vec![f32::MIN_POSITIVE / 2.0; 256];
+
+        let start = std::time::Instant::now();
+        for _ in 0..2000 {
+            for y in states.iter_mut() {
+                *y -= *y * coeff;
+            }
+            std::hint::black_box(&mut states);
+        }
+
+        (start.elapsed(), states[0])
+    }
+
+    #[test]
+    fn flush_denormals_guard_cost() {
+        let mut unguarded = std::time::Duration::MAX;
+        let mut guarded = std::time::Duration::MAX;
+
+        // Take the fastest of a few runs to reduce noise.
+        for _ in 0..5 {
+            let (elapsed, state) = decay_filter_tails();
+            assert!(state.is_subnormal());
+            unguarded = unguarded.min(elapsed);
+
+            let _guard = FlushDenormalsGuard::new();
+            let (elapsed, state) = decay_filter_tails();
+            assert_eq!(state, 0.0);
+            guarded = guarded.min(elapsed);
+        }
+
+        println!("decaying filter tails: {unguarded:?} unguarded, {guarded:?} guarded");
+        assert!(guarded < unguarded);
+    }
+}
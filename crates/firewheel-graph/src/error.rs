@@ -150,6 +150,8 @@ pub enum NodeError {
         node_id: Option<NodeID>,
         error: Box<dyn Error>,
     },
+    /// The given node was not found in the graph.
+    NodeNotFound(NodeID),
 }
 
 impl Error for NodeError {}
@@ -179,6 +181,9 @@ impl std::fmt::Display for NodeError {
                     write!(f, "Node failed to activate: {}", error)
                 }
             }
+            NodeError::NodeNotFound(node_id) => {
+                write!(f, "Could not find node with ID {:?}", node_id)
+            }
         }
     }
 }
@@ -203,3 +208,58 @@ impl std::fmt::Display for ActivateCtxError {
         }
     }
 }
+
+#[derive(Debug)]
+pub enum ChangeSampleRateError {
+    NotActivated,
+    NodeFailedToActived(NodeError),
+}
+
+impl Error for ChangeSampleRateError {}
+
+impl std::fmt::Display for ChangeSampleRateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChangeSampleRateError::NotActivated => {
+                write!(f, "Firewheel context is not activated")
+            }
+            ChangeSampleRateError::NodeFailedToActived(e) => {
+                write!(f, "Audio node failed to re-activate: {}", e)
+            }
+        }
+    }
+}
+
+/// An error occurred while setting the output channel map.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputChannelMapError {
+    /// The number of channels in the map does not match the number of
+    /// output channels in the audio stream.
+    InvalidNumChannels { expected: usize, got: usize },
+    /// The given physical channel is out of range, or it appears in the map
+    /// more than once.
+    InvalidChannel(usize),
+}
+
+impl Error for OutputChannelMapError {}
+
+impl std::fmt::Display for OutputChannelMapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputChannelMapError::InvalidNumChannels { expected, got } => {
+                write!(
+                    f,
+                    "Output channel map has {} channels, but the audio stream has {} output channels",
+                    got, expected
+                )
+            }
+            OutputChannelMapError::InvalidChannel(channel) => {
+                write!(
+                    f,
+                    "Output channel {} in the output channel map is out of range or appears more than once",
+                    channel
+                )
+            }
+        }
+    }
+}
@@ -8,16 +8,18 @@ use std::sync::Arc;
 use std::time::Instant;
 
 use ahash::{AHashMap, AHashSet};
-use firewheel_core::clock::{ClockSamples, ClockSeconds};
+use firewheel_core::clock::{ClockSamples, ClockSeconds, EventDelay};
 use firewheel_core::{ChannelConfig, ChannelCount, StreamInfo};
 use thunderdome::Arena;
 
 use crate::basic_nodes::dummy::DummyAudioNode;
 use crate::context::FirewheelConfig;
 use crate::error::{AddEdgeError, CompileGraphError, NodeError};
-use firewheel_core::node::{AudioNode, AudioNodeProcessor};
+use crate::meter::NodeMeter;
+use crate::processor::ProcessorEntry;
+use firewheel_core::node::{AudioNode, AudioNodeInfo};
 
-pub(crate) use self::compiler::{CompiledSchedule, ScheduleHeapData};
+pub(crate) use self::compiler::{CompiledSchedule, NewNodeProcessor, ScheduleHeapData};
 
 pub use self::compiler::{Edge, EdgeID, InPortIdx, NodeEntry, OutPortIdx};
 
@@ -83,6 +85,9 @@ pub struct NodeWeight<C> {
     pub node: Box<dyn AudioNode<C>>,
     pub activated: bool,
     pub updates: bool,
+    pub(crate) meter: Arc<NodeMeter>,
+    /// The priority used for culling (see [`AudioGraph::set_node_priority`]).
+    pub(crate) priority: Option<f32>,
 }
 
 #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
@@ -112,12 +117,17 @@ pub struct AudioGraph<C: Send + 'static> {
     graph_in_id: NodeID,
     graph_out_id: NodeID,
     needs_compile: bool,
+    /// Incremented every time the graph is changed in a way that requires
+    /// it to be compiled again.
+    num_edits: u64,
 
     active_state: Option<ActiveState>,
 
     nodes_to_remove_from_schedule: Vec<NodeID>,
     active_nodes_to_remove: AHashMap<NodeID, NodeEntry<NodeWeight<C>>>,
-    new_node_processors: Vec<(NodeID, Box<dyn AudioNodeProcessor<C>>)>,
+    new_node_processors: Vec<NewNodeProcessor<C>>,
+    bypass_events: Vec<(NodeID, bool, EventDelay)>,
+    priority_events: Vec<(NodeID, Option<f32>)>,
 }
 
 impl<C: Send + 'static> AudioGraph<C> {
@@ -134,6 +144,8 @@ impl<C: Send + 'static> AudioGraph<C> {
                     node: Box::new(DummyAudioNode),
                     activated: false,
                     updates: false,
+                    meter: Arc::new(NodeMeter::new(config.num_graph_inputs.get() as usize)),
+                    priority: None,
                 },
             )),
             debug_name: "graph_in",
@@ -150,6 +162,8 @@ impl<C: Send + 'static> AudioGraph<C> {
                     node: Box::new(DummyAudioNode),
                     activated: false,
                     updates: false,
+                    meter: Arc::new(NodeMeter::new(0)),
+                    priority: None,
                 },
             )),
             debug_name: "graph_out",
@@ -164,10 +178,13 @@ impl<C: Send + 'static> AudioGraph<C> {
             graph_in_id,
             graph_out_id,
             needs_compile: true,
+            num_edits: 0,
             active_state: None,
             nodes_to_remove_from_schedule: Vec::with_capacity(config.initial_node_capacity),
             active_nodes_to_remove: AHashMap::with_capacity(config.initial_edge_capacity),
             new_node_processors: Vec::with_capacity(config.initial_node_capacity),
+            bypass_events: Vec::new(),
+            priority_events: Vec::new(),
         }
     }
 
@@ -221,35 +238,7 @@ impl<C: Send + 'static> AudioGraph<C> {
 
         let channel_config = channel_config.unwrap_or(info.default_channel_config);
 
-        if channel_config.num_inputs < info.num_min_supported_inputs
-            || channel_config.num_inputs > info.num_max_supported_inputs
-            || channel_config.num_outputs < info.num_min_supported_outputs
-            || channel_config.num_outputs > info.num_max_supported_outputs
-        {
-            return Err(NodeError::InvalidChannelConfig {
-                channel_config,
-                node_info: info,
-                msg: None,
-            });
-        }
-
-        if info.equal_num_ins_and_outs {
-            if channel_config.num_inputs != channel_config.num_outputs {
-                return Err(NodeError::InvalidChannelConfig {
-                    channel_config,
-                    node_info: info,
-                    msg: None,
-                });
-            }
-        }
-
-        if let Err(e) = node.channel_config_supported(channel_config) {
-            return Err(NodeError::InvalidChannelConfig {
-                channel_config,
-                node_info: info,
-                msg: Some(e),
-            });
-        }
+        validate_channel_config(node.as_ref(), &info, channel_config)?;
 
         let processor = node.activate(&stream_info, channel_config).map_err(|e| {
             NodeError::ActivationFailed {
@@ -258,26 +247,119 @@ impl<C: Send + 'static> AudioGraph<C> {
             }
         })?;
 
+        let meter = Arc::new(NodeMeter::new(channel_config.num_outputs.get() as usize));
+
         let new_id = NodeID {
             idx: self.nodes.insert(NodeEntry::new(
                 channel_config,
                 NodeWeight {
                     node,
-                    activated: false,
+                    activated: true,
                     updates: info.updates,
+                    meter: Arc::clone(&meter),
+                    priority: None,
                 },
             )),
             debug_name,
         };
         self.nodes[new_id.idx].id = new_id;
 
-        self.new_node_processors.push((new_id, processor));
+        self.new_node_processors.push(NewNodeProcessor {
+            node_id: new_id,
+            entry: ProcessorEntry::new(processor, &info, meter),
+            replaces_running: false,
+        });
 
-        self.needs_compile = true;
+        self.set_needs_compile();
 
         Ok(new_id)
     }
 
+    /// Replace the node with the given ID with a new [`AudioNode`], keeping
+    /// its ID and all of its connections.
+    ///
+    /// The new node must support the channel configuration of the node it
+    /// replaces. Its processor takes over from the old processor the next
+    /// time a schedule is sent to the audio thread, at which point
+    /// [`AudioNodeProcessor::migrate_state_from`] is called on the new
+    /// processor so that it can carry over state from the old one. The old
+    /// node is deactivated once its processor has been returned from the
+    /// audio thread.
+    ///
+    /// The graph input and output nodes cannot be replaced.
+    ///
+    /// [`AudioNodeProcessor::migrate_state_from`]: firewheel_core::node::AudioNodeProcessor::migrate_state_from
+    pub fn replace_node(
+        &mut self,
+        node_id: NodeID,
+        mut node: Box<dyn AudioNode<C>>,
+    ) -> Result<(), NodeError> {
+        if node_id == self.graph_in_id || node_id == self.graph_out_id {
+            return Err(NodeError::NodeNotFound(node_id));
+        }
+
+        let stream_info = &self.active_state.as_ref().unwrap().stream_info;
+
+        let node_entry = self
+            .nodes
+            .get_mut(node_id.idx)
+            .ok_or(NodeError::NodeNotFound(node_id))?;
+        let channel_config = node_entry.channel_config;
+
+        let info = node.info();
+
+        validate_channel_config(node.as_ref(), &info, channel_config)?;
+
+        let processor = node.activate(stream_info, channel_config).map_err(|e| {
+            NodeError::ActivationFailed {
+                node_id: Some(node_id),
+                error: e,
+            }
+        })?;
+        let meter = Arc::clone(&node_entry.weight.meter);
+        let priority = node_entry.weight.priority;
+        let entry = ProcessorEntry::new(processor, &info, Arc::clone(&meter));
+
+        let old_weight = std::mem::replace(
+            &mut node_entry.weight,
+            NodeWeight {
+                node,
+                activated: true,
+                updates: info.updates,
+                meter,
+                priority,
+            },
+        );
+
+        if let Some(queued) = self
+            .new_node_processors
+            .iter_mut()
+            .find(|p| p.node_id == node_id)
+        {
+            // The old processor was never sent to the audio thread, so the
+            // old node can be deactivated right away.
+            let old_entry = std::mem::replace(&mut queued.entry, entry);
+            let mut old_node = old_weight.node;
+            old_node.deactivate(Some(old_entry.processor));
+        } else {
+            self.new_node_processors.push(NewNodeProcessor {
+                node_id,
+                entry,
+                replaces_running: true,
+            });
+
+            if old_weight.activated {
+                let mut old_entry = NodeEntry::new(channel_config, old_weight);
+                old_entry.id = node_id;
+                self.active_nodes_to_remove.insert(node_id, old_entry);
+            }
+        }
+
+        self.set_needs_compile();
+
+        Ok(())
+    }
+
     /// Get an immutable reference to a node.
     ///
     /// This will return `None` if a node with the given ID does not
@@ -308,6 +390,111 @@ impl<C: Send + 'static> AudioGraph<C> {
         self.nodes.get(node_id.idx)
     }
 
+    /// Bypass or un-bypass the given node.
+    ///
+    /// While a node is bypassed, its processor is not run and each of its
+    /// input channels is passed straight through to the output channel with
+    /// the same index. Any extra output channels are left silent.
+    ///
+    /// * `delay` - When the change should take effect. This is applied
+    ///   sample-accurately within the processing block, and faded over
+    ///   [`FirewheelConfig::bypass_fade_secs`]. Only one delayed change is
+    ///   kept per node, so scheduling a new one replaces any change that
+    ///   has not yet taken effect.
+    ///
+    /// This will return `false` if a node with the given ID does not
+    /// exist in the graph, or if the ID is of the graph input or graph
+    /// output node.
+    pub fn set_node_bypassed(
+        &mut self,
+        node_id: NodeID,
+        bypassed: bool,
+        delay: EventDelay,
+    ) -> bool {
+        if node_id == self.graph_in_id
+            || node_id == self.graph_out_id
+            || !self.nodes.contains(node_id.idx)
+        {
+            return false;
+        }
+
+        self.bypass_events.push((node_id, bypassed, delay));
+
+        true
+    }
+
+    /// Set the priority of the given node, for example from the distance
+    /// of a sound source to the listener in a game.
+    ///
+    /// While the processor is under CPU pressure, nodes with a priority
+    /// below [`VoiceCulling::min_priority`] are culled: their processor is
+    /// not run and their outputs are silent. See [`VoiceCulling`] for more
+    /// details.
+    ///
+    /// Set this to `None` to never cull the node. By default nodes have no
+    /// priority.
+    ///
+    /// This will return `false` if a node with the given ID does not
+    /// exist in the graph, or if the ID is of the graph input or graph
+    /// output node.
+    ///
+    /// [`VoiceCulling`]: crate::VoiceCulling
+    /// [`VoiceCulling::min_priority`]: crate::VoiceCulling::min_priority
+    pub fn set_node_priority(&mut self, node_id: NodeID, priority: Option<f32>) -> bool {
+        if node_id == self.graph_in_id || node_id == self.graph_out_id {
+            return false;
+        }
+
+        let Some(node_entry) = self.nodes.get_mut(node_id.idx) else {
+            return false;
+        };
+
+        node_entry.weight.priority = priority;
+        self.priority_events.push((node_id, priority));
+
+        true
+    }
+
+    /// The priority of the given node which was set with
+    /// [`AudioGraph::set_node_priority`], or `None` if it has no priority
+    /// or if a node with the given ID does not exist.
+    pub fn node_priority(&self, node_id: NodeID) -> Option<f32> {
+        self.nodes.get(node_id.idx).and_then(|n| n.weight.priority)
+    }
+
+    /// Enable or disable measuring the peak level of each output channel of
+    /// the given node, which can be read with
+    /// [`AudioGraph::node_channel_peaks`]. This is useful for multichannel
+    /// meters in editors.
+    ///
+    /// By default metering is disabled, since it costs some processing
+    /// time.
+    ///
+    /// This will return `false` if a node with the given ID does not
+    /// exist in the graph.
+    pub fn set_node_metering_enabled(&mut self, node_id: NodeID, enabled: bool) -> bool {
+        let Some(node_entry) = self.nodes.get(node_id.idx) else {
+            return false;
+        };
+
+        node_entry.weight.meter.set_enabled(enabled);
+
+        true
+    }
+
+    /// Returns the peak level (as raw linear gain) of each output channel
+    /// of the given node since the last call to this method, and resets
+    /// the peaks.
+    ///
+    /// This will return `None` if a node with the given ID does not exist
+    /// in the graph, or if metering is not enabled for the node (see
+    /// [`AudioGraph::set_node_metering_enabled`]).
+    pub fn node_channel_peaks(&self, node_id: NodeID) -> Option<Vec<f32>> {
+        let meter = &self.nodes.get(node_id.idx)?.weight.meter;
+
+        meter.is_enabled().then(|| meter.take_peaks())
+    }
+
     /// Remove the given node from the graph.
     ///
     /// This will automatically remove all edges from the graph that
@@ -324,7 +511,7 @@ impl<C: Send + 'static> AudioGraph<C> {
             return Err(());
         }
 
-        let node_entry = self.nodes.remove(node_id.idx).ok_or(())?;
+        let mut node_entry = self.nodes.remove(node_id.idx).ok_or(())?;
 
         let mut removed_edges: Vec<EdgeID> = Vec::new();
 
@@ -342,13 +529,44 @@ impl<C: Send + 'static> AudioGraph<C> {
                 .remove(&(node_id, InPortIdx(port_idx)));
         }
 
-        self.nodes_to_remove_from_schedule.push(node_id);
+        let queued = self
+            .new_node_processors
+            .iter()
+            .position(|p| p.node_id == node_id)
+            .map(|i| self.new_node_processors.remove(i));
+
+        match queued {
+            Some(queued) if !queued.replaces_running => {
+                // The processor was never sent to the audio thread, so the
+                // node can be deactivated right away.
+                node_entry
+                    .weight
+                    .node
+                    .deactivate(Some(queued.entry.processor));
+            }
+            Some(queued) => {
+                // The queued processor was never sent to the audio thread,
+                // but the processor it was going to replace is still running
+                // and must be removed from the schedule. The node is done
+                // with, so it can be deactivated with the queued processor
+                // right away, and the running processor is dropped once it
+                // has been returned from the audio thread.
+                node_entry
+                    .weight
+                    .node
+                    .deactivate(Some(queued.entry.processor));
+                self.nodes_to_remove_from_schedule.push(node_id);
+            }
+            None => {
+                self.nodes_to_remove_from_schedule.push(node_id);
 
-        if node_entry.weight.activated {
-            self.active_nodes_to_remove.insert(node_id, node_entry);
+                if node_entry.weight.activated {
+                    self.active_nodes_to_remove.insert(node_id, node_entry);
+                }
+            }
         }
 
-        self.needs_compile = true;
+        self.set_needs_compile();
         Ok(removed_edges)
     }
 
@@ -385,7 +603,7 @@ impl<C: Send + 'static> AudioGraph<C> {
                 }
             }
 
-            self.needs_compile = true;
+            self.set_needs_compile();
         }
 
         let graph_out_node = self.nodes.get_mut(self.graph_in_id.idx).unwrap();
@@ -405,7 +623,7 @@ impl<C: Send + 'static> AudioGraph<C> {
                 }
             }
 
-            self.needs_compile = true;
+            self.set_needs_compile();
         }
 
         Ok(removed_edges)
@@ -508,7 +726,7 @@ impl<C: Send + 'static> AudioGraph<C> {
             }
         }
 
-        self.needs_compile = true;
+        self.set_needs_compile();
 
         Ok(new_edge_id)
     }
@@ -552,7 +770,7 @@ impl<C: Send + 'static> AudioGraph<C> {
             self.connected_input_ports
                 .remove(&(edge.dst_node, edge.dst_port));
 
-            self.needs_compile = true;
+            self.set_needs_compile();
 
             true
         } else {
@@ -565,6 +783,137 @@ impl<C: Send + 'static> AudioGraph<C> {
         self.edges.get(edge_id.0)
     }
 
+    /// Find the shortest path of connected nodes from `from` to `to`,
+    /// following edges in the direction that audio flows.
+    ///
+    /// The returned path starts with `from` and ends with `to`. If there
+    /// are multiple shortest paths, then any one of them may be returned.
+    ///
+    /// Returns `None` if there is no such path, or if either node does not
+    /// exist in the graph.
+    pub fn path_between(&self, from: NodeID, to: NodeID) -> Option<Vec<NodeID>> {
+        if !self.nodes.contains(from.idx) || !self.nodes.contains(to.idx) {
+            return None;
+        }
+
+        let successors = self.successors();
+
+        // Breadth-first search, remembering which node each node was
+        // reached from.
+        let mut reached_from: AHashMap<NodeID, NodeID> = AHashMap::new();
+        let mut queue = std::collections::VecDeque::new();
+        queue.push_back(from);
+
+        while let Some(node_id) = queue.pop_front() {
+            if node_id == to {
+                let mut path = vec![to];
+                let mut current = to;
+                while current != from {
+                    current = reached_from[&current];
+                    path.push(current);
+                }
+                path.reverse();
+
+                return Some(path);
+            }
+
+            for &next in successors.get(&node_id).into_iter().flatten() {
+                if next != from && !reached_from.contains_key(&next) {
+                    reached_from.insert(next, node_id);
+                    queue.push_back(next);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Find the longest path of connected nodes from `from` to `to`,
+    /// following edges in the direction that audio flows.
+    ///
+    /// This is useful for reasoning about latency, since the longest path
+    /// is the one with the most nodes which could each add a delay.
+    ///
+    /// The returned path starts with `from` and ends with `to`. If the
+    /// graph contains a cycle, then edges which would revisit a node on the
+    /// current path are ignored.
+    ///
+    /// Returns `None` if there is no such path, or if either node does not
+    /// exist in the graph.
+    pub fn longest_path_between(&self, from: NodeID, to: NodeID) -> Option<Vec<NodeID>> {
+        if !self.nodes.contains(from.idx) || !self.nodes.contains(to.idx) {
+            return None;
+        }
+
+        let successors = self.successors();
+
+        // For every visited node, the next node on the longest path from
+        // it to `to` along with the length of that path, or `None` if `to`
+        // cannot be reached from it.
+        let mut longest: AHashMap<NodeID, Option<(NodeID, usize)>> = AHashMap::new();
+        let mut on_path = AHashSet::new();
+
+        fn visit(
+            node_id: NodeID,
+            to: NodeID,
+            successors: &AHashMap<NodeID, Vec<NodeID>>,
+            longest: &mut AHashMap<NodeID, Option<(NodeID, usize)>>,
+            on_path: &mut AHashSet<NodeID>,
+        ) -> Option<usize> {
+            if node_id == to {
+                return Some(0);
+            }
+            if let Some(entry) = longest.get(&node_id) {
+                return entry.map(|(_, len)| len);
+            }
+
+            on_path.insert(node_id);
+
+            let mut best: Option<(NodeID, usize)> = None;
+            for &next in successors.get(&node_id).into_iter().flatten() {
+                if on_path.contains(&next) {
+                    continue;
+                }
+
+                if let Some(len) = visit(next, to, successors, longest, on_path) {
+                    if best.map(|(_, best_len)| len + 1 > best_len).unwrap_or(true) {
+                        best = Some((next, len + 1));
+                    }
+                }
+            }
+
+            on_path.remove(&node_id);
+            longest.insert(node_id, best);
+
+            best.map(|(_, len)| len)
+        }
+
+        visit(from, to, &successors, &mut longest, &mut on_path)?;
+
+        let mut path = vec![from];
+        let mut current = from;
+        while current != to {
+            current = longest[&current].unwrap().0;
+            path.push(current);
+        }
+
+        Some(path)
+    }
+
+    /// Build a map from each node to the nodes its outputs are connected to.
+    fn successors(&self) -> AHashMap<NodeID, Vec<NodeID>> {
+        let mut successors: AHashMap<NodeID, Vec<NodeID>> = AHashMap::new();
+
+        for (_, edge) in self.edges.iter() {
+            let next = successors.entry(edge.src_node).or_default();
+            if !next.contains(&edge.dst_node) {
+                next.push(edge.dst_node);
+            }
+        }
+
+        successors
+    }
+
     fn remove_edges_with_input_port(
         &mut self,
         node_id: NodeID,
@@ -650,6 +999,17 @@ impl<C: Send + 'static> AudioGraph<C> {
         self.needs_compile
     }
 
+    fn set_needs_compile(&mut self) {
+        self.needs_compile = true;
+        self.num_edits += 1;
+    }
+
+    /// The number of changes made to the graph which required it to be
+    /// compiled again, used to debounce compiles.
+    pub(crate) fn num_edits(&self) -> u64 {
+        self.num_edits
+    }
+
     pub(crate) fn compile(
         &mut self,
         stream_info: StreamInfo,
@@ -696,14 +1056,21 @@ impl<C: Send + 'static> AudioGraph<C> {
         }
     }
 
-    pub(crate) fn on_processor_dropped(
+    pub(crate) fn drain_bypass_events(
         &mut self,
-        mut nodes: Arena<Box<dyn AudioNodeProcessor<C>>>,
-    ) {
-        for (node_id, processor) in nodes.drain() {
+    ) -> std::vec::Drain<'_, (NodeID, bool, EventDelay)> {
+        self.bypass_events.drain(..)
+    }
+
+    pub(crate) fn drain_priority_events(&mut self) -> std::vec::Drain<'_, (NodeID, Option<f32>)> {
+        self.priority_events.drain(..)
+    }
+
+    pub(crate) fn on_processor_dropped(&mut self, mut nodes: Arena<ProcessorEntry<C>>) {
+        for (node_id, entry) in nodes.drain() {
             if let Some(node_entry) = self.nodes.get_mut(node_id) {
                 if node_entry.weight.activated {
-                    node_entry.weight.node.deactivate(Some(processor));
+                    node_entry.weight.node.deactivate(Some(entry.processor));
                     node_entry.weight.activated = false;
                 }
             }
@@ -727,7 +1094,15 @@ impl<C: Send + 'static> AudioGraph<C> {
                 .activate(&stream_info, node_entry.channel_config)
             {
                 Ok(processor) => {
-                    self.new_node_processors.push((node_entry.id, processor));
+                    let info = node_entry.weight.node.info();
+
+                    let meter = Arc::clone(&node_entry.weight.meter);
+
+                    self.new_node_processors.push(NewNodeProcessor {
+                        node_id: node_entry.id,
+                        entry: ProcessorEntry::new(processor, &info, meter),
+                        replaces_running: false,
+                    });
                     node_entry.weight.activated = true;
                 }
                 Err(e) => {
@@ -749,7 +1124,72 @@ impl<C: Send + 'static> AudioGraph<C> {
                 event_time_samples_shared,
                 main_thread_clock_start_instant,
             });
-            self.needs_compile = true;
+            self.set_needs_compile();
+            Ok(())
+        }
+    }
+
+    /// Re-activate all active nodes with new stream info (i.e. after a change
+    /// in sample rate) without deactivating them first.
+    ///
+    /// The new processors replace the old ones the next time a schedule is
+    /// sent to the processor. If a node fails to re-activate, then it keeps
+    /// running with its old processor and the first such error is returned.
+    pub(crate) fn reactivate(&mut self, stream_info: StreamInfo) -> Result<(), NodeError> {
+        let mut error = None;
+
+        for (_, node_entry) in self.nodes.iter_mut() {
+            if !node_entry.weight.activated {
+                continue;
+            }
+
+            match node_entry
+                .weight
+                .node
+                .activate(&stream_info, node_entry.channel_config)
+            {
+                Ok(processor) => {
+                    let info = node_entry.weight.node.info();
+                    let meter = Arc::clone(&node_entry.weight.meter);
+                    let entry = ProcessorEntry::new(processor, &info, meter);
+
+                    // If a processor for this node was still waiting to be
+                    // sent, then it is stale and can be dropped here.
+                    // Whether or not it replaces a running processor stays
+                    // the same.
+                    if let Some(queued) = self
+                        .new_node_processors
+                        .iter_mut()
+                        .find(|p| p.node_id == node_entry.id)
+                    {
+                        queued.entry = entry;
+                    } else {
+                        self.new_node_processors.push(NewNodeProcessor {
+                            node_id: node_entry.id,
+                            entry,
+                            replaces_running: true,
+                        });
+                    }
+                }
+                Err(e) => {
+                    if error.is_none() {
+                        error = Some(NodeError::ActivationFailed {
+                            node_id: Some(node_entry.id),
+                            error: e,
+                        });
+                    }
+                }
+            }
+        }
+
+        if let Some(active_state) = &mut self.active_state {
+            active_state.stream_info = stream_info;
+        }
+        self.set_needs_compile();
+
+        if let Some(e) = error {
+            Err(e)
+        } else {
             Ok(())
         }
     }
@@ -761,8 +1201,14 @@ impl<C: Send + 'static> AudioGraph<C> {
                     .new_node_processors
                     .iter()
                     .enumerate()
-                    .find_map(|(i, (id, _))| if *id == node_entry.id { Some(i) } else { None })
-                    .map(|i| self.new_node_processors.remove(i).1);
+                    .find_map(|(i, p)| {
+                        if p.node_id == node_entry.id {
+                            Some(i)
+                        } else {
+                            None
+                        }
+                    })
+                    .map(|i| self.new_node_processors.remove(i).entry.processor);
 
                 node_entry.weight.node.deactivate(processor);
                 node_entry.weight.activated = false;
@@ -772,6 +1218,8 @@ impl<C: Send + 'static> AudioGraph<C> {
         self.active_nodes_to_remove.clear();
         self.nodes_to_remove_from_schedule.clear();
         self.new_node_processors.clear();
+        self.bypass_events.clear();
+        self.priority_events.clear();
         self.active_state = None;
     }
 
@@ -783,3 +1231,115 @@ impl<C: Send + 'static> AudioGraph<C> {
         }
     }
 }
+
+fn validate_channel_config<C: 'static>(
+    node: &dyn AudioNode<C>,
+    info: &AudioNodeInfo,
+    channel_config: ChannelConfig,
+) -> Result<(), NodeError> {
+    if channel_config.num_inputs < info.num_min_supported_inputs
+        || channel_config.num_inputs > info.num_max_supported_inputs
+        || channel_config.num_outputs < info.num_min_supported_outputs
+        || channel_config.num_outputs > info.num_max_supported_outputs
+    {
+        return Err(NodeError::InvalidChannelConfig {
+            channel_config,
+            node_info: *info,
+            msg: None,
+        });
+    }
+
+    if info.equal_num_ins_and_outs {
+        if channel_config.num_inputs != channel_config.num_outputs {
+            return Err(NodeError::InvalidChannelConfig {
+                channel_config,
+                node_info: *info,
+                msg: None,
+            });
+        }
+    }
+
+    if let Err(e) = node.channel_config_supported(channel_config) {
+        return Err(NodeError::InvalidChannelConfig {
+            channel_config,
+            node_info: *info,
+            msg: Some(e),
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ┌────┐  ┌───┐  ┌───┐  ┌───┐  ┌─────┐
+    // │ in ┼──► a ┼──► b ┼──► c ┼──► out │
+    // └────┘  └─┬─┘  └───┘  └───┘  └──▲──┘
+    //           └─────────────────────┘
+    #[test]
+    fn paths_between_nodes() {
+        let mut graph = AudioGraph::<()>::new(&FirewheelConfig {
+            num_graph_inputs: ChannelCount::MONO,
+            num_graph_outputs: ChannelCount::STEREO,
+            ..Default::default()
+        });
+        graph
+            .activate(
+                StreamInfo::default(),
+                Instant::now(),
+                Arc::new(AtomicU64::new(0)),
+            )
+            .unwrap();
+
+        let graph_in = graph.graph_in_node();
+        let graph_out = graph.graph_out_node();
+        let a = graph
+            .add_node(DummyAudioNode.into(), Some((1, 2).into()))
+            .unwrap();
+        let b = graph
+            .add_node(DummyAudioNode.into(), Some((1, 1).into()))
+            .unwrap();
+        let c = graph
+            .add_node(DummyAudioNode.into(), Some((1, 1).into()))
+            .unwrap();
+        let unconnected = graph
+            .add_node(DummyAudioNode.into(), Some((1, 1).into()))
+            .unwrap();
+
+        graph.connect(graph_in, 0, a, 0, false).unwrap();
+        graph.connect(a, 0, b, 0, false).unwrap();
+        graph.connect(b, 0, c, 0, false).unwrap();
+        graph.connect(c, 0, graph_out, 0, false).unwrap();
+        graph.connect(a, 1, graph_out, 1, false).unwrap();
+
+        assert_eq!(
+            graph.path_between(graph_in, graph_out),
+            Some(vec![graph_in, a, graph_out])
+        );
+        assert_eq!(
+            graph.longest_path_between(graph_in, graph_out),
+            Some(vec![graph_in, a, b, c, graph_out])
+        );
+
+        // Every step of a path follows an edge.
+        let path = graph.longest_path_between(a, graph_out).unwrap();
+        assert_eq!(path.first(), Some(&a));
+        assert_eq!(path.last(), Some(&graph_out));
+        for step in path.windows(2) {
+            assert!(graph
+                .edges()
+                .any(|e| e.src_node == step[0] && e.dst_node == step[1]));
+        }
+
+        assert_eq!(graph.path_between(b, b), Some(vec![b]));
+        assert_eq!(graph.longest_path_between(b, b), Some(vec![b]));
+
+        // Edges are only followed in the direction that audio flows.
+        assert_eq!(graph.path_between(c, a), None);
+        assert_eq!(graph.longest_path_between(c, a), None);
+        assert_eq!(graph.path_between(graph_in, unconnected), None);
+        assert_eq!(graph.longest_path_between(unconnected, graph_out), None);
+    }
+}
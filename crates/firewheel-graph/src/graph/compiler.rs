@@ -9,7 +9,7 @@ use super::NodeID;
 
 mod schedule;
 
-pub use schedule::{CompiledSchedule, ScheduleHeapData};
+pub use schedule::{CompiledSchedule, NewNodeProcessor, ScheduleHeapData};
 use schedule::{InBufferAssignment, OutBufferAssignment, ScheduledNode};
 
 pub struct NodeEntry<N> {
@@ -1,6 +1,7 @@
+use ahash::AHashSet;
 use arrayvec::ArrayVec;
 use smallvec::SmallVec;
-use std::fmt::Debug;
+use std::{fmt::Debug, ops::Range};
 
 use firewheel_core::{
     node::{AudioNodeProcessor, ProcessStatus},
@@ -8,6 +9,7 @@ use firewheel_core::{
 };
 
 use super::NodeID;
+use crate::processor::ProcessorEntry;
 
 /// A [ScheduledNode] is a [Node] that has been assigned buffers
 /// and a place in the schedule.
@@ -128,34 +130,73 @@ pub(super) struct OutBufferAssignment {
     pub generation: usize,
 }
 
-pub struct ScheduleHeapData<C> {
+/// A processor which is waiting to be sent to the audio thread.
+pub struct NewNodeProcessor<C: Send + 'static> {
+    pub node_id: NodeID,
+    pub entry: ProcessorEntry<C>,
+    /// Whether this processor takes over from a processor of the same node
+    /// which is already running in the audio thread (i.e. after a change in
+    /// sample rate), rather than being the first processor of a new node.
+    pub replaces_running: bool,
+}
+
+pub struct ScheduleHeapData<C: Send + 'static> {
     pub schedule: CompiledSchedule,
     pub nodes_to_remove: Vec<NodeID>,
     pub removed_node_processors: Vec<(NodeID, Box<dyn AudioNodeProcessor<C>>)>,
-    pub new_node_processors: Vec<(NodeID, Box<dyn AudioNodeProcessor<C>>)>,
+    pub new_node_processors: Vec<NewNodeProcessor<C>>,
+    /// The processors of the previous schedule which are removed or
+    /// replaced by this one, sorted by node ID. They keep processing the
+    /// previous schedule while it is crossfaded with this one.
+    pub outgoing_processors: Vec<(NodeID, ProcessorEntry<C>)>,
 }
 
-impl<C> ScheduleHeapData<C> {
+impl<C: Send + 'static> ScheduleHeapData<C> {
     pub fn new(
         schedule: CompiledSchedule,
         nodes_to_remove: Vec<NodeID>,
-        new_node_processors: Vec<(NodeID, Box<dyn AudioNodeProcessor<C>>)>,
+        new_node_processors: Vec<NewNodeProcessor<C>>,
     ) -> Self {
-        let num_nodes_to_remove = nodes_to_remove.len();
+        // Processors which are displaced by new ones are returned along
+        // with the removed ones, so make sure there is room for both in
+        // order to avoid allocating in the audio thread.
+        let max_removed_processors = nodes_to_remove.len() + new_node_processors.len();
 
         Self {
             schedule,
             nodes_to_remove,
-            removed_node_processors: Vec::with_capacity(num_nodes_to_remove),
+            removed_node_processors: Vec::with_capacity(max_removed_processors),
             new_node_processors,
+            outgoing_processors: Vec::new(),
         }
     }
+
+    /// Allocate everything the processor needs to crossfade from the
+    /// previous schedule to this one, so that nothing is allocated in the
+    /// audio thread.
+    pub fn prepare_crossfade(&mut self) {
+        // The processors of new and replaced nodes are only used by this
+        // schedule, so there is no need to record their outputs for the
+        // previous schedule.
+        let new_nodes: AHashSet<NodeID> =
+            self.new_node_processors.iter().map(|p| p.node_id).collect();
+        self.schedule
+            .prepare_crossfade(|node_id| !new_nodes.contains(&node_id));
+
+        self.outgoing_processors =
+            Vec::with_capacity(self.nodes_to_remove.len() + self.new_node_processors.len());
+    }
+
+    /// Whether or not [`ScheduleHeapData::prepare_crossfade`] was called.
+    pub fn can_crossfade(&self) -> bool {
+        self.schedule.recorded_outputs.is_some()
+    }
 }
 
-impl<C> Debug for ScheduleHeapData<C> {
+impl<C: Send + 'static> Debug for ScheduleHeapData<C> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let new_node_processors: Vec<NodeID> =
-            self.new_node_processors.iter().map(|(id, _)| *id).collect();
+            self.new_node_processors.iter().map(|p| p.node_id).collect();
 
         f.debug_struct("ScheduleHeapData")
             .field("schedule", &self.schedule)
@@ -173,6 +214,25 @@ pub struct CompiledSchedule {
     buffer_silence_flags: Vec<bool>,
     num_buffers: usize,
     max_block_samples: usize,
+
+    /// The outputs of the nodes in the last processed block, which are
+    /// only recorded while the previous schedule is being crossfaded with
+    /// this one.
+    recorded_outputs: Option<RecordedOutputs>,
+}
+
+/// A copy of the outputs of the nodes which exist in both the previous
+/// schedule and this one.
+///
+/// A node processor can only be processed once per block, so while two
+/// schedules are crossfaded, shared nodes are processed with the new
+/// schedule and their outputs are reused by the old schedule.
+struct RecordedOutputs {
+    /// For each slot of the node arena, the node whose outputs are recorded
+    /// and the range of recorded channels.
+    slots: Vec<Option<(NodeID, Range<usize>)>>,
+    buffers: Vec<f32>,
+    silence_flags: Vec<bool>,
 }
 
 impl Debug for CompiledSchedule {
@@ -206,6 +266,7 @@ impl CompiledSchedule {
             buffer_silence_flags: vec![false; num_buffers],
             num_buffers,
             max_block_samples,
+            recorded_outputs: None,
         }
     }
 
@@ -213,6 +274,36 @@ impl CompiledSchedule {
         self.max_block_samples
     }
 
+    /// Allocate the buffers used to record the outputs of the nodes for
+    /// which `should_record` returns `true`. See
+    /// [`CompiledSchedule::process_and_record`].
+    fn prepare_crossfade(&mut self, mut should_record: impl FnMut(NodeID) -> bool) {
+        let mut slots: Vec<Option<(NodeID, Range<usize>)>> = Vec::new();
+        let mut num_channels = 0;
+
+        for scheduled_node in self.schedule.iter() {
+            let num_outputs = scheduled_node.output_buffers.len();
+
+            if num_outputs == 0 || !should_record(scheduled_node.id) {
+                continue;
+            }
+
+            let slot = scheduled_node.id.idx.slot() as usize;
+            if slots.len() <= slot {
+                slots.resize(slot + 1, None);
+            }
+
+            slots[slot] = Some((scheduled_node.id, num_channels..num_channels + num_outputs));
+            num_channels += num_outputs;
+        }
+
+        self.recorded_outputs = Some(RecordedOutputs {
+            slots,
+            buffers: vec![0.0; num_channels * self.max_block_samples],
+            silence_flags: vec![true; num_channels],
+        });
+    }
+
     pub fn prepare_graph_inputs(
         &mut self,
         samples: usize,
@@ -293,9 +384,177 @@ impl CompiledSchedule {
         (read_outputs)(outputs.as_slice(), silence_mask);
     }
 
+    /// Copy the graph inputs which were prepared for `other` into this
+    /// schedule.
+    pub fn copy_graph_inputs_from(&mut self, other: &CompiledSchedule, samples: usize) {
+        let samples = samples.min(self.max_block_samples);
+
+        let graph_in_node = self.schedule.first().unwrap();
+        let other_graph_in_node = other.schedule.first().unwrap();
+
+        for (b, other_b) in graph_in_node
+            .output_buffers
+            .iter()
+            .zip(other_graph_in_node.output_buffers.iter())
+        {
+            let silent = other.buffer_silence_flags[other_b.buffer_index];
+
+            if !silent {
+                let start = other_b.buffer_index * other.max_block_samples;
+                buffer_slice_mut(
+                    &self.buffers,
+                    b.buffer_index,
+                    self.max_block_samples,
+                    samples,
+                )
+                .copy_from_slice(&other.buffers[start..start + samples]);
+            } else if !*silence_mask_mut(&mut self.buffer_silence_flags, b.buffer_index) {
+                buffer_slice_mut(
+                    &self.buffers,
+                    b.buffer_index,
+                    self.max_block_samples,
+                    samples,
+                )
+                .fill(0.0);
+            }
+
+            *silence_mask_mut(&mut self.buffer_silence_flags, b.buffer_index) = silent;
+        }
+    }
+
+    /// Mix the graph outputs of `other` into the graph outputs of this
+    /// schedule, where the outputs of this schedule are multiplied by
+    /// `fade_in` and the outputs of `other` by `fade_out` (one gain per
+    /// frame).
+    pub fn crossfade_graph_outputs_from(
+        &mut self,
+        other: &CompiledSchedule,
+        samples: usize,
+        fade_in: &[f32],
+        fade_out: &[f32],
+    ) {
+        let samples = samples.min(self.max_block_samples);
+        let fade_in = &fade_in[..samples];
+        let fade_out = &fade_out[..samples];
+
+        let graph_out_node = self.schedule.last().unwrap();
+        let other_graph_out_node = other.schedule.last().unwrap();
+
+        for (b, other_b) in graph_out_node
+            .input_buffers
+            .iter()
+            .zip(other_graph_out_node.input_buffers.iter())
+        {
+            let other_silent = other.buffer_silence_flags[other_b.buffer_index];
+            let silent = silence_mask_mut(&mut self.buffer_silence_flags, b.buffer_index);
+
+            if *silent && other_silent {
+                continue;
+            }
+
+            let out = buffer_slice_mut(
+                &self.buffers,
+                b.buffer_index,
+                self.max_block_samples,
+                samples,
+            );
+
+            if other_silent {
+                for (s, &g) in out.iter_mut().zip(fade_in) {
+                    *s *= g;
+                }
+                continue;
+            }
+
+            let start = other_b.buffer_index * other.max_block_samples;
+            let other_out = &other.buffers[start..start + samples];
+
+            if *silent {
+                for ((s, &other_s), &g) in out.iter_mut().zip(other_out).zip(fade_out) {
+                    *s = other_s * g;
+                }
+            } else {
+                for (((s, &other_s), &g_in), &g_out) in
+                    out.iter_mut().zip(other_out).zip(fade_in).zip(fade_out)
+                {
+                    *s = *s * g_in + other_s * g_out;
+                }
+            }
+
+            *silent = false;
+        }
+    }
+
+    /// Copy the outputs of the given node which were recorded in the last
+    /// call to [`CompiledSchedule::process_and_record`].
+    ///
+    /// Returns the silence mask of the outputs, or `None` if the outputs of
+    /// this node were not recorded.
+    pub fn copy_recorded_outputs(
+        &self,
+        node_id: NodeID,
+        outputs: &mut [&mut [f32]],
+    ) -> Option<SilenceMask> {
+        let recorded = self.recorded_outputs.as_ref()?;
+
+        let (recorded_id, channels) = recorded.slots.get(node_id.idx.slot() as usize)?.as_ref()?;
+        if *recorded_id != node_id {
+            return None;
+        }
+
+        let mut silence_mask = SilenceMask::NONE_SILENT;
+
+        for (i, (out, ch)) in outputs.iter_mut().zip(channels.clone()).enumerate() {
+            let samples = out.len().min(self.max_block_samples);
+
+            if recorded.silence_flags[ch] {
+                out[..samples].fill(0.0);
+                silence_mask.set_channel(i, true);
+            } else {
+                let start = ch * self.max_block_samples;
+                out[..samples].copy_from_slice(&recorded.buffers[start..start + samples]);
+            }
+        }
+
+        Some(silence_mask)
+    }
+
     pub fn process(
         &mut self,
         samples: usize,
+        process: impl FnMut(
+            NodeID,
+            SilenceMask,
+            SilenceMask,
+            &[&[f32]],
+            &mut [&mut [f32]],
+        ) -> ProcessStatus,
+    ) {
+        self.process_internal(samples, false, process);
+    }
+
+    /// The same as [`CompiledSchedule::process`], but the outputs of the
+    /// nodes which exist in the previous schedule are also recorded, so
+    /// that they can be read with [`CompiledSchedule::copy_recorded_outputs`]
+    /// while the previous schedule is being crossfaded with this one.
+    pub fn process_and_record(
+        &mut self,
+        samples: usize,
+        process: impl FnMut(
+            NodeID,
+            SilenceMask,
+            SilenceMask,
+            &[&[f32]],
+            &mut [&mut [f32]],
+        ) -> ProcessStatus,
+    ) {
+        self.process_internal(samples, true, process);
+    }
+
+    fn process_internal(
+        &mut self,
+        samples: usize,
+        record: bool,
         mut process: impl FnMut(
             NodeID,
             SilenceMask,
@@ -309,7 +568,11 @@ impl CompiledSchedule {
         let mut inputs: ArrayVec<&[f32], 64> = ArrayVec::new();
         let mut outputs: ArrayVec<&mut [f32], 64> = ArrayVec::new();
 
-        for scheduled_node in self.schedule.iter() {
+        // Skip the graph in node. Its output buffers have already been filled
+        // in by [`CompiledSchedule::prepare_graph_inputs`], and its processor
+        // (a dummy node) returns `ProcessStatus::NoOutputsModified`, which
+        // would clear them.
+        for scheduled_node in self.schedule.iter().skip(1) {
             let mut in_silence_mask = SilenceMask::NONE_SILENT;
             let mut out_silence_mask = SilenceMask::NONE_SILENT;
 
@@ -386,6 +649,48 @@ impl CompiledSchedule {
                     }
                 }
             }
+
+            if record {
+                if let Some(recorded) = &mut self.recorded_outputs {
+                    recorded.record(
+                        scheduled_node,
+                        &self.buffers,
+                        &self.buffer_silence_flags,
+                        self.max_block_samples,
+                        samples,
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl RecordedOutputs {
+    fn record(
+        &mut self,
+        scheduled_node: &ScheduledNode,
+        buffers: &[f32],
+        buffer_silence_flags: &[bool],
+        max_block_samples: usize,
+        samples: usize,
+    ) {
+        let Some(Some((node_id, channels))) = self.slots.get(scheduled_node.id.idx.slot() as usize)
+        else {
+            return;
+        };
+        if *node_id != scheduled_node.id {
+            return;
+        }
+
+        for (b, ch) in scheduled_node.output_buffers.iter().zip(channels.clone()) {
+            let silent = buffer_silence_flags[b.buffer_index];
+            self.silence_flags[ch] = silent;
+
+            if !silent {
+                let src = b.buffer_index * max_block_samples;
+                let dst = ch * max_block_samples;
+                self.buffers[dst..dst + samples].copy_from_slice(&buffers[src..src + samples]);
+            }
         }
     }
 }
@@ -496,6 +801,40 @@ mod tests {
         verify_edge(edge0, &graph, &schedule);
     }
 
+    // The graph inputs must reach the graph outputs, even though the
+    // processor of the graph in node does not modify its outputs.
+    #[test]
+    fn graph_inputs_pass_through() {
+        let mut graph = AudioGraph::<()>::new(&FirewheelConfig {
+            num_graph_inputs: ChannelCount::MONO,
+            num_graph_outputs: ChannelCount::MONO,
+            ..Default::default()
+        });
+        graph
+            .activate(
+                StreamInfo::default(),
+                Instant::now(),
+                Arc::new(AtomicU64::new(0)),
+            )
+            .unwrap();
+
+        let graph_in = graph.graph_in_node();
+        let graph_out = graph.graph_out_node();
+        graph.connect(graph_in, 0, graph_out, 0, false).unwrap();
+
+        let mut schedule = graph.compile_internal(128).unwrap();
+
+        schedule.prepare_graph_inputs(128, 1, |inputs| {
+            inputs[0].fill(0.5);
+            SilenceMask::NONE_SILENT
+        });
+        schedule.process(128, |_, _, _, _, _| ProcessStatus::NoOutputsModified);
+        schedule.read_graph_outputs(128, 1, |outputs, silence_mask| {
+            assert!(!silence_mask.is_channel_silent(0));
+            assert!(outputs[0].iter().all(|&s| s == 0.5));
+        });
+    }
+
     // Graph compile test 1:
     //
     //              ┌───┐  ┌───┐
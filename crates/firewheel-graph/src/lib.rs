@@ -1,8 +1,21 @@
 pub mod backend;
 pub mod basic_nodes;
 mod context;
+mod denormal;
 pub mod error;
 pub mod graph;
+mod meter;
 pub mod processor;
+mod spsc;
+#[cfg(feature = "metrics")]
+mod trace;
+#[cfg(feature = "wav")]
+pub mod wav;
 
-pub use context::{FirewheelConfig, FirewheelGraphCtx, UpdateStatus};
+pub use context::{
+    BlockSize, DspLoad, FirewheelConfig, FirewheelGraphCtx, GlitchEvent, GlitchKind,
+    RecompileStrategy, RunawayProtection, UpdateStatus, VoiceCulling, GLITCH_LOG_CAPACITY,
+};
+
+#[cfg(feature = "metrics")]
+pub use trace::TraceEvent;
@@ -0,0 +1,64 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use atomic_float::AtomicF32;
+use firewheel_core::node::ProcessStatus;
+
+/// The peak level of each output channel of a node, shared between the
+/// audio graph and the processor.
+///
+/// Each node has one of these, but the processor only measures the peaks
+/// while metering is enabled for the node.
+pub(crate) struct NodeMeter {
+    enabled: AtomicBool,
+    peaks: Box<[AtomicF32]>,
+}
+
+impl NodeMeter {
+    pub fn new(num_channels: usize) -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            peaks: (0..num_channels).map(|_| AtomicF32::new(0.0)).collect(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+
+        if !enabled {
+            for peak in self.peaks.iter() {
+                peak.store(0.0, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Measure the peak level of each output channel of a processed block.
+    pub fn update(&self, outputs: &[&mut [f32]], status: &ProcessStatus, samples: usize) {
+        let ProcessStatus::OutputsModified { out_silence_mask } = status else {
+            return;
+        };
+
+        for (ch_i, (out, peak)) in outputs.iter().zip(self.peaks.iter()).enumerate() {
+            if ch_i < 64 && out_silence_mask.is_channel_silent(ch_i) {
+                continue;
+            }
+
+            let block_peak = out[..samples]
+                .iter()
+                .fold(0.0f32, |peak, s| peak.max(s.abs()));
+            peak.fetch_max(block_peak, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the peak level of each channel since the last call, and
+    /// resets them.
+    pub fn take_peaks(&self) -> Vec<f32> {
+        self.peaks
+            .iter()
+            .map(|peak| peak.swap(0.0, Ordering::Relaxed))
+            .collect()
+    }
+}
@@ -1,38 +1,94 @@
 use std::{
     ops::Range,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
         Arc,
     },
     time::Instant,
 };
 
+use arrayvec::ArrayVec;
+use atomic_float::AtomicF32;
 use thunderdome::Arena;
 
-use crate::graph::{NodeID, ScheduleHeapData};
+use crate::{
+    context::is_culled,
+    denormal::{FlushDenormalsGuard, PreserveDenormalsGuard},
+    graph::{NewNodeProcessor, NodeID, ScheduleHeapData},
+    meter::NodeMeter,
+    spsc, DspLoad, FirewheelConfig, GlitchEvent, GlitchKind, RunawayProtection, VoiceCulling,
+};
+
+#[cfg(feature = "metrics")]
+use crate::trace::TraceRecorder;
 use firewheel_core::{
-    clock::{ClockSamples, ClockSeconds},
-    node::{AudioNodeProcessor, ProcInfo, ProcessStatus, StreamStatus},
+    clock::{ClockSamples, ClockSeconds, EventDelay},
+    node::{AudioNodeInfo, AudioNodeProcessor, ProcInfo, ProcessStatus, StreamStatus},
+    param::smoother::ParamSmoother,
+    util::{db_to_gain_clamped_neg_100_db, FadeCurve},
     SilenceMask, StreamInfo,
 };
 
+/// How often the summary of the DSP load in [`SharedProcessorState`] is
+/// updated.
+const DSP_LOAD_REPORT_INTERVAL_SECS: f64 = 0.1;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FirewheelProcessorStatus {
     Ok,
     /// If this is returned, then the [`FirewheelProcessor`] must be dropped.
     DropProcessor,
+    /// The length of the input or output buffer did not match
+    /// `samples * num_channels`.
+    ///
+    /// Nothing was processed and the output buffer was filled with silence.
+    /// The processor can still be used for the next block.
+    InvalidBuffer,
+}
+
+/// The settings for capturing the tail of effects such as reverbs and
+/// delays when rendering offline with [`FirewheelProcessor::render_offline`].
+///
+/// After the input has been rendered, rendering continues with silent input
+/// until a whole block of output falls below
+/// [`TailCapture::silence_threshold_db`], or until
+/// [`TailCapture::max_tail_secs`] of tail has been rendered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TailCapture {
+    /// Once the peak level of a block of output (in decibels) is at or
+    /// below this, the tail is considered to have decayed.
+    ///
+    /// By default this is set to `-80.0`.
+    pub silence_threshold_db: f32,
+    /// The maximum length of the tail in seconds. This stops rendering of
+    /// tails which never decay (i.e. a delay with full feedback).
+    ///
+    /// By default this is set to `30.0`.
+    pub max_tail_secs: f32,
+}
+
+impl Default for TailCapture {
+    fn default() -> Self {
+        Self {
+            silence_threshold_db: -80.0,
+            max_tail_secs: 30.0,
+        }
+    }
 }
 
 pub struct FirewheelProcessor<C: Send + 'static> {
-    nodes: Arena<Box<dyn AudioNodeProcessor<C>>>,
+    nodes: Arena<ProcessorEntry<C>>,
     schedule_data: Option<Box<ScheduleHeapData<C>>>,
+    /// An old schedule which could not be returned to the context yet
+    /// because the channel was full.
+    schedule_to_return: Option<Box<ScheduleHeapData<C>>>,
+    /// A message with a node state buffer which could not be sent to the
+    /// context yet because the channel was full.
+    state_to_return: Option<ProcessorToContextMsg<C>>,
     user_cx: Option<C>,
 
-    // TODO: Do research on whether `rtrb` is compatible with
-    // webassembly. If not, use conditional compilation to
-    // use a different channel type when targeting webassembly.
-    from_graph_rx: rtrb::Consumer<ContextToProcessorMsg<C>>,
-    to_graph_tx: rtrb::Producer<ProcessorToContextMsg<C>>,
+    from_graph_rx: spsc::Consumer<(u64, ContextToProcessorMsg<C>)>,
+    to_graph_tx: spsc::Producer<ProcessorToContextMsg<C>>,
 
     clock_samples_shared: Arc<AtomicU64>,
     clock_samples: ClockSamples,
@@ -40,25 +96,86 @@ pub struct FirewheelProcessor<C: Send + 'static> {
     main_to_internal_clock_offset: Option<ClockSeconds>,
 
     running: bool,
+    shared_state: Arc<SharedProcessorState>,
+    last_msg_seq: u64,
     stream_info: StreamInfo,
     sample_rate_recip: f64,
+    output_safety_limit: Option<f32>,
+    schedule_crossfade: ScheduleCrossfade<C>,
+    input_gain: ParamSmoother,
+    /// The fade of each input channel, used to fade channels in and out
+    /// when they are muted.
+    input_channel_fades: Vec<Fade>,
+    input_mute_fade: FadeParams,
+    bypass_fade: FadeParams,
+    /// The physical output channel of each logical output channel of the
+    /// graph, or `None` to use the same order.
+    output_channel_map: Option<ArrayVec<u8, 64>>,
+    /// The buffers used to process the graph in blocks of a fixed size, or
+    /// `None` if the graph is processed in blocks of variable size.
+    fixed_block: Option<FixedBlockBuffers>,
+    dsp_load: DspLoadMeter,
+    runaway_monitor: Option<RunawayMonitor>,
+    voice_culling: Option<VoiceCulling>,
+    /// Whether or not the smoothed DSP load is high enough for low-priority
+    /// nodes to be culled. See [`VoiceCulling`].
+    under_cpu_pressure: bool,
+    /// The number of schedules which have been swapped in but not yet
+    /// reported to the context.
+    unreported_schedule_swaps: usize,
+    #[cfg(feature = "metrics")]
+    trace: TraceRecorder,
+}
+
+/// The settings a new [`FirewheelProcessor`] starts with, taken from the
+/// [`FirewheelConfig`] and the current state of the context.
+pub(crate) struct ProcessorSettings<'a> {
+    pub config: &'a FirewheelConfig,
+    pub stream_info: StreamInfo,
+    pub node_capacity: usize,
+    pub main_thread_clock_start_instant: Instant,
+    pub input_raw_gain: f32,
+    /// A bit for each stream input channel which is muted.
+    pub muted_input_channels: u64,
+    pub output_channel_map: Option<ArrayVec<u8, 64>>,
 }
 
 impl<C: Send + 'static> FirewheelProcessor<C> {
     pub(crate) fn new(
-        from_graph_rx: rtrb::Consumer<ContextToProcessorMsg<C>>,
-        to_graph_tx: rtrb::Producer<ProcessorToContextMsg<C>>,
+        from_graph_rx: spsc::Consumer<(u64, ContextToProcessorMsg<C>)>,
+        to_graph_tx: spsc::Producer<ProcessorToContextMsg<C>>,
         clock_samples_shared: Arc<AtomicU64>,
-        main_thread_clock_start_instant: Instant,
-        node_capacity: usize,
-        stream_info: StreamInfo,
+        shared_state: Arc<SharedProcessorState>,
+        settings: ProcessorSettings,
+        #[cfg(feature = "metrics")] trace: TraceRecorder,
         user_cx: C,
     ) -> Self {
+        let ProcessorSettings {
+            config,
+            stream_info,
+            node_capacity,
+            main_thread_clock_start_instant,
+            input_raw_gain,
+            muted_input_channels,
+            output_channel_map,
+        } = settings;
+
         let sample_rate_recip = f64::from(stream_info.sample_rate).recip();
+        let input_gain = ParamSmoother::new(
+            input_raw_gain,
+            stream_info.sample_rate,
+            stream_info.max_block_samples as usize,
+            Default::default(),
+        );
+        let input_channel_fades = (0..stream_info.num_stream_in_channels as usize)
+            .map(|i| Fade::new(muted_input_channels & (1 << i) == 0))
+            .collect();
 
         Self {
             nodes: Arena::with_capacity(node_capacity * 2),
             schedule_data: None,
+            schedule_to_return: None,
+            state_to_return: None,
             user_cx: Some(user_cx),
             from_graph_rx,
             to_graph_tx,
@@ -67,8 +184,49 @@ impl<C: Send + 'static> FirewheelProcessor<C> {
             main_thread_clock_start_instant,
             main_to_internal_clock_offset: None,
             running: true,
+            shared_state,
+            last_msg_seq: 0,
             stream_info,
             sample_rate_recip,
+            output_safety_limit: config.output_safety_limit,
+            schedule_crossfade: ScheduleCrossfade {
+                fade_secs: config.schedule_fade_secs.max(0.0),
+                fade_samples: (config.schedule_fade_secs.max(0.0) * stream_info.sample_rate as f32)
+                    .round() as usize,
+                active_fade_samples: 0,
+                frames: 0,
+                curve: config.fade_curve,
+                old_schedule: None,
+                queued_schedule: None,
+                fade_in_gains: vec![0.0; stream_info.max_block_samples as usize],
+                fade_out_gains: vec![0.0; stream_info.max_block_samples as usize],
+            },
+            input_gain,
+            input_channel_fades,
+            input_mute_fade: FadeParams::new(
+                INPUT_MUTE_FADE_SECS,
+                stream_info.sample_rate,
+                config.fade_curve,
+            ),
+            bypass_fade: FadeParams::new(
+                config.bypass_fade_secs,
+                stream_info.sample_rate,
+                config.fade_curve,
+            ),
+            output_channel_map,
+            fixed_block: config
+                .fixed_block_samples
+                .filter(|&n| n > 0)
+                .map(|_| FixedBlockBuffers::new(&stream_info)),
+            dsp_load: DspLoadMeter::default(),
+            runaway_monitor: config
+                .runaway_protection
+                .map(|c| RunawayMonitor::new(c, config.fade_curve)),
+            voice_culling: config.voice_culling,
+            under_cpu_pressure: false,
+            unreported_schedule_swaps: 0,
+            #[cfg(feature = "metrics")]
+            trace,
         }
     }
 
@@ -76,6 +234,11 @@ impl<C: Send + 'static> FirewheelProcessor<C> {
     ///
     /// If this returns [`ProcessStatus::DropProcessor`], then this
     /// [`FirewheelProcessor`] must be dropped.
+    ///
+    /// If the length of `input` is not `samples * num_in_channels` or the
+    /// length of `output` is not `samples * num_out_channels`, then this
+    /// returns [`FirewheelProcessorStatus::InvalidBuffer`] instead of
+    /// panicking.
     pub fn process_interleaved(
         &mut self,
         input: &[f32],
@@ -85,6 +248,293 @@ impl<C: Send + 'static> FirewheelProcessor<C> {
         samples: usize,
         internal_clock_seconds: ClockSeconds,
         stream_status: StreamStatus,
+    ) -> FirewheelProcessorStatus {
+        if input.len() != samples * num_in_channels || output.len() != samples * num_out_channels {
+            output.fill(0.0);
+            return FirewheelProcessorStatus::InvalidBuffer;
+        }
+
+        self.process_internal(
+            StreamInput::Interleaved {
+                buffer: input,
+                num_channels: num_in_channels,
+            },
+            StreamOutput::Interleaved {
+                buffer: output,
+                num_channels: num_out_channels,
+            },
+            samples,
+            internal_clock_seconds,
+            stream_status,
+        )
+    }
+
+    /// Process the given buffers of audio data, where the input is
+    /// interleaved and the output is planar (one buffer per channel).
+    ///
+    /// This is the same as [`FirewheelProcessor::process_interleaved`], but
+    /// the output of the graph is copied straight into the output channels
+    /// without interleaving it first.
+    ///
+    /// If the length of `input` is not `samples * num_in_channels` or any of
+    /// the output channels is shorter than `samples`, then this returns
+    /// [`FirewheelProcessorStatus::InvalidBuffer`] instead of panicking. Only
+    /// the first `samples` frames of each output channel are written.
+    pub fn process_interleaved_in_planar_out(
+        &mut self,
+        input: &[f32],
+        output: &mut [&mut [f32]],
+        num_in_channels: usize,
+        samples: usize,
+        internal_clock_seconds: ClockSeconds,
+        stream_status: StreamStatus,
+    ) -> FirewheelProcessorStatus {
+        if input.len() != samples * num_in_channels || output.iter().any(|ch| ch.len() < samples) {
+            for ch in output.iter_mut() {
+                ch.fill(0.0);
+            }
+            return FirewheelProcessorStatus::InvalidBuffer;
+        }
+
+        self.process_internal(
+            StreamInput::Interleaved {
+                buffer: input,
+                num_channels: num_in_channels,
+            },
+            StreamOutput::Planar(output),
+            samples,
+            internal_clock_seconds,
+            stream_status,
+        )
+    }
+
+    /// Process the given buffers of audio data, where the input is planar
+    /// (one buffer per channel) and the output is interleaved.
+    ///
+    /// This is the same as [`FirewheelProcessor::process_interleaved`], but
+    /// the input channels are copied straight into the graph without
+    /// de-interleaving them first.
+    ///
+    /// If any of the input channels is shorter than `samples` or the length
+    /// of `output` is not `samples * num_out_channels`, then this returns
+    /// [`FirewheelProcessorStatus::InvalidBuffer`] instead of panicking. Only
+    /// the first `samples` frames of each input channel are read.
+    pub fn process_planar_in_interleaved_out(
+        &mut self,
+        input: &[&[f32]],
+        output: &mut [f32],
+        num_out_channels: usize,
+        samples: usize,
+        internal_clock_seconds: ClockSeconds,
+        stream_status: StreamStatus,
+    ) -> FirewheelProcessorStatus {
+        if input.iter().any(|ch| ch.len() < samples) || output.len() != samples * num_out_channels {
+            output.fill(0.0);
+            return FirewheelProcessorStatus::InvalidBuffer;
+        }
+
+        self.process_internal(
+            StreamInput::Planar(input),
+            StreamOutput::Interleaved {
+                buffer: output,
+                num_channels: num_out_channels,
+            },
+            samples,
+            internal_clock_seconds,
+            stream_status,
+        )
+    }
+
+    /// Render the given interleaved input offline and return the
+    /// interleaved output.
+    ///
+    /// The input is processed in blocks of up to
+    /// [`StreamInfo::max_block_samples`] frames, and the internal clock is
+    /// advanced by the number of frames processed rather than by the
+    /// system time.
+    ///
+    /// * `num_frames` - The number of frames to render (not counting the
+    ///   tail). If `input` is shorter than this (i.e. if the graph has no
+    ///   inputs and `input` is empty), then the rest of the input is
+    ///   silent. Any input past this is ignored.
+    ///
+    /// If `tail_capture` is `Some`, then rendering continues after
+    /// `num_frames` until the tail of the output has decayed, so that the
+    /// tails of effects such as reverbs and delays are not cut off. See
+    /// [`TailCapture`] for more details.
+    ///
+    /// Rendering stops early if the processor needs to be dropped.
+    pub fn render_offline(
+        &mut self,
+        input: &[f32],
+        num_in_channels: usize,
+        num_out_channels: usize,
+        num_frames: usize,
+        tail_capture: Option<TailCapture>,
+    ) -> Vec<f32> {
+        let block_frames = (self.stream_info.max_block_samples as usize).max(1);
+        let input_frames = input.len().checked_div(num_in_channels).unwrap_or(0);
+        let silent_input = vec![0.0; block_frames * num_in_channels];
+
+        let mut output = Vec::with_capacity(num_frames * num_out_channels);
+        let mut frames_rendered = 0;
+
+        while frames_rendered < num_frames {
+            let mut frames = block_frames.min(num_frames - frames_rendered);
+
+            let in_block = if frames_rendered < input_frames {
+                // Don't let a block straddle the end of the input.
+                frames = frames.min(input_frames - frames_rendered);
+                &input[frames_rendered * num_in_channels
+                    ..(frames_rendered + frames) * num_in_channels]
+            } else {
+                &silent_input[..frames * num_in_channels]
+            };
+
+            if !self.render_offline_block(
+                in_block,
+                &mut output,
+                num_in_channels,
+                num_out_channels,
+                frames,
+                frames_rendered,
+            ) {
+                return output;
+            }
+
+            frames_rendered += frames;
+        }
+
+        let Some(tail_capture) = tail_capture else {
+            return output;
+        };
+
+        let threshold = db_to_gain_clamped_neg_100_db(tail_capture.silence_threshold_db);
+        let max_tail_frames = (f64::from(tail_capture.max_tail_secs.max(0.0))
+            * f64::from(self.stream_info.sample_rate))
+        .round() as usize;
+
+        let mut tail_frames = 0;
+        while tail_frames < max_tail_frames {
+            let frames = block_frames.min(max_tail_frames - tail_frames);
+            let block_start = output.len();
+
+            if !self.render_offline_block(
+                &silent_input[..frames * num_in_channels],
+                &mut output,
+                num_in_channels,
+                num_out_channels,
+                frames,
+                frames_rendered,
+            ) {
+                break;
+            }
+
+            frames_rendered += frames;
+            tail_frames += frames;
+
+            let peak = output[block_start..]
+                .iter()
+                .fold(0.0f32, |peak, s| peak.max(s.abs()));
+            if peak <= threshold {
+                break;
+            }
+        }
+
+        output
+    }
+
+    /// Returns `false` if rendering should stop.
+    fn render_offline_block(
+        &mut self,
+        input: &[f32],
+        output: &mut Vec<f32>,
+        num_in_channels: usize,
+        num_out_channels: usize,
+        frames: usize,
+        frames_rendered: usize,
+    ) -> bool {
+        let block_start = output.len();
+        output.resize(block_start + frames * num_out_channels, 0.0);
+
+        let status = self.process_interleaved(
+            input,
+            &mut output[block_start..],
+            num_in_channels,
+            num_out_channels,
+            frames,
+            ClockSeconds(frames_rendered as f64 * self.sample_rate_recip),
+            StreamStatus::empty(),
+        );
+
+        status != FirewheelProcessorStatus::DropProcessor
+    }
+
+    fn process_internal(
+        &mut self,
+        input: StreamInput,
+        output: StreamOutput,
+        samples: usize,
+        internal_clock_seconds: ClockSeconds,
+        stream_status: StreamStatus,
+    ) -> FirewheelProcessorStatus {
+        self.report_glitches(stream_status, internal_clock_seconds);
+
+        if let Some(mut fixed_block) = self.fixed_block.take() {
+            let status = self.process_fixed_blocks(
+                &mut fixed_block,
+                input,
+                output,
+                samples,
+                internal_clock_seconds,
+                stream_status,
+            );
+            self.fixed_block = Some(fixed_block);
+
+            return status;
+        }
+
+        self.process_blocks(
+            input,
+            output,
+            samples,
+            internal_clock_seconds,
+            stream_status,
+        )
+    }
+
+    /// Send a [`GlitchEvent`] to the context for each glitch reported by
+    /// the audio backend.
+    fn report_glitches(
+        &mut self,
+        stream_status: StreamStatus,
+        internal_clock_seconds: ClockSeconds,
+    ) {
+        for (flag, kind) in [
+            (StreamStatus::INPUT_OVERFLOW, GlitchKind::InputOverflow),
+            (StreamStatus::OUTPUT_UNDERFLOW, GlitchKind::OutputUnderflow),
+        ] {
+            if stream_status.contains(flag) {
+                // If the channel is full then just drop the event.
+                let _ = self
+                    .to_graph_tx
+                    .push(ProcessorToContextMsg::Glitch(GlitchEvent {
+                        stream_time_secs: internal_clock_seconds.0,
+                        kind,
+                    }));
+            }
+        }
+    }
+
+    /// Process the given buffers in blocks of up to
+    /// [`StreamInfo::max_block_samples`] frames.
+    fn process_blocks(
+        &mut self,
+        input: StreamInput,
+        mut output: StreamOutput,
+        samples: usize,
+        internal_clock_seconds: ClockSeconds,
+        stream_status: StreamStatus,
     ) -> FirewheelProcessorStatus {
         self.clock_samples_shared
             .store(self.clock_samples.0, Ordering::SeqCst);
@@ -108,43 +558,66 @@ impl<C: Send + 'static> FirewheelProcessor<C> {
         let mut clock_seconds = internal_clock_seconds + main_to_internal_clock_offset;
 
         self.poll_messages();
+        self.report_schedule_swaps();
 
         if !self.running {
-            output.fill(0.0);
+            output.silence(0..samples);
             return FirewheelProcessorStatus::DropProcessor;
         }
 
         if self.schedule_data.is_none() || samples == 0 {
-            output.fill(0.0);
+            output.silence(0..samples);
+            self.shared_state
+                .output_silent
+                .store(true, Ordering::Relaxed);
             return FirewheelProcessorStatus::Ok;
         };
 
-        assert_eq!(input.len(), samples * num_in_channels);
-        assert_eq!(output.len(), samples * num_out_channels);
+        let num_in_channels = input.num_channels();
+        let num_out_channels = output.num_channels();
 
+        let mut output_silent = true;
         let mut samples_processed = 0;
         while samples_processed < samples {
             let block_samples =
                 (samples - samples_processed).min(self.stream_info.max_block_samples as usize);
+            let frames = samples_processed..samples_processed + block_samples;
+
+            // Poll messages before the graph inputs are prepared so that a
+            // new schedule is never processed with inputs that were prepared
+            // for the old one.
+            if samples_processed > 0 {
+                self.poll_messages();
+            }
 
             // Prepare graph input buffers.
-            self.schedule_data
-                .as_mut()
-                .unwrap()
-                .schedule
-                .prepare_graph_inputs(
-                    block_samples,
-                    num_in_channels,
-                    |channels: &mut [&mut [f32]]| -> SilenceMask {
-                        firewheel_core::util::deinterleave(
-                            channels,
-                            &input[samples_processed * num_in_channels
-                                ..(samples_processed + block_samples) * num_in_channels],
-                            num_in_channels,
-                            true,
-                        )
-                    },
-                );
+            let input_gain = &mut self.input_gain;
+            let input_channel_fades = &mut self.input_channel_fades;
+            let input_mute_fade = self.input_mute_fade;
+            let schedule_data = self.schedule_data.as_mut().unwrap();
+            schedule_data.schedule.prepare_graph_inputs(
+                block_samples,
+                num_in_channels,
+                |channels: &mut [&mut [f32]]| -> SilenceMask {
+                    let mut silence_mask = input.read_block(frames.clone(), channels);
+
+                    apply_input_gain(input_gain, channels, silence_mask, block_samples);
+                    apply_input_channel_mutes(
+                        input_channel_fades,
+                        &input_mute_fade,
+                        channels,
+                        &mut silence_mask,
+                        block_samples,
+                    );
+
+                    silence_mask
+                },
+            );
+            if let Some(old_schedule_data) = &mut self.schedule_crossfade.old_schedule {
+                old_schedule_data
+                    .schedule
+                    .copy_graph_inputs_from(&schedule_data.schedule, block_samples);
+            }
 
             let next_clock_seconds =
                 clock_seconds + ClockSeconds(block_samples as f64 * self.sample_rate_recip);
@@ -156,7 +629,10 @@ impl<C: Send + 'static> FirewheelProcessor<C> {
                 stream_status,
             );
 
+            self.apply_schedule_crossfade(block_samples);
+
             // Copy the output of the graph to the output buffer.
+            let channel_map = self.output_channel_map.as_deref();
             self.schedule_data
                 .as_mut()
                 .unwrap()
@@ -165,19 +641,25 @@ impl<C: Send + 'static> FirewheelProcessor<C> {
                     block_samples,
                     num_out_channels,
                     |channels: &[&[f32]], silence_mask| {
-                        firewheel_core::util::interleave(
-                            channels,
-                            &mut output[samples_processed * num_out_channels
-                                ..(samples_processed + block_samples) * num_out_channels],
-                            num_out_channels,
-                            Some(silence_mask),
-                        );
+                        output_silent &= silence_mask.all_channels_silent(channels.len());
+
+                        output.write_block(frames.clone(), channels, silence_mask, channel_map);
                     },
                 );
 
+            if let Some(monitor) = &mut self.runaway_monitor {
+                if let Some(peak) = monitor.process(&mut output, frames.clone()) {
+                    let _ = self
+                        .to_graph_tx
+                        .push(ProcessorToContextMsg::RunawayDetected {
+                            peak_db: firewheel_core::util::gain_to_db_clamped_neg_100_db(peak),
+                        });
+                }
+            }
+
             if !self.running {
                 if samples_processed < samples {
-                    output[samples_processed * num_out_channels..].fill(0.0);
+                    output.silence(samples_processed..samples);
                 }
                 break;
             }
@@ -187,6 +669,19 @@ impl<C: Send + 'static> FirewheelProcessor<C> {
             clock_seconds = next_clock_seconds;
         }
 
+        self.shared_state
+            .output_silent
+            .store(output_silent, Ordering::Relaxed);
+
+        // A schedule may have been swapped in at the end of a fade.
+        self.report_schedule_swaps();
+
+        // Run the safety stage once over the final output buffer (including
+        // any portion that was zeroed because the processor was stopped).
+        if let Some(ceiling) = self.output_safety_limit {
+            output.apply_safety_limit(samples, ceiling);
+        }
+
         if self.running {
             FirewheelProcessorStatus::Ok
         } else {
@@ -194,116 +689,4517 @@ impl<C: Send + 'static> FirewheelProcessor<C> {
         }
     }
 
+    /// Process the graph in blocks of a fixed size. The input is buffered
+    /// until a whole block is available, so the output lags behind the input
+    /// by one block.
+    fn process_fixed_blocks(
+        &mut self,
+        fixed_block: &mut FixedBlockBuffers,
+        input: StreamInput,
+        mut output: StreamOutput,
+        samples: usize,
+        internal_clock_seconds: ClockSeconds,
+        stream_status: StreamStatus,
+    ) -> FirewheelProcessorStatus {
+        let block_samples = self.stream_info.max_block_samples as usize;
+
+        let mut frames_done = 0;
+        while frames_done < samples {
+            let pos = fixed_block.pos;
+            let frames = (samples - frames_done).min(block_samples - pos);
+            let stream_frames = frames_done..frames_done + frames;
+
+            // The time of the first frame of the block which is being
+            // collected, which may have been in a previous buffer.
+            let block_clock_seconds = internal_clock_seconds
+                + ClockSeconds((frames_done as f64 - pos as f64) * self.sample_rate_recip);
+
+            {
+                let mut in_channels: ArrayVec<&mut [f32], 64> = fixed_block
+                    .input
+                    .iter_mut()
+                    .map(|ch| &mut ch[pos..pos + frames])
+                    .collect();
+                input.read_block(stream_frames.clone(), &mut in_channels);
+
+                // The output channel map was already applied when the block
+                // was processed.
+                let out_channels: ArrayVec<&[f32], 64> = fixed_block
+                    .output
+                    .iter()
+                    .map(|ch| &ch[pos..pos + frames])
+                    .collect();
+                output.write_block(
+                    stream_frames,
+                    &out_channels,
+                    fixed_block.out_silence_mask,
+                    None,
+                );
+            }
+
+            fixed_block.pos += frames;
+            frames_done += frames;
+
+            if fixed_block.pos < block_samples {
+                continue;
+            }
+            fixed_block.pos = 0;
+
+            let in_channels: ArrayVec<&[f32], 64> =
+                fixed_block.input.iter().map(|ch| ch.as_slice()).collect();
+            let mut out_channels: ArrayVec<&mut [f32], 64> = fixed_block
+                .output
+                .iter_mut()
+                .map(|ch| ch.as_mut_slice())
+                .collect();
+
+            let status = self.process_blocks(
+                StreamInput::Planar(&in_channels),
+                StreamOutput::Planar(&mut out_channels),
+                block_samples,
+                block_clock_seconds,
+                stream_status,
+            );
+
+            fixed_block.out_silence_mask =
+                if self.shared_state.output_silent.load(Ordering::Relaxed) {
+                    SilenceMask::new_all_silent(out_channels.len())
+                } else {
+                    SilenceMask::NONE_SILENT
+                };
+
+            if status == FirewheelProcessorStatus::DropProcessor {
+                output.silence(frames_done..samples);
+                return status;
+            }
+        }
+
+        FirewheelProcessorStatus::Ok
+    }
+
+    /// Apply all pending messages from the context.
+    ///
+    /// Messages are always applied strictly in the order they were sent
+    /// (FIFO), so for example a bypass event sent after a new schedule will
+    /// always see the nodes in that schedule.
     fn poll_messages(&mut self) {
-        while let Ok(msg) = self.from_graph_rx.pop() {
+        if !self.return_old_schedule() || !self.return_state_buffer() {
+            return;
+        }
+
+        if self.schedule_crossfade.queued_schedule.is_some() {
+            if self.schedule_crossfade.is_active() {
+                return;
+            }
+
+            let (new_schedule_data, fade_samples) =
+                self.schedule_crossfade.queued_schedule.take().unwrap();
+            if !self.apply_new_schedule(new_schedule_data, fade_samples) {
+                return;
+            }
+        }
+
+        while let Ok((seq, msg)) = self.from_graph_rx.pop() {
+            debug_assert_eq!(seq, self.last_msg_seq + 1);
+            self.last_msg_seq = seq;
+
             match msg {
-                ContextToProcessorMsg::NewSchedule(mut new_schedule_data) => {
-                    assert_eq!(
-                        new_schedule_data.schedule.max_block_samples(),
-                        self.stream_info.max_block_samples as usize
-                    );
+                ContextToProcessorMsg::NewSchedule {
+                    schedule_data: new_schedule_data,
+                    fade_samples,
+                } => {
+                    if self.schedule_crossfade.is_active() {
+                        // Only two schedules can be crossfaded at a time.
+                        // Stop polling messages until the crossfade is done
+                        // so that the messages after this one see the nodes
+                        // in it.
+                        self.schedule_crossfade.queued_schedule =
+                            Some((new_schedule_data, fade_samples));
+                        self.shared_state
+                            .applied_msg_seq
+                            .store(seq, Ordering::Release);
+                        return;
+                    }
 
-                    if let Some(mut old_schedule_data) = self.schedule_data.take() {
-                        std::mem::swap(
-                            &mut old_schedule_data.removed_node_processors,
-                            &mut new_schedule_data.removed_node_processors,
-                        );
-
-                        for node_id in new_schedule_data.nodes_to_remove.iter() {
-                            if let Some(processor) = self.nodes.remove(node_id.idx) {
-                                old_schedule_data
-                                    .removed_node_processors
-                                    .push((*node_id, processor));
+                    if !self.apply_new_schedule(new_schedule_data, fade_samples) {
+                        self.shared_state
+                            .applied_msg_seq
+                            .store(seq, Ordering::Release);
+                        return;
+                    }
+                }
+                ContextToProcessorMsg::SetBypassed {
+                    node_id,
+                    bypassed,
+                    delay,
+                } => {
+                    if let Some(entry) = self.entry_mut(node_id) {
+                        entry.bypass.schedule(bypassed, delay);
+                    }
+                }
+                ContextToProcessorMsg::SetPriority { node_id, priority } => {
+                    if let Some(entry) = self.entry_mut(node_id) {
+                        entry.priority = priority;
+                    }
+                }
+                ContextToProcessorMsg::SerializeNodeState {
+                    node_id,
+                    mut buffer,
+                } => {
+                    self.state_to_return = Some(match self.entry_mut(node_id) {
+                        Some(entry) => {
+                            entry.processor.serialize_state(&mut buffer);
+                            ProcessorToContextMsg::NodeState {
+                                node_id,
+                                state: buffer,
                             }
                         }
-
-                        self.to_graph_tx
-                            .push(ProcessorToContextMsg::ReturnSchedule(old_schedule_data))
-                            .unwrap();
+                        None => ProcessorToContextMsg::ReturnStateBuffer(buffer),
+                    });
+                }
+                ContextToProcessorMsg::RestoreNodeState { node_id, state } => {
+                    if let Some(entry) = self.entry_mut(node_id) {
+                        entry.processor.restore_state(&state);
                     }
 
-                    for (node_id, processor) in new_schedule_data.new_node_processors.drain(..) {
-                        assert!(self.nodes.insert_at(node_id.idx, processor).is_none());
+                    // Send the buffer back so that it is not deallocated on
+                    // the audio thread.
+                    self.state_to_return = Some(ProcessorToContextMsg::ReturnStateBuffer(state));
+                }
+                ContextToProcessorMsg::SetInputGain(raw_gain) => {
+                    self.input_gain.set(raw_gain);
+                }
+                ContextToProcessorMsg::SetInputChannelMuted { channel, muted } => {
+                    if let Some(fade) = self.input_channel_fades.get_mut(channel) {
+                        fade.set(!muted);
+                    }
+                }
+                ContextToProcessorMsg::SetOutputChannelMap(map) => {
+                    self.output_channel_map = map;
+                }
+                #[cfg(feature = "metrics")]
+                ContextToProcessorMsg::SetTracingEnabled(enabled) => {
+                    self.trace.set_enabled(enabled);
+                }
+                ContextToProcessorMsg::SetSampleRate(sample_rate) => {
+                    self.set_sample_rate(sample_rate);
+                }
+                ContextToProcessorMsg::ResetRunawayProtection => {
+                    if let Some(monitor) = &mut self.runaway_monitor {
+                        monitor.reset();
                     }
-
-                    self.schedule_data = Some(new_schedule_data);
                 }
                 ContextToProcessorMsg::Stop => {
                     self.running = false;
+                    self.shared_state.running.store(false, Ordering::Relaxed);
                 }
             }
+
+            self.shared_state
+                .applied_msg_seq
+                .store(seq, Ordering::Release);
+
+            if !self.return_state_buffer() {
+                // Stop polling messages until the buffer can be sent so that
+                // it is not deallocated in the audio thread.
+                return;
+            }
         }
     }
 
-    fn process_block(
+    /// Swap in a new schedule, crossfading from the old one if a fade is
+    /// requested.
+    ///
+    /// Returns `false` if messages should not be polled any further in this
+    /// block.
+    fn apply_new_schedule(
         &mut self,
-        block_samples: usize,
-        clock_samples: ClockSamples,
-        clock_seconds: Range<ClockSeconds>,
-        stream_status: StreamStatus,
-    ) {
-        self.poll_messages();
+        new_schedule_data: Box<ScheduleHeapData<C>>,
+        fade_samples: Option<usize>,
+    ) -> bool {
+        let fade_samples = fade_samples.unwrap_or(self.schedule_crossfade.fade_samples);
 
-        if !self.running {
-            return;
+        // The context only allocates the buffers needed for a crossfade when
+        // a fade is requested, so swap immediately if they are missing.
+        if fade_samples > 0 && self.schedule_data.is_some() && new_schedule_data.can_crossfade() {
+            self.schedule_crossfade.active_fade_samples = fade_samples;
+            self.schedule_crossfade.frames = 0;
+            self.swap_schedule(new_schedule_data, true);
+            return true;
         }
 
-        let Some(schedule_data) = &mut self.schedule_data else {
-            return;
-        };
+        self.swap_schedule(new_schedule_data, false);
 
-        let user_cx = self.user_cx.as_mut().unwrap();
+        // If the old schedule could not be returned to the context, then
+        // stop polling messages until it can be so that schedules are always
+        // returned in order.
+        self.schedule_to_return.is_none()
+    }
 
-        schedule_data.schedule.process(
-            block_samples,
-            |node_id: NodeID,
-             in_silence_mask: SilenceMask,
-             out_silence_mask: SilenceMask,
-             inputs: &[&[f32]],
-             outputs: &mut [&mut [f32]]|
-             -> ProcessStatus {
-                self.nodes[node_id.idx].process(
-                    inputs,
-                    outputs,
-                    ProcInfo {
-                        samples: block_samples,
-                        in_silence_mask,
-                        out_silence_mask,
-                        clock_samples,
-                        clock_seconds: clock_seconds.clone(),
-                        stream_status,
-                    },
-                    user_cx,
-                )
-            },
-        );
+    /// The processor entry that messages addressed to the given node should
+    /// be applied to.
+    ///
+    /// While schedules are being crossfaded, the processors of removed and
+    /// replaced nodes are no longer in the arena, so messages addressed to
+    /// them only reach the processors of the new schedule.
+    fn entry_mut(&mut self, node_id: NodeID) -> Option<&mut ProcessorEntry<C>> {
+        self.nodes.get_mut(node_id.idx)
     }
-}
 
-impl<C: Send + 'static> Drop for FirewheelProcessor<C> {
-    fn drop(&mut self) {
-        // Make sure the nodes are not deallocated in the audio thread.
-        let mut nodes = Arena::new();
-        std::mem::swap(&mut nodes, &mut self.nodes);
+    /// Recompute everything in the processor that depends on the sample rate.
+    fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.stream_info.sample_rate = sample_rate;
+        self.sample_rate_recip = f64::from(sample_rate).recip();
+        self.schedule_crossfade.fade_samples =
+            (self.schedule_crossfade.fade_secs * sample_rate as f32).round() as usize;
 
-        let _ = self.to_graph_tx.push(ProcessorToContextMsg::Dropped {
-            nodes,
-            _schedule_data: self.schedule_data.take(),
-            user_cx: self.user_cx.take(),
-        });
+        self.input_gain.set_sample_rate(sample_rate);
+        self.input_mute_fade.set_sample_rate(sample_rate);
+        self.bypass_fade.set_sample_rate(sample_rate);
     }
-}
 
-pub(crate) enum ContextToProcessorMsg<C: Send + 'static> {
-    NewSchedule(Box<ScheduleHeapData<C>>),
-    Stop,
-}
+    /// Swap in a new schedule, returning the old one to the context.
+    ///
+    /// If `crossfade` is `true`, then the old schedule and the processors of
+    /// the nodes which were removed or replaced are kept until the crossfade
+    /// is done instead.
+    fn swap_schedule(&mut self, mut new_schedule_data: Box<ScheduleHeapData<C>>, crossfade: bool) {
+        assert_eq!(
+            new_schedule_data.schedule.max_block_samples(),
+            self.stream_info.max_block_samples as usize
+        );
 
-pub(crate) enum ProcessorToContextMsg<C: Send + 'static> {
-    ReturnSchedule(Box<ScheduleHeapData<C>>),
-    Dropped {
-        nodes: Arena<Box<dyn AudioNodeProcessor<C>>>,
-        _schedule_data: Option<Box<ScheduleHeapData<C>>>,
-        user_cx: Option<C>,
-    },
+        let mut old_schedule_data = self.schedule_data.take();
+
+        if let Some(old_schedule_data) = &mut old_schedule_data {
+            std::mem::swap(
+                &mut old_schedule_data.removed_node_processors,
+                &mut new_schedule_data.removed_node_processors,
+            );
+
+            for node_id in new_schedule_data.nodes_to_remove.iter() {
+                if let Some(entry) = self.nodes.remove(node_id.idx) {
+                    if crossfade {
+                        new_schedule_data
+                            .outgoing_processors
+                            .push((*node_id, entry));
+                    } else {
+                        old_schedule_data
+                            .removed_node_processors
+                            .push((*node_id, entry.processor));
+                    }
+                }
+            }
+        }
+
+        for NewNodeProcessor { node_id, entry, .. } in
+            new_schedule_data.new_node_processors.drain(..)
+        {
+            // The index (including the generation) of the node whose
+            // processor currently occupies this slot, if any.
+            let displaced_idx = self.nodes.contains_slot(node_id.idx.slot());
+
+            if let Some(displaced) = self.nodes.insert_at(node_id.idx, entry) {
+                let displaced_idx = displaced_idx.unwrap();
+
+                // If the displaced processor belongs to the same node, then
+                // the node was re-activated (i.e. after a change in sample
+                // rate) or replaced, so carry over its state. A processor of
+                // a different node should never be left in the slot, but its
+                // state must not leak into an unrelated node if it is.
+                if displaced_idx == node_id.idx {
+                    if let Some(new_entry) = self.nodes.get_mut(node_id.idx) {
+                        new_entry.inherit_state(&displaced);
+                        new_entry
+                            .processor
+                            .migrate_state_from(displaced.processor.as_ref());
+                    }
+                }
+
+                // Send the displaced processor back to the context so that
+                // it is not deallocated in the audio thread.
+                let displaced_id = NodeID {
+                    idx: displaced_idx,
+                    ..node_id
+                };
+                if crossfade {
+                    new_schedule_data
+                        .outgoing_processors
+                        .push((displaced_id, displaced));
+                } else if let Some(old_schedule_data) = &mut old_schedule_data {
+                    old_schedule_data
+                        .removed_node_processors
+                        .push((displaced_id, displaced.processor));
+                }
+            }
+        }
+
+        if crossfade {
+            // Sorted so that the old schedule can look up its processors.
+            new_schedule_data
+                .outgoing_processors
+                .sort_unstable_by_key(|(node_id, _)| *node_id);
+        }
+
+        self.schedule_data = Some(new_schedule_data);
+        self.unreported_schedule_swaps += 1;
+
+        if crossfade {
+            self.schedule_crossfade.old_schedule = old_schedule_data;
+        } else if old_schedule_data.is_some() {
+            debug_assert!(self.schedule_to_return.is_none());
+            self.schedule_to_return = old_schedule_data;
+            self.return_old_schedule();
+        }
+    }
+
+    /// Tell the context how many new schedules have been swapped in since
+    /// the last report. If the channel is full, then this is tried again on
+    /// the next block.
+    fn report_schedule_swaps(&mut self) {
+        if self.unreported_schedule_swaps == 0 {
+            return;
+        }
+
+        if self
+            .to_graph_tx
+            .push(ProcessorToContextMsg::SchedulesSwapped(
+                self.unreported_schedule_swaps,
+            ))
+            .is_ok()
+        {
+            self.unreported_schedule_swaps = 0;
+        }
+    }
+
+    /// Try to send the old schedule back to the context.
+    ///
+    /// Returns `false` if the channel is full, in which case this should be
+    /// tried again later.
+    fn return_old_schedule(&mut self) -> bool {
+        let Some(old_schedule_data) = self.schedule_to_return.take() else {
+            return true;
+        };
+
+        match self
+            .to_graph_tx
+            .push(ProcessorToContextMsg::ReturnSchedule(old_schedule_data))
+        {
+            Ok(()) => true,
+            Err(spsc::PushError::Full(msg)) => {
+                if let ProcessorToContextMsg::ReturnSchedule(old_schedule_data) = msg {
+                    self.schedule_to_return = Some(old_schedule_data);
+                }
+                false
+            }
+        }
+    }
+
+    /// Try to send a node state buffer back to the context.
+    ///
+    /// Returns `false` if the channel is full, in which case this should be
+    /// tried again later.
+    fn return_state_buffer(&mut self) -> bool {
+        let Some(msg) = self.state_to_return.take() else {
+            return true;
+        };
+
+        match self.to_graph_tx.push(msg) {
+            Ok(()) => true,
+            Err(spsc::PushError::Full(msg)) => {
+                self.state_to_return = Some(msg);
+                false
+            }
+        }
+    }
+
+    /// Crossfade the graph outputs of the old schedule into the graph
+    /// outputs of the new one, and return the old schedule to the context
+    /// once the crossfade is done.
+    fn apply_schedule_crossfade(&mut self, block_samples: usize) {
+        let crossfade = &mut self.schedule_crossfade;
+        let Some(old_schedule_data) = &crossfade.old_schedule else {
+            return;
+        };
+
+        let fade_samples = crossfade.active_fade_samples;
+        for (i, (fade_in, fade_out)) in crossfade.fade_in_gains[..block_samples]
+            .iter_mut()
+            .zip(crossfade.fade_out_gains[..block_samples].iter_mut())
+            .enumerate()
+        {
+            let t = (crossfade.frames + i) as f32 / fade_samples as f32;
+            *fade_in = crossfade.curve.gain(t);
+            *fade_out = crossfade.curve.gain(1.0 - t);
+        }
+
+        self.schedule_data
+            .as_mut()
+            .unwrap()
+            .schedule
+            .crossfade_graph_outputs_from(
+                &old_schedule_data.schedule,
+                block_samples,
+                &crossfade.fade_in_gains,
+                &crossfade.fade_out_gains,
+            );
+
+        crossfade.frames += block_samples;
+        if crossfade.frames >= fade_samples {
+            self.finish_schedule_crossfade();
+        }
+    }
+
+    /// Return the old schedule of the crossfade to the context, along with
+    /// the processors of the nodes which were removed or replaced.
+    fn finish_schedule_crossfade(&mut self) {
+        let Some(mut old_schedule_data) = self.schedule_crossfade.old_schedule.take() else {
+            return;
+        };
+
+        if let Some(schedule_data) = &mut self.schedule_data {
+            // The capacity of this vector was reserved for every removed and
+            // replaced processor, so this does not allocate.
+            old_schedule_data.removed_node_processors.extend(
+                schedule_data
+                    .outgoing_processors
+                    .drain(..)
+                    .map(|(node_id, entry)| (node_id, entry.processor)),
+            );
+        }
+
+        debug_assert!(self.schedule_to_return.is_none());
+        self.schedule_to_return = Some(old_schedule_data);
+        self.return_old_schedule();
+    }
+
+    fn process_block(
+        &mut self,
+        block_samples: usize,
+        clock_samples: ClockSamples,
+        clock_seconds: Range<ClockSeconds>,
+        stream_status: StreamStatus,
+    ) {
+        if !self.running {
+            return;
+        }
+
+        let Some(schedule_data) = self.schedule_data.as_deref_mut() else {
+            return;
+        };
+
+        self.shared_state
+            .last_block_samples
+            .store(block_samples as u32, Ordering::Relaxed);
+
+        let user_cx = self.user_cx.as_mut().unwrap();
+        let nodes = &mut self.nodes;
+        let sample_rate = self.stream_info.sample_rate;
+        let bypass_fade = self.bypass_fade;
+        let mut num_culled_nodes = 0;
+        let cull_below_priority = self
+            .voice_culling
+            .filter(|_| self.under_cpu_pressure)
+            .map(|c| c.min_priority);
+        #[cfg(feature = "metrics")]
+        let trace = &mut self.trace;
+
+        // Flush denormals to zero while processing nodes to avoid CPU spikes
+        // when signals decay towards zero. The previous state is restored when
+        // the guard is dropped.
+        let _denormal_guard = FlushDenormalsGuard::new();
+
+        let proc_start = Instant::now();
+
+        let mut process_node = |entry: &mut ProcessorEntry<C>,
+                                node_id: NodeID,
+                                in_silence_mask: SilenceMask,
+                                out_silence_mask: SilenceMask,
+                                inputs: &[&[f32]],
+                                outputs: &mut [&mut [f32]]|
+         -> ProcessStatus {
+            #[cfg(feature = "metrics")]
+            let start = trace.is_enabled().then(Instant::now);
+
+            if is_culled(entry.priority, cull_below_priority) {
+                num_culled_nodes += 1;
+                return ProcessStatus::NoOutputsModified;
+            }
+
+            let status = entry.process(
+                inputs,
+                outputs,
+                ProcInfo {
+                    samples: block_samples,
+                    in_silence_mask,
+                    out_silence_mask,
+                    clock_samples,
+                    clock_seconds: clock_seconds.clone(),
+                    stream_status,
+                },
+                sample_rate,
+                &bypass_fade,
+                user_cx,
+            );
+
+            if entry.meter.is_enabled() {
+                entry.meter.update(outputs, &status, block_samples);
+            }
+
+            #[cfg(feature = "metrics")]
+            if let Some(start) = start {
+                trace.record(node_id, start, Instant::now());
+            }
+            #[cfg(not(feature = "metrics"))]
+            let _ = node_id;
+
+            status
+        };
+
+        if let Some(old_schedule_data) = self.schedule_crossfade.old_schedule.as_deref_mut() {
+            // Every processor can only be processed once per block, so the
+            // nodes which exist in both schedules are processed with the new
+            // schedule, and the old schedule reuses their outputs.
+            schedule_data.schedule.process_and_record(
+                block_samples,
+                |node_id, in_silence_mask, out_silence_mask, inputs, outputs| {
+                    process_node(
+                        &mut nodes[node_id.idx],
+                        node_id,
+                        in_silence_mask,
+                        out_silence_mask,
+                        inputs,
+                        outputs,
+                    )
+                },
+            );
+
+            let outgoing_processors = &mut schedule_data.outgoing_processors;
+            let new_schedule = &schedule_data.schedule;
+            old_schedule_data.schedule.process(
+                block_samples,
+                |node_id, in_silence_mask, out_silence_mask, inputs, outputs| {
+                    match outgoing_processors.binary_search_by_key(&node_id, |(id, _)| *id) {
+                        Ok(i) => process_node(
+                            &mut outgoing_processors[i].1,
+                            node_id,
+                            in_silence_mask,
+                            out_silence_mask,
+                            inputs,
+                            outputs,
+                        ),
+                        Err(_) => match new_schedule.copy_recorded_outputs(node_id, outputs) {
+                            Some(out_silence_mask) => {
+                                ProcessStatus::OutputsModified { out_silence_mask }
+                            }
+                            None => ProcessStatus::NoOutputsModified,
+                        },
+                    }
+                },
+            );
+        } else {
+            schedule_data.schedule.process(
+                block_samples,
+                |node_id, in_silence_mask, out_silence_mask, inputs, outputs| {
+                    process_node(
+                        &mut nodes[node_id.idx],
+                        node_id,
+                        in_silence_mask,
+                        out_silence_mask,
+                        inputs,
+                        outputs,
+                    )
+                },
+            );
+        }
+
+        #[cfg(feature = "metrics")]
+        self.trace.end_block();
+
+        let proc_time_secs = proc_start.elapsed().as_secs_f64();
+        let block_secs = block_samples as f64 * self.sample_rate_recip;
+
+        self.shared_state
+            .num_culled_nodes
+            .store(num_culled_nodes, Ordering::Relaxed);
+
+        if let Some(dsp_load) = self.dsp_load.add(proc_time_secs, block_secs) {
+            self.shared_state
+                .dsp_load_average
+                .store(dsp_load.average, Ordering::Relaxed);
+            self.shared_state
+                .dsp_load_peak
+                .store(dsp_load.peak, Ordering::Relaxed);
+        }
+
+        if let Some(voice_culling) = &self.voice_culling {
+            self.under_cpu_pressure = voice_culling
+                .is_under_pressure(self.dsp_load.smoothed_load(), self.under_cpu_pressure);
+            self.shared_state
+                .under_cpu_pressure
+                .store(self.under_cpu_pressure, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The crossfade between the outputs of the old and the new schedule which
+/// is applied while swapping schedules.
+///
+/// A node processor can only be processed once per block, so the nodes which
+/// exist in both schedules are processed with the new schedule, and the old
+/// schedule reuses their outputs. Only the processors of the nodes which were
+/// removed or replaced keep processing with the old schedule until the
+/// crossfade is done.
+struct ScheduleCrossfade<C: Send + 'static> {
+    /// The default length of the crossfade in seconds, used to recompute
+    /// `fade_samples` when the sample rate changes.
+    fade_secs: f32,
+    /// The default length of the crossfade. If this is `0`, then schedules
+    /// are swapped immediately.
+    fade_samples: usize,
+    /// The length of the crossfade which is currently in progress.
+    active_fade_samples: usize,
+    /// The number of frames of the current crossfade which were processed.
+    frames: usize,
+    curve: FadeCurve,
+    /// The schedule which is being faded out, or `None` if no crossfade is
+    /// in progress.
+    old_schedule: Option<Box<ScheduleHeapData<C>>>,
+    /// A schedule which was received while a crossfade was in progress,
+    /// along with the length of its fade. Messages are not polled while this
+    /// is `Some`.
+    queued_schedule: Option<(Box<ScheduleHeapData<C>>, Option<usize>)>,
+    /// The gain of the new schedule for each frame of the current block.
+    fade_in_gains: Vec<f32>,
+    /// The gain of the old schedule for each frame of the current block.
+    fade_out_gains: Vec<f32>,
+}
+
+impl<C: Send + 'static> ScheduleCrossfade<C> {
+    fn is_active(&self) -> bool {
+        self.old_schedule.is_some()
+    }
+}
+
+/// Accumulates the time spent processing so that a summary can be published
+/// to the context periodically instead of once every block.
+#[derive(Default)]
+struct DspLoadMeter {
+    proc_time_secs: f64,
+    block_secs: f64,
+    peak_load: f64,
+    /// The load smoothed over roughly the last report interval, which is
+    /// updated every block.
+    smoothed_load: f64,
+}
+
+impl DspLoadMeter {
+    fn add(&mut self, proc_time_secs: f64, block_secs: f64) -> Option<DspLoad> {
+        self.proc_time_secs += proc_time_secs;
+        self.block_secs += block_secs;
+
+        if block_secs > 0.0 {
+            let load = proc_time_secs / block_secs;
+            self.peak_load = self.peak_load.max(load);
+
+            let coeff = (-block_secs / DSP_LOAD_REPORT_INTERVAL_SECS).exp();
+            self.smoothed_load = load + (self.smoothed_load - load) * coeff;
+        }
+
+        if self.block_secs < DSP_LOAD_REPORT_INTERVAL_SECS {
+            return None;
+        }
+
+        let dsp_load = DspLoad {
+            average: (self.proc_time_secs / self.block_secs) as f32,
+            peak: self.peak_load as f32,
+        };
+
+        self.proc_time_secs = 0.0;
+        self.block_secs = 0.0;
+        self.peak_load = 0.0;
+
+        Some(dsp_load)
+    }
+
+    fn smoothed_load(&self) -> f32 {
+        self.smoothed_load as f32
+    }
+}
+
+/// Detects rapidly increasing output levels and attenuates the output when
+/// they are detected. See [`RunawayProtection`].
+struct RunawayMonitor {
+    min_level: f32,
+    growth_ratio: f32,
+    num_blocks: u32,
+    attenuation: f32,
+    prev_peak: f32,
+    growth_blocks: u32,
+    gain: f32,
+    target_gain: f32,
+    curve: FadeCurve,
+}
+
+impl RunawayMonitor {
+    fn new(config: RunawayProtection, curve: FadeCurve) -> Self {
+        Self {
+            min_level: firewheel_core::util::db_to_gain(config.min_level_db),
+            growth_ratio: firewheel_core::util::db_to_gain(config.growth_db_per_block.max(0.0)),
+            num_blocks: config.num_blocks.max(1),
+            attenuation: firewheel_core::util::db_to_gain_clamped_neg_100_db(
+                config.attenuation_db.min(0.0),
+            ),
+            prev_peak: 0.0,
+            growth_blocks: 0,
+            gain: 1.0,
+            target_gain: 1.0,
+            curve,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.prev_peak = 0.0;
+        self.growth_blocks = 0;
+        self.target_gain = 1.0;
+    }
+
+    /// Monitor the given frames of the output and attenuate them if needed.
+    ///
+    /// Returns the peak level of the block if a runaway was just detected.
+    fn process(&mut self, output: &mut StreamOutput, frames: Range<usize>) -> Option<f32> {
+        let mut detected = None;
+
+        if self.target_gain == 1.0 {
+            let peak = output.peak(frames.clone());
+
+            if peak >= self.min_level && peak >= self.prev_peak * self.growth_ratio {
+                self.growth_blocks += 1;
+            } else {
+                self.growth_blocks = 0;
+            }
+            self.prev_peak = peak;
+
+            if self.growth_blocks >= self.num_blocks {
+                self.target_gain = self.attenuation;
+                detected = Some(peak);
+            }
+        }
+
+        if self.gain == 1.0 && self.target_gain == 1.0 {
+            return detected;
+        }
+
+        // Ramp to the new gain over the block to avoid clicks.
+        let num_frames = frames.len();
+        let delta = self.target_gain - self.gain;
+        let (start_gain, curve) = (self.gain, self.curve);
+
+        output.apply_gain(frames, |i| {
+            let t = (i + 1) as f32 / num_frames.max(1) as f32;
+            start_gain + delta * curve.gain(t)
+        });
+
+        self.gain = self.target_gain;
+
+        detected
+    }
+}
+
+/// A node processor along with the state the processor keeps for it.
+pub(crate) struct ProcessorEntry<C: Send + 'static> {
+    pub processor: Box<dyn AudioNodeProcessor<C>>,
+    meter: Arc<NodeMeter>,
+    bypass: BypassState,
+    silent_when_inputs_silent: bool,
+    preserve_denormals: bool,
+    /// The priority used for culling, or `None` if this node is never
+    /// culled.
+    priority: Option<f32>,
+}
+
+impl<C: Send + 'static> ProcessorEntry<C> {
+    pub fn new(
+        processor: Box<dyn AudioNodeProcessor<C>>,
+        info: &AudioNodeInfo,
+        meter: Arc<NodeMeter>,
+    ) -> Self {
+        Self {
+            processor,
+            meter,
+            bypass: BypassState::default(),
+            silent_when_inputs_silent: info.silent_when_inputs_silent,
+            preserve_denormals: info.preserve_denormals,
+            priority: None,
+        }
+    }
+
+    /// Carry over the bypass and culling state from the processor entry that
+    /// this one is replacing.
+    fn inherit_state(&mut self, old: &Self) {
+        self.bypass = old.bypass.clone();
+        self.priority = old.priority;
+    }
+
+    fn process(
+        &mut self,
+        inputs: &[&[f32]],
+        outputs: &mut [&mut [f32]],
+        proc_info: ProcInfo,
+        sample_rate: u32,
+        bypass_fade: &FadeParams,
+        cx: &mut C,
+    ) -> ProcessStatus {
+        let samples = proc_info.samples;
+        let switch = self.bypass.take_switch(&proc_info, sample_rate);
+
+        if self.silent_when_inputs_silent
+            && !inputs.is_empty()
+            && proc_info.in_silence_mask.all_channels_silent(inputs.len())
+        {
+            // The outputs of this node are silent when all of its inputs are
+            // silent, so there is no need to call the processor. This holds
+            // whether or not the node is bypassed.
+            self.bypass.advance(switch, samples, bypass_fade);
+            return ProcessStatus::NoOutputsModified;
+        }
+
+        let _denormal_guard = self.preserve_denormals.then(PreserveDenormalsGuard::new);
+
+        let in_silence_mask = proc_info.in_silence_mask;
+        let prev_out_silence_mask = proc_info.out_silence_mask;
+
+        if switch.is_none() {
+            match self.bypass.fade.settled() {
+                Some(false) => return self.processor.process(inputs, outputs, proc_info, cx),
+                Some(true) => {
+                    // The node is bypassed for the whole block, so there is no
+                    // need to process it.
+                    let mut out_silence_mask = prev_out_silence_mask;
+
+                    for (i, out) in outputs.iter_mut().enumerate() {
+                        if let Some(input) = inputs.get(i) {
+                            out[..samples].copy_from_slice(&input[..samples]);
+                            out_silence_mask.set_channel(i, in_silence_mask.is_channel_silent(i));
+                        } else {
+                            if !out_silence_mask.is_channel_silent(i) {
+                                out[..samples].fill(0.0);
+                            }
+                            out_silence_mask.set_channel(i, true);
+                        }
+                    }
+
+                    return ProcessStatus::outputs_modified(out_silence_mask);
+                }
+                None => {}
+            }
+        }
+
+        // The node is switched or faded between processing and bypassing
+        // within this block, so both its output and its inputs are needed.
+        let mut out_silence_mask = match self.processor.process(inputs, outputs, proc_info, cx) {
+            ProcessStatus::NoOutputsModified => {
+                // The outputs must be cleared here since the inputs are mixed
+                // into them below.
+                for (i, out) in outputs.iter_mut().enumerate() {
+                    if !prev_out_silence_mask.is_channel_silent(i) {
+                        out[..samples].fill(0.0);
+                    }
+                }
+                SilenceMask::new_all_silent(outputs.len())
+            }
+            ProcessStatus::OutputsModified { out_silence_mask } => out_silence_mask,
+        };
+
+        for (i, out) in outputs.iter_mut().enumerate() {
+            let input = inputs
+                .get(i)
+                .filter(|_| !in_silence_mask.is_channel_silent(i));
+
+            if input.is_none() && out_silence_mask.is_channel_silent(i) {
+                continue;
+            }
+
+            // Every channel follows the same fade, starting from the state
+            // at the start of the block.
+            let mut fade = self.bypass.fade;
+
+            for (frame, out_s) in out[..samples].iter_mut().enumerate() {
+                if let Some((switch_frame, bypassed)) = switch {
+                    if frame == switch_frame {
+                        fade.set(bypassed);
+                    }
+                }
+
+                let pos = fade.next(bypass_fade.step);
+                let in_s = input.map_or(0.0, |input| input[frame]);
+
+                *out_s = if pos >= 1.0 {
+                    in_s
+                } else if pos <= 0.0 {
+                    *out_s
+                } else {
+                    *out_s * bypass_fade.curve.gain(1.0 - pos) + in_s * bypass_fade.curve.gain(pos)
+                };
+            }
+
+            out_silence_mask.set_channel(i, false);
+        }
+
+        self.bypass.advance(switch, samples, bypass_fade);
+
+        ProcessStatus::outputs_modified(out_silence_mask)
+    }
+}
+
+/// The bypass state of a node in the processor.
+#[derive(Default, Clone)]
+struct BypassState {
+    /// The fade between processing the node (`0.0`) and bypassing it
+    /// (`1.0`).
+    fade: Fade,
+    scheduled: Option<(bool, EventDelay)>,
+}
+
+impl BypassState {
+    fn schedule(&mut self, bypassed: bool, delay: EventDelay) {
+        if let EventDelay::Immediate = delay {
+            self.fade.set(bypassed);
+            self.scheduled = None;
+        } else {
+            self.scheduled = Some((bypassed, delay));
+        }
+    }
+
+    /// Returns the frame in the current block at which the node is switched
+    /// to the given bypass state, if a scheduled change lands in this block.
+    fn take_switch(&mut self, proc_info: &ProcInfo, sample_rate: u32) -> Option<(usize, bool)> {
+        let (bypassed, delay) = self.scheduled?;
+
+        let switch_frame = match delay {
+            EventDelay::Immediate => 0,
+            EventDelay::DelayUntilSample(clock_samples) => {
+                clock_samples.0.saturating_sub(proc_info.clock_samples.0) as usize
+            }
+            EventDelay::DelayUntilSeconds(clock_seconds) => {
+                let frames =
+                    (clock_seconds - proc_info.clock_seconds.start).0 * f64::from(sample_rate);
+                frames.max(0.0).round() as usize
+            }
+        };
+
+        if switch_frame >= proc_info.samples {
+            return None;
+        }
+
+        self.scheduled = None;
+
+        if bypassed == self.fade.target {
+            return None;
+        }
+
+        Some((switch_frame, bypassed))
+    }
+
+    /// Advance the fade to the end of the current block.
+    fn advance(&mut self, switch: Option<(usize, bool)>, samples: usize, params: &FadeParams) {
+        match switch {
+            Some((switch_frame, bypassed)) => {
+                self.fade.advance(switch_frame, params.step);
+                self.fade.set(bypassed);
+                self.fade.advance(samples - switch_frame, params.step);
+            }
+            None => self.fade.advance(samples, params.step),
+        }
+    }
+}
+
+/// The length of the fade used when an input channel is muted or unmuted.
+const INPUT_MUTE_FADE_SECS: f32 = 10.0 / 1000.0;
+
+/// The length and shape of a [`Fade`].
+#[derive(Debug, Clone, Copy)]
+struct FadeParams {
+    secs: f32,
+    /// How far a fade moves per frame.
+    step: f32,
+    curve: FadeCurve,
+}
+
+impl FadeParams {
+    fn new(secs: f32, sample_rate: u32, curve: FadeCurve) -> Self {
+        let mut params = Self {
+            secs: secs.max(0.0),
+            step: 1.0,
+            curve,
+        };
+        params.set_sample_rate(sample_rate);
+        params
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: u32) {
+        let frames = (self.secs * sample_rate as f32).round();
+
+        // A fade shorter than one frame switches right away.
+        self.step = if frames > 1.0 { frames.recip() } else { 1.0 };
+    }
+}
+
+/// A click-free fade between two states, where `0.0` is the "off" state and
+/// `1.0` is the "on" state. The gain is shaped with [`FadeParams::curve`].
+#[derive(Default, Debug, Clone, Copy)]
+struct Fade {
+    pos: f32,
+    /// Whether the fade is moving towards `1.0` or towards `0.0`.
+    target: bool,
+}
+
+impl Fade {
+    fn new(on: bool) -> Self {
+        Self {
+            pos: if on { 1.0 } else { 0.0 },
+            target: on,
+        }
+    }
+
+    fn set(&mut self, on: bool) {
+        self.target = on;
+    }
+
+    /// Returns the state the fade has settled in, or `None` if it is still
+    /// in progress.
+    fn settled(&self) -> Option<bool> {
+        let end = if self.target { 1.0 } else { 0.0 };
+        (self.pos == end).then_some(self.target)
+    }
+
+    /// Advance the fade by one frame and return the new position.
+    #[inline]
+    fn next(&mut self, step: f32) -> f32 {
+        self.advance(1, step);
+        self.pos
+    }
+
+    fn advance(&mut self, frames: usize, step: f32) {
+        let delta = step * frames as f32;
+
+        self.pos = if self.target {
+            (self.pos + delta).min(1.0)
+        } else {
+            (self.pos - delta).max(0.0)
+        };
+    }
+}
+
+/// Apply the (smoothed) input gain to the graph input channels.
+fn apply_input_gain(
+    input_gain: &mut ParamSmoother,
+    channels: &mut [&mut [f32]],
+    silence_mask: SilenceMask,
+    samples: usize,
+) {
+    if input_gain.constant_value() == Some(1.0) {
+        return;
+    }
+
+    let gain = input_gain.process(samples);
+
+    // Hint to the compiler to optimize loop.
+    assert!(samples <= gain.values.len());
+
+    for (i, ch) in channels.iter_mut().enumerate() {
+        if silence_mask.is_channel_silent(i) {
+            continue;
+        }
+
+        for (s, &g) in ch[..samples].iter_mut().zip(gain.values.iter()) {
+            *s *= g;
+        }
+    }
+}
+
+/// Fade out the input channels which are muted, marking them as silent
+/// once they are fully faded out.
+fn apply_input_channel_mutes(
+    channel_fades: &mut [Fade],
+    params: &FadeParams,
+    channels: &mut [&mut [f32]],
+    silence_mask: &mut SilenceMask,
+    samples: usize,
+) {
+    for (i, (ch, fade)) in channels
+        .iter_mut()
+        .zip(channel_fades.iter_mut())
+        .enumerate()
+    {
+        match fade.settled() {
+            Some(true) => {}
+            Some(false) => {
+                if !silence_mask.is_channel_silent(i) {
+                    ch[..samples].fill(0.0);
+                    silence_mask.set_channel(i, true);
+                }
+            }
+            None => {
+                if silence_mask.is_channel_silent(i) {
+                    fade.advance(samples, params.step);
+                    continue;
+                }
+
+                for s in ch[..samples].iter_mut() {
+                    *s *= params.curve.gain(fade.next(params.step));
+                }
+            }
+        }
+    }
+}
+
+/// Replace any non-finite samples with `0.0` and clamp all samples to the
+/// range `[-ceiling, ceiling]`.
+fn apply_safety_limit(buffer: &mut [f32], ceiling: f32) {
+    for s in buffer.iter_mut() {
+        *s = if s.is_finite() {
+            s.min(ceiling).max(-ceiling)
+        } else {
+            0.0
+        };
+    }
+}
+
+/// The buffers used when processing the graph in blocks of a fixed size.
+struct FixedBlockBuffers {
+    input: Vec<Vec<f32>>,
+    /// The output of the last processed block, which is played back while
+    /// the input for the next block is collected.
+    output: Vec<Vec<f32>>,
+    /// The number of frames which have been collected for the next block.
+    pos: usize,
+    out_silence_mask: SilenceMask,
+}
+
+impl FixedBlockBuffers {
+    fn new(stream_info: &StreamInfo) -> Self {
+        let block_samples = stream_info.max_block_samples as usize;
+
+        Self {
+            input: (0..stream_info.num_stream_in_channels)
+                .map(|_| vec![0.0; block_samples])
+                .collect(),
+            output: (0..stream_info.num_stream_out_channels)
+                .map(|_| vec![0.0; block_samples])
+                .collect(),
+            pos: 0,
+            out_silence_mask: SilenceMask::new_all_silent(
+                stream_info.num_stream_out_channels as usize,
+            ),
+        }
+    }
+}
+
+/// The input buffer given to the processor by the audio backend.
+enum StreamInput<'a> {
+    Interleaved {
+        buffer: &'a [f32],
+        num_channels: usize,
+    },
+    Planar(&'a [&'a [f32]]),
+}
+
+impl<'a> StreamInput<'a> {
+    fn num_channels(&self) -> usize {
+        match self {
+            Self::Interleaved { num_channels, .. } => *num_channels,
+            Self::Planar(channels) => channels.len(),
+        }
+    }
+
+    /// Copy the given frames of the input into the graph input channels.
+    fn read_block(&self, frames: Range<usize>, channels: &mut [&mut [f32]]) -> SilenceMask {
+        match self {
+            Self::Interleaved {
+                buffer,
+                num_channels,
+            } => firewheel_core::util::deinterleave(
+                channels,
+                &buffer[frames.start * num_channels..frames.end * num_channels],
+                *num_channels,
+                true,
+            ),
+            Self::Planar(input) => {
+                let mut silence_mask = SilenceMask::NONE_SILENT;
+
+                for (ch_i, ch) in channels.iter_mut().enumerate() {
+                    let ch = &mut ch[..frames.len()];
+
+                    let silent = if let Some(in_ch) = input.get(ch_i) {
+                        ch.copy_from_slice(&in_ch[frames.clone()]);
+                        ch.iter().all(|&s| s == 0.0)
+                    } else {
+                        ch.fill(0.0);
+                        true
+                    };
+
+                    if silent && ch_i < 64 {
+                        silence_mask.set_channel(ch_i, true);
+                    }
+                }
+
+                silence_mask
+            }
+        }
+    }
+}
+
+/// The output buffer given to the processor by the audio backend.
+enum StreamOutput<'a, 'b> {
+    Interleaved {
+        buffer: &'a mut [f32],
+        num_channels: usize,
+    },
+    Planar(&'a mut [&'b mut [f32]]),
+}
+
+impl<'a, 'b> StreamOutput<'a, 'b> {
+    fn num_channels(&self) -> usize {
+        match self {
+            Self::Interleaved { num_channels, .. } => *num_channels,
+            Self::Planar(channels) => channels.len(),
+        }
+    }
+
+    /// Fill the given frames of the output with silence.
+    fn silence(&mut self, frames: Range<usize>) {
+        match self {
+            Self::Interleaved {
+                buffer,
+                num_channels,
+            } => buffer[frames.start * *num_channels..frames.end * *num_channels].fill(0.0),
+            Self::Planar(channels) => {
+                for ch in channels.iter_mut() {
+                    ch[frames.clone()].fill(0.0);
+                }
+            }
+        }
+    }
+
+    /// Copy the graph output channels into the given frames of the output.
+    ///
+    /// If `channel_map` is `Some` and has one entry for every output
+    /// channel, then each graph output channel is written to the output
+    /// channel given by its entry.
+    fn write_block(
+        &mut self,
+        frames: Range<usize>,
+        channels: &[&[f32]],
+        silence_mask: SilenceMask,
+        channel_map: Option<&[u8]>,
+    ) {
+        if let Some(channel_map) = channel_map.filter(|m| m.len() == self.num_channels()) {
+            self.write_block_mapped(frames, channels, silence_mask, channel_map);
+            return;
+        }
+
+        match self {
+            Self::Interleaved {
+                buffer,
+                num_channels,
+            } => firewheel_core::util::interleave(
+                channels,
+                &mut buffer[frames.start * *num_channels..frames.end * *num_channels],
+                *num_channels,
+                Some(silence_mask),
+            ),
+            Self::Planar(output) => {
+                for (ch_i, out_ch) in output.iter_mut().enumerate() {
+                    let out_ch = &mut out_ch[frames.clone()];
+
+                    match channels.get(ch_i) {
+                        Some(ch) if ch_i >= 64 || !silence_mask.is_channel_silent(ch_i) => {
+                            out_ch.copy_from_slice(&ch[..out_ch.len()]);
+                        }
+                        _ => out_ch.fill(0.0),
+                    }
+                }
+            }
+        }
+    }
+
+    fn write_block_mapped(
+        &mut self,
+        frames: Range<usize>,
+        channels: &[&[f32]],
+        silence_mask: SilenceMask,
+        channel_map: &[u8],
+    ) {
+        for (ch_i, &out_ch_i) in channel_map.iter().enumerate() {
+            let out_ch_i = usize::from(out_ch_i);
+            let ch = channels
+                .get(ch_i)
+                .filter(|_| !silence_mask.is_channel_silent(ch_i));
+
+            match self {
+                Self::Interleaved {
+                    buffer,
+                    num_channels,
+                } => {
+                    let out_frames = buffer
+                        [frames.start * *num_channels..frames.end * *num_channels]
+                        .chunks_exact_mut(*num_channels);
+
+                    if let Some(ch) = ch {
+                        for (out_frame, &s) in out_frames.zip(ch.iter()) {
+                            out_frame[out_ch_i] = s;
+                        }
+                    } else {
+                        for out_frame in out_frames {
+                            out_frame[out_ch_i] = 0.0;
+                        }
+                    }
+                }
+                Self::Planar(output) => {
+                    let out_ch = &mut output[out_ch_i][frames.clone()];
+
+                    if let Some(ch) = ch {
+                        out_ch.copy_from_slice(&ch[..out_ch.len()]);
+                    } else {
+                        out_ch.fill(0.0);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Multiply every sample in the given frames by a gain, where `gain` is
+    /// called once per frame with the index of the frame in the range.
+    fn apply_gain(&mut self, frames: Range<usize>, mut gain: impl FnMut(usize) -> f32) {
+        match self {
+            Self::Interleaved {
+                buffer,
+                num_channels,
+            } => {
+                let num_channels = (*num_channels).max(1);
+
+                for (i, frame) in buffer[frames.start * num_channels..frames.end * num_channels]
+                    .chunks_exact_mut(num_channels)
+                    .enumerate()
+                {
+                    let gain = gain(i);
+                    for s in frame.iter_mut() {
+                        *s *= gain;
+                    }
+                }
+            }
+            Self::Planar(channels) => {
+                for (i, frame) in frames.enumerate() {
+                    let gain = gain(i);
+                    for ch in channels.iter_mut() {
+                        ch[frame] *= gain;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the peak absolute value in the given frames, or infinity if
+    /// any sample is not finite.
+    fn peak(&self, frames: Range<usize>) -> f32 {
+        let peak = |peak: f32, &s: &f32| {
+            if s.is_finite() {
+                peak.max(s.abs())
+            } else {
+                f32::INFINITY
+            }
+        };
+
+        match self {
+            Self::Interleaved {
+                buffer,
+                num_channels,
+            } => buffer[frames.start * num_channels..frames.end * num_channels]
+                .iter()
+                .fold(0.0, peak),
+            Self::Planar(channels) => channels
+                .iter()
+                .fold(0.0, |p, ch| ch[frames.clone()].iter().fold(p, peak)),
+        }
+    }
+
+    fn apply_safety_limit(&mut self, samples: usize, ceiling: f32) {
+        match self {
+            Self::Interleaved { buffer, .. } => apply_safety_limit(buffer, ceiling),
+            Self::Planar(channels) => {
+                for ch in channels.iter_mut() {
+                    apply_safety_limit(&mut ch[..samples], ceiling);
+                }
+            }
+        }
+    }
+}
+
+impl<C: Send + 'static> Drop for FirewheelProcessor<C> {
+    fn drop(&mut self) {
+        self.shared_state.running.store(false, Ordering::Relaxed);
+
+        self.finish_schedule_crossfade();
+        if let Some((new_schedule_data, _)) = self.schedule_crossfade.queued_schedule.take() {
+            if self.schedule_to_return.is_none() {
+                self.swap_schedule(new_schedule_data, false);
+            }
+        }
+        self.return_old_schedule();
+        self.return_state_buffer();
+
+        // Make sure the nodes are not deallocated in the audio thread.
+        let mut nodes = Arena::new();
+        std::mem::swap(&mut nodes, &mut self.nodes);
+
+        let _ = self.to_graph_tx.push(ProcessorToContextMsg::Dropped {
+            nodes,
+            _schedule_data: self.schedule_data.take(),
+            user_cx: self.user_cx.take(),
+        });
+    }
+}
+
+/// State which is shared between the context and the processor.
+#[derive(Debug)]
+pub(crate) struct SharedProcessorState {
+    /// Whether or not the processor is still running.
+    pub running: AtomicBool,
+    /// The sequence number of the last message from the context that the
+    /// processor has applied.
+    pub applied_msg_seq: AtomicU64,
+    /// Whether or not every channel of the graph output was silent in the
+    /// last processed buffer.
+    pub output_silent: AtomicBool,
+    /// The number of frames in the last processed block.
+    pub last_block_samples: AtomicU32,
+    /// The most recent summary of the DSP load (see [`DspLoad`]). This is
+    /// shared instead of being sent as a message so that it can never take
+    /// up room in the channel which is needed by other messages.
+    pub dsp_load_average: AtomicF32,
+    pub dsp_load_peak: AtomicF32,
+    /// Whether or not low-priority nodes are being culled. See
+    /// [`VoiceCulling`].
+    pub under_cpu_pressure: AtomicBool,
+    /// The number of nodes which were culled in the last processed block.
+    pub num_culled_nodes: AtomicU32,
+}
+
+impl SharedProcessorState {
+    pub fn new() -> Self {
+        Self {
+            running: AtomicBool::new(true),
+            applied_msg_seq: AtomicU64::new(0),
+            output_silent: AtomicBool::new(true),
+            last_block_samples: AtomicU32::new(0),
+            dsp_load_average: AtomicF32::new(0.0),
+            dsp_load_peak: AtomicF32::new(0.0),
+            under_cpu_pressure: AtomicBool::new(false),
+            num_culled_nodes: AtomicU32::new(0),
+        }
+    }
+}
+
+/// A message sent from the context to the processor.
+///
+/// Each message is tagged with a sequence number when it is sent, starting
+/// at `1` and increasing by one for every message.
+pub(crate) enum ContextToProcessorMsg<C: Send + 'static> {
+    NewSchedule {
+        schedule_data: Box<ScheduleHeapData<C>>,
+        /// The length of the fade to use when swapping in this schedule, or
+        /// `None` to use [`FirewheelConfig::schedule_fade_secs`].
+        fade_samples: Option<usize>,
+    },
+    SetBypassed {
+        node_id: NodeID,
+        bypassed: bool,
+        delay: EventDelay,
+    },
+    SetPriority {
+        node_id: NodeID,
+        priority: Option<f32>,
+    },
+    /// Serialize the DSP state of the given node into `buffer` and send it
+    /// back with [`ProcessorToContextMsg::NodeState`], or with
+    /// [`ProcessorToContextMsg::ReturnStateBuffer`] if the node does not
+    /// exist.
+    SerializeNodeState {
+        node_id: NodeID,
+        buffer: Vec<u8>,
+    },
+    RestoreNodeState {
+        node_id: NodeID,
+        state: Vec<u8>,
+    },
+    SetInputGain(f32),
+    SetInputChannelMuted {
+        channel: usize,
+        muted: bool,
+    },
+    SetOutputChannelMap(Option<ArrayVec<u8, 64>>),
+    /// The sample rate of the stream has changed. This is sent after the
+    /// schedule containing the re-activated node processors.
+    SetSampleRate(u32),
+    ResetRunawayProtection,
+    #[cfg(feature = "metrics")]
+    SetTracingEnabled(bool),
+    Stop,
+}
+
+pub(crate) enum ProcessorToContextMsg<C: Send + 'static> {
+    ReturnSchedule(Box<ScheduleHeapData<C>>),
+    /// The given number of new schedules have been swapped in.
+    SchedulesSwapped(usize),
+    /// Runaway feedback was detected in the output, and the output is now
+    /// being attenuated.
+    RunawayDetected {
+        peak_db: f32,
+    },
+    /// The audio backend reported a glitch in the stream.
+    Glitch(GlitchEvent),
+    /// The serialized DSP state of a node.
+    NodeState {
+        node_id: NodeID,
+        state: Vec<u8>,
+    },
+    /// A node state buffer which is no longer needed by the processor, so
+    /// that it can be reused or deallocated on the main thread.
+    ReturnStateBuffer(Vec<u8>),
+    Dropped {
+        nodes: Arena<ProcessorEntry<C>>,
+        _schedule_data: Option<Box<ScheduleHeapData<C>>>,
+        user_cx: Option<C>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use firewheel_core::{
+        node::{AudioNode, AudioNodeInfo},
+        ChannelConfig, ChannelCount,
+    };
+
+    use super::*;
+    use crate::{error::NodeError, FirewheelConfig, FirewheelGraphCtx};
+
+    struct BadNode;
+
+    impl AudioNode<()> for BadNode {
+        fn debug_name(&self) -> &'static str {
+            "bad"
+        }
+
+        fn info(&self) -> AudioNodeInfo {
+            AudioNodeInfo {
+                num_min_supported_outputs: ChannelCount::STEREO,
+                num_max_supported_outputs: ChannelCount::STEREO,
+                default_channel_config: ChannelConfig {
+                    num_inputs: ChannelCount::ZERO,
+                    num_outputs: ChannelCount::STEREO,
+                },
+                ..Default::default()
+            }
+        }
+
+        fn activate(
+            &mut self,
+            _stream_info: &StreamInfo,
+            _channel_config: ChannelConfig,
+        ) -> Result<Box<dyn AudioNodeProcessor<()>>, Box<dyn std::error::Error>> {
+            Ok(Box::new(BadProcessor))
+        }
+    }
+
+    struct BadProcessor;
+
+    impl AudioNodeProcessor<()> for BadProcessor {
+        fn process(
+            &mut self,
+            _inputs: &[&[f32]],
+            outputs: &mut [&mut [f32]],
+            proc_info: ProcInfo,
+            _cx: &mut (),
+        ) -> ProcessStatus {
+            outputs[0][..proc_info.samples].fill(f32::NAN);
+            outputs[1][..proc_info.samples].fill(1e9);
+
+            ProcessStatus::all_outputs_filled()
+        }
+    }
+
+    fn process_bad_node(output_safety_limit: Option<f32>) -> Vec<f32> {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            output_safety_limit,
+            ..Default::default()
+        });
+        let mut processor = cx.activate(StreamInfo::default(), ()).unwrap();
+
+        let graph = cx.graph_mut().unwrap();
+        let node = graph.add_node(Box::new(BadNode), None).unwrap();
+        let graph_out = graph.graph_out_node();
+        graph.connect(node, 0, graph_out, 0, false).unwrap();
+        graph.connect(node, 1, graph_out, 1, false).unwrap();
+        cx.update();
+
+        let mut output = vec![0.0; 64 * 2];
+        processor.process_interleaved(
+            &[],
+            &mut output,
+            0,
+            2,
+            64,
+            ClockSeconds(0.0),
+            StreamStatus::empty(),
+        );
+
+        output
+    }
+
+    struct ConstNode(f32);
+
+    impl AudioNode<()> for ConstNode {
+        fn debug_name(&self) -> &'static str {
+            "const"
+        }
+
+        fn info(&self) -> AudioNodeInfo {
+            AudioNodeInfo {
+                num_min_supported_inputs: ChannelCount::MONO,
+                num_max_supported_inputs: ChannelCount::MONO,
+                num_min_supported_outputs: ChannelCount::MONO,
+                num_max_supported_outputs: ChannelCount::MONO,
+                default_channel_config: ChannelConfig {
+                    num_inputs: ChannelCount::MONO,
+                    num_outputs: ChannelCount::MONO,
+                },
+                ..Default::default()
+            }
+        }
+
+        fn activate(
+            &mut self,
+            _stream_info: &StreamInfo,
+            _channel_config: ChannelConfig,
+        ) -> Result<Box<dyn AudioNodeProcessor<()>>, Box<dyn std::error::Error>> {
+            Ok(Box::new(ConstProcessor(self.0)))
+        }
+    }
+
+    struct ConstProcessor(f32);
+
+    impl AudioNodeProcessor<()> for ConstProcessor {
+        fn process(
+            &mut self,
+            _inputs: &[&[f32]],
+            outputs: &mut [&mut [f32]],
+            proc_info: ProcInfo,
+            _cx: &mut (),
+        ) -> ProcessStatus {
+            outputs[0][..proc_info.samples].fill(self.0);
+
+            ProcessStatus::all_outputs_filled()
+        }
+    }
+
+    #[test]
+    fn sample_accurate_bypass() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_inputs: ChannelCount::MONO,
+            num_graph_outputs: ChannelCount::MONO,
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    num_stream_in_channels: 1,
+                    num_stream_out_channels: 1,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let graph = cx.graph_mut().unwrap();
+        let node = graph.add_node(Box::new(ConstNode(0.5)), None).unwrap();
+        let graph_in = graph.graph_in_node();
+        let graph_out = graph.graph_out_node();
+        graph.connect(graph_in, 0, node, 0, false).unwrap();
+        graph.connect(node, 0, graph_out, 0, false).unwrap();
+        assert!(graph.set_node_bypassed(
+            node,
+            true,
+            EventDelay::DelayUntilSample(ClockSamples(20))
+        ));
+        cx.update();
+
+        let input = vec![1.0; 64];
+        let mut output = vec![0.0; 64];
+        processor.process_interleaved(
+            &input,
+            &mut output,
+            1,
+            1,
+            64,
+            ClockSeconds(0.0),
+            StreamStatus::empty(),
+        );
+
+        assert!(output[..20].iter().all(|&s| s == 0.5));
+        assert!(output[20..].iter().all(|&s| s == 1.0));
+
+        // Un-bypass in the middle of the second block.
+        cx.graph_mut().unwrap().set_node_bypassed(
+            node,
+            false,
+            EventDelay::DelayUntilSample(ClockSamples(64 + 37)),
+        );
+        cx.update();
+
+        processor.process_interleaved(
+            &input,
+            &mut output,
+            1,
+            1,
+            64,
+            ClockSeconds(0.0),
+            StreamStatus::empty(),
+        );
+
+        assert!(output[..37].iter().all(|&s| s == 1.0));
+        assert!(output[37..].iter().all(|&s| s == 0.5));
+    }
+
+    #[test]
+    fn input_channel_mute() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_inputs: ChannelCount::STEREO,
+            num_graph_outputs: ChannelCount::STEREO,
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    num_stream_in_channels: 2,
+                    num_stream_out_channels: 2,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let graph = cx.graph_mut().unwrap();
+        let graph_in = graph.graph_in_node();
+        let graph_out = graph.graph_out_node();
+        graph.connect(graph_in, 0, graph_out, 0, false).unwrap();
+        graph.connect(graph_in, 1, graph_out, 1, false).unwrap();
+        cx.set_input_channel_muted(1, true);
+        assert!(cx.is_input_channel_muted(1));
+        assert!(!cx.is_input_channel_muted(0));
+        cx.update();
+
+        let input = vec![1.0; 64 * 2];
+        let mut output = vec![0.0; 64 * 2];
+        let mut process = |output: &mut [f32]| {
+            processor.process_interleaved(
+                &input,
+                output,
+                2,
+                2,
+                64,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+        };
+
+        // The mute is faded in to avoid clicks.
+        process(&mut output);
+        assert!(output[1] > 0.9);
+        assert!(output[64 * 2 - 1] < output[1]);
+
+        for _ in 0..100 {
+            process(&mut output);
+        }
+
+        assert!(output.chunks_exact(2).all(|s| s[0] == 1.0 && s[1] == 0.0));
+    }
+
+    #[test]
+    fn bypass_fade() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_inputs: ChannelCount::MONO,
+            num_graph_outputs: ChannelCount::MONO,
+            bypass_fade_secs: 64.0 / 44100.0,
+            fade_curve: FadeCurve::EqualPower,
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    num_stream_in_channels: 1,
+                    num_stream_out_channels: 1,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let graph = cx.graph_mut().unwrap();
+        let node = graph.add_node(Box::new(ConstNode(-0.5)), None).unwrap();
+        let graph_in = graph.graph_in_node();
+        let graph_out = graph.graph_out_node();
+        graph.connect(graph_in, 0, node, 0, false).unwrap();
+        graph.connect(node, 0, graph_out, 0, false).unwrap();
+        graph.set_node_bypassed(node, true, EventDelay::DelayUntilSample(ClockSamples(20)));
+        cx.update();
+
+        let input = vec![0.5; 64];
+        let process = |processor: &mut FirewheelProcessor<()>| {
+            let mut output = vec![0.0; 64];
+            processor.process_interleaved(
+                &input,
+                &mut output,
+                1,
+                1,
+                64,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+            output
+        };
+
+        let mut output = process(&mut processor);
+        output.extend_from_slice(&process(&mut processor));
+
+        // The output of the node is crossfaded with its input, starting at
+        // the scheduled frame.
+        let curve = FadeCurve::EqualPower;
+        assert!(output[..20].iter().all(|&s| s == -0.5));
+        for (i, &s) in output[20..84].iter().enumerate() {
+            let pos = (i + 1) as f32 / 64.0;
+            let expected = -0.5 * curve.gain(1.0 - pos) + 0.5 * curve.gain(pos);
+            assert!((s - expected).abs() < 1e-4);
+        }
+        assert!(output[84..].iter().all(|&s| s == 0.5));
+
+        // Un-bypassing is faded the same way.
+        cx.graph_mut()
+            .unwrap()
+            .set_node_bypassed(node, false, EventDelay::Immediate);
+        cx.update();
+
+        let output = process(&mut processor);
+        for (i, &s) in output.iter().enumerate() {
+            let pos = 1.0 - (i + 1) as f32 / 64.0;
+            let expected = -0.5 * curve.gain(1.0 - pos) + 0.5 * curve.gain(pos);
+            assert!((s - expected).abs() < 1e-4);
+        }
+        assert!(process(&mut processor).iter().all(|&s| s == -0.5));
+    }
+
+    #[test]
+    fn input_channel_mute_curve() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_inputs: ChannelCount::MONO,
+            num_graph_outputs: ChannelCount::MONO,
+            fade_curve: FadeCurve::Cosine,
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    num_stream_in_channels: 1,
+                    num_stream_out_channels: 1,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let graph = cx.graph_mut().unwrap();
+        let graph_in = graph.graph_in_node();
+        let graph_out = graph.graph_out_node();
+        graph.connect(graph_in, 0, graph_out, 0, false).unwrap();
+        cx.set_input_channel_muted(0, true);
+        cx.update();
+
+        let input = vec![1.0; 64];
+        let mut output = Vec::new();
+        for _ in 0..8 {
+            let mut block = vec![0.0; 64];
+            processor.process_interleaved(
+                &input,
+                &mut block,
+                1,
+                1,
+                64,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+            output.extend_from_slice(&block);
+        }
+
+        // The mute fades out over 10 ms following the configured curve.
+        let fade_frames = 441;
+        for (i, &s) in output[..fade_frames].iter().enumerate() {
+            let expected = FadeCurve::Cosine.gain(1.0 - (i + 1) as f32 / fade_frames as f32);
+            assert!((s - expected).abs() < 1e-4);
+        }
+        assert!(output[fade_frames..].iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn input_gain() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_inputs: ChannelCount::MONO,
+            num_graph_outputs: ChannelCount::MONO,
+            ..Default::default()
+        });
+        cx.set_input_gain(-6.0);
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    num_stream_in_channels: 1,
+                    num_stream_out_channels: 1,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let graph = cx.graph_mut().unwrap();
+        let graph_in = graph.graph_in_node();
+        let graph_out = graph.graph_out_node();
+        graph.connect(graph_in, 0, graph_out, 0, false).unwrap();
+        cx.update();
+
+        let input = vec![1.0; 64];
+        let mut output = vec![0.0; 64];
+        processor.process_interleaved(
+            &input,
+            &mut output,
+            1,
+            1,
+            64,
+            ClockSeconds(0.0),
+            StreamStatus::empty(),
+        );
+
+        assert!(output.iter().all(|&s| (s - 0.5).abs() < 0.01));
+    }
+
+    #[test]
+    fn dsp_load() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig::default());
+        let mut processor = cx.activate(StreamInfo::default(), ()).unwrap();
+        cx.update();
+
+        assert_eq!(cx.dsp_load(), Some(Default::default()));
+
+        let mut output = vec![0.0; 1024 * 2];
+        for _ in 0..10 {
+            processor.process_interleaved(
+                &[],
+                &mut output,
+                0,
+                2,
+                1024,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+        }
+        cx.update();
+
+        let dsp_load = cx.dsp_load().unwrap();
+        assert!(dsp_load.average > 0.0);
+        assert!(dsp_load.peak >= dsp_load.average);
+    }
+
+    #[test]
+    fn output_safety_limit() {
+        let output = process_bad_node(None);
+        assert!(output.chunks_exact(2).all(|s| s[0].is_nan() && s[1] == 1e9));
+
+        let output = process_bad_node(Some(1.0));
+        assert!(output.chunks_exact(2).all(|s| s[0] == 0.0 && s[1] == 1.0));
+
+        let output = process_bad_node(Some(0.5));
+        assert!(output.chunks_exact(2).all(|s| s[0] == 0.0 && s[1] == 0.5));
+    }
+
+    #[test]
+    fn is_running() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig::default());
+        assert!(!cx.is_running());
+
+        let mut processor = cx.activate(StreamInfo::default(), ()).unwrap();
+        cx.update();
+        assert!(cx.is_running());
+
+        let mut output = vec![0.0; 64 * 2];
+        let status = processor.process_interleaved(
+            &[],
+            &mut output,
+            0,
+            2,
+            64,
+            ClockSeconds(0.0),
+            StreamStatus::empty(),
+        );
+        assert_eq!(status, FirewheelProcessorStatus::Ok);
+        assert!(cx.is_running());
+
+        // The audio thread drops the processor without being told to stop.
+        drop(processor);
+        assert!(!cx.is_running());
+
+        cx.update();
+        assert!(!cx.is_running());
+    }
+
+    #[test]
+    fn output_is_silent() {
+        use crate::basic_nodes::beep_test::BeepTestNode;
+
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig::default());
+        assert!(cx.output_is_silent());
+
+        let mut processor = cx.activate(StreamInfo::default(), ()).unwrap();
+
+        let graph = cx.graph_mut().unwrap();
+        let beep = graph
+            .add_node(Box::new(BeepTestNode::new(440.0, -12.0, false)), None)
+            .unwrap();
+        let graph_out = graph.graph_out_node();
+        graph.connect(beep, 0, graph_out, 0, false).unwrap();
+        graph.connect(beep, 1, graph_out, 1, false).unwrap();
+        cx.update();
+
+        let mut output = vec![0.0; 64 * 2];
+        let mut process = |processor: &mut FirewheelProcessor<()>| {
+            processor.process_interleaved(
+                &[],
+                &mut output,
+                0,
+                2,
+                64,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+        };
+
+        process(&mut processor);
+        assert!(cx.output_is_silent());
+
+        cx.graph()
+            .node::<BeepTestNode>(beep)
+            .unwrap()
+            .set_enabled(true);
+        process(&mut processor);
+        assert!(!cx.output_is_silent());
+
+        cx.graph()
+            .node::<BeepTestNode>(beep)
+            .unwrap()
+            .set_enabled(false);
+        process(&mut processor);
+        assert!(cx.output_is_silent());
+    }
+
+    #[test]
+    fn messages_are_applied_in_order() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_inputs: ChannelCount::MONO,
+            num_graph_outputs: ChannelCount::MONO,
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    num_stream_in_channels: 1,
+                    num_stream_out_channels: 1,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+        assert_eq!(cx.sent_message_seq(), Some(0));
+        assert_eq!(cx.applied_message_seq(), Some(0));
+
+        // The input gain is sent right away.
+        cx.set_input_gain(-6.0);
+
+        let graph = cx.graph_mut().unwrap();
+        let node = graph.add_node(Box::new(ConstNode(0.25)), None).unwrap();
+        let graph_in = graph.graph_in_node();
+        let graph_out = graph.graph_out_node();
+        graph.connect(graph_in, 0, node, 0, false).unwrap();
+        graph.connect(node, 0, graph_out, 0, false).unwrap();
+        graph.set_node_bypassed(node, true, EventDelay::Immediate);
+
+        // The new schedule is sent before the bypass event, so the bypass
+        // event can only take effect if the messages are applied in order.
+        cx.update();
+        assert_eq!(cx.sent_message_seq(), Some(3));
+        assert_eq!(cx.applied_message_seq(), Some(0));
+
+        let input = vec![1.0; 64];
+        let mut output = vec![0.0; 64];
+        for _ in 0..100 {
+            processor.process_interleaved(
+                &input,
+                &mut output,
+                1,
+                1,
+                64,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+        }
+
+        assert_eq!(cx.applied_message_seq(), Some(3));
+        assert!(output.iter().all(|&s| (s - 0.5).abs() < 0.01));
+    }
+
+    #[test]
+    fn schedule_fade() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_outputs: ChannelCount::MONO,
+            schedule_fade_secs: 64.0 / 44100.0,
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    num_stream_out_channels: 1,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let graph = cx.graph_mut().unwrap();
+        let graph_out = graph.graph_out_node();
+        let node = graph.add_node(Box::new(ConstNode(0.5)), None).unwrap();
+        graph.connect(node, 0, graph_out, 0, false).unwrap();
+        cx.update();
+
+        let process = |processor: &mut FirewheelProcessor<()>| {
+            let mut output = vec![0.0; 32];
+            processor.process_interleaved(
+                &[],
+                &mut output,
+                0,
+                1,
+                32,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+            output
+        };
+
+        // The first schedule is used right away.
+        assert!(process(&mut processor).iter().all(|&s| s == 0.5));
+
+        // Replace the node with one that outputs the opposite value.
+        let graph = cx.graph_mut().unwrap();
+        graph.remove_node(node).unwrap();
+        let node = graph.add_node(Box::new(ConstNode(-0.5)), None).unwrap();
+        graph.connect(node, 0, graph_out, 0, false).unwrap();
+        cx.update();
+
+        let mut output = Vec::new();
+        for _ in 0..8 {
+            output.extend_from_slice(&process(&mut processor));
+        }
+
+        // The outputs of the old and the new node are crossfaded instead of
+        // jumping from one to the other.
+        assert_eq!(output[0], 0.5);
+        assert!(output[32].abs() < 1e-6);
+        assert!(output[64..].iter().all(|&s| s == -0.5));
+        assert!(output.windows(2).all(|w| (w[1] - w[0]).abs() < 0.02));
+    }
+
+    #[test]
+    fn schedule_crossfade_shares_nodes() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_outputs: ChannelCount::STEREO,
+            schedule_fade_secs: 64.0 / 44100.0,
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    num_stream_out_channels: 2,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let graph = cx.graph_mut().unwrap();
+        let graph_out = graph.graph_out_node();
+        let source = graph.add_node(Box::new(ConstNode(0.5)), None).unwrap();
+        let counting = graph
+            .add_node(
+                Box::new(CountingNode {
+                    calls: Arc::clone(&calls),
+                    silent_when_inputs_silent: false,
+                }),
+                None,
+            )
+            .unwrap();
+        graph.connect(source, 0, counting, 0, false).unwrap();
+        graph.connect(counting, 0, graph_out, 0, false).unwrap();
+        cx.update();
+
+        let process = |processor: &mut FirewheelProcessor<()>| {
+            let mut output = vec![0.0; 32 * 2];
+            processor.process_interleaved(
+                &[],
+                &mut output,
+                0,
+                2,
+                32,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+            output
+        };
+
+        let output = process(&mut processor);
+        assert!(output.chunks(2).all(|f| f == [0.5, 0.0]));
+
+        // Add a node to the second output while the shared nodes keep
+        // running.
+        let graph = cx.graph_mut().unwrap();
+        let added = graph.add_node(Box::new(ConstNode(0.25)), None).unwrap();
+        graph.connect(added, 0, graph_out, 1, false).unwrap();
+        cx.update();
+
+        let calls_before = calls.load(Ordering::Relaxed);
+        let mut output = Vec::new();
+        for _ in 0..4 {
+            output.extend_from_slice(&process(&mut processor));
+        }
+
+        // The shared node is processed once per block, and its output is
+        // used by both schedules, so only the new node fades in.
+        assert_eq!(calls.load(Ordering::Relaxed) - calls_before, 4);
+        let (left, right): (Vec<f32>, Vec<f32>) = output.chunks(2).map(|f| (f[0], f[1])).unzip();
+        assert!(left.iter().all(|&s| (s - 0.5).abs() < 1e-6));
+        assert_eq!(right[0], 0.0);
+        assert!((right[32] - 0.125).abs() < 1e-6);
+        assert!(right[64..].iter().all(|&s| s == 0.25));
+        assert!(right.windows(2).all(|w| (w[1] - w[0]).abs() < 0.01));
+    }
+
+    #[test]
+    fn messages_during_schedule_crossfade() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_outputs: ChannelCount::new(3).unwrap(),
+            schedule_fade_secs: 64.0 / 44100.0,
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    num_stream_out_channels: 3,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        // Returns the last frame of the output.
+        let process = |processor: &mut FirewheelProcessor<()>| {
+            let mut output = vec![0.0; 32 * 3];
+            processor.process_interleaved(
+                &[],
+                &mut output,
+                0,
+                3,
+                32,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+            [output[31 * 3], output[31 * 3 + 1], output[31 * 3 + 2]]
+        };
+
+        let graph = cx.graph_mut().unwrap();
+        let graph_out = graph.graph_out_node();
+        let a = graph.add_node(Box::new(ConstNode(0.5)), None).unwrap();
+        graph.connect(a, 0, graph_out, 0, false).unwrap();
+        cx.update();
+        assert_eq!(process(&mut processor), [0.5, 0.0, 0.0]);
+
+        // Start crossfading to a schedule with a new node.
+        let graph = cx.graph_mut().unwrap();
+        let b = graph.add_node(Box::new(ConstNode(0.25)), None).unwrap();
+        graph.connect(b, 0, graph_out, 1, false).unwrap();
+        cx.update();
+        let frame = process(&mut processor);
+        assert!(frame[1] > 0.0 && frame[1] < 0.25);
+
+        // The processor of the new node is already in use while the
+        // schedules are crossfaded, so this is applied to it right away.
+        let graph = cx.graph_mut().unwrap();
+        graph.set_node_bypassed(b, true, EventDelay::Immediate);
+        cx.update();
+
+        // This schedule is sent while the first crossfade is still in
+        // progress, so it waits for that crossfade to finish.
+        let graph = cx.graph_mut().unwrap();
+        let c = graph.add_node(Box::new(ConstNode(0.125)), None).unwrap();
+        graph.connect(c, 0, graph_out, 2, false).unwrap();
+        cx.update();
+        assert_eq!(process(&mut processor)[1..], [0.0, 0.0]);
+        assert_eq!(cx.applied_message_seq(), cx.sent_message_seq());
+
+        cx.update();
+        let frame = process(&mut processor);
+        assert!((frame[0] - 0.5).abs() < 1e-6);
+        assert!(frame[2] > 0.0 && frame[2] < 0.125);
+
+        for _ in 0..4 {
+            cx.update();
+            process(&mut processor);
+        }
+        assert_eq!(process(&mut processor), [0.5, 0.0, 0.125]);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn chrome_trace() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig::default());
+        cx.set_tracing_enabled(true);
+        let mut processor = cx.activate(StreamInfo::default(), ()).unwrap();
+
+        let graph = cx.graph_mut().unwrap();
+        let graph_out = graph.graph_out_node();
+        let node_a = graph.add_node(Box::new(ConstNode(0.5)), None).unwrap();
+        let node_b = graph.add_node(Box::new(ConstNode(0.25)), None).unwrap();
+        graph.connect(node_a, 0, graph_out, 0, false).unwrap();
+        graph.connect(node_b, 0, graph_out, 1, false).unwrap();
+        cx.update();
+
+        let mut output = vec![0.0; 64 * 2];
+        for _ in 0..3 {
+            processor.process_interleaved(
+                &[],
+                &mut output,
+                0,
+                2,
+                64,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+        }
+        cx.update();
+
+        for node_id in [node_a, node_b] {
+            let blocks: Vec<u64> = cx
+                .trace_events()
+                .iter()
+                .filter(|e| e.node_id == node_id)
+                .map(|e| e.block)
+                .collect();
+            assert_eq!(blocks, vec![0, 1, 2]);
+        }
+
+        let mut json = Vec::new();
+        cx.write_chrome_trace(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+
+        assert!(json.starts_with("{\"traceEvents\":["));
+        assert_eq!(
+            json.matches("\"ph\":\"X\"").count(),
+            cx.trace_events().len()
+        );
+        assert_eq!(json.matches("\"name\":\"const\"").count(), 6);
+    }
+
+    struct CountingNode {
+        calls: Arc<std::sync::atomic::AtomicUsize>,
+        silent_when_inputs_silent: bool,
+    }
+
+    impl AudioNode<()> for CountingNode {
+        fn debug_name(&self) -> &'static str {
+            "counting"
+        }
+
+        fn info(&self) -> AudioNodeInfo {
+            AudioNodeInfo {
+                num_min_supported_inputs: ChannelCount::MONO,
+                num_max_supported_inputs: ChannelCount::MONO,
+                num_min_supported_outputs: ChannelCount::MONO,
+                num_max_supported_outputs: ChannelCount::MONO,
+                default_channel_config: ChannelConfig {
+                    num_inputs: ChannelCount::MONO,
+                    num_outputs: ChannelCount::MONO,
+                },
+                silent_when_inputs_silent: self.silent_when_inputs_silent,
+                ..Default::default()
+            }
+        }
+
+        fn activate(
+            &mut self,
+            _stream_info: &StreamInfo,
+            _channel_config: ChannelConfig,
+        ) -> Result<Box<dyn AudioNodeProcessor<()>>, Box<dyn std::error::Error>> {
+            Ok(Box::new(CountingProcessor(Arc::clone(&self.calls))))
+        }
+    }
+
+    struct CountingProcessor(Arc<std::sync::atomic::AtomicUsize>);
+
+    impl AudioNodeProcessor<()> for CountingProcessor {
+        fn process(
+            &mut self,
+            inputs: &[&[f32]],
+            outputs: &mut [&mut [f32]],
+            proc_info: ProcInfo,
+            _cx: &mut (),
+        ) -> ProcessStatus {
+            self.0.fetch_add(1, Ordering::Relaxed);
+
+            if proc_info.in_silence_mask.is_channel_silent(0) {
+                return ProcessStatus::NoOutputsModified;
+            }
+
+            outputs[0][..proc_info.samples].copy_from_slice(&inputs[0][..proc_info.samples]);
+
+            ProcessStatus::all_outputs_filled()
+        }
+    }
+
+    fn process_counting_node(silent_when_inputs_silent: bool) -> (Vec<f32>, usize) {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_inputs: ChannelCount::MONO,
+            num_graph_outputs: ChannelCount::MONO,
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    num_stream_in_channels: 1,
+                    num_stream_out_channels: 1,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let graph = cx.graph_mut().unwrap();
+        let node = graph
+            .add_node(
+                Box::new(CountingNode {
+                    calls: Arc::clone(&calls),
+                    silent_when_inputs_silent,
+                }),
+                None,
+            )
+            .unwrap();
+        let graph_in = graph.graph_in_node();
+        let graph_out = graph.graph_out_node();
+        graph.connect(graph_in, 0, node, 0, false).unwrap();
+        graph.connect(node, 0, graph_out, 0, false).unwrap();
+        cx.update();
+
+        let mut output = Vec::new();
+        for input_val in [0.0, 0.0, 0.25, 0.0] {
+            let input = vec![input_val; 64];
+            let mut block = vec![1.0; 64];
+            processor.process_interleaved(
+                &input,
+                &mut block,
+                1,
+                1,
+                64,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+            output.extend_from_slice(&block);
+        }
+
+        (output, calls.load(Ordering::Relaxed))
+    }
+
+    #[test]
+    fn skip_silent_nodes() {
+        let (output, calls) = process_counting_node(true);
+        assert_eq!(calls, 1);
+
+        let (expected, expected_calls) = process_counting_node(false);
+        assert_eq!(expected_calls, 4);
+
+        assert_eq!(output, expected);
+        assert!(output[128..192].iter().all(|&s| s == 0.25));
+    }
+
+    #[test]
+    fn invalid_buffer_lengths() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig::default());
+        let mut processor = cx.activate(StreamInfo::default(), ()).unwrap();
+
+        let graph = cx.graph_mut().unwrap();
+        let node = graph.add_node(Box::new(ConstNode(0.5)), None).unwrap();
+        let graph_out = graph.graph_out_node();
+        graph.connect(node, 0, graph_out, 0, false).unwrap();
+        cx.update();
+
+        for (input_len, output_len, num_in_channels, num_out_channels, samples) in [
+            (0, 64 * 2, 0, 2, 64),
+            (0, 63 * 2, 0, 2, 64),
+            (0, 64 * 2 + 1, 0, 2, 64),
+            (64, 64 * 2, 0, 2, 64),
+            (0, 64 * 2, 1, 2, 64),
+            (0, 64 * 2, 0, 3, 64),
+            (0, 0, 0, 2, 64),
+            (0, 64 * 2, 0, 2, 0),
+            (0, 0, 0, 2, 0),
+        ] {
+            let input = vec![1.0; input_len];
+            let mut output = vec![1.0; output_len];
+
+            let status = processor.process_interleaved(
+                &input,
+                &mut output,
+                num_in_channels,
+                num_out_channels,
+                samples,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+
+            if input_len == samples * num_in_channels && output_len == samples * num_out_channels {
+                assert_eq!(status, FirewheelProcessorStatus::Ok);
+            } else {
+                assert_eq!(status, FirewheelProcessorStatus::InvalidBuffer);
+                assert!(output.iter().all(|&s| s == 0.0));
+            }
+        }
+
+        // The processor still works after being given invalid buffers.
+        let mut output = vec![0.0; 64 * 2];
+        let status = processor.process_interleaved(
+            &[],
+            &mut output,
+            0,
+            2,
+            64,
+            ClockSeconds(0.0),
+            StreamStatus::empty(),
+        );
+        assert_eq!(status, FirewheelProcessorStatus::Ok);
+        assert!(output.chunks_exact(2).all(|s| s[0] == 0.5 && s[1] == 0.0));
+    }
+
+    struct TrackedNode {
+        value: f32,
+        deactivated: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl AudioNode<()> for TrackedNode {
+        fn debug_name(&self) -> &'static str {
+            "tracked"
+        }
+
+        fn info(&self) -> AudioNodeInfo {
+            AudioNode::<()>::info(&ConstNode(self.value))
+        }
+
+        fn activate(
+            &mut self,
+            _stream_info: &StreamInfo,
+            _channel_config: ChannelConfig,
+        ) -> Result<Box<dyn AudioNodeProcessor<()>>, Box<dyn std::error::Error>> {
+            Ok(Box::new(ConstProcessor(self.value)))
+        }
+
+        fn deactivate(&mut self, processor: Option<Box<dyn AudioNodeProcessor<()>>>) {
+            if processor.is_some() {
+                self.deactivated.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    #[test]
+    fn replace_graph() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_inputs: ChannelCount::MONO,
+            num_graph_outputs: ChannelCount::MONO,
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    num_stream_in_channels: 1,
+                    num_stream_out_channels: 1,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let deactivated = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let add_chain = |graph: &mut crate::graph::AudioGraph<()>, values: &[f32]| {
+            let mut prev = graph.graph_in_node();
+            for &value in values {
+                let node = graph
+                    .add_node(
+                        Box::new(TrackedNode {
+                            value,
+                            deactivated: Arc::clone(&deactivated),
+                        }),
+                        None,
+                    )
+                    .unwrap();
+                graph.connect(prev, 0, node, 0, false).unwrap();
+                prev = node;
+            }
+            let graph_out = graph.graph_out_node();
+            graph.connect(prev, 0, graph_out, 0, false).unwrap();
+        };
+
+        add_chain(cx.graph_mut().unwrap(), &[0.1, 0.2, 0.3]);
+        cx.update();
+
+        let input = vec![0.0; 64];
+        let mut output = vec![0.0; 64];
+        let process = |processor: &mut FirewheelProcessor<()>, output: &mut [f32]| {
+            processor.process_interleaved(
+                &input,
+                output,
+                1,
+                1,
+                64,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+        };
+
+        process(&mut processor, &mut output);
+        assert!(output.iter().all(|&s| s == 0.3));
+
+        // Nodes which were added but never sent to the processor are
+        // deactivated right away.
+        cx.replace_graph(None, |graph| add_chain(graph, &[0.4]))
+            .unwrap();
+        assert_eq!(deactivated.load(Ordering::Relaxed), 0);
+        cx.replace_graph(None, |graph| add_chain(graph, &[0.5, 0.6]))
+            .unwrap();
+        assert_eq!(deactivated.load(Ordering::Relaxed), 1);
+        cx.update();
+
+        process(&mut processor, &mut output);
+        assert!(output.iter().all(|&s| s == 0.6));
+        assert_eq!(cx.graph().nodes().count(), 4);
+
+        // All of the old processors are returned to the context.
+        cx.update();
+        assert_eq!(deactivated.load(Ordering::Relaxed), 4);
+
+        // Replace the graph again with a crossfade over two blocks.
+        cx.replace_graph(Some(128.0 / 44_100.0), |graph| add_chain(graph, &[0.7]))
+            .unwrap();
+        cx.update();
+
+        process(&mut processor, &mut output);
+        let mut crossfade = output.clone();
+
+        // The old nodes keep running until the crossfade is done.
+        cx.update();
+        assert_eq!(deactivated.load(Ordering::Relaxed), 4);
+
+        process(&mut processor, &mut output);
+        crossfade.extend_from_slice(&output);
+        assert_eq!(crossfade[0], 0.6);
+        assert!((crossfade[64] - 0.65).abs() < 1e-6);
+        assert!(crossfade
+            .windows(2)
+            .all(|w| w[1] > w[0] && w[1] - w[0] < 0.001));
+
+        process(&mut processor, &mut output);
+        assert!(output.iter().all(|&s| s == 0.7));
+
+        cx.update();
+        assert_eq!(deactivated.load(Ordering::Relaxed), 6);
+    }
+
+    /// A node with an internal feedback loop whose gain is above unity.
+    struct UnstableNode;
+
+    impl AudioNode<()> for UnstableNode {
+        fn debug_name(&self) -> &'static str {
+            "unstable"
+        }
+
+        fn info(&self) -> AudioNodeInfo {
+            AudioNode::<()>::info(&ConstNode(0.0))
+        }
+
+        fn activate(
+            &mut self,
+            _stream_info: &StreamInfo,
+            _channel_config: ChannelConfig,
+        ) -> Result<Box<dyn AudioNodeProcessor<()>>, Box<dyn std::error::Error>> {
+            Ok(Box::new(UnstableProcessor(0.01)))
+        }
+    }
+
+    struct UnstableProcessor(f32);
+
+    impl AudioNodeProcessor<()> for UnstableProcessor {
+        fn process(
+            &mut self,
+            _inputs: &[&[f32]],
+            outputs: &mut [&mut [f32]],
+            proc_info: ProcInfo,
+            _cx: &mut (),
+        ) -> ProcessStatus {
+            for s in outputs[0][..proc_info.samples].iter_mut() {
+                self.0 *= 1.005;
+                *s = self.0;
+            }
+
+            ProcessStatus::all_outputs_filled()
+        }
+    }
+
+    fn process_unstable_node(
+        runaway_protection: Option<RunawayProtection>,
+    ) -> (FirewheelGraphCtx<()>, Vec<f32>) {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_outputs: ChannelCount::MONO,
+            output_safety_limit: None,
+            runaway_protection,
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    num_stream_out_channels: 1,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let graph = cx.graph_mut().unwrap();
+        let node = graph.add_node(Box::new(UnstableNode), None).unwrap();
+        let graph_out = graph.graph_out_node();
+        graph.connect(node, 0, graph_out, 0, false).unwrap();
+        cx.update();
+
+        let mut output = vec![0.0; 64 * 40];
+        for block in output.chunks_exact_mut(64) {
+            processor.process_interleaved(
+                &[],
+                block,
+                0,
+                1,
+                64,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+        }
+        cx.update();
+
+        (cx, output)
+    }
+
+    #[test]
+    fn runaway_protection() {
+        const DANGER_LEVEL: f32 = 1.0;
+
+        let (cx, output) = process_unstable_node(None);
+        assert_eq!(cx.runaway_detected(), None);
+        assert!(output.iter().any(|&s| s.abs() > DANGER_LEVEL));
+
+        let (mut cx, output) = process_unstable_node(Some(RunawayProtection::default()));
+        let peak_db = cx.runaway_detected().unwrap();
+        assert!(peak_db < 0.0);
+        assert!(output.iter().all(|&s| s.abs() < DANGER_LEVEL));
+        assert!(output[64 * 39..].iter().all(|&s| s == 0.0));
+
+        cx.reset_runaway_protection();
+        assert_eq!(cx.runaway_detected(), None);
+    }
+
+    fn process_culled_voices(max_dsp_load: f32) -> (FirewheelGraphCtx<()>, [NodeID; 3], Vec<f32>) {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_outputs: ChannelCount::new(3).unwrap(),
+            voice_culling: Some(VoiceCulling {
+                min_priority: 0.5,
+                max_dsp_load,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    num_stream_out_channels: 3,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let graph = cx.graph_mut().unwrap();
+        let graph_out = graph.graph_out_node();
+        let near = graph.add_node(Box::new(ConstNode(0.5)), None).unwrap();
+        let far = graph.add_node(Box::new(ConstNode(0.25)), None).unwrap();
+        let unprioritized = graph.add_node(Box::new(ConstNode(0.125)), None).unwrap();
+        graph.connect(near, 0, graph_out, 0, false).unwrap();
+        graph.connect(far, 0, graph_out, 1, false).unwrap();
+        graph
+            .connect(unprioritized, 0, graph_out, 2, false)
+            .unwrap();
+        assert!(graph.set_node_priority(near, Some(1.0)));
+        assert!(graph.set_node_priority(far, Some(0.1)));
+        cx.update();
+
+        let mut output = vec![0.0; 64 * 3];
+        for _ in 0..2 {
+            processor.process_interleaved(
+                &[],
+                &mut output,
+                0,
+                3,
+                64,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+        }
+        cx.update();
+
+        (cx, [near, far, unprioritized], output)
+    }
+
+    #[test]
+    fn voice_culling() {
+        // Never under CPU pressure.
+        let (cx, [near, far, unprioritized], output) = process_culled_voices(f32::INFINITY);
+        assert!(!cx.is_under_cpu_pressure());
+        assert_eq!(cx.culled_nodes().count(), 0);
+        assert_eq!(cx.num_culled_nodes(), 0);
+        assert!(output
+            .chunks_exact(3)
+            .all(|s| s[0] == 0.5 && s[1] == 0.25 && s[2] == 0.125));
+
+        // Always under CPU pressure.
+        let (cx, _, output) = process_culled_voices(0.0);
+        assert!(cx.is_under_cpu_pressure());
+        assert_eq!(cx.culled_nodes().collect::<Vec<_>>(), vec![far]);
+        assert_eq!(cx.num_culled_nodes(), 1);
+        assert!(cx.is_node_culled(far));
+        assert!(!cx.is_node_culled(near));
+        assert!(!cx.is_node_culled(unprioritized));
+        assert!(output
+            .chunks_exact(3)
+            .all(|s| s[0] == 0.5 && s[1] == 0.0 && s[2] == 0.125));
+    }
+
+    #[test]
+    fn voice_culling_hysteresis() {
+        let voice_culling = VoiceCulling {
+            max_dsp_load: 0.8,
+            resume_dsp_load: 0.6,
+            ..Default::default()
+        };
+
+        assert!(!voice_culling.is_under_pressure(0.7, false));
+        assert!(voice_culling.is_under_pressure(0.8, false));
+
+        // Culling lowers the load, but not far enough to stop culling.
+        assert!(voice_culling.is_under_pressure(0.7, true));
+        assert!(!voice_culling.is_under_pressure(0.5, true));
+
+        // A spike in a single block is smoothed out.
+        let mut meter = DspLoadMeter::default();
+        for _ in 0..100 {
+            meter.add(0.0005, 0.001);
+        }
+        meter.add(0.002, 0.001);
+        assert!(!voice_culling.is_under_pressure(meter.smoothed_load(), false));
+
+        // A sustained high load is not.
+        for _ in 0..500 {
+            meter.add(0.0009, 0.001);
+        }
+        assert!(voice_culling.is_under_pressure(meter.smoothed_load(), false));
+    }
+
+    /// A node which outputs its sample rate divided by `100_000` and
+    /// records every sample rate it was activated with.
+    struct SampleRateNode {
+        activations: Arc<std::sync::Mutex<Vec<u32>>>,
+        deactivated: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl AudioNode<()> for SampleRateNode {
+        fn debug_name(&self) -> &'static str {
+            "sample_rate"
+        }
+
+        fn info(&self) -> AudioNodeInfo {
+            AudioNode::<()>::info(&ConstNode(0.0))
+        }
+
+        fn activate(
+            &mut self,
+            stream_info: &StreamInfo,
+            _channel_config: ChannelConfig,
+        ) -> Result<Box<dyn AudioNodeProcessor<()>>, Box<dyn std::error::Error>> {
+            self.activations
+                .lock()
+                .unwrap()
+                .push(stream_info.sample_rate);
+            Ok(Box::new(ConstProcessor(
+                stream_info.sample_rate as f32 / 100_000.0,
+            )))
+        }
+
+        fn deactivate(&mut self, _processor: Option<Box<dyn AudioNodeProcessor<()>>>) {
+            self.deactivated.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn change_sample_rate() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_inputs: ChannelCount::MONO,
+            num_graph_outputs: ChannelCount::STEREO,
+            ..Default::default()
+        });
+        assert!(matches!(
+            cx.change_sample_rate(44100),
+            Err(crate::error::ChangeSampleRateError::NotActivated)
+        ));
+
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    sample_rate: 48000,
+                    num_stream_in_channels: 1,
+                    num_stream_out_channels: 2,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let activations = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let deactivated = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let new_node = || {
+            Box::new(SampleRateNode {
+                activations: Arc::clone(&activations),
+                deactivated: Arc::clone(&deactivated),
+            })
+        };
+
+        let graph = cx.graph_mut().unwrap();
+        let graph_in = graph.graph_in_node();
+        let graph_out = graph.graph_out_node();
+        let node = graph.add_node(new_node(), None).unwrap();
+        let bypassed = graph.add_node(new_node(), None).unwrap();
+        graph.connect(node, 0, graph_out, 0, false).unwrap();
+        graph.connect(graph_in, 0, bypassed, 0, false).unwrap();
+        graph.connect(bypassed, 0, graph_out, 1, false).unwrap();
+        graph.set_node_bypassed(bypassed, true, EventDelay::Immediate);
+        cx.update();
+
+        let input = vec![1.0; 64];
+        let mut output = vec![0.0; 64 * 2];
+        let process = |processor: &mut FirewheelProcessor<()>, output: &mut [f32]| {
+            processor.process_interleaved(
+                &input,
+                output,
+                1,
+                2,
+                64,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+        };
+
+        process(&mut processor, &mut output);
+        assert!(output.chunks_exact(2).all(|s| s[0] == 0.48 && s[1] == 1.0));
+
+        cx.change_sample_rate(44100).unwrap();
+        assert_eq!(cx.stream_info().unwrap().sample_rate, 44100);
+        assert_eq!(
+            *activations.lock().unwrap(),
+            vec![48000, 48000, 44100, 44100]
+        );
+
+        // The old processors keep running until the new schedule is sent.
+        process(&mut processor, &mut output);
+        assert!(output.chunks_exact(2).all(|s| s[0] == 0.48 && s[1] == 1.0));
+
+        // The re-activated nodes take over without a gap, and the bypassed
+        // node stays bypassed.
+        cx.update();
+        for _ in 0..4 {
+            process(&mut processor, &mut output);
+            assert!(output.chunks_exact(2).all(|s| s[0] == 0.441 && s[1] == 1.0));
+            cx.update();
+        }
+        assert_eq!(processor.stream_info.sample_rate, 44100);
+
+        // The replaced processors were dropped without being deactivated.
+        assert_eq!(deactivated.load(Ordering::Relaxed), 0);
+        assert_eq!(activations.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn remove_node_after_change_sample_rate() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_inputs: ChannelCount::MONO,
+            num_graph_outputs: ChannelCount::MONO,
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    sample_rate: 48000,
+                    num_stream_in_channels: 1,
+                    num_stream_out_channels: 1,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let activations = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let deactivated = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let graph = cx.graph_mut().unwrap();
+        let node = graph
+            .add_node(
+                Box::new(SampleRateNode {
+                    activations: Arc::clone(&activations),
+                    deactivated: Arc::clone(&deactivated),
+                }),
+                None,
+            )
+            .unwrap();
+        let graph_out = graph.graph_out_node();
+        graph.connect(node, 0, graph_out, 0, false).unwrap();
+        cx.update();
+
+        let input = vec![0.0; 64];
+        let mut output = vec![0.0; 64];
+        let mut process = |processor: &mut FirewheelProcessor<()>| {
+            processor.process_interleaved(
+                &input,
+                &mut output,
+                1,
+                1,
+                64,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+        };
+
+        process(&mut processor);
+        assert_eq!(processor.nodes.len(), 3);
+
+        // Remove the node while its re-activated processor is still waiting
+        // to be sent.
+        cx.change_sample_rate(44100).unwrap();
+        cx.graph_mut().unwrap().remove_node(node).unwrap();
+        assert_eq!(deactivated.load(Ordering::Relaxed), 1);
+
+        // The processor that was running is still removed from the audio
+        // thread.
+        cx.update();
+        process(&mut processor);
+        assert_eq!(processor.nodes.len(), 2);
+        assert!(!processor.nodes.contains(node.idx));
+
+        // A new node which reuses the slot does not inherit anything from
+        // the removed one.
+        let graph = cx.graph_mut().unwrap();
+        let new_node = graph.add_node(Box::new(ConstNode(0.5)), None).unwrap();
+        assert_eq!(new_node.idx.slot(), node.idx.slot());
+        graph.connect(new_node, 0, graph_out, 0, false).unwrap();
+        cx.update();
+        process(&mut processor);
+        cx.update();
+        assert_eq!(processor.nodes.len(), 3);
+        assert_eq!(deactivated.load(Ordering::Relaxed), 1);
+        assert_eq!(*activations.lock().unwrap(), vec![48000, 44100]);
+    }
+
+    #[test]
+    fn schedule_swap_pending() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_inputs: ChannelCount::MONO,
+            num_graph_outputs: ChannelCount::MONO,
+            schedule_fade_secs: 128.0 / 44100.0,
+            ..Default::default()
+        });
+        assert!(!cx.schedule_swap_pending());
+
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    num_stream_in_channels: 1,
+                    num_stream_out_channels: 1,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let mut output = vec![0.0; 64];
+        let mut process = |processor: &mut FirewheelProcessor<()>| {
+            processor.process_interleaved(
+                &[0.0; 64],
+                &mut output,
+                1,
+                1,
+                64,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+        };
+
+        // The first schedule is swapped in immediately.
+        cx.update();
+        assert!(cx.schedule_swap_pending());
+        process(&mut processor);
+        assert!(cx.schedule_swap_pending());
+        cx.update();
+        assert!(!cx.schedule_swap_pending());
+
+        // The next schedule is also swapped in right away, even though the
+        // old schedule keeps being crossfaded with it after that.
+        let graph = cx.graph_mut().unwrap();
+        let node = graph.add_node(Box::new(ConstNode(0.5)), None).unwrap();
+        let graph_out = graph.graph_out_node();
+        graph.connect(node, 0, graph_out, 0, false).unwrap();
+        cx.update();
+        assert!(cx.schedule_swap_pending());
+
+        process(&mut processor);
+        cx.update();
+        assert!(!cx.schedule_swap_pending());
+    }
+
+    fn new_swapped_channels_processor() -> (FirewheelGraphCtx<()>, FirewheelProcessor<()>) {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_inputs: ChannelCount::STEREO,
+            ..Default::default()
+        });
+        let processor = cx
+            .activate(
+                StreamInfo {
+                    num_stream_in_channels: 2,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+        cx.set_input_gain(-6.0);
+
+        let graph = cx.graph_mut().unwrap();
+        let graph_in = graph.graph_in_node();
+        let graph_out = graph.graph_out_node();
+        graph.connect(graph_in, 0, graph_out, 1, false).unwrap();
+        graph.connect(graph_in, 1, graph_out, 0, false).unwrap();
+        cx.update();
+
+        (cx, processor)
+    }
+
+    #[test]
+    fn mixed_format_processing() {
+        let samples = StreamInfo::default().max_block_samples as usize * 3 / 2;
+        let planar_in: Vec<Vec<f32>> = (0..2)
+            .map(|ch| {
+                (0..samples)
+                    .map(|i| (i as f32 * 0.01 + ch as f32).sin())
+                    .collect()
+            })
+            .collect();
+        let interleaved_in: Vec<f32> = (0..samples)
+            .flat_map(|i| [planar_in[0][i], planar_in[1][i]])
+            .collect();
+
+        // Process with interleaved buffers on both sides for reference.
+        let (_cx, mut processor) = new_swapped_channels_processor();
+        let mut expected = vec![0.0; samples * 2];
+        for _ in 0..2 {
+            let status = processor.process_interleaved(
+                &interleaved_in,
+                &mut expected,
+                2,
+                2,
+                samples,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+            assert_eq!(status, FirewheelProcessorStatus::Ok);
+        }
+        assert!(expected.iter().any(|&s| s != 0.0));
+
+        let (_cx, mut processor) = new_swapped_channels_processor();
+        let mut out_l = vec![0.0; samples];
+        let mut out_r = vec![0.0; samples];
+        for _ in 0..2 {
+            let status = processor.process_interleaved_in_planar_out(
+                &interleaved_in,
+                &mut [&mut out_l, &mut out_r],
+                2,
+                samples,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+            assert_eq!(status, FirewheelProcessorStatus::Ok);
+        }
+        let interleaved_out: Vec<f32> = (0..samples).flat_map(|i| [out_l[i], out_r[i]]).collect();
+        assert_eq!(interleaved_out, expected);
+
+        let (_cx, mut processor) = new_swapped_channels_processor();
+        let mut output = vec![0.0; samples * 2];
+        for _ in 0..2 {
+            let status = processor.process_planar_in_interleaved_out(
+                &[&planar_in[0], &planar_in[1]],
+                &mut output,
+                2,
+                samples,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+            assert_eq!(status, FirewheelProcessorStatus::Ok);
+        }
+        assert_eq!(output, expected);
+
+        // Planar channels which are too short are rejected.
+        let status = processor.process_planar_in_interleaved_out(
+            &[&planar_in[0][..samples - 1], &planar_in[1]],
+            &mut output,
+            2,
+            samples,
+            ClockSeconds(0.0),
+            StreamStatus::empty(),
+        );
+        assert_eq!(status, FirewheelProcessorStatus::InvalidBuffer);
+        assert!(output.iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn manual_flush_recompile_strategy() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_inputs: ChannelCount::MONO,
+            num_graph_outputs: ChannelCount::MONO,
+            recompile_strategy: crate::RecompileStrategy::ManualFlush,
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    num_stream_in_channels: 1,
+                    num_stream_out_channels: 1,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let mut output = vec![0.0; 64];
+        let process = |processor: &mut FirewheelProcessor<()>, output: &mut [f32]| {
+            processor.process_interleaved(
+                &[0.0; 64],
+                output,
+                1,
+                1,
+                64,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+        };
+
+        let graph = cx.graph_mut().unwrap();
+        let graph_out = graph.graph_out_node();
+        let a = graph.add_node(Box::new(ConstNode(0.5)), None).unwrap();
+        graph.connect(a, 0, graph_out, 0, false).unwrap();
+
+        // Nothing is sent to the processor until the graph is flushed.
+        for _ in 0..4 {
+            cx.update();
+            process(&mut processor, &mut output);
+            assert!(output.iter().all(|&s| s == 0.0));
+        }
+        assert_eq!(cx.sent_message_seq(), Some(0));
+
+        cx.flush().unwrap();
+        assert!(!cx.graph().needs_compile());
+        process(&mut processor, &mut output);
+        assert!(output.iter().all(|&s| s == 0.5));
+
+        // Edits made after the flush are held back together, including the
+        // bypass of an existing node.
+        let graph = cx.graph_mut().unwrap();
+        let b = graph.add_node(Box::new(ConstNode(0.25)), None).unwrap();
+        graph.disconnect(a, 0, graph_out, 0);
+        graph.connect(b, 0, graph_out, 0, false).unwrap();
+        graph.set_node_bypassed(b, true, EventDelay::Immediate);
+        cx.update();
+        process(&mut processor, &mut output);
+        assert!(output.iter().all(|&s| s == 0.5));
+
+        cx.flush().unwrap();
+        process(&mut processor, &mut output);
+        assert!(output.iter().all(|&s| s == 0.0));
+
+        // Switching back to immediate compiles on the next update.
+        cx.set_recompile_strategy(crate::RecompileStrategy::Immediate);
+        cx.graph_mut()
+            .unwrap()
+            .set_node_bypassed(b, false, EventDelay::Immediate);
+        cx.update();
+        process(&mut processor, &mut output);
+        assert!(output.iter().all(|&s| s == 0.25));
+    }
+
+    /// A very simple "reverb" which feeds its output back into itself.
+    struct FeedbackNode(f32);
+
+    impl AudioNode<()> for FeedbackNode {
+        fn debug_name(&self) -> &'static str {
+            "feedback"
+        }
+
+        fn info(&self) -> AudioNodeInfo {
+            AudioNodeInfo {
+                num_min_supported_inputs: ChannelCount::MONO,
+                num_max_supported_inputs: ChannelCount::MONO,
+                num_min_supported_outputs: ChannelCount::MONO,
+                num_max_supported_outputs: ChannelCount::MONO,
+                default_channel_config: ChannelConfig {
+                    num_inputs: ChannelCount::MONO,
+                    num_outputs: ChannelCount::MONO,
+                },
+                ..Default::default()
+            }
+        }
+
+        fn activate(
+            &mut self,
+            _stream_info: &StreamInfo,
+            _channel_config: ChannelConfig,
+        ) -> Result<Box<dyn AudioNodeProcessor<()>>, Box<dyn std::error::Error>> {
+            Ok(Box::new(FeedbackProcessor {
+                feedback: self.0,
+                state: 0.0,
+            }))
+        }
+    }
+
+    struct FeedbackProcessor {
+        feedback: f32,
+        state: f32,
+    }
+
+    impl AudioNodeProcessor<()> for FeedbackProcessor {
+        fn process(
+            &mut self,
+            inputs: &[&[f32]],
+            outputs: &mut [&mut [f32]],
+            proc_info: ProcInfo,
+            _cx: &mut (),
+        ) -> ProcessStatus {
+            for (&in_s, out_s) in inputs[0][..proc_info.samples]
+                .iter()
+                .zip(outputs[0][..proc_info.samples].iter_mut())
+            {
+                self.state = in_s + self.state * self.feedback;
+                *out_s = self.state;
+            }
+
+            ProcessStatus::all_outputs_filled()
+        }
+    }
+
+    fn render_impulse(feedback: f32, tail_capture: Option<TailCapture>) -> Vec<f32> {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_inputs: ChannelCount::MONO,
+            num_graph_outputs: ChannelCount::MONO,
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    max_block_samples: 256,
+                    num_stream_in_channels: 1,
+                    num_stream_out_channels: 1,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let graph = cx.graph_mut().unwrap();
+        let node = graph
+            .add_node(Box::new(FeedbackNode(feedback)), None)
+            .unwrap();
+        let graph_in = graph.graph_in_node();
+        let graph_out = graph.graph_out_node();
+        graph.connect(graph_in, 0, node, 0, false).unwrap();
+        graph.connect(node, 0, graph_out, 0, false).unwrap();
+        cx.update();
+
+        let mut impulse = vec![0.0; 1000];
+        impulse[0] = 1.0;
+
+        processor.render_offline(&impulse, 1, 1, impulse.len(), tail_capture)
+    }
+
+    #[test]
+    fn render_offline_tail_capture() {
+        // Without tail capture, the output is cut off at the end of the input.
+        let output = render_impulse(0.999, None);
+        assert_eq!(output.len(), 1000);
+        assert!(output[999] > 0.1);
+
+        // With tail capture, rendering continues until the tail has decayed.
+        let tail_capture = TailCapture {
+            silence_threshold_db: -60.0,
+            ..Default::default()
+        };
+        let output = render_impulse(0.999, Some(tail_capture));
+        assert!(output.len() > 1000);
+        assert_eq!(output.len() % 256, 1000 % 256);
+
+        let last_block = &output[output.len() - 256..];
+        assert!(last_block.iter().all(|s| s.abs() <= 0.001));
+        let prev_block = &output[output.len() - 512..output.len() - 256];
+        assert!(prev_block.iter().any(|s| s.abs() > 0.001));
+
+        // Tails which never decay are limited in length.
+        let output = render_impulse(
+            1.0,
+            Some(TailCapture {
+                max_tail_secs: 0.1,
+                ..Default::default()
+            }),
+        );
+        assert_eq!(output.len(), 1000 + 4410);
+    }
+
+    /// A node which outputs the result of a calculation that is denormal
+    /// unless denormals are flushed to zero.
+    struct DenormalNode {
+        preserve_denormals: bool,
+    }
+
+    impl AudioNode<()> for DenormalNode {
+        fn debug_name(&self) -> &'static str {
+            "denormal"
+        }
+
+        fn info(&self) -> AudioNodeInfo {
+            AudioNodeInfo {
+                num_min_supported_outputs: ChannelCount::MONO,
+                num_max_supported_outputs: ChannelCount::MONO,
+                default_channel_config: ChannelConfig {
+                    num_inputs: ChannelCount::ZERO,
+                    num_outputs: ChannelCount::MONO,
+                },
+                preserve_denormals: self.preserve_denormals,
+                ..Default::default()
+            }
+        }
+
+        fn activate(
+            &mut self,
+            _stream_info: &StreamInfo,
+            _channel_config: ChannelConfig,
+        ) -> Result<Box<dyn AudioNodeProcessor<()>>, Box<dyn std::error::Error>> {
+            Ok(Box::new(DenormalProcessor))
+        }
+    }
+
+    struct DenormalProcessor;
+
+    impl AudioNodeProcessor<()> for DenormalProcessor {
+        fn process(
+            &mut self,
+            _inputs: &[&[f32]],
+            outputs: &mut [&mut [f32]],
+            proc_info: ProcInfo,
+            _cx: &mut (),
+        ) -> ProcessStatus {
+            for s in outputs[0][..proc_info.samples].iter_mut() {
+                *s = std::hint::black_box(f32::MIN_POSITIVE) * std::hint::black_box(0.5);
+            }
+
+            ProcessStatus::all_outputs_filled()
+        }
+    }
+
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature = "sse"
+    ))]
+    #[test]
+    fn preserve_denormals() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            schedule_fade_secs: 0.0,
+            ..Default::default()
+        });
+        let mut processor = cx.activate(StreamInfo::default(), ()).unwrap();
+
+        let graph = cx.graph_mut().unwrap();
+        let exact = graph
+            .add_node(
+                Box::new(DenormalNode {
+                    preserve_denormals: true,
+                }),
+                None,
+            )
+            .unwrap();
+        let flushed = graph
+            .add_node(
+                Box::new(DenormalNode {
+                    preserve_denormals: false,
+                }),
+                None,
+            )
+            .unwrap();
+        let graph_out = graph.graph_out_node();
+        graph.connect(exact, 0, graph_out, 0, false).unwrap();
+        graph.connect(flushed, 0, graph_out, 1, false).unwrap();
+        cx.update();
+
+        let mut output = vec![1.0; 64 * 2];
+        processor.process_interleaved(
+            &[],
+            &mut output,
+            0,
+            2,
+            64,
+            ClockSeconds(0.0),
+            StreamStatus::empty(),
+        );
+
+        for frame in output.chunks_exact(2) {
+            assert!(frame[0].is_subnormal());
+            assert_eq!(frame[1], 0.0);
+        }
+    }
+
+    /// A one-pole lowpass filter.
+    struct LowpassNode(f32);
+
+    impl AudioNode<()> for LowpassNode {
+        fn debug_name(&self) -> &'static str {
+            "lowpass"
+        }
+
+        fn info(&self) -> AudioNodeInfo {
+            AudioNodeInfo {
+                num_min_supported_inputs: ChannelCount::MONO,
+                num_max_supported_inputs: ChannelCount::MONO,
+                num_min_supported_outputs: ChannelCount::MONO,
+                num_max_supported_outputs: ChannelCount::MONO,
+                default_channel_config: ChannelConfig {
+                    num_inputs: ChannelCount::MONO,
+                    num_outputs: ChannelCount::MONO,
+                },
+                ..Default::default()
+            }
+        }
+
+        fn activate(
+            &mut self,
+            _stream_info: &StreamInfo,
+            _channel_config: ChannelConfig,
+        ) -> Result<Box<dyn AudioNodeProcessor<()>>, Box<dyn std::error::Error>> {
+            Ok(Box::new(LowpassProcessor {
+                coeff: self.0,
+                z: 0.0,
+            }))
+        }
+    }
+
+    struct LowpassProcessor {
+        coeff: f32,
+        z: f32,
+    }
+
+    impl AudioNodeProcessor<()> for LowpassProcessor {
+        fn process(
+            &mut self,
+            inputs: &[&[f32]],
+            outputs: &mut [&mut [f32]],
+            proc_info: ProcInfo,
+            _cx: &mut (),
+        ) -> ProcessStatus {
+            for (&in_s, out_s) in inputs[0][..proc_info.samples]
+                .iter()
+                .zip(outputs[0][..proc_info.samples].iter_mut())
+            {
+                self.z += self.coeff * (in_s - self.z);
+                *out_s = self.z;
+            }
+
+            ProcessStatus::all_outputs_filled()
+        }
+
+        fn migrate_state_from(&mut self, old: &dyn AudioNodeProcessor<()>) {
+            if let Some(old) = old.downcast_ref::<LowpassProcessor>() {
+                self.z = old.z;
+            }
+        }
+
+        fn serialize_state(&self, out: &mut Vec<u8>) {
+            out.extend_from_slice(&self.z.to_le_bytes());
+        }
+
+        fn restore_state(&mut self, state: &[u8]) {
+            if let Ok(bytes) = state.try_into() {
+                self.z = f32::from_le_bytes(bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn replace_node_migrates_state() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_inputs: ChannelCount::MONO,
+            num_graph_outputs: ChannelCount::MONO,
+            schedule_fade_secs: 0.0,
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    num_stream_in_channels: 1,
+                    num_stream_out_channels: 1,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let graph = cx.graph_mut().unwrap();
+        let filter = graph.add_node(Box::new(LowpassNode(0.5)), None).unwrap();
+        let graph_in = graph.graph_in_node();
+        let graph_out = graph.graph_out_node();
+        graph.connect(graph_in, 0, filter, 0, false).unwrap();
+        graph.connect(filter, 0, graph_out, 0, false).unwrap();
+        cx.update();
+
+        let input = vec![1.0; 64];
+        let mut output = vec![0.0; 64];
+        let process = |processor: &mut FirewheelProcessor<()>, output: &mut [f32]| {
+            processor.process_interleaved(
+                &input,
+                output,
+                1,
+                1,
+                64,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+        };
+
+        process(&mut processor, &mut output);
+        assert!((output[63] - 1.0).abs() < 1e-6);
+
+        // The new filter carries on from the state of the old one instead of
+        // starting from silence.
+        let graph = cx.graph_mut().unwrap();
+        graph
+            .replace_node(filter, Box::new(LowpassNode(0.25)))
+            .unwrap();
+        assert!(graph
+            .node::<LowpassNode>(filter)
+            .is_some_and(|n| n.0 == 0.25));
+        cx.update();
+
+        process(&mut processor, &mut output);
+        assert!(output.iter().all(|&s| (s - 1.0).abs() < 1e-6));
+
+        // A processor of a different type is not migrated.
+        let graph = cx.graph_mut().unwrap();
+        graph
+            .replace_node(filter, Box::new(ConstNode(0.5)))
+            .unwrap();
+        cx.update();
+
+        process(&mut processor, &mut output);
+        assert!(output.iter().all(|&s| s == 0.5));
+
+        let graph = cx.graph_mut().unwrap();
+        graph
+            .replace_node(filter, Box::new(LowpassNode(0.5)))
+            .unwrap();
+        cx.update();
+
+        process(&mut processor, &mut output);
+        assert_eq!(output[0], 0.5);
+
+        assert!(matches!(
+            cx.graph_mut()
+                .unwrap()
+                .replace_node(graph_out, Box::new(LowpassNode(0.5))),
+            Err(NodeError::NodeNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn replace_then_remove_node() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_inputs: ChannelCount::MONO,
+            num_graph_outputs: ChannelCount::MONO,
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    num_stream_in_channels: 1,
+                    num_stream_out_channels: 1,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let deactivated = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let new_node = |value| {
+            Box::new(TrackedNode {
+                value,
+                deactivated: Arc::clone(&deactivated),
+            })
+        };
+
+        let graph = cx.graph_mut().unwrap();
+        let node = graph.add_node(new_node(0.5), None).unwrap();
+        let graph_out = graph.graph_out_node();
+        graph.connect(node, 0, graph_out, 0, false).unwrap();
+        cx.update();
+
+        let input = vec![0.0; 64];
+        let mut output = vec![0.0; 64];
+        let mut process = |processor: &mut FirewheelProcessor<()>| {
+            processor.process_interleaved(
+                &input,
+                &mut output,
+                1,
+                1,
+                64,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+        };
+
+        process(&mut processor);
+        assert_eq!(processor.nodes.len(), 3);
+
+        // The new node is deactivated right away since its processor was
+        // never sent to the audio thread.
+        let graph = cx.graph_mut().unwrap();
+        graph.replace_node(node, new_node(0.25)).unwrap();
+        graph.remove_node(node).unwrap();
+        assert_eq!(deactivated.load(Ordering::Relaxed), 1);
+
+        // The old node is deactivated once its running processor has been
+        // removed from the audio thread and returned.
+        cx.update();
+        process(&mut processor);
+        assert_eq!(processor.nodes.len(), 2);
+        cx.update();
+        assert_eq!(deactivated.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn output_channel_map() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_outputs: ChannelCount::new(4).unwrap(),
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    num_stream_out_channels: 4,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let graph = cx.graph_mut().unwrap();
+        let graph_out = graph.graph_out_node();
+        for (i, value) in [0.1, 0.2, 0.3, 0.4].into_iter().enumerate() {
+            let node = graph.add_node(Box::new(ConstNode(value)), None).unwrap();
+            graph.connect(node, 0, graph_out, i, false).unwrap();
+        }
+        cx.update();
+
+        assert_eq!(
+            cx.set_output_channel_map(Some(&[0, 1, 3])),
+            Err(crate::error::OutputChannelMapError::InvalidNumChannels {
+                expected: 4,
+                got: 3
+            })
+        );
+        assert_eq!(
+            cx.set_output_channel_map(Some(&[0, 1, 1, 2])),
+            Err(crate::error::OutputChannelMapError::InvalidChannel(1))
+        );
+        assert_eq!(
+            cx.set_output_channel_map(Some(&[0, 1, 2, 4])),
+            Err(crate::error::OutputChannelMapError::InvalidChannel(4))
+        );
+        assert_eq!(cx.output_channel_map(), None);
+
+        cx.set_output_channel_map(Some(&[0, 1, 3, 2])).unwrap();
+        assert_eq!(cx.output_channel_map(), Some(&[0, 1, 3, 2][..]));
+
+        let mut output = vec![0.0; 64 * 4];
+        processor.process_interleaved(
+            &[],
+            &mut output,
+            0,
+            4,
+            64,
+            ClockSeconds(0.0),
+            StreamStatus::empty(),
+        );
+        for frame in output.chunks_exact(4) {
+            assert_eq!(frame, [0.1, 0.2, 0.4, 0.3]);
+        }
+
+        let mut planar = vec![vec![0.0; 64]; 4];
+        let mut planar_refs: Vec<&mut [f32]> =
+            planar.iter_mut().map(|ch| ch.as_mut_slice()).collect();
+        processor.process_interleaved_in_planar_out(
+            &[],
+            &mut planar_refs,
+            0,
+            64,
+            ClockSeconds(0.0),
+            StreamStatus::empty(),
+        );
+        assert!(planar[2].iter().all(|&s| s == 0.4));
+        assert!(planar[3].iter().all(|&s| s == 0.3));
+
+        cx.set_output_channel_map(None).unwrap();
+        processor.process_interleaved(
+            &[],
+            &mut output,
+            0,
+            4,
+            64,
+            ClockSeconds(0.0),
+            StreamStatus::empty(),
+        );
+        assert_eq!(&output[..4], [0.1, 0.2, 0.3, 0.4]);
+    }
+
+    /// A node which records the size of every block it processes.
+    struct BlockSizeNode(Arc<std::sync::Mutex<Vec<usize>>>);
+
+    impl AudioNode<()> for BlockSizeNode {
+        fn debug_name(&self) -> &'static str {
+            "block_size"
+        }
+
+        fn info(&self) -> AudioNodeInfo {
+            AudioNodeInfo {
+                num_min_supported_outputs: ChannelCount::MONO,
+                num_max_supported_outputs: ChannelCount::MONO,
+                default_channel_config: ChannelConfig {
+                    num_inputs: ChannelCount::ZERO,
+                    num_outputs: ChannelCount::MONO,
+                },
+                ..Default::default()
+            }
+        }
+
+        fn activate(
+            &mut self,
+            _stream_info: &StreamInfo,
+            _channel_config: ChannelConfig,
+        ) -> Result<Box<dyn AudioNodeProcessor<()>>, Box<dyn std::error::Error>> {
+            Ok(Box::new(BlockSizeProcessor(Arc::clone(&self.0))))
+        }
+    }
+
+    struct BlockSizeProcessor(Arc<std::sync::Mutex<Vec<usize>>>);
+
+    impl AudioNodeProcessor<()> for BlockSizeProcessor {
+        fn process(
+            &mut self,
+            _inputs: &[&[f32]],
+            outputs: &mut [&mut [f32]],
+            proc_info: ProcInfo,
+            _cx: &mut (),
+        ) -> ProcessStatus {
+            self.0.lock().unwrap().push(proc_info.samples);
+            outputs[0][..proc_info.samples].fill(0.5);
+
+            ProcessStatus::all_outputs_filled()
+        }
+    }
+
+    fn process_block_sizes(
+        fixed_block_samples: Option<u32>,
+    ) -> (FirewheelGraphCtx<()>, Vec<usize>, Vec<f32>) {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_outputs: ChannelCount::MONO,
+            fixed_block_samples,
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    max_block_samples: 512,
+                    num_stream_out_channels: 1,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let block_sizes = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let graph = cx.graph_mut().unwrap();
+        let node = graph
+            .add_node(Box::new(BlockSizeNode(Arc::clone(&block_sizes))), None)
+            .unwrap();
+        let graph_out = graph.graph_out_node();
+        graph.connect(node, 0, graph_out, 0, false).unwrap();
+        cx.update();
+
+        let mut output = Vec::new();
+        for buffer_samples in [100, 300, 512] {
+            let mut buffer = vec![0.0; buffer_samples];
+            processor.process_interleaved(
+                &[],
+                &mut buffer,
+                0,
+                1,
+                buffer_samples,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+            output.extend_from_slice(&buffer);
+        }
+
+        let block_sizes = block_sizes.lock().unwrap().clone();
+        (cx, block_sizes, output)
+    }
+
+    #[test]
+    fn fixed_block_size() {
+        let (cx, block_sizes, output) = process_block_sizes(Some(128));
+
+        // Nodes only ever see blocks of the fixed size, no matter the size of
+        // the buffers from the backend.
+        assert_eq!(cx.block_size(), Some(crate::BlockSize::Fixed(128)));
+        assert_eq!(cx.stream_info().unwrap().max_block_samples, 128);
+        assert_eq!(block_sizes, vec![128; 7]);
+
+        // The output lags behind by one block.
+        assert_eq!(output.len(), 912);
+        assert!(output[..128].iter().all(|&s| s == 0.0));
+        assert!(output[128..].iter().all(|&s| s == 0.5));
+
+        let (cx, block_sizes, _) = process_block_sizes(None);
+        assert_eq!(
+            cx.block_size(),
+            Some(crate::BlockSize::Variable {
+                max_block_samples: 512,
+                last_block_samples: 512,
+            })
+        );
+        assert_eq!(block_sizes, vec![100, 300, 512]);
+    }
+
+    /// A node which records the time at the start of every block it
+    /// processes.
+    struct BlockClockNode(Arc<std::sync::Mutex<Vec<ClockSeconds>>>);
+
+    impl AudioNode<()> for BlockClockNode {
+        fn debug_name(&self) -> &'static str {
+            "block_clock"
+        }
+
+        fn info(&self) -> AudioNodeInfo {
+            ConstNode(0.0).info()
+        }
+
+        fn activate(
+            &mut self,
+            _stream_info: &StreamInfo,
+            _channel_config: ChannelConfig,
+        ) -> Result<Box<dyn AudioNodeProcessor<()>>, Box<dyn std::error::Error>> {
+            Ok(Box::new(BlockClockNode(Arc::clone(&self.0))))
+        }
+    }
+
+    impl AudioNodeProcessor<()> for BlockClockNode {
+        fn process(
+            &mut self,
+            _inputs: &[&[f32]],
+            outputs: &mut [&mut [f32]],
+            proc_info: ProcInfo,
+            _cx: &mut (),
+        ) -> ProcessStatus {
+            self.0.lock().unwrap().push(proc_info.clock_seconds.start);
+            outputs[0][..proc_info.samples].fill(0.5);
+
+            ProcessStatus::all_outputs_filled()
+        }
+    }
+
+    #[test]
+    fn fixed_block_clock_and_channel_map() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_outputs: ChannelCount::STEREO,
+            fixed_block_samples: Some(128),
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    max_block_samples: 512,
+                    num_stream_out_channels: 2,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+        let sample_rate = f64::from(cx.stream_info().unwrap().sample_rate);
+
+        let block_clocks = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let graph = cx.graph_mut().unwrap();
+        let node = graph
+            .add_node(Box::new(BlockClockNode(Arc::clone(&block_clocks))), None)
+            .unwrap();
+        let graph_out = graph.graph_out_node();
+        graph.connect(node, 0, graph_out, 0, false).unwrap();
+        cx.update();
+        cx.set_output_channel_map(Some(&[1, 0])).unwrap();
+
+        let mut output = Vec::new();
+        for buffer_samples in [100, 300, 512] {
+            let mut buffer = vec![0.0; buffer_samples * 2];
+            processor.process_interleaved(
+                &[],
+                &mut buffer,
+                0,
+                2,
+                buffer_samples,
+                ClockSeconds((output.len() / 2) as f64 / sample_rate),
+                StreamStatus::empty(),
+            );
+            output.extend_from_slice(&buffer);
+        }
+
+        // Each block starts at the time of its first frame, even when it is
+        // collected across two buffers.
+        let block_clocks = block_clocks.lock().unwrap().clone();
+        let offset = processor.main_to_internal_clock_offset.unwrap();
+        assert_eq!(block_clocks.len(), 7);
+        assert!((block_clocks[0].0 - offset.0).abs() < 1e-9);
+        for pair in block_clocks.windows(2) {
+            assert!(((pair[1].0 - pair[0].0) * sample_rate - 128.0).abs() < 1e-6);
+        }
+
+        // The output channel map is applied.
+        assert!(output[..128 * 2].iter().all(|&s| s == 0.0));
+        for frame in output[128 * 2..].chunks_exact(2) {
+            assert_eq!(frame, [0.0, 0.5]);
+        }
+    }
+
+    /// A node which outputs a different constant level on each of its two
+    /// output channels.
+    struct TwoLevelNode(f32, f32);
+
+    impl AudioNode<()> for TwoLevelNode {
+        fn debug_name(&self) -> &'static str {
+            "two_level"
+        }
+
+        fn info(&self) -> AudioNodeInfo {
+            AudioNodeInfo {
+                num_min_supported_outputs: ChannelCount::STEREO,
+                num_max_supported_outputs: ChannelCount::STEREO,
+                default_channel_config: ChannelConfig {
+                    num_inputs: ChannelCount::ZERO,
+                    num_outputs: ChannelCount::STEREO,
+                },
+                ..Default::default()
+            }
+        }
+
+        fn activate(
+            &mut self,
+            _stream_info: &StreamInfo,
+            _channel_config: ChannelConfig,
+        ) -> Result<Box<dyn AudioNodeProcessor<()>>, Box<dyn std::error::Error>> {
+            Ok(Box::new(TwoLevelProcessor(self.0, self.1)))
+        }
+    }
+
+    struct TwoLevelProcessor(f32, f32);
+
+    impl AudioNodeProcessor<()> for TwoLevelProcessor {
+        fn process(
+            &mut self,
+            _inputs: &[&[f32]],
+            outputs: &mut [&mut [f32]],
+            proc_info: ProcInfo,
+            _cx: &mut (),
+        ) -> ProcessStatus {
+            outputs[0][..proc_info.samples].fill(self.0);
+            outputs[1][..proc_info.samples].fill(self.1);
+            // Make the peak of the first channel a single sample.
+            outputs[0][10] = self.0 * 2.0;
+
+            ProcessStatus::all_outputs_filled()
+        }
+    }
+
+    #[test]
+    fn node_channel_peaks() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig::default());
+        let mut processor = cx.activate(StreamInfo::default(), ()).unwrap();
+
+        let graph = cx.graph_mut().unwrap();
+        let node = graph
+            .add_node(Box::new(TwoLevelNode(0.25, -0.75)), None)
+            .unwrap();
+        let graph_out = graph.graph_out_node();
+        graph.connect(node, 0, graph_out, 0, false).unwrap();
+        graph.connect(node, 1, graph_out, 1, false).unwrap();
+        cx.update();
+
+        let mut output = vec![0.0; 64 * 2];
+        let mut process = |processor: &mut FirewheelProcessor<()>| {
+            processor.process_interleaved(
+                &[],
+                &mut output,
+                0,
+                2,
+                64,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+        };
+
+        // Metering is disabled by default.
+        process(&mut processor);
+        assert_eq!(cx.graph().node_channel_peaks(node), None);
+
+        assert!(cx
+            .graph_mut()
+            .unwrap()
+            .set_node_metering_enabled(node, true));
+        process(&mut processor);
+        assert_eq!(cx.graph().node_channel_peaks(node), Some(vec![0.5, 0.75]));
+
+        // The peaks are reset once they are read.
+        assert_eq!(cx.graph().node_channel_peaks(node), Some(vec![0.0, 0.0]));
+
+        assert!(cx
+            .graph_mut()
+            .unwrap()
+            .set_node_metering_enabled(node, false));
+        assert_eq!(cx.graph().node_channel_peaks(node), None);
+    }
+
+    /// Feed an impulse into a feedback loop which sums the input with the
+    /// returned signal at half the gain, and return the output along with
+    /// the actual delay of the loop.
+    fn feedback_impulse_response(
+        fixed_block_samples: Option<u32>,
+        delay: crate::basic_nodes::FeedbackDelay,
+    ) -> (Vec<f32>, Option<usize>) {
+        use crate::basic_nodes::{feedback_nodes, FeedbackReturnNode, SumNode};
+
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_inputs: ChannelCount::MONO,
+            num_graph_outputs: ChannelCount::MONO,
+            fixed_block_samples,
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    max_block_samples: 64,
+                    num_stream_in_channels: 1,
+                    num_stream_out_channels: 1,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let graph = cx.graph_mut().unwrap();
+        let (send, ret) = feedback_nodes(delay, 0.5);
+        let send = graph
+            .add_node(
+                Box::new(send),
+                Some(ChannelConfig {
+                    num_inputs: ChannelCount::MONO,
+                    num_outputs: ChannelCount::ZERO,
+                }),
+            )
+            .unwrap();
+        let ret = graph
+            .add_node(
+                Box::new(ret),
+                Some(ChannelConfig {
+                    num_inputs: ChannelCount::ZERO,
+                    num_outputs: ChannelCount::MONO,
+                }),
+            )
+            .unwrap();
+        let sum = graph
+            .add_node(
+                Box::new(SumNode),
+                Some(ChannelConfig {
+                    num_inputs: ChannelCount::STEREO,
+                    num_outputs: ChannelCount::MONO,
+                }),
+            )
+            .unwrap();
+        let graph_in = graph.graph_in_node();
+        let graph_out = graph.graph_out_node();
+        graph.connect(graph_in, 0, sum, 0, false).unwrap();
+        graph.connect(ret, 0, sum, 1, false).unwrap();
+        graph.connect(sum, 0, send, 0, false).unwrap();
+        graph.connect(sum, 0, graph_out, 0, false).unwrap();
+        cx.update();
+
+        let mut input = vec![0.0; 256];
+        input[0] = 1.0;
+        let mut output = vec![0.0; 256];
+        processor.process_interleaved(
+            &input,
+            &mut output,
+            1,
+            1,
+            256,
+            ClockSeconds(0.0),
+            StreamStatus::empty(),
+        );
+
+        let delay_samples = cx
+            .graph()
+            .node::<FeedbackReturnNode>(ret)
+            .unwrap()
+            .delay_samples();
+
+        (output, delay_samples)
+    }
+
+    #[test]
+    fn feedback_delay() {
+        use crate::basic_nodes::FeedbackDelay;
+
+        let echoes = |output: &[f32]| -> Vec<(usize, f32)> {
+            output
+                .iter()
+                .enumerate()
+                .filter(|(_, &s)| s != 0.0)
+                .map(|(i, &s)| (i, s))
+                .take(4)
+                .collect()
+        };
+
+        // A loop of one block repeats the impulse once per block.
+        let (block_output, delay_samples) =
+            feedback_impulse_response(None, FeedbackDelay::Blocks(1));
+        assert_eq!(delay_samples, Some(64));
+        assert_eq!(
+            echoes(&block_output),
+            vec![(0, 1.0), (64, 0.5), (128, 0.25), (192, 0.125)]
+        );
+
+        // A delay of exactly one block is the same as `Blocks(1)`.
+        let (samples_output, delay_samples) =
+            feedback_impulse_response(None, FeedbackDelay::Samples(64));
+        assert_eq!(delay_samples, Some(64));
+        assert_eq!(samples_output, block_output);
+
+        // A one-sample delay can't be shorter than a block, so activating
+        // the nodes fails...
+        assert_eq!(FeedbackDelay::Samples(1).to_samples(64), None);
+        assert_eq!(FeedbackDelay::Blocks(0).to_samples(64), None);
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig::default());
+        let _processor = cx
+            .activate(
+                StreamInfo {
+                    max_block_samples: 64,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+        let (send, ret) = crate::basic_nodes::feedback_nodes(FeedbackDelay::Samples(1), 0.5);
+        let graph = cx.graph_mut().unwrap();
+        assert!(matches!(
+            graph.add_node(Box::new(send), None),
+            Err(NodeError::ActivationFailed { node_id: None, .. })
+        ));
+        assert!(matches!(
+            graph.add_node(Box::new(ret), None),
+            Err(NodeError::ActivationFailed { node_id: None, .. })
+        ));
+
+        // ...unless the graph is processed one sample at a time, in which
+        // case the loop repeats the impulse on every sample. This adds one
+        // sample of latency.
+        let (sample_output, delay_samples) =
+            feedback_impulse_response(Some(1), FeedbackDelay::Samples(1));
+        assert_eq!(delay_samples, Some(1));
+        assert_eq!(
+            echoes(&sample_output),
+            vec![(1, 1.0), (2, 0.5), (3, 0.25), (4, 0.125)]
+        );
+        assert_ne!(sample_output, block_output);
+    }
+
+    #[test]
+    fn restore_node_state() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig {
+            num_graph_inputs: ChannelCount::MONO,
+            num_graph_outputs: ChannelCount::MONO,
+            ..Default::default()
+        });
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    num_stream_in_channels: 1,
+                    num_stream_out_channels: 1,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let graph = cx.graph_mut().unwrap();
+        let filter = graph.add_node(Box::new(LowpassNode(0.01)), None).unwrap();
+        let graph_in = graph.graph_in_node();
+        let graph_out = graph.graph_out_node();
+        graph.connect(graph_in, 0, filter, 0, false).unwrap();
+        graph.connect(filter, 0, graph_out, 0, false).unwrap();
+        cx.update();
+
+        let process = |processor: &mut FirewheelProcessor<()>, input: &[f32]| {
+            let mut output = vec![0.0; 64];
+            processor.process_interleaved(
+                input,
+                &mut output,
+                1,
+                1,
+                64,
+                ClockSeconds(0.0),
+                StreamStatus::empty(),
+            );
+            output
+        };
+
+        let ramp: Vec<f32> = (0..64).map(|i| i as f32 / 64.0).collect();
+        let silence = vec![0.0; 64];
+
+        process(&mut processor, &ramp);
+
+        // The state is captured right before the next block is processed.
+        cx.request_node_state(filter, 4);
+        let continuation = process(&mut processor, &silence);
+        cx.update();
+        let saved_state = cx.take_node_state(filter).unwrap();
+        assert_eq!(saved_state.len(), 4);
+        assert_eq!(cx.take_node_state(filter), None);
+
+        // Change the state of the filter.
+        process(&mut processor, &ramp);
+        assert_ne!(process(&mut processor, &silence), continuation);
+
+        // Restoring the state continues exactly where it was saved.
+        let saved_state_ptr = saved_state.as_ptr();
+        cx.restore_node_state(filter, saved_state);
+        assert_eq!(process(&mut processor, &silence), continuation);
+
+        // The buffer is sent back and reused for the next request.
+        cx.update();
+        cx.request_node_state(filter, 4);
+        process(&mut processor, &silence);
+        cx.update();
+        let new_state = cx.take_node_state(filter).unwrap();
+        assert_eq!(new_state.as_ptr(), saved_state_ptr);
+    }
+
+    #[test]
+    fn glitch_log() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig::default());
+        let mut processor = cx.activate(StreamInfo::default(), ()).unwrap();
+
+        let mut output = vec![0.0; 64 * 2];
+        let mut process = |processor: &mut FirewheelProcessor<()>, secs: f64, status| {
+            processor.process_interleaved(&[], &mut output, 0, 2, 64, ClockSeconds(secs), status);
+        };
+
+        process(&mut processor, 0.5, StreamStatus::empty());
+        process(&mut processor, 1.0, StreamStatus::OUTPUT_UNDERFLOW);
+        process(&mut processor, 1.5, StreamStatus::empty());
+        process(
+            &mut processor,
+            2.5,
+            StreamStatus::INPUT_OVERFLOW | StreamStatus::OUTPUT_UNDERFLOW,
+        );
+        cx.update();
+
+        assert_eq!(
+            cx.glitches().copied().collect::<Vec<_>>(),
+            vec![
+                GlitchEvent {
+                    stream_time_secs: 1.0,
+                    kind: GlitchKind::OutputUnderflow,
+                },
+                GlitchEvent {
+                    stream_time_secs: 2.5,
+                    kind: GlitchKind::InputOverflow,
+                },
+                GlitchEvent {
+                    stream_time_secs: 2.5,
+                    kind: GlitchKind::OutputUnderflow,
+                },
+            ]
+        );
+        assert_eq!(cx.num_glitches(), 3);
+
+        cx.clear_glitches();
+        assert_eq!(cx.glitches().count(), 0);
+        assert_eq!(cx.num_glitches(), 0);
+
+        // Once the log is full, the oldest glitches are overwritten.
+        for i in 0..crate::GLITCH_LOG_CAPACITY + 8 {
+            process(&mut processor, i as f64, StreamStatus::OUTPUT_UNDERFLOW);
+            cx.update();
+        }
+        assert_eq!(cx.glitches().count(), crate::GLITCH_LOG_CAPACITY);
+        assert_eq!(cx.glitches().next().unwrap().stream_time_secs, 8.0);
+        assert_eq!(cx.num_glitches(), crate::GLITCH_LOG_CAPACITY as u64 + 8);
+    }
 }
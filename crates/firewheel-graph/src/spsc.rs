@@ -0,0 +1,223 @@
+//! The single-producer single-consumer channels used to send messages
+//! between the context and the processor.
+//!
+//! On native platforms this uses [`rtrb`]. When targeting WebAssembly, a
+//! simple lock-free ring buffer is used instead.
+
+#[cfg(not(target_family = "wasm"))]
+pub(crate) use rtrb::{Consumer, Producer, PushError};
+
+#[cfg(target_family = "wasm")]
+pub(crate) use wasm::{Consumer, Producer, PushError};
+
+/// Create a new channel with the given capacity.
+#[cfg(not(target_family = "wasm"))]
+pub(crate) fn channel<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    rtrb::RingBuffer::new(capacity)
+}
+
+/// Create a new channel with the given capacity.
+#[cfg(target_family = "wasm")]
+pub(crate) fn channel<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    wasm::channel(capacity)
+}
+
+// This is also compiled for tests so that it can be tested on native
+// platforms.
+#[cfg(any(target_family = "wasm", test))]
+mod wasm {
+    use std::{
+        cell::UnsafeCell,
+        mem::MaybeUninit,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    /// The error returned when the channel is full.
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub enum PushError<T> {
+        Full(T),
+    }
+
+    impl<T> std::fmt::Debug for PushError<T> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "Full(_)")
+        }
+    }
+
+    /// The error returned when the channel is empty.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PopError;
+
+    struct Shared<T> {
+        /// One more slot than the capacity, so that a full channel can be
+        /// told apart from an empty one.
+        slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+        /// The index of the next slot to pop from. Only written by the
+        /// consumer.
+        head: AtomicUsize,
+        /// The index of the next slot to push to. Only written by the
+        /// producer.
+        tail: AtomicUsize,
+    }
+
+    // The slots between `head` and `tail` are only accessed by the consumer,
+    // and the rest are only accessed by the producer.
+    unsafe impl<T: Send> Send for Shared<T> {}
+    unsafe impl<T: Send> Sync for Shared<T> {}
+
+    impl<T> Shared<T> {
+        fn next(&self, index: usize) -> usize {
+            if index + 1 == self.slots.len() {
+                0
+            } else {
+                index + 1
+            }
+        }
+    }
+
+    impl<T> Drop for Shared<T> {
+        fn drop(&mut self) {
+            let mut head = *self.head.get_mut();
+            let tail = *self.tail.get_mut();
+
+            while head != tail {
+                // SAFETY: The slots between `head` and `tail` have been
+                // pushed but not popped, so they are initialized.
+                unsafe { self.slots[head].get_mut().assume_init_drop() };
+                head = self.next(head);
+            }
+        }
+    }
+
+    pub fn channel<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+        let shared = Arc::new(Shared {
+            slots: (0..capacity + 1)
+                .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+                .collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        });
+
+        (
+            Producer {
+                shared: Arc::clone(&shared),
+            },
+            Consumer { shared },
+        )
+    }
+
+    pub struct Producer<T> {
+        shared: Arc<Shared<T>>,
+    }
+
+    impl<T> Producer<T> {
+        /// Push a value onto the channel.
+        ///
+        /// This never blocks or allocates.
+        pub fn push(&mut self, value: T) -> Result<(), PushError<T>> {
+            let tail = self.shared.tail.load(Ordering::Relaxed);
+            let next_tail = self.shared.next(tail);
+
+            if next_tail == self.shared.head.load(Ordering::Acquire) {
+                return Err(PushError::Full(value));
+            }
+
+            // SAFETY: The slot at `tail` is not between `head` and `tail`, so
+            // the consumer does not access it.
+            unsafe { (*self.shared.slots[tail].get()).write(value) };
+            self.shared.tail.store(next_tail, Ordering::Release);
+
+            Ok(())
+        }
+    }
+
+    pub struct Consumer<T> {
+        shared: Arc<Shared<T>>,
+    }
+
+    impl<T> Consumer<T> {
+        /// Pop a value from the channel.
+        ///
+        /// This never blocks.
+        pub fn pop(&mut self) -> Result<T, PopError> {
+            let head = self.shared.head.load(Ordering::Relaxed);
+
+            if head == self.shared.tail.load(Ordering::Acquire) {
+                return Err(PopError);
+            }
+
+            // SAFETY: The slot at `head` has been pushed but not popped, and
+            // the producer does not access it until `head` is advanced.
+            let value = unsafe { (*self.shared.slots[head].get()).assume_init_read() };
+            self.shared
+                .head
+                .store(self.shared.next(head), Ordering::Release);
+
+            Ok(value)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn push_and_pop() {
+            let (mut tx, mut rx) = channel(2);
+
+            assert_eq!(rx.pop(), Err(PopError));
+            assert_eq!(tx.push(1), Ok(()));
+            assert_eq!(tx.push(2), Ok(()));
+            assert_eq!(tx.push(3), Err(PushError::Full(3)));
+
+            // Wrap around the end of the ring.
+            for i in 3..10 {
+                assert_eq!(rx.pop(), Ok(i - 2));
+                assert_eq!(tx.push(i), Ok(()));
+            }
+            assert_eq!(rx.pop(), Ok(8));
+            assert_eq!(rx.pop(), Ok(9));
+            assert_eq!(rx.pop(), Err(PopError));
+        }
+
+        #[test]
+        fn drop_values_in_channel() {
+            let value = Arc::new(());
+            let (mut tx, rx) = channel(4);
+            tx.push(Arc::clone(&value)).unwrap();
+            tx.push(Arc::clone(&value)).unwrap();
+
+            drop(tx);
+            drop(rx);
+            assert_eq!(Arc::strong_count(&value), 1);
+        }
+
+        #[test]
+        fn across_threads() {
+            let (mut tx, mut rx) = channel(8);
+
+            let producer = std::thread::spawn(move || {
+                for i in 0..1000 {
+                    let mut value = i;
+                    while let Err(PushError::Full(v)) = tx.push(value) {
+                        value = v;
+                        std::thread::yield_now();
+                    }
+                }
+            });
+
+            let mut expected = 0;
+            while expected < 1000 {
+                if let Ok(value) = rx.pop() {
+                    assert_eq!(value, expected);
+                    expected += 1;
+                }
+            }
+
+            producer.join().unwrap();
+        }
+    }
+}
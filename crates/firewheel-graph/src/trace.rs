@@ -0,0 +1,117 @@
+//! Per-node timing traces which can be viewed in `chrome://tracing` (or
+//! any other viewer which supports the Chrome trace event format, such as
+//! [Perfetto](https://ui.perfetto.dev)).
+//!
+//! While tracing is enabled, the processor records how long each node took
+//! to process in every block. These events are sent to the context through
+//! a pre-allocated channel, so no allocations happen in the audio thread.
+//! If the context does not call [`FirewheelGraphCtx::update`] often enough
+//! and the channel fills up, then events are dropped.
+//!
+//! [`FirewheelGraphCtx::update`]: crate::FirewheelGraphCtx::update
+
+use std::{io, time::Instant};
+
+use crate::{graph::NodeID, spsc};
+
+/// The maximum number of trace events which can be waiting in the channel
+/// between the processor and the context.
+pub(crate) const TRACE_CHANNEL_CAPACITY: usize = 16384;
+
+/// The time it took a node to process a single block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceEvent {
+    /// The node which was processed.
+    pub node_id: NodeID,
+    /// The index of the processed block, starting from `0` when the
+    /// processor was created.
+    pub block: u64,
+    /// The time the node started processing, in microseconds since the
+    /// context was activated.
+    pub start_us: f64,
+    /// How long the node took to process, in microseconds.
+    pub duration_us: f64,
+}
+
+/// Records trace events in the audio thread.
+pub(crate) struct TraceRecorder {
+    tx: spsc::Producer<TraceEvent>,
+    start_instant: Instant,
+    enabled: bool,
+    block: u64,
+}
+
+impl TraceRecorder {
+    pub fn new(tx: spsc::Producer<TraceEvent>, start_instant: Instant, enabled: bool) -> Self {
+        Self {
+            tx,
+            start_instant,
+            enabled,
+            block: 0,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Call this once at the end of every processed block.
+    pub fn end_block(&mut self) {
+        self.block += 1;
+    }
+
+    pub fn record(&mut self, node_id: NodeID, start: Instant, end: Instant) {
+        // If the channel is full, then just drop this event.
+        let _ = self.tx.push(TraceEvent {
+            node_id,
+            block: self.block,
+            start_us: (start - self.start_instant).as_secs_f64() * 1_000_000.0,
+            duration_us: (end - start).as_secs_f64() * 1_000_000.0,
+        });
+    }
+}
+
+/// Write the given events as JSON in the Chrome trace event format.
+pub(crate) fn write_chrome_trace<W: io::Write>(
+    events: &[TraceEvent],
+    mut writer: W,
+) -> io::Result<()> {
+    writer.write_all(b"{\"traceEvents\":[")?;
+
+    for (i, event) in events.iter().enumerate() {
+        if i > 0 {
+            writer.write_all(b",")?;
+        }
+
+        write!(
+            writer,
+            "{{\"name\":\"{}\",\"cat\":\"node\",\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\"pid\":0,\"tid\":0,\"args\":{{\"node\":{},\"block\":{}}}}}",
+            escape_json(event.node_id.debug_name),
+            event.start_us,
+            event.duration_us,
+            event.node_id.idx.slot(),
+            event.block,
+        )?;
+    }
+
+    writer.write_all(b"]}")
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
@@ -0,0 +1,485 @@
+//! Writing the output of offline renders to WAV files.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// The size of the RIFF, fmt, and data headers in bytes.
+const HEADER_LEN: u32 = 44;
+/// The size of the headers in bytes when the fmt chunk uses
+/// `WAVE_FORMAT_EXTENSIBLE`, which adds 24 bytes to the chunk.
+const EXTENSIBLE_HEADER_LEN: u32 = HEADER_LEN + 24;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// The last 14 bytes of the `KSDATAFORMAT_SUBTYPE_*` GUIDs, which are
+/// the same for every format. The first two bytes are the format tag.
+const SUBFORMAT_GUID_TAIL: [u8; 14] = [
+    0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+];
+
+/// The format of the samples in a WAV file.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WavSampleFormat {
+    /// 16 bit signed integer PCM.
+    #[default]
+    Int16,
+    /// 24 bit signed integer PCM.
+    Int24,
+    /// 32 bit IEEE floating point. Samples are written as-is, so values
+    /// outside of the range `[-1.0, 1.0]` are not clipped.
+    Float32,
+}
+
+impl WavSampleFormat {
+    fn bits_per_sample(&self) -> u16 {
+        match self {
+            Self::Int16 => 16,
+            Self::Int24 => 24,
+            Self::Float32 => 32,
+        }
+    }
+
+    fn format_tag(&self) -> u16 {
+        match self {
+            Self::Int16 | Self::Int24 => WAVE_FORMAT_PCM,
+            Self::Float32 => WAVE_FORMAT_IEEE_FLOAT,
+        }
+    }
+}
+
+/// The properties of a WAV file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WavSpec {
+    pub sample_rate: u32,
+    pub num_channels: u16,
+    pub format: WavSampleFormat,
+    /// Whether or not to add triangular (TPDF) dither when converting to
+    /// an integer format, which turns the distortion caused by rounding
+    /// into a low level of constant noise. This has no effect with
+    /// [`WavSampleFormat::Float32`].
+    ///
+    /// By default this is set to `true`.
+    pub dither: bool,
+}
+
+impl Default for WavSpec {
+    fn default() -> Self {
+        Self {
+            sample_rate: 44100,
+            num_channels: 2,
+            format: WavSampleFormat::Int16,
+            dither: true,
+        }
+    }
+}
+
+/// Writes interleaved `f32` samples, such as the output of
+/// [`FirewheelProcessor::render_offline`], to a WAV file.
+///
+/// The sizes in the header are filled in by [`WavWriter::finalize`]. If the
+/// writer is dropped without being finalized, then it is finalized and any
+/// errors are ignored.
+///
+/// [`FirewheelProcessor::render_offline`]: crate::processor::FirewheelProcessor::render_offline
+pub struct WavWriter<W: Write + Seek> {
+    writer: Option<W>,
+    spec: WavSpec,
+    /// The size of the headers before the sample data in bytes.
+    header_len: u32,
+    /// The size of one frame in bytes.
+    block_align: u16,
+    /// The number of bytes of sample data written so far.
+    data_len: u32,
+    rng_state: u32,
+    buffer: Vec<u8>,
+}
+
+impl WavWriter<BufWriter<File>> {
+    /// Create a new WAV file at the given path, overwriting any existing
+    /// file.
+    pub fn create(path: impl AsRef<Path>, spec: WavSpec) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), spec)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// Create a new WAV writer and write the header.
+    ///
+    /// Files with more than two channels or with 24 bit samples are
+    /// written with a `WAVE_FORMAT_EXTENSIBLE` header, as required by the
+    /// format. The channels are assigned to speakers in the standard
+    /// order (front left, front right, front center, LFE, back left, ...).
+    pub fn new(mut writer: W, spec: WavSpec) -> io::Result<Self> {
+        if spec.num_channels == 0 || spec.sample_rate == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A WAV file must have at least one channel and a non-zero sample rate",
+            ));
+        }
+
+        let bits_per_sample = spec.format.bits_per_sample();
+        let too_large = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The number of channels or the sample rate is too large for a WAV file",
+            )
+        };
+        let block_align = spec
+            .num_channels
+            .checked_mul(bits_per_sample / 8)
+            .ok_or_else(too_large)?;
+        let byte_rate = spec
+            .sample_rate
+            .checked_mul(u32::from(block_align))
+            .ok_or_else(too_large)?;
+
+        let extensible = spec.num_channels > 2 || spec.format == WavSampleFormat::Int24;
+        let (header_len, format_tag, fmt_len) = if extensible {
+            (EXTENSIBLE_HEADER_LEN, WAVE_FORMAT_EXTENSIBLE, 40u32)
+        } else {
+            (HEADER_LEN, spec.format.format_tag(), 16u32)
+        };
+
+        let mut header = Vec::with_capacity(header_len as usize);
+        header.extend_from_slice(b"RIFF");
+        // The sizes are filled in when the writer is finalized.
+        header.extend_from_slice(&(header_len - 8).to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&fmt_len.to_le_bytes());
+        header.extend_from_slice(&format_tag.to_le_bytes());
+        header.extend_from_slice(&spec.num_channels.to_le_bytes());
+        header.extend_from_slice(&spec.sample_rate.to_le_bytes());
+        header.extend_from_slice(&byte_rate.to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&bits_per_sample.to_le_bytes());
+        if extensible {
+            // The size of the extension.
+            header.extend_from_slice(&22u16.to_le_bytes());
+            // The number of valid bits in each sample.
+            header.extend_from_slice(&bits_per_sample.to_le_bytes());
+            header.extend_from_slice(&channel_mask(spec.num_channels).to_le_bytes());
+            header.extend_from_slice(&spec.format.format_tag().to_le_bytes());
+            header.extend_from_slice(&SUBFORMAT_GUID_TAIL);
+        }
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+
+        debug_assert_eq!(header.len(), header_len as usize);
+        writer.write_all(&header)?;
+
+        Ok(Self {
+            writer: Some(writer),
+            spec,
+            header_len,
+            block_align,
+            data_len: 0,
+            rng_state: 0x9E37_79B9,
+            buffer: Vec::new(),
+        })
+    }
+
+    pub fn spec(&self) -> &WavSpec {
+        &self.spec
+    }
+
+    /// The number of frames written so far.
+    pub fn frames_written(&self) -> u64 {
+        u64::from(self.data_len) / u64::from(self.block_align)
+    }
+
+    /// Write a block of interleaved samples.
+    ///
+    /// The length of `samples` should be a multiple of the number of
+    /// channels.
+    pub fn write_interleaved(&mut self, samples: &[f32]) -> io::Result<()> {
+        if self.writer.is_none() {
+            return Ok(());
+        }
+
+        let bytes_per_sample = usize::from(self.spec.format.bits_per_sample() / 8);
+        let num_bytes = samples.len() * bytes_per_sample;
+
+        if u64::from(self.data_len) + num_bytes as u64 > u64::from(u32::MAX - self.header_len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The WAV file would be larger than 4 GiB",
+            ));
+        }
+
+        self.buffer.clear();
+        self.buffer.reserve(num_bytes);
+
+        match self.spec.format {
+            WavSampleFormat::Int16 => {
+                for &s in samples {
+                    let dither = self.next_dither();
+                    let s = quantize(s, i16::MAX as f32, dither) as i16;
+                    self.buffer.extend_from_slice(&s.to_le_bytes());
+                }
+            }
+            WavSampleFormat::Int24 => {
+                for &s in samples {
+                    let dither = self.next_dither();
+                    let s = quantize(s, 8_388_607.0, dither);
+                    self.buffer.extend_from_slice(&s.to_le_bytes()[..3]);
+                }
+            }
+            WavSampleFormat::Float32 => {
+                for &s in samples {
+                    self.buffer.extend_from_slice(&s.to_le_bytes());
+                }
+            }
+        }
+
+        self.writer.as_mut().unwrap().write_all(&self.buffer)?;
+        self.data_len += num_bytes as u32;
+
+        Ok(())
+    }
+
+    /// Fill in the sizes in the header and flush the writer.
+    ///
+    /// Returns the inner writer.
+    pub fn finalize(mut self) -> io::Result<W> {
+        let result = self.finalize_internal();
+        let writer = self.writer.take().unwrap();
+        result.map(|()| writer)
+    }
+
+    fn finalize_internal(&mut self) -> io::Result<()> {
+        let Some(writer) = self.writer.as_mut() else {
+            return Ok(());
+        };
+
+        // Chunks must have an even length.
+        let pad = self.data_len % 2;
+        if pad != 0 {
+            writer.write_all(&[0])?;
+        }
+
+        writer.seek(SeekFrom::Start(4))?;
+        writer.write_all(&(self.header_len - 8 + self.data_len + pad).to_le_bytes())?;
+        writer.seek(SeekFrom::Start(u64::from(self.header_len) - 4))?;
+        writer.write_all(&self.data_len.to_le_bytes())?;
+        writer.seek(SeekFrom::End(0))?;
+        writer.flush()
+    }
+
+    /// Returns the triangular dither to add to the next sample in units of
+    /// the least significant bit, or `0.0` if dithering is disabled.
+    fn next_dither(&mut self) -> f32 {
+        if !self.spec.dither {
+            return 0.0;
+        }
+
+        // The sum of two uniform random values has a triangular
+        // distribution.
+        let a = self.next_random();
+        let b = self.next_random();
+        a + b - 1.0
+    }
+
+    /// A uniform random value in the range `[0.0, 1.0)`, using a xorshift
+    /// generator.
+    fn next_random(&mut self) -> f32 {
+        let mut x = self.rng_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng_state = x;
+
+        (x >> 8) as f32 * (1.0 / (1u32 << 24) as f32)
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finalize_internal();
+    }
+}
+
+/// The speaker positions of the channels in a `WAVE_FORMAT_EXTENSIBLE`
+/// file. Channels beyond the 18 standard speaker positions are left
+/// unassigned.
+fn channel_mask(num_channels: u16) -> u32 {
+    const SPEAKER_FRONT_CENTER: u32 = 0x4;
+    const NUM_SPEAKER_POSITIONS: u16 = 18;
+
+    match num_channels {
+        1 => SPEAKER_FRONT_CENTER,
+        n if n <= NUM_SPEAKER_POSITIONS => (1 << n) - 1,
+        _ => 0,
+    }
+}
+
+/// Convert a sample to an integer with the given maximum value.
+#[inline]
+fn quantize(s: f32, max: f32, dither: f32) -> i32 {
+    (s.clamp(-1.0, 1.0) * max + dither)
+        .round()
+        .clamp(-max - 1.0, max) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use firewheel_core::{ChannelConfig, ChannelCount, StreamInfo};
+
+    use super::*;
+    use crate::{basic_nodes::beep_test::BeepTestNode, FirewheelConfig, FirewheelGraphCtx};
+
+    fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn render_beep_to_wav() {
+        let mut cx = FirewheelGraphCtx::<()>::new(FirewheelConfig::default());
+        let mut processor = cx
+            .activate(
+                StreamInfo {
+                    sample_rate: 48000,
+                    ..Default::default()
+                },
+                (),
+            )
+            .unwrap();
+
+        let graph = cx.graph_mut().unwrap();
+        let beep = graph
+            .add_node(
+                Box::new(BeepTestNode::new(440.0, -12.0, true)),
+                Some(ChannelConfig {
+                    num_inputs: ChannelCount::ZERO,
+                    num_outputs: ChannelCount::STEREO,
+                }),
+            )
+            .unwrap();
+        let graph_out = graph.graph_out_node();
+        graph.connect(beep, 0, graph_out, 0, false).unwrap();
+        graph.connect(beep, 1, graph_out, 1, false).unwrap();
+        cx.update();
+
+        // Render half a second. The graph has no inputs, so no input is
+        // given.
+        let output = processor.render_offline(&[], 0, 2, 24000, None);
+        assert_eq!(output.len(), 24000 * 2);
+        assert!(output.iter().any(|&s| s.abs() > 0.1));
+
+        let path = std::env::temp_dir().join(format!(
+            "firewheel_render_beep_to_wav_{}.wav",
+            std::process::id()
+        ));
+        let mut writer = WavWriter::create(
+            &path,
+            WavSpec {
+                sample_rate: 48000,
+                num_channels: 2,
+                format: WavSampleFormat::Int24,
+                dither: true,
+            },
+        )
+        .unwrap();
+        for block in output.chunks(1024 * 2) {
+            writer.write_interleaved(block).unwrap();
+        }
+        assert_eq!(writer.frames_written(), 24000);
+        writer.finalize().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(read_u32(&bytes, 4) as usize, bytes.len() - 8);
+        assert_eq!(&bytes[8..12], b"WAVE");
+        assert_eq!(&bytes[12..16], b"fmt ");
+        // 24 bit samples need the extensible format.
+        assert_eq!(read_u32(&bytes, 16), 40);
+        assert_eq!(read_u16(&bytes, 20), WAVE_FORMAT_EXTENSIBLE);
+        let num_channels = read_u16(&bytes, 22);
+        let sample_rate = read_u32(&bytes, 24);
+        let block_align = read_u16(&bytes, 32);
+        assert_eq!(num_channels, 2);
+        assert_eq!(sample_rate, 48000);
+        assert_eq!(read_u32(&bytes, 28), 48000 * 6);
+        assert_eq!(block_align, 6);
+        assert_eq!(read_u16(&bytes, 34), 24);
+        assert_eq!(read_u16(&bytes, 36), 22);
+        assert_eq!(read_u16(&bytes, 38), 24);
+        assert_eq!(read_u32(&bytes, 40), 0x3);
+        assert_eq!(read_u16(&bytes, 44), WAVE_FORMAT_PCM);
+        assert_eq!(&bytes[46..60], &SUBFORMAT_GUID_TAIL);
+        assert_eq!(&bytes[60..64], b"data");
+
+        let data_len = read_u32(&bytes, 64);
+        let duration_secs = f64::from(data_len / u32::from(block_align)) / f64::from(sample_rate);
+        assert_eq!(duration_secs, 0.5);
+
+        // The samples read back match the render to within the dither.
+        let data = &bytes[68..];
+        for (frame, &expected) in data.chunks_exact(3).zip(output.iter()) {
+            let s = i32::from_le_bytes([0, frame[0], frame[1], frame[2]]) >> 8;
+            let s = s as f32 / 8_388_607.0;
+            assert!((s - expected).abs() < 2.0 / 8_388_607.0);
+        }
+    }
+
+    #[test]
+    fn wav_header_formats() {
+        let header = |spec: WavSpec| {
+            WavWriter::new(io::Cursor::new(Vec::new()), spec)
+                .unwrap()
+                .finalize()
+                .unwrap()
+                .into_inner()
+        };
+
+        // 16 bit stereo uses the plain PCM header.
+        let bytes = header(WavSpec::default());
+        assert_eq!(bytes.len(), HEADER_LEN as usize);
+        assert_eq!(read_u16(&bytes, 20), WAVE_FORMAT_PCM);
+        assert_eq!(&bytes[36..40], b"data");
+
+        // More than two channels of floats use the extensible header with
+        // the float subformat.
+        let bytes = header(WavSpec {
+            num_channels: 6,
+            format: WavSampleFormat::Float32,
+            ..Default::default()
+        });
+        assert_eq!(bytes.len(), EXTENSIBLE_HEADER_LEN as usize);
+        assert_eq!(read_u16(&bytes, 20), WAVE_FORMAT_EXTENSIBLE);
+        assert_eq!(read_u32(&bytes, 40), 0x3F);
+        assert_eq!(read_u16(&bytes, 44), WAVE_FORMAT_IEEE_FLOAT);
+        assert_eq!(&bytes[60..64], b"data");
+
+        // Sizes which don't fit in the header are rejected instead of
+        // overflowing.
+        for spec in [
+            WavSpec {
+                num_channels: u16::MAX,
+                format: WavSampleFormat::Float32,
+                ..Default::default()
+            },
+            WavSpec {
+                sample_rate: u32::MAX / 4,
+                num_channels: 8,
+                ..Default::default()
+            },
+        ] {
+            let err = WavWriter::new(io::Cursor::new(Vec::new()), spec)
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}